@@ -0,0 +1,75 @@
+//! Endpoint addresses: `tcp://host:port`, `unix:///path` and `vsock://cid:port`
+
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use vsock::{VsockAddr, VsockListener, VsockStream};
+use xtransport::BoxedTransport;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Addr {
+    Tcp(String),
+    Unix(String),
+    Vsock { cid: u32, port: u32 },
+}
+
+impl Addr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if let Some(host_port) = text.strip_prefix("tcp://") {
+            return Ok(Addr::Tcp(host_port.to_string()));
+        }
+        if let Some(path) = text.strip_prefix("unix://") {
+            return Ok(Addr::Unix(path.to_string()));
+        }
+        if let Some(cid_port) = text.strip_prefix("vsock://") {
+            let (cid, port) = cid_port
+                .split_once(':')
+                .and_then(|(cid, port)| Some((cid.parse().ok()?, port.parse().ok()?)))
+                .ok_or_else(|| format!("invalid vsock address {:?}, expected vsock://cid:port", text))?;
+            return Ok(Addr::Vsock { cid, port });
+        }
+        Err(format!("unsupported address {:?}, expected tcp://, unix:// or vsock://", text))
+    }
+
+    pub fn connect(&self) -> Result<BoxedTransport, String> {
+        let stream: BoxedTransport = match self {
+            Addr::Tcp(host_port) => {
+                let stream = TcpStream::connect(host_port).map_err(|e| format!("connect {}: {}", host_port, e))?;
+                let _ = stream.set_nodelay(true);
+                Box::new(stream)
+            }
+            Addr::Unix(path) => Box::new(UnixStream::connect(path).map_err(|e| format!("connect {}: {}", path, e))?),
+            Addr::Vsock { cid, port } => Box::new(
+                VsockStream::connect(&VsockAddr::new(*cid, *port))
+                    .map_err(|e| format!("connect vsock {}:{}: {}", cid, port, e))?,
+            ),
+        };
+        Ok(stream)
+    }
+
+    /// Listen and return the first connection
+    pub fn accept_one(&self) -> Result<BoxedTransport, String> {
+        let stream: BoxedTransport = match self {
+            Addr::Tcp(host_port) => {
+                let listener = TcpListener::bind(host_port).map_err(|e| format!("listen {}: {}", host_port, e))?;
+                let (stream, _) = listener.accept().map_err(|e| format!("accept: {}", e))?;
+                let _ = stream.set_nodelay(true);
+                Box::new(stream)
+            }
+            Addr::Unix(path) => {
+                // A socket file left by an earlier run would make bind fail
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path).map_err(|e| format!("listen {}: {}", path, e))?;
+                let (stream, _) = listener.accept().map_err(|e| format!("accept: {}", e))?;
+                let _ = std::fs::remove_file(path);
+                Box::new(stream)
+            }
+            Addr::Vsock { cid, port } => {
+                let listener = VsockListener::bind(&VsockAddr::new(*cid, *port))
+                    .map_err(|e| format!("listen vsock {}:{}: {}", cid, port, e))?;
+                let (stream, _) = listener.accept().map_err(|e| format!("accept: {}", e))?;
+                Box::new(stream)
+            }
+        };
+        Ok(stream)
+    }
+}
@@ -0,0 +1,165 @@
+//! Ad-hoc file transfer over xtransport
+//!
+//! ```text
+//! xtransfer send <file> --to <addr> [--config <file>]
+//! xtransfer recv --listen <addr> [--out <file>] [--config <file>]
+//! ```
+//!
+//! Addresses are `tcp://host:port`, `unix:///path` or `vsock://cid:port`.
+//!
+//! A transfer is a header message (file size as 8 bytes little endian, then
+//! the file name), the content in messages of up to `CHUNK_SIZE` bytes and a
+//! trailer with the CRC32 of the whole content. The receiver answers with one
+//! byte, `VERIFIED` or `CHECKSUM_MISMATCH`, and the sender fails on the latter.
+
+mod addr;
+mod progress;
+
+use addr::Addr;
+use progress::Progress;
+use std::fs::File;
+use std::io::{Read, Write};
+use xtransport::{config::ENV_PREFIX, BoxedTransport, TransportConfig, XTransport};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+const VERIFIED: u8 = 0;
+const CHECKSUM_MISMATCH: u8 = 1;
+
+const USAGE: &str = "usage:
+  xtransfer send <file> --to <addr> [--config <file>]
+  xtransfer recv --listen <addr> [--out <file>] [--config <file>]
+addresses: tcp://host:port, unix:///path, vsock://cid:port";
+
+fn main() {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("send") => send(&args[1..]),
+        Some("recv") => recv(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("xtransfer: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Value following `--name`, if present
+fn option<'a>(args: &'a [String], name: &str) -> Result<Option<&'a str>, String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(pos) => args
+            .get(pos + 1)
+            .map(|value| Some(value.as_str()))
+            .ok_or_else(|| format!("{} needs a value", name)),
+        None => Ok(None),
+    }
+}
+
+/// Tuning from `--config <file>`, else from `XTRANSPORT_*` variables, else defaults
+fn load_config(args: &[String]) -> Result<TransportConfig, String> {
+    if let Some(path) = option(args, "--config")? {
+        let toml = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        return TransportConfig::from_toml_str(&toml).map_err(|_| format!("{}: invalid config", path));
+    }
+    if std::env::vars().any(|(name, _)| name.starts_with(ENV_PREFIX)) {
+        return TransportConfig::from_env().map_err(|_| "invalid XTRANSPORT_* variables".to_string());
+    }
+    Ok(TransportConfig::default())
+}
+
+fn send(args: &[String]) -> Result<(), String> {
+    let path = args.first().filter(|arg| !arg.starts_with("--")).ok_or(USAGE)?;
+    let to = Addr::parse(option(args, "--to")?.ok_or(USAGE)?)?;
+    let mut file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let size = file.metadata().map_err(|e| format!("{}: {}", path, e))?.len();
+    let name = std::path::Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut transport = XTransport::boxed(to.connect()?, load_config(args)?);
+    let mut header = size.to_le_bytes().to_vec();
+    header.extend_from_slice(name.as_bytes());
+    transport.send_message(&header).map_err(|e| format!("send: {}", e))?;
+
+    let progress = Progress::new("send", size);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut sent = 0u64;
+    while sent < size {
+        let len = file.read(&mut chunk).map_err(|e| format!("{}: {}", path, e))?;
+        if len == 0 {
+            return Err(format!("{}: file shrank during the transfer", path));
+        }
+        hasher.update(&chunk[..len]);
+        transport.send_message(&chunk[..len]).map_err(|e| format!("send: {}", e))?;
+        sent += len as u64;
+        progress.update(sent);
+    }
+    progress.finish();
+
+    let checksum = hasher.finalize();
+    transport.send_message(&checksum.to_le_bytes()).map_err(|e| format!("send: {}", e))?;
+    match receive(&mut transport)?.first() {
+        Some(&VERIFIED) => {
+            eprintln!("sent {} ({} bytes, crc32 {:08x}), verified by the receiver", name, size, checksum);
+            Ok(())
+        }
+        Some(&CHECKSUM_MISMATCH) => Err("receiver reports a checksum mismatch".to_string()),
+        _ => Err("unexpected reply from the receiver".to_string()),
+    }
+}
+
+fn recv(args: &[String]) -> Result<(), String> {
+    let listen = Addr::parse(option(args, "--listen")?.ok_or(USAGE)?)?;
+    let mut transport = XTransport::boxed(listen.accept_one()?, load_config(args)?);
+
+    let header = receive(&mut transport)?;
+    if header.len() < 8 {
+        return Err("invalid transfer header".to_string());
+    }
+    let size = u64::from_le_bytes(header[..8].try_into().expect("8 bytes"));
+    let name = String::from_utf8_lossy(&header[8..]).into_owned();
+    // Only the sender's base name is used, never a path it chose
+    let out = match option(args, "--out")? {
+        Some(out) => out.to_string(),
+        None => std::path::Path::new(&name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| !name.is_empty())
+            .ok_or("sender gave no file name, use --out")?,
+    };
+    let mut file = File::create(&out).map_err(|e| format!("{}: {}", out, e))?;
+
+    let progress = Progress::new("recv", size);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut received = 0u64;
+    while received < size {
+        let chunk = receive(&mut transport)?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).map_err(|e| format!("{}: {}", out, e))?;
+        received += chunk.len() as u64;
+        progress.update(received);
+    }
+    progress.finish();
+    file.flush().map_err(|e| format!("{}: {}", out, e))?;
+
+    let trailer = receive(&mut transport)?;
+    let expected = trailer
+        .get(..4)
+        .map(|crc| u32::from_le_bytes(crc.try_into().expect("4 bytes")))
+        .ok_or("invalid transfer trailer")?;
+    let checksum = hasher.finalize();
+    let verdict = if received == size && checksum == expected { VERIFIED } else { CHECKSUM_MISMATCH };
+    transport.send_message(&[verdict]).map_err(|e| format!("send: {}", e))?;
+    if verdict != VERIFIED {
+        return Err(format!("checksum mismatch: got {:08x}, sender has {:08x}", checksum, expected));
+    }
+    eprintln!("received {} into {} ({} bytes, crc32 {:08x} verified)", name, out, received, checksum);
+    Ok(())
+}
+
+fn receive(transport: &mut XTransport<BoxedTransport>) -> Result<Vec<u8>, String> {
+    transport.recv_message().map_err(|e| format!("receive: {}", e))
+}
@@ -0,0 +1,47 @@
+//! Single-line progress bar on stderr
+
+use std::io::Write;
+use std::time::Instant;
+
+const BAR_WIDTH: usize = 30;
+
+pub struct Progress {
+    label: &'static str,
+    total: u64,
+    start: Instant,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: u64) -> Self {
+        Progress {
+            label,
+            total,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn update(&self, done: u64) {
+        let fraction = if self.total == 0 { 1.0 } else { done as f64 / self.total as f64 };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { done as f64 / elapsed / 1024.0 / 1024.0 } else { 0.0 };
+        let mut stderr = std::io::stderr();
+        let _ = write!(
+            stderr,
+            "\r{} [{}{}] {:>3}% {:.1}/{:.1} MiB {:.1} MiB/s",
+            self.label,
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            (fraction * 100.0) as u32,
+            done as f64 / 1024.0 / 1024.0,
+            self.total as f64 / 1024.0 / 1024.0,
+            rate
+        );
+        let _ = stderr.flush();
+    }
+
+    pub fn finish(&self) {
+        self.update(self.total);
+        eprintln!();
+    }
+}
@@ -1,7 +1,7 @@
+mod script;
+
 use log::info;
-use std::os::unix::net::UnixStream;
 use vsock::{VsockAddr, VsockStream};
-use std::time::Instant;
 use xtransport::{TransportConfig, XTransport};
 
 const DATA_SIZE: usize =  2 * 1024; // 1 MB
@@ -24,33 +24,42 @@ fn main() {
     let stream = VsockStream::connect(&addr).expect("Failed to connect to server");
     info!("Connected!");
 
-    let mut transport = XTransport::new(stream, TransportConfig::default().with_ack(false));
+    let config = TransportConfig::load(TransportConfig::default().with_ack(false)).expect("Invalid transport config");
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(pos) = args.iter().position(|arg| arg == "--script") {
+        let path = args.get(pos + 1).expect("--script needs a file");
+        script::run_file(stream, config, path);
+        return;
+    }
+    let mut transport = XTransport::new(stream, config);
 
     // Send 100MB data
     info!("Sending {} MB of data...", DATA_SIZE / 1024 / 1024);
     let data = vec![0xAB; DATA_SIZE];
 
-    let start = Instant::now();
+    transport.reset_stats();
     transport
         .send_message(&data)
         .expect("Failed to send message");
-    let elapsed = start.elapsed();
-    let speed = (DATA_SIZE as f64 / 1024.0 / 1024.0) / elapsed.as_secs_f64();
+    let stats = transport.stats();
+    let elapsed = stats.elapsed_micros as f64 / 1_000_000.0;
+    let speed = stats.send_throughput() / 1024.0 / 1024.0;
 
     info!("=== Send Complete ===");
     info!("Total sent: {} MB", DATA_SIZE / 1024 / 1024);
-    info!("Time: {:.2} seconds", elapsed.as_secs_f64());
+    info!("Time: {:.2} seconds", elapsed);
     info!("Speed: {:.2} MB/s", speed);
 
     // Receive data from server
     info!("Receiving data from server...");
-    let start = Instant::now();
+    transport.reset_stats();
     let recv_data = transport.recv_message().expect("Failed to receive message");
-    let elapsed = start.elapsed();
-    let speed = (recv_data.len() as f64 / 1024.0 / 1024.0) / elapsed.as_secs_f64();
+    let stats = transport.stats();
+    let elapsed = stats.elapsed_micros as f64 / 1_000_000.0;
+    let speed = stats.recv_throughput() / 1024.0 / 1024.0;
 
     info!("=== Receive Complete ===");
     info!("Total received: {} MB", recv_data.len() / 1024 / 1024);
-    info!("Time: {:.2} seconds", elapsed.as_secs_f64());
+    info!("Time: {:.2} seconds", elapsed);
     info!("Speed: {:.2} MB/s", speed);
 }
@@ -0,0 +1,129 @@
+//! Scripted test scenarios run over one connection
+//!
+//! One step per line; `#` starts a comment:
+//!
+//! ```text
+//! send 4096 0xab      # send a 4096-byte message filled with 0xab (fill defaults to 0)
+//! recv 1048576 0xcd   # receive a message, asserting its size and fill byte
+//! recv                # receive a message of any size
+//! sleep 100           # pause for 100 ms
+//! ```
+//!
+//! Size and fill are checked only when given; `*` skips the size check while
+//! still checking the fill.
+
+use log::info;
+use std::time::{Duration, Instant};
+use xtransport::{Read, TransportConfig, Write, XTransport};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Send { size: usize, fill: u8 },
+    Recv { size: Option<usize>, fill: Option<u8> },
+    Sleep { millis: u64 },
+}
+
+/// A step with the script line it came from
+pub struct Line {
+    pub number: usize,
+    pub step: Step,
+}
+
+pub fn parse(text: &str) -> Result<Vec<Line>, String> {
+    let mut lines = Vec::new();
+    for (index, raw) in text.lines().enumerate() {
+        let number = index + 1;
+        let words: Vec<&str> = raw.split('#').next().unwrap_or("").split_whitespace().collect();
+        let step = match words.as_slice() {
+            [] => continue,
+            ["send", size] => Step::Send { size: parse_size(size, number)?, fill: 0 },
+            ["send", size, fill] => Step::Send {
+                size: parse_size(size, number)?,
+                fill: parse_byte(fill, number)?,
+            },
+            ["recv"] => Step::Recv { size: None, fill: None },
+            ["recv", size] => Step::Recv { size: parse_any_size(size, number)?, fill: None },
+            ["recv", size, fill] => Step::Recv {
+                size: parse_any_size(size, number)?,
+                fill: Some(parse_byte(fill, number)?),
+            },
+            ["sleep", millis] => Step::Sleep {
+                millis: millis.parse().map_err(|_| format!("line {}: invalid delay {:?}", number, millis))?,
+            },
+            _ => return Err(format!("line {}: unknown step {:?}", number, raw.trim())),
+        };
+        lines.push(Line { number, step });
+    }
+    Ok(lines)
+}
+
+fn parse_size(word: &str, number: usize) -> Result<usize, String> {
+    word.parse().map_err(|_| format!("line {}: invalid size {:?}", number, word))
+}
+
+fn parse_any_size(word: &str, number: usize) -> Result<Option<usize>, String> {
+    match word {
+        "*" => Ok(None),
+        word => parse_size(word, number).map(Some),
+    }
+}
+
+fn parse_byte(word: &str, number: usize) -> Result<u8, String> {
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("line {}: invalid fill byte {:?}", number, word))
+}
+
+/// Run every step in order, stopping at the first failed assertion
+pub fn run<T: Read + Write>(transport: &mut XTransport<T>, lines: &[Line]) -> Result<(), String> {
+    for line in lines {
+        let start = Instant::now();
+        match line.step {
+            Step::Send { size, fill } => {
+                transport
+                    .send_message(&vec![fill; size])
+                    .map_err(|e| format!("line {}: send failed: {}", line.number, e))?;
+            }
+            Step::Recv { size, fill } => {
+                let data = transport
+                    .recv_message()
+                    .map_err(|e| format!("line {}: recv failed: {}", line.number, e))?;
+                if let Some(size) = size
+                    && data.len() != size
+                {
+                    return Err(format!("line {}: expected {} bytes, got {}", line.number, size, data.len()));
+                }
+                if let Some(fill) = fill
+                    && let Some(offset) = data.iter().position(|&b| b != fill)
+                {
+                    return Err(format!(
+                        "line {}: expected fill {:#04x}, got {:#04x} at offset {}",
+                        line.number, fill, data[offset], offset
+                    ));
+                }
+            }
+            Step::Sleep { millis } => std::thread::sleep(Duration::from_millis(millis)),
+        }
+        info!("line {}: {:?} ok in {:?}", line.number, line.step, start.elapsed());
+    }
+    Ok(())
+}
+
+/// Load and run the script at `path`, exiting with an error status if it fails
+pub fn run_file<T: Read + Write>(stream: T, config: TransportConfig, path: &str) {
+    let text = std::fs::read_to_string(path).expect("Failed to read script");
+    let lines = parse(&text).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(2);
+    });
+    let mut transport = XTransport::new(stream, config);
+    match run(&mut transport, &lines) {
+        Ok(()) => info!("Script passed: {} steps", lines.len()),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xtransport::protocol::{MessageHead, PacketHeader};
+use xtransport::{MAGIC, VERSION, VERSION_2};
+
+fuzz_target!(|input: (PacketHeader, MessageHead)| {
+    let (header, head) = input;
+
+    let mut wire = Vec::new();
+    header.write_to(&mut wire);
+    match PacketHeader::parse(&wire) {
+        // Version 1 headers keep only the low 2 bytes of the length
+        Ok(parsed) if header.version == VERSION => {
+            assert_eq!(parsed, PacketHeader { length: header.length & 0xffff, ..header })
+        }
+        Ok(parsed) => assert_eq!(parsed, header),
+        Err(_) => assert!(header.magic != MAGIC || (header.version != VERSION && header.version != VERSION_2)),
+    }
+    assert_eq!(MessageHead::from_bytes(&head.to_bytes()).ok(), Some(head));
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xtransport::protocol::{MessageHead, Packet, PacketType};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((packet, len)) = Packet::parse(data) {
+        assert!(len <= data.len());
+        if packet.header.pkt_type == PacketType::MessageHead as u8
+            && let Ok(head) = MessageHead::parse(&packet.data)
+        {
+            let _ = head.validate();
+        }
+    }
+});
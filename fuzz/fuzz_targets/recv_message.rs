@@ -0,0 +1,31 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::{Cursor, Read, Write};
+use xtransport::{TransportConfig, XTransport};
+
+/// Peer that sends the fuzz input and discards everything written to it
+struct Replay<'a>(Cursor<&'a [u8]>);
+
+impl Read for Replay<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Replay<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut transport = XTransport::new(Replay(Cursor::new(data)), TransportConfig::default());
+    // Stop at the end of the input, or the first error that a real peer
+    // would see as a broken connection
+    while transport.recv_message().is_ok() {}
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xtransport::protocol::Packet;
+use xtransport::{FrameScanner, Scan, MAX_PAYLOAD_SIZE_V1};
+
+fuzz_target!(|data: &[u8]| {
+    match FrameScanner::new(MAX_PAYLOAD_SIZE_V1).scan(data) {
+        Scan::Packet { skip, len } => {
+            let (_, parsed) = Packet::parse(&data[skip..]).expect("scanner found a valid packet");
+            assert_eq!(parsed, len);
+        }
+        Scan::Incomplete { skip } => assert!(skip <= data.len()),
+    }
+});
@@ -0,0 +1,97 @@
+//! Echo server over USART2 of an STM32F411 (PA2 TX, PA3 RX, 115200 baud)
+//!
+//! The USART2 interrupt only moves received bytes into an `SpscQueue`; the
+//! protocol runs in `idle`, which reads them through `IrqSerial`, echoes each
+//! message and sleeps whenever the queue runs dry. Flash it with
+//! `cargo run --release` and a probe supported by probe-rs.
+
+#![no_std]
+#![no_main]
+
+use core::mem::MaybeUninit;
+use embedded_alloc::LlffHeap as Heap;
+use panic_halt as _;
+
+/// `XTransport` buffers messages on the heap
+const HEAP_SIZE: usize = 32 * 1024;
+
+#[global_allocator]
+static HEAP: Heap = Heap::empty();
+
+fn init_heap() {
+    static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+    // SAFETY: called once in `init`, before anything allocates
+    unsafe { HEAP.init(core::ptr::addr_of_mut!(HEAP_MEM) as usize, HEAP_SIZE) }
+}
+
+#[rtic::app(device = stm32f4xx_hal::pac)]
+mod app {
+    use stm32f4xx_hal::{
+        pac,
+        prelude::*,
+        serial::{Config, Event, Rx, Tx},
+    };
+    use xtransport::error::ErrorKind;
+    use xtransport::irq::{on_rx_interrupt, Array, IrqSerial, Producer, SpscQueue};
+    use xtransport::{TransportConfig, XTransport};
+
+    /// Room for a few packets of the default size, filled at 11.5 KB/s
+    const QUEUE_SIZE: usize = 2048;
+
+    #[shared]
+    struct Shared {}
+
+    #[local]
+    struct Local {
+        rx: Rx<pac::USART2>,
+        producer: Producer<'static, Array<QUEUE_SIZE>>,
+        transport: XTransport<IrqSerial<'static, Tx<pac::USART2>, Array<QUEUE_SIZE>>>,
+    }
+
+    #[init(local = [queue: SpscQueue<QUEUE_SIZE> = SpscQueue::new()])]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        super::init_heap();
+        let dp = cx.device;
+        let clocks = dp.RCC.constrain().cfgr.sysclk(84.MHz()).freeze();
+        let gpioa = dp.GPIOA.split();
+        let mut serial = dp
+            .USART2
+            .serial((gpioa.pa2, gpioa.pa3), Config::default().baudrate(115_200.bps()), &clocks)
+            .unwrap();
+        serial.listen(Event::RxNotEmpty);
+        let (tx, rx) = serial.split();
+
+        // The queue is a 'static local of `init`, so both ends live forever
+        let (producer, consumer) = cx.local.queue.split();
+        let transport = XTransport::new(IrqSerial::new(consumer, tx), TransportConfig::default());
+        (Shared {}, Local { rx, producer, transport })
+    }
+
+    #[task(binds = USART2, local = [rx, producer])]
+    fn usart2(cx: usart2::Context) {
+        on_rx_interrupt(cx.local.rx, cx.local.producer);
+    }
+
+    #[idle(local = [transport])]
+    fn idle(cx: idle::Context) -> ! {
+        let transport = cx.local.transport;
+        loop {
+            match transport.recv_message() {
+                Ok(message) => {
+                    let _ = transport.send_message(&message);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    // With interrupts masked a byte cannot slip in between the
+                    // check and WFI, which still wakes on the pending interrupt
+                    cortex_m::interrupt::free(|_| {
+                        if transport.get_ref().rx_pending() == 0 {
+                            cortex_m::asm::wfi();
+                        }
+                    });
+                }
+                // A damaged packet is dropped; the peer resends it in ACK mode
+                Err(_) => {}
+            }
+        }
+    }
+}
@@ -1,15 +1,33 @@
 use log::info;
-use std::os::unix::net::UnixListener;
 use vsock::{VsockAddr, VsockListener, VsockStream, VMADDR_CID_ANY};
-use std::time::Instant;
-use xtransport::{TransportConfig, XTransport};
+use xtransport::{server::XServer, Result, TransportConfig, XTransport};
 
 const DATA_SIZE: usize = 200 * 1000 * 1024; // 200 MB
-const SOCKET_PATH: &str = "/tmp/xtransfer.sock";
+
+/// Serve Prometheus metrics on the address given with `--metrics <addr>`
+#[cfg(feature = "metrics")]
+fn start_metrics_exporter() {
+    let args: Vec<String> = std::env::args().collect();
+    let Some(pos) = args.iter().position(|arg| arg == "--metrics") else {
+        return;
+    };
+    let addr: std::net::SocketAddr = args.get(pos + 1)
+        .expect("--metrics needs an address")
+        .parse()
+        .expect("Invalid --metrics address");
+    metrics_exporter_prometheus::PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()
+        .expect("Failed to start the metrics exporter");
+    xtransport::telemetry::describe();
+    info!("Serving metrics on http://{}/metrics", addr);
+}
 
 fn main() {
     // env_logger::init();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+    #[cfg(feature = "metrics")]
+    start_metrics_exporter();
 
     // method 1 unix
     // Remove socket file if it exists
@@ -25,43 +43,54 @@ fn main() {
     let addr = VsockAddr::new(VMADDR_CID_ANY, 1234);
     let listener = VsockListener::bind(&addr).expect("Failed to bind to vsock");
     info!("Server listening on {:?}", addr);
-    let (stream, _) = listener.accept().expect("Failed to accept connection");
-    info!("Client connected");
 
-    let mut transport = XTransport::new(
-        stream,
-        TransportConfig::default()
-            .with_max_frame_size(2048)
-            .with_ack(false),
-    );
+    // Fail at startup rather than on the first connection
+    let config = || {
+        TransportConfig::load(
+            TransportConfig::default()
+                .with_max_frame_size(2048)
+                .with_ack(false),
+        )
+        .expect("Invalid transport config")
+    };
+    config();
+
+    let accept = move || {
+        let (stream, peer) = listener.accept()?;
+        info!("Client connected from {:?}", peer);
+        Ok(stream)
+    };
+    let server = XServer::spawn(accept, config, handle_client);
+    server.join();
+}
 
-    // Receive data from client
-    info!("Receiving data from client...");
-    let start = Instant::now();
-    let recv_data = transport.recv_message().expect("Failed to receive message");
-    let elapsed = start.elapsed();
-    let speed = (recv_data.len() as f64 / 1024.0 / 1024.0) / elapsed.as_secs_f64();
+/// Report the upload of a client and send DATA_SIZE bytes back
+fn handle_client(id: u64, transport: &mut XTransport<VsockStream>, recv_data: Vec<u8>) -> Result<()> {
+    let stats = transport.stats();
+    let elapsed = stats.elapsed_micros as f64 / 1_000_000.0;
+    let speed = stats.recv_throughput() / 1024.0 / 1024.0;
 
-    info!("=== Receive Complete ===");
+    info!("=== Receive Complete (session {}) ===", id);
     info!("Total received: {} MB", recv_data.len() / 1024 / 1024);
-    info!("Time: {:.2} seconds", elapsed.as_secs_f64());
+    info!("Time: {:.2} seconds", elapsed);
     info!("Speed: {:.2} MB/s", speed);
 
     // Send 100MB data back
     info!("Sending {} MB of data back...", DATA_SIZE / 1024 / 1024);
     let data = vec![0xCD; DATA_SIZE];
 
-    let start = Instant::now();
-    transport
-        .send_message(&data)
-        .expect("Failed to send message");
-    let elapsed = start.elapsed();
-    let speed = (DATA_SIZE as f64 / 1024.0 / 1024.0) / elapsed.as_secs_f64();
+    transport.reset_stats();
+    transport.send_message(&data)?;
+    let stats = transport.stats();
+    let elapsed = stats.elapsed_micros as f64 / 1_000_000.0;
+    let speed = stats.send_throughput() / 1024.0 / 1024.0;
 
     info!("=== Send Complete ===");
     info!("Total sent: {} MB", DATA_SIZE / 1024 / 1024);
-    info!("Time: {:.2} seconds", elapsed.as_secs_f64());
+    info!("Time: {:.2} seconds", elapsed);
     info!("Speed: {:.2} MB/s", speed);
 
     info!("Client handler finished");
+    transport.reset_stats();
+    Ok(())
 }
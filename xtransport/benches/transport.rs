@@ -0,0 +1,95 @@
+//! Packet encoding, CRC32, stream decoding and loopback message throughput
+//!
+//! Run with `cargo bench -p xtransport --features std`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use xtransport::decoder::PacketDecoder;
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::{TransportConfig, XTransport, HEADER_SIZE};
+
+const PAYLOAD_SIZES: [usize; 3] = [64, 1024, 4080];
+const MESSAGE_SIZES: [usize; 4] = [64, 4 * 1024, 64 * 1024, 1024 * 1024];
+
+fn packet_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_encode");
+    for size in PAYLOAD_SIZES {
+        let payload = vec![0xa5u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &payload, |b, payload| {
+            b.iter(|| Packet::new(PacketType::Data, 7, black_box(payload.clone())).to_wire())
+        });
+    }
+    group.finish();
+}
+
+fn packet_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_parse");
+    for size in PAYLOAD_SIZES {
+        let bytes = Packet::new(PacketType::Data, 7, vec![0xa5u8; size]).to_wire();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &bytes, |b, bytes| {
+            b.iter(|| Packet::parse(black_box(bytes)).expect("valid packet"))
+        });
+    }
+    group.finish();
+}
+
+fn crc32(c: &mut Criterion) {
+    let mut group = c.benchmark_group("crc32");
+    let packet = Packet::new(PacketType::Data, 0, vec![0x5au8; 64 * 1024 - HEADER_SIZE - 1]);
+    group.throughput(Throughput::Bytes(packet.data.len() as u64));
+    group.bench_function("verify_64k", |b| b.iter(|| black_box(&packet).verify_crc()));
+    group.finish();
+}
+
+fn stream_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stream_decode");
+    for size in PAYLOAD_SIZES {
+        let stream: Vec<u8> = (0..256u32)
+            .flat_map(|seq| Packet::new(PacketType::Data, seq, vec![0xa5u8; size]).to_wire())
+            .collect();
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &stream, |b, stream| {
+            b.iter(|| {
+                let mut decoder = PacketDecoder::new(64 * 1024);
+                let mut reader: &[u8] = stream;
+                let mut packets = 0;
+                while decoder.fill_from(&mut reader).is_ok() {
+                    while let Some(packet) = decoder.decode() {
+                        packet.expect("valid packet");
+                        packets += 1;
+                    }
+                }
+                assert_eq!(packets, 256);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Messages sent over a Unix socket pair to a receiver thread
+fn loopback(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loopback");
+    for size in MESSAGE_SIZES {
+        let (a, b) = UnixStream::pair().expect("socket pair");
+        let receiver = thread::spawn(move || {
+            let mut rx = XTransport::new(b, TransportConfig::default());
+            while rx.recv_message().is_ok() {}
+        });
+        let mut tx = XTransport::new(a, TransportConfig::default());
+        let message = vec![0xa5u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter(|| tx.send_message(message).expect("send"))
+        });
+        drop(tx);
+        receiver.join().expect("receiver thread");
+    }
+    group.finish();
+}
+
+criterion_group!(benches, packet_encode, packet_parse, crc32, stream_decode, loopback);
+criterion_main!(benches);
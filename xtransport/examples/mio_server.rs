@@ -0,0 +1,98 @@
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use std::collections::BTreeMap;
+use std::thread;
+use xtransport::pollable::PollableTransport;
+use xtransport::{TransportConfig, XTransport};
+
+const LISTENER: Token = Token(0);
+const MESSAGES_PER_CLIENT: usize = 20;
+const MESSAGE_SIZE: usize = 16 * 1024;
+
+/// Echo server on one thread, serving every connection from a single mio event loop
+fn serve(mut listener: TcpListener, clients: usize) -> std::io::Result<()> {
+    let mut poll = Poll::new()?;
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+    let mut sessions: BTreeMap<Token, PollableTransport<TcpStream>> = BTreeMap::new();
+    let mut next_token = 1;
+    let mut served = 0;
+    let mut events = Events::with_capacity(1024);
+
+    while served < clients {
+        poll.poll(&mut events, None)?;
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                loop {
+                    let stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    };
+                    stream.set_nodelay(true)?;
+                    let token = Token(next_token);
+                    next_token += 1;
+                    let mut session = PollableTransport::new(stream, TransportConfig::default());
+                    poll.registry().register(&mut session, token, Interest::READABLE)?;
+                    sessions.insert(token, session);
+                }
+                continue;
+            }
+
+            let token = event.token();
+            let session = match sessions.get_mut(&token) {
+                Some(session) => session,
+                None => continue,
+            };
+            let result = session.flush_ready().and_then(|_| {
+                for message in session.recv_ready()? {
+                    session.transport_mut().send_message(&message)?;
+                }
+                Ok(())
+            });
+            match result {
+                Ok(()) => session.update_interest(poll.registry(), token)?,
+                Err(e) => {
+                    log::info!("Session {:?} closed: {}", token, e);
+                    let mut session = sessions.remove(&token).expect("session looked up above");
+                    poll.registry().deregister(&mut session)?;
+                    served += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+    let clients: usize = std::env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(100);
+
+    let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).expect("Failed to bind");
+    let addr = listener.local_addr().expect("Failed to get address");
+    let server = thread::spawn(move || serve(listener, clients));
+
+    // Plain blocking clients, one thread each
+    let start = std::time::Instant::now();
+    let threads: Vec<_> = (0..clients)
+        .map(|client| {
+            thread::spawn(move || {
+                let stream = std::net::TcpStream::connect(addr).expect("Failed to connect");
+                stream.set_nodelay(true).expect("Failed to set TCP_NODELAY");
+                let mut transport = XTransport::new(stream, TransportConfig::default());
+                for i in 0..MESSAGES_PER_CLIENT {
+                    let message = vec![(client + i) as u8; MESSAGE_SIZE];
+                    transport.send_message(&message).expect("Failed to send message");
+                    let echo = transport.recv_message().expect("Failed to receive echo");
+                    assert_eq!(echo, message);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("Client thread panicked");
+    }
+    server.join().expect("Server thread panicked").expect("Server failed");
+
+    let messages = clients * MESSAGES_PER_CLIENT;
+    println!("{} clients, {} messages echoed by one server thread in {:.1} ms", clients, messages, start.elapsed().as_secs_f64() * 1000.0);
+}
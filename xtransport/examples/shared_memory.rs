@@ -1,9 +1,10 @@
 use shared_memory::ShmemConf;
+use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use xtransport::{Read, Result, TransportConfig, Write, XTransport};
+use xtransport::{BusyPoll, Read, Result, TransportConfig, Write, XTransport};
 
 const BUFFER_SIZE: usize = 2048; // 2KB shared memory buffer
 const DATA_SIZE: usize = 10 * 1024 * 1024; // 10MB test data
@@ -44,11 +45,7 @@ impl SharedMemoryStream {
     fn available_read(&self) -> usize {
         let write_pos = self.write_pos.load(Ordering::Acquire);
         let read_pos = self.read_pos.load(Ordering::Acquire);
-        if write_pos >= read_pos {
-            write_pos - read_pos
-        } else {
-            0
-        }
+        write_pos.saturating_sub(read_pos)
     }
 
     fn available_write(&self) -> usize {
@@ -65,16 +62,12 @@ impl Read for SharedMemoryStream {
             return Ok(0);
         }
 
-        // Wait for data to be available
-        loop {
-            let available = self.available_read();
-            if available > 0 {
-                break;
-            }
+        // Non-blocking: the transport busy-polls until data is available
+        if self.available_read() == 0 {
             if self.closed.load(Ordering::Acquire) {
                 return Ok(0); // EOF
             }
-            thread::sleep(Duration::from_micros(10));
+            return Err(xtransport::Error::new(xtransport::error::ErrorKind::WouldBlock));
         }
 
         let available = self.available_read();
@@ -155,7 +148,8 @@ fn main() {
         .size(BUFFER_SIZE)
         .create()
     {
-        Ok(m) => Arc::new(m),
+        // Only the main thread holds the mapping; the threads get its address
+        Ok(m) => Rc::new(m),
         Err(e) => {
             eprintln!("Failed to create shared memory: {}", e);
             return;
@@ -222,7 +216,8 @@ fn main() {
             reader_write_pos,
             reader_closed,
         );
-        let mut transport = XTransport::new(stream, TransportConfig::default());
+        let config = TransportConfig::default().with_busy_poll(BusyPoll::new());
+        let mut transport = XTransport::new(stream, config);
 
         println!("[Reader] Receiving data...");
         let start = std::time::Instant::now();
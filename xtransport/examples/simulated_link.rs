@@ -0,0 +1,59 @@
+use std::thread;
+use xtransport::error::ErrorKind;
+use xtransport::testing::{SimConfig, SimTransport};
+use xtransport::{Clock, TransportConfig, XTransport};
+
+const DATA_SIZE: usize = 1024 * 1024; // 1MB test data
+
+fn main() {
+    env_logger::init();
+
+    // 5ms +/- 1ms latency at 10 MB/s, losing 2% and duplicating 1% of the writes
+    let link = SimConfig::new()
+        .with_latency(5_000, 1_000)
+        .with_bandwidth(10 * 1024 * 1024)
+        .with_loss(0.02)
+        .with_duplicate(0.01)
+        .with_reorder(0.01)
+        .with_seed(42);
+    let (a, b) = SimTransport::pair(link);
+    let config = |clock| {
+        TransportConfig::default()
+            .with_ack(true)
+            .with_window(16)
+            .with_retransmit(20, 10)
+            .with_clock(clock)
+    };
+    let sender_config = config(a.clock());
+    let receiver_config = config(b.clock());
+    let clock = a.clock();
+
+    let sender = thread::spawn(move || {
+        let mut transport = XTransport::new(a, sender_config);
+        let data = vec![0xAB; DATA_SIZE];
+        transport.send_message(&data).expect("Failed to send message");
+        transport.stats()
+    });
+
+    let mut transport = XTransport::new(b, receiver_config);
+    let received = loop {
+        match transport.recv_message() {
+            Ok(message) => break message,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => panic!("Failed to receive message: {}", e),
+        }
+    };
+    assert!(received.len() == DATA_SIZE && received.iter().all(|&b| b == 0xAB));
+
+    // Keep acknowledging until the sender has seen the last ACK
+    while !sender.is_finished() {
+        match transport.recv_message() {
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            _ => break,
+        }
+    }
+    let stats = sender.join().expect("Sender thread panicked");
+
+    println!("Received {} KB in {:.1} ms of virtual time", DATA_SIZE / 1024, clock.now_micros() as f64 / 1000.0);
+    println!("Sent {} packets, {} retransmissions", stats.packets_sent, stats.retransmissions);
+}
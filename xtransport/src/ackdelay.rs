@@ -0,0 +1,48 @@
+/// Smoothed inter-arrival gap below which traffic counts as a bulk stream
+const BULK_GAP_MICROS: u64 = 1_000;
+
+/// Classifies the peer's send pattern from packet inter-arrival times
+///
+/// Back-to-back arrivals indicate a bulk stream, where ACKs are best delayed
+/// and coalesced; sparse arrivals indicate request/response traffic, where a
+/// delayed ACK only adds latency.
+pub struct AckDelayEstimator {
+    last_arrival: Option<u64>,
+    /// Exponentially weighted moving average of the gap, in microseconds
+    gap: Option<u64>,
+}
+
+impl AckDelayEstimator {
+    pub fn new() -> Self {
+        AckDelayEstimator {
+            last_arrival: None,
+            gap: None,
+        }
+    }
+
+    pub fn on_arrival(&mut self, now: u64) {
+        if let Some(last) = self.last_arrival {
+            let sample = now.saturating_sub(last);
+            self.gap = Some(match self.gap {
+                Some(gap) => gap - gap / 8 + sample / 8,
+                None => sample,
+            });
+        }
+        self.last_arrival = Some(now);
+    }
+
+    /// Smoothed inter-arrival gap, once two packets have arrived
+    pub fn gap(&self) -> Option<u64> {
+        self.gap
+    }
+
+    pub fn is_bulk(&self) -> bool {
+        self.gap.is_some_and(|gap| gap < BULK_GAP_MICROS)
+    }
+}
+
+impl Default for AckDelayEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
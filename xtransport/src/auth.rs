@@ -0,0 +1,62 @@
+//! Peer authentication during the handshake
+//!
+//! A transport configured with an `Authenticator` puts a challenge in its
+//! capability block as the `TLV_AUTH_CHALLENGE` TLV, whichever end it is. The
+//! side that called `handshake` then sends a PING whose token is `AUTH_TOKEN`,
+//! followed by its response to the peer's challenge, if the peer sent one.
+//! The PONG echoing that token means the response was accepted and carries
+//! the peer's response to the challenge this side sent, so with
+//! authenticators on both ends each one proves itself to the other. A
+//! rejected response ends the connection with `GOAWAY_UNAUTHORIZED`.
+//!
+//! Until its peer has authenticated, a transport with an authenticator fails
+//! the receive with `Unauthorized` on any packet that carries message data,
+//! and keeps the peer's capabilities (wire version, size limits, fragment
+//! offsets, connection ID) without applying them.
+
+use alloc::vec::Vec;
+
+/// PING token carrying the response to an authentication challenge ("AUTH")
+pub(crate) const AUTH_TOKEN: u64 = 0x4155_5448_0000_0000;
+
+/// Credentials check run during the handshake
+///
+/// Both ends of a connection use the same kind of authenticator: each end
+/// calls `challenge` and `verify` for the peer's proof, and `respond` for its
+/// own. A plain token needs no challenge; a challenge-response scheme
+/// hands out a fresh random challenge and checks a keyed hash of it.
+pub trait Authenticator {
+    /// Challenge for the peer (empty for a plain token)
+    fn challenge(&mut self) -> Vec<u8>;
+    /// Response to the peer's challenge
+    fn respond(&mut self, challenge: &[u8]) -> Vec<u8>;
+    /// True if `response` answers `challenge`
+    fn verify(&mut self, challenge: &[u8], response: &[u8]) -> bool;
+}
+
+/// Shared secret token sent as is
+pub struct TokenAuthenticator {
+    token: Vec<u8>,
+}
+
+impl TokenAuthenticator {
+    pub fn new(token: &[u8]) -> Self {
+        TokenAuthenticator { token: token.to_vec() }
+    }
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn challenge(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn respond(&mut self, _challenge: &[u8]) -> Vec<u8> {
+        self.token.clone()
+    }
+
+    fn verify(&mut self, _challenge: &[u8], response: &[u8]) -> bool {
+        // Compare every byte so the time taken does not reveal the matching prefix
+        response.len() == self.token.len()
+            && response.iter().zip(&self.token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
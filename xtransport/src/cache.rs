@@ -0,0 +1,152 @@
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::vec::Vec;
+
+/// Payloads smaller than this are always sent in full
+pub const DEDUP_MIN_SIZE: usize = 64;
+
+/// 64-bit FNV-1a hash identifying a payload in the dedup cache
+pub fn payload_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Bounded FIFO of recently transferred payloads, keyed by content hash
+///
+/// Sender and receiver each keep one with the same capacity. Both insert exactly
+/// the messages flagged as cacheable, in wire order, so their contents stay in step.
+pub struct PayloadCache {
+    entries: VecDeque<(u64, Vec<u8>)>,
+    capacity: usize,
+}
+
+impl PayloadCache {
+    pub fn new(capacity: usize) -> Self {
+        PayloadCache {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Most recently inserted payload with the given hash
+    pub fn get(&self, hash: u64) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(h, _)| *h == hash)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    pub fn insert(&mut self, hash: u64, data: Vec<u8>) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((hash, data));
+    }
+}
+
+/// Bounded history of message keys or IDs, forgetting the oldest once full
+pub struct KeyHistory {
+    order: VecDeque<u64>,
+    keys: BTreeSet<u64>,
+    capacity: usize,
+}
+
+impl KeyHistory {
+    pub fn new(capacity: usize) -> Self {
+        KeyHistory {
+            order: VecDeque::new(),
+            keys: BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, key: u64) -> bool {
+        self.keys.contains(&key)
+    }
+
+    /// Record a key, returning false if it is already in the history
+    pub fn insert(&mut self, key: u64) -> bool {
+        if self.keys.contains(&key) {
+            return false;
+        }
+        if self.capacity == 0 {
+            return true;
+        }
+        if self.order.len() == self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.keys.remove(&oldest);
+        }
+        self.order.push_back(key);
+        self.keys.insert(key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn hash_is_fnv1a() {
+        assert_eq!(payload_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(payload_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(payload_hash(b"ab"), payload_hash(b"ba"));
+    }
+
+    #[test]
+    fn full_cache_evicts_the_oldest_entry() {
+        let mut cache = PayloadCache::new(2);
+        cache.insert(1, vec![1]);
+        cache.insert(2, vec![2]);
+        cache.insert(3, vec![3]);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(2), Some(&[2u8][..]));
+        assert_eq!(cache.get(3), Some(&[3u8][..]));
+    }
+
+    #[test]
+    fn same_hash_returns_the_latest_payload() {
+        let mut cache = PayloadCache::new(4);
+        cache.insert(7, vec![1]);
+        cache.insert(7, vec![2]);
+        assert_eq!(cache.get(7), Some(&[2u8][..]));
+    }
+
+    #[test]
+    fn disabled_cache_keeps_nothing() {
+        let mut cache = PayloadCache::new(0);
+        assert!(!cache.is_enabled());
+        cache.insert(1, vec![1]);
+        assert_eq!(cache.get(1), None);
+    }
+    #[test]
+    fn key_history_forgets_the_oldest_key() {
+        let mut history = KeyHistory::new(2);
+        assert!(history.insert(1));
+        assert!(history.insert(2));
+        assert!(!history.insert(1), "still remembered");
+        assert!(history.insert(3));
+        assert!(history.insert(1), "forgotten once two newer keys came in");
+        assert!(!history.insert(3));
+    }
+
+    #[test]
+    fn empty_key_history_remembers_nothing() {
+        let mut history = KeyHistory::new(0);
+        assert!(history.insert(1));
+        assert!(history.insert(1));
+    }
+}
@@ -0,0 +1,28 @@
+//! Cancelling a send or receive from another thread
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Flag cancelling the operations it is passed to, shared by all its clones
+///
+/// Keep one clone to call `cancel` from another thread (or an interrupt
+/// handler) and pass the other to `send_message_cancellable` or
+/// `recv_message_cancellable`. Once cancelled, a token stays cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
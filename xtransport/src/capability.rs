@@ -0,0 +1,171 @@
+//! Capabilities advertised to the peer by `XTransport::handshake`
+//!
+//! The handshake is a PING whose token is `HELLO_TOKEN`, followed by the
+//! sender's capability block; a peer that knows the handshake appends its own
+//! block to the PONG, after the 24 timestamp bytes. Older builds answer it like
+//! any other PING, which reads as a peer with no capabilities.
+//!
+//! Capability block (little endian):
+//! - Features: 8 bytes, one bit per `Feature`
+//! - TLVs until the end of the payload: type (2 bytes), length (2 bytes), value
+
+use crate::{
+    error::{Error, ErrorKind},
+    Result,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// PING token marking a handshake ("HELLO")
+pub(crate) const HELLO_TOKEN: u64 = 0x4845_4c4c_4f00_0000;
+
+/// TLV type: largest packet payload the sender accepts (u32)
+pub const TLV_MAX_PAYLOAD_SIZE: u16 = 1;
+/// TLV type: largest message the sender accepts (u64)
+pub const TLV_MAX_MESSAGE_SIZE: u16 = 2;
+/// TLV type: challenge the peer must answer before sending messages (see `auth`)
+pub const TLV_AUTH_CHALLENGE: u16 = 3;
+/// TLV type: ID the connecting side gave the connection, which both ends put in their logs (u64)
+pub const TLV_CONNECTION_ID: u16 = 4;
+
+/// Connections this process has numbered, mixed into each new connection ID
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Optional protocol features, one bit each in the capability block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Feature {
+    /// Understands NACK packets (`CrcPolicy::Nack`)
+    Nack = 0,
+    /// Resolves Reference packets from a dedup cache
+    DedupCache = 1,
+    /// Delivers GroupHead groups together
+    Groups = 2,
+    /// Honours the compressed flag of `MessageOptions`
+    Compression = 3,
+    /// Honours the encrypted flag of `MessageOptions`
+    Encryption = 4,
+    /// Selective acknowledgements
+    Sack = 5,
+    /// Forward error correction
+    Fec = 6,
+    /// Packet payloads above the default frame size
+    JumboFrames = 7,
+    /// Parses version 2 packet headers, whose 4-byte length allows payloads above 64 KB
+    WireV2 = 8,
+    /// Understands Goaway packets announcing why the peer closes the connection
+    Goaway = 9,
+    /// Checks the whole-message digest of MessageHeads flagged `MESSAGE_FLAG_DIGEST`
+    MessageDigest = 10,
+    /// Understands Fin packets closing one direction of the connection
+    HalfClose = 11,
+    /// Advertises its receive window in ACKs and WindowUpdate packets
+    FlowControl = 12,
+    /// Unpacks Batch packets carrying several small messages
+    Batch = 13,
+    /// Drops a partly received message on a MessageReset packet
+    MessageReset = 14,
+    /// Reads the byte offset that MessageData packets carry after the message ID
+    FragmentOffsets = 15,
+    /// Strips and reports the original-payload digest of MessageHeads flagged `MESSAGE_FLAG_ORIGINAL`
+    OriginalDigest = 16,
+}
+
+/// ID for a new connection, from the time, a count of the process's
+/// connections and the count's address, which differs between processes
+///
+/// Unique enough to tell sessions apart in logs; it is no secret.
+pub(crate) fn new_connection_id(now_micros: u64) -> u64 {
+    let count = CONNECTIONS.fetch_add(1, Ordering::Relaxed) as u64;
+    let place = &CONNECTIONS as *const AtomicUsize as usize as u64;
+    // splitmix64 finalizer, so that nearby inputs give unrelated IDs
+    let mut z = now_micros ^ place.rotate_left(32) ^ count.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Feature bits and TLVs describing one end of a connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct Capabilities {
+    features: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::int_keys"))]
+    tlvs: BTreeMap<u16, Vec<u8>>,
+}
+
+impl Capabilities {
+    /// No features, as reported for a peer that predates the handshake
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_feature(mut self, feature: Feature) -> Self {
+        self.features |= 1 << feature as u8;
+        self
+    }
+
+    pub fn without_feature(mut self, feature: Feature) -> Self {
+        self.features &= !(1 << feature as u8);
+        self
+    }
+
+    /// Attach a typed value; types not defined here are free for applications
+    pub fn with_tlv(mut self, tlv_type: u16, value: &[u8]) -> Self {
+        self.tlvs.insert(tlv_type, value.to_vec());
+        self
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u8) != 0
+    }
+
+    /// Raw feature bits, including ones this build does not know
+    pub fn feature_bits(&self) -> u64 {
+        self.features
+    }
+
+    pub fn tlv(&self, tlv_type: u16) -> Option<&[u8]> {
+        self.tlvs.get(&tlv_type).map(|value| value.as_slice())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(8 + self.tlvs.values().map(|v| 4 + v.len()).sum::<usize>());
+        buf.extend_from_slice(&self.features.to_le_bytes());
+        for (tlv_type, value) in &self.tlvs {
+            // Values longer than a TLV can hold are cut; callers keep them short
+            let len = value.len().min(u16::MAX as usize);
+            buf.extend_from_slice(&tlv_type.to_le_bytes());
+            buf.extend_from_slice(&(len as u16).to_le_bytes());
+            buf.extend_from_slice(&value[..len]);
+        }
+        buf
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 8 {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let mut features = [0u8; 8];
+        features.copy_from_slice(&buf[0..8]);
+        let mut caps = Capabilities {
+            features: u64::from_le_bytes(features),
+            tlvs: BTreeMap::new(),
+        };
+
+        let mut rest = &buf[8..];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(Error::new(ErrorKind::InvalidPacket));
+            }
+            let tlv_type = u16::from_le_bytes([rest[0], rest[1]]);
+            let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+            let value = rest.get(4..4 + len).ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+            caps.tlvs.insert(tlv_type, value.to_vec());
+            rest = &rest[4 + len..];
+        }
+        Ok(caps)
+    }
+}
@@ -0,0 +1,269 @@
+//! Wire capture of the frames exchanged over a transport
+//!
+//! A capture starts with an 8-byte file header:
+//! - Magic: `0x58544346` ("XTCF"), little endian
+//! - Version: `0x01`
+//! - Reserved: 3 bytes
+//!
+//! followed by one record per frame:
+//! - Direction: 1 byte (0 = sent, 1 = received)
+//! - Flags: 1 byte (`FRAME_FLAG_MALFORMED`)
+//! - Reserved: 2 bytes
+//! - Timestamp: 8 bytes, microseconds from the recorder's clock (0 without one)
+//! - Original length: 4 bytes, frame length on the wire
+//! - Captured length: 4 bytes, bytes that follow (at most the snapshot length)
+//! - Frame: packet header and payload as on the wire
+//!
+//! All integers are little endian.
+
+use crate::{
+    clock::Clock,
+    config::HEADER_SIZE,
+    error::{Error, ErrorKind},
+    io::{Read, Write},
+    protocol::PacketHeader,
+    Result,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+pub const CAPTURE_MAGIC: u32 = 0x58544346; // "XTCF"
+pub const CAPTURE_VERSION: u8 = 0x01;
+pub const CAPTURE_HEADER_SIZE: usize = 8;
+pub const RECORD_HEADER_SIZE: usize = 20;
+
+/// Record flag: the bytes do not start with a valid packet header, so the
+/// rest of that direction's stream is captured unparsed
+pub const FRAME_FLAG_MALFORMED: u8 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Direction {
+    Sent = 0,
+    Received = 1,
+}
+
+/// One direction of the byte stream, split back into frames
+struct FrameSplitter {
+    buf: Vec<u8>,
+    malformed: bool,
+}
+
+impl FrameSplitter {
+    fn new() -> Self {
+        FrameSplitter {
+            buf: Vec::new(),
+            malformed: false,
+        }
+    }
+
+    /// Append bytes, returning every frame they complete
+    fn push(&mut self, bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        if self.malformed {
+            frames.push((FRAME_FLAG_MALFORMED, bytes.to_vec()));
+            return frames;
+        }
+
+        self.buf.extend_from_slice(bytes);
+        while self.buf.len() >= HEADER_SIZE {
+            let header = match PacketHeader::parse(&self.buf) {
+                Ok(header) => header,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(_) => {
+                    // No way to find the next frame boundary; keep the rest raw
+                    self.malformed = true;
+                    frames.push((FRAME_FLAG_MALFORMED, core::mem::take(&mut self.buf)));
+                    break;
+                }
+            };
+            let total = header.size() + header.length as usize;
+            if self.buf.len() < total {
+                break;
+            }
+            frames.push((0, self.buf.drain(..total).collect()));
+        }
+        frames
+    }
+}
+
+/// Transport wrapper that records every frame passing through it
+pub struct FrameRecorder<T, W> {
+    inner: T,
+    out: W,
+    clock: Option<Box<dyn Clock + Send>>,
+    snap_len: usize,
+    sent: FrameSplitter,
+    received: FrameSplitter,
+}
+
+impl<T, W: Write> FrameRecorder<T, W> {
+    /// Wrap `inner`, writing the capture to `out`
+    pub fn new(inner: T, mut out: W) -> Result<Self> {
+        let mut header = [0u8; CAPTURE_HEADER_SIZE];
+        header[0..4].copy_from_slice(&CAPTURE_MAGIC.to_le_bytes());
+        header[4] = CAPTURE_VERSION;
+        out.write_all(&header)?;
+
+        Ok(FrameRecorder {
+            inner,
+            out,
+            #[cfg(feature = "std")]
+            clock: Some(Box::new(crate::clock::StdClock::new())),
+            #[cfg(not(feature = "std"))]
+            clock: None,
+            snap_len: usize::MAX,
+            sent: FrameSplitter::new(),
+            received: FrameSplitter::new(),
+        })
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + Send + 'static) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Capture at most `bytes` of every frame (header included)
+    pub fn with_snap_len(mut self, bytes: usize) -> Self {
+        self.snap_len = bytes;
+        self
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.out)
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        let frames = match direction {
+            Direction::Sent => self.sent.push(bytes),
+            Direction::Received => self.received.push(bytes),
+        };
+        let timestamp = self.clock.as_ref().map_or(0, |clock| clock.now_micros());
+
+        for (flags, frame) in frames {
+            let captured = &frame[..frame.len().min(self.snap_len)];
+            let mut record = [0u8; RECORD_HEADER_SIZE];
+            record[0] = direction as u8;
+            record[1] = flags;
+            record[4..12].copy_from_slice(&timestamp.to_le_bytes());
+            record[12..16].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+            record[16..20].copy_from_slice(&(captured.len() as u32).to_le_bytes());
+
+            // A broken capture must not take the connection down with it
+            let result = self.out.write_all(&record).and_then(|_| self.out.write_all(captured));
+            if let Err(e) = result {
+                log::warn!("Failed to write capture record: {}", e);
+            }
+        }
+    }
+}
+
+impl<T: Read, W: Write> Read for FrameRecorder<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record(Direction::Received, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Write, W: Write> Write for FrameRecorder<T, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(Direction::Sent, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()?;
+        self.out.flush()
+    }
+}
+
+/// One frame read back from a capture
+pub struct CapturedFrame {
+    pub direction: Direction,
+    pub flags: u8,
+    pub timestamp_micros: u64,
+    /// Length of the frame on the wire
+    pub original_len: usize,
+    /// Captured bytes, starting with the packet header
+    pub data: Vec<u8>,
+}
+
+impl CapturedFrame {
+    /// Parsed packet header, if the frame has one
+    pub fn header(&self) -> Option<PacketHeader> {
+        if self.flags & FRAME_FLAG_MALFORMED != 0 {
+            return None;
+        }
+        PacketHeader::parse(&self.data).ok()
+    }
+
+    /// Captured payload after the packet header
+    pub fn payload(&self) -> &[u8] {
+        let offset = self.header().map_or(HEADER_SIZE, |header| header.size());
+        self.data.get(offset..).unwrap_or(&[])
+    }
+}
+
+/// Reader for captures written by `FrameRecorder`
+pub struct FrameReader<R> {
+    inner: R,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Check the capture file header and position at the first record
+    pub fn new(mut inner: R) -> Result<Self> {
+        let mut header = [0u8; CAPTURE_HEADER_SIZE];
+        inner.read_exact(&mut header)?;
+        if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != CAPTURE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidMagic));
+        }
+        if header[4] != CAPTURE_VERSION {
+            return Err(Error::new(ErrorKind::InvalidVersion));
+        }
+        Ok(FrameReader { inner })
+    }
+
+    /// Next frame, or `None` at the end of the capture
+    pub fn next_frame(&mut self) -> Result<Option<CapturedFrame>> {
+        let mut record = [0u8; RECORD_HEADER_SIZE];
+        // A clean end of capture falls between records
+        if self.inner.read(&mut record[..1])? == 0 {
+            return Ok(None);
+        }
+        self.inner.read_exact(&mut record[1..])?;
+
+        let direction = match record[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return Err(Error::new(ErrorKind::InvalidPacket)),
+        };
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&record[4..12]);
+        let original_len = u32::from_le_bytes([record[12], record[13], record[14], record[15]]) as usize;
+        let captured_len = u32::from_le_bytes([record[16], record[17], record[18], record[19]]) as usize;
+
+        let mut data = alloc::vec![0u8; captured_len];
+        self.inner.read_exact(&mut data)?;
+        Ok(Some(CapturedFrame {
+            direction,
+            flags: record[1],
+            timestamp_micros: u64::from_le_bytes(timestamp),
+            original_len,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for FrameReader<R> {
+    type Item = Result<CapturedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
@@ -0,0 +1,282 @@
+//! Authenticated encryption of packet payloads
+//!
+//! With `TransportConfig::with_encryption` the payload of every packet is
+//! sealed on the wire as a nonce (8 bytes, little endian), the ciphertext and
+//! the cipher's tag; the header's length and CRC describe the sealed payload.
+//! The magic, version, type and sequence number of the header are
+//! authenticated with the payload, so a packet cannot be moved to another
+//! sequence number or type. The nonce counts the packets sealed for the
+//! connection, retransmissions included, and never repeats. Its top bit is
+//! the `Role` of the sender, so the two directions never seal under the same
+//! nonce even when they share a key, and a packet reflected back to its
+//! sender fails to open.
+//!
+//! The receiver keeps a window of the last `REPLAY_WINDOW` nonces it accepted
+//! and drops packets that repeat one or are older than the window. A packet
+//! failing authentication, whether forged, altered or cut short, fails the
+//! receive with `AuthenticationFailed`.
+
+use crate::{error::ErrorKind, protocol::PacketHeader, Error, Result};
+use crate::config::VERSION_2;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Size of the nonce in front of every sealed payload
+pub const NONCE_SIZE: usize = 8;
+/// Header bytes authenticated with the payload: magic, version, type and sequence number
+pub const AAD_SIZE: usize = 10;
+/// Nonces behind the newest accepted one that are still checked individually
+pub const REPLAY_WINDOW: u64 = 64;
+/// Nonce bit set in the packets a `Role::Responder` seals
+const RESPONDER_NONCE: u64 = 1 << 63;
+
+/// End of the connection an `Encryption` seals for
+///
+/// The two ends must take different roles; the side calling `handshake` is
+/// usually the initiator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    /// Nonce bits marking the packets this role seals
+    fn nonce_bits(self) -> u64 {
+        match self {
+            Role::Initiator => 0,
+            Role::Responder => RESPONDER_NONCE,
+        }
+    }
+}
+
+/// AEAD cipher keyed for one direction of a connection
+///
+/// The transport gives every sealed packet a distinct `nonce`; a cipher
+/// needing a longer one pads it, e.g. 4 zero bytes followed by the 8 bytes of
+/// the counter in little endian for a 96-bit nonce.
+pub trait Cipher {
+    /// Size of the authentication tag appended to every payload
+    fn tag_len(&self) -> usize;
+    /// Encrypt `buf` in place and write its tag, authenticating `aad` along with it
+    fn seal(&mut self, nonce: u64, aad: &[u8], buf: &mut [u8], tag: &mut [u8]) -> Result<()>;
+    /// Check `tag` against `buf` and `aad` and decrypt `buf` in place, failing with `AuthenticationFailed`
+    fn open(&mut self, nonce: u64, aad: &[u8], buf: &mut [u8], tag: &[u8]) -> Result<()>;
+}
+
+/// Nonces accepted recently, newest first
+pub struct ReplayWindow {
+    newest: Option<u64>,
+    /// Bit `n` set if `newest - n` has been accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        ReplayWindow { newest: None, seen: 0 }
+    }
+
+    /// True if `nonce` was not accepted before and is recent enough to tell
+    pub fn is_fresh(&self, nonce: u64) -> bool {
+        match self.newest {
+            Some(newest) if nonce <= newest => {
+                let age = newest - nonce;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+            _ => true,
+        }
+    }
+
+    /// Record `nonce` as accepted
+    pub fn accept(&mut self, nonce: u64) {
+        match self.newest {
+            Some(newest) if nonce <= newest => {
+                let age = newest - nonce;
+                if age < REPLAY_WINDOW {
+                    self.seen |= 1 << age;
+                }
+            }
+            Some(newest) => {
+                let shift = nonce - newest;
+                self.seen = if shift < REPLAY_WINDOW { self.seen << shift | 1 } else { 1 };
+                self.newest = Some(nonce);
+            }
+            None => {
+                self.seen = 1;
+                self.newest = Some(nonce);
+            }
+        }
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ciphers of both directions of a connection, with the state of their nonces
+///
+/// It lives in the `TransportConfig`, so a transport rebuilt from the same
+/// configuration (after `XTransport::into_parts`, or by a `Reconnector`)
+/// continues the nonce sequence instead of reusing nonces under the same key.
+pub struct Encryption {
+    role: Role,
+    send: Box<dyn Cipher + Send>,
+    recv: Box<dyn Cipher + Send>,
+    next_nonce: u64,
+    replay: ReplayWindow,
+}
+
+impl Encryption {
+    pub fn new(role: Role, send: impl Cipher + Send + 'static, recv: impl Cipher + Send + 'static) -> Self {
+        Encryption {
+            role,
+            send: Box::new(send),
+            recv: Box::new(recv),
+            next_nonce: 0,
+            replay: ReplayWindow::new(),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Bytes sealing adds to a payload
+    pub fn overhead(&self) -> usize {
+        NONCE_SIZE + self.send.tag_len()
+    }
+
+    /// Append a packet with the payload `parts` sealed under the next nonce to `out`
+    ///
+    /// The length and CRC of `header` are replaced by those of the sealed payload.
+    pub fn seal_packet(&mut self, header: &PacketHeader, parts: &[&[u8]], out: &mut Vec<u8>) -> Result<()> {
+        if self.next_nonce & RESPONDER_NONCE != 0 {
+            // 2^63 packets later; never reuse a nonce
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        let nonce = self.next_nonce | self.role.nonce_bits();
+        let start = out.len();
+        header.write_to(out);
+        let payload_start = out.len();
+        out.extend_from_slice(&nonce.to_le_bytes());
+        for part in parts {
+            out.extend_from_slice(part);
+        }
+        let body_end = out.len();
+        out.resize(body_end + self.send.tag_len(), 0);
+        let (body, tag) = out[payload_start + NONCE_SIZE..].split_at_mut(body_end - payload_start - NONCE_SIZE);
+        if let Err(e) = self.send.seal(nonce, &aad(header), body, tag) {
+            out.truncate(start);
+            return Err(e);
+        }
+        self.next_nonce += 1;
+
+        let sealed = PacketHeader {
+            length: (out.len() - payload_start) as u32,
+            crc32: crc32fast::hash(&out[payload_start..]),
+            ..*header
+        };
+        if sealed.version == VERSION_2 {
+            out[start..payload_start].copy_from_slice(&sealed.to_bytes_v2());
+        } else {
+            out[start..payload_start].copy_from_slice(&sealed.to_bytes());
+        }
+        Ok(())
+    }
+
+    /// Authenticate and decrypt the payload of a received packet in place
+    ///
+    /// Returns false, leaving `payload` as it was, for a packet replaying a
+    /// nonce already accepted or older than the replay window. A nonce
+    /// carrying this end's own role fails with `AuthenticationFailed`.
+    pub fn open_payload(&mut self, header: &PacketHeader, payload: &mut Vec<u8>) -> Result<bool> {
+        let tag_len = self.recv.tag_len();
+        if payload.len() < NONCE_SIZE + tag_len {
+            return Err(Error::new(ErrorKind::AuthenticationFailed).with_seq(header.seq));
+        }
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&payload[..NONCE_SIZE]);
+        let nonce = u64::from_le_bytes(nonce);
+        if nonce & RESPONDER_NONCE == self.role.nonce_bits() {
+            return Err(Error::new(ErrorKind::AuthenticationFailed).with_seq(header.seq));
+        }
+        if !self.replay.is_fresh(nonce) {
+            return Ok(false);
+        }
+
+        let body_end = payload.len() - tag_len;
+        let (body, tag) = payload[NONCE_SIZE..].split_at_mut(body_end - NONCE_SIZE);
+        self.recv
+            .open(nonce, &aad(header), body, tag)
+            .map_err(|e| e.with_seq(header.seq))?;
+        self.replay.accept(nonce);
+        payload.truncate(body_end);
+        payload.drain(..NONCE_SIZE);
+        Ok(true)
+    }
+}
+
+/// Associated data of a packet: the header fields that do not describe the sealed payload
+fn aad(header: &PacketHeader) -> [u8; AAD_SIZE] {
+    let mut aad = [0u8; AAD_SIZE];
+    aad.copy_from_slice(&header.to_bytes()[..AAD_SIZE]);
+    aad
+}
+
+#[cfg(feature = "chacha20poly1305")]
+pub use chacha::{ChaCha20Poly1305Cipher, KEY_SIZE};
+
+#[cfg(feature = "chacha20poly1305")]
+mod chacha {
+    use super::Cipher;
+    use crate::{error::ErrorKind, Error, Result};
+    use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+    use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+
+    /// Size of a ChaCha20-Poly1305 key
+    pub const KEY_SIZE: usize = 32;
+    const TAG_SIZE: usize = 16;
+
+    /// ChaCha20-Poly1305 (RFC 8439) with a 32-byte key
+    pub struct ChaCha20Poly1305Cipher {
+        aead: ChaCha20Poly1305,
+    }
+
+    impl ChaCha20Poly1305Cipher {
+        pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+            ChaCha20Poly1305Cipher {
+                aead: ChaCha20Poly1305::new(key.into()),
+            }
+        }
+    }
+
+    /// 4 zero bytes followed by the counter in little endian
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    impl Cipher for ChaCha20Poly1305Cipher {
+        fn tag_len(&self) -> usize {
+            TAG_SIZE
+        }
+
+        fn seal(&mut self, counter: u64, aad: &[u8], buf: &mut [u8], tag: &mut [u8]) -> Result<()> {
+            let sealed = self
+                .aead
+                .encrypt_in_place_detached(&nonce(counter), aad, buf)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput))?;
+            tag.copy_from_slice(&sealed);
+            Ok(())
+        }
+
+        fn open(&mut self, counter: u64, aad: &[u8], buf: &mut [u8], tag: &[u8]) -> Result<()> {
+            self.aead
+                .decrypt_in_place_detached(&nonce(counter), aad, buf, Tag::from_slice(tag))
+                .map_err(|_| Error::new(ErrorKind::AuthenticationFailed))
+        }
+    }
+}
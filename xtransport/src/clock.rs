@@ -0,0 +1,124 @@
+/// Time source used for timestamps exchanged with the peer, in microseconds
+pub trait Clock {
+    fn now_micros(&self) -> u64;
+
+    /// Wait until the clock has advanced by `micros`, spinning by default
+    fn sleep_micros(&self, micros: u64) {
+        let until = self.now_micros().saturating_add(micros);
+        while self.now_micros() < until {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Any `Fn() -> u64` returning microseconds can serve as a clock
+impl<F: Fn() -> u64> Clock for F {
+    fn now_micros(&self) -> u64 {
+        self()
+    }
+}
+
+/// Monotonic clock anchored to the system time at creation
+///
+/// Readings are close to Unix time in microseconds but never go backwards,
+/// even if the wall clock is adjusted afterwards.
+#[cfg(feature = "std")]
+pub struct StdClock {
+    start: std::time::Instant,
+    start_micros: u64,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    pub fn new() -> Self {
+        let start_micros = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        StdClock {
+            start: std::time::Instant::now(),
+            start_micros,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now_micros(&self) -> u64 {
+        self.start_micros + self.start.elapsed().as_micros() as u64
+    }
+
+    fn sleep_micros(&self, micros: u64) {
+        std::thread::sleep(std::time::Duration::from_micros(micros));
+    }
+}
+
+/// Clock that only moves when told to, for tests
+///
+/// Clones share the same time, so a test can keep one handle and give the
+/// other to a transport. Sleeping advances the clock instead of waiting.
+#[cfg(all(target_has_atomic = "64", feature = "alloc"))]
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    micros: alloc::sync::Arc<core::sync::atomic::AtomicU64>,
+}
+
+#[cfg(all(target_has_atomic = "64", feature = "alloc"))]
+impl ManualClock {
+    pub fn new(start_micros: u64) -> Self {
+        ManualClock {
+            micros: alloc::sync::Arc::new(core::sync::atomic::AtomicU64::new(start_micros)),
+        }
+    }
+
+    pub fn set(&self, micros: u64) {
+        self.micros.store(micros, core::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, micros: u64) {
+        self.micros.fetch_add(micros, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(all(target_has_atomic = "64", feature = "alloc"))]
+impl Clock for ManualClock {
+    fn now_micros(&self) -> u64 {
+        self.micros.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn sleep_micros(&self, micros: u64) {
+        self.advance(micros);
+    }
+}
+
+/// Adapter for a free-running hardware counter, e.g. a timer peripheral or
+/// cycle counter on a target without a wall clock
+///
+/// `read_ticks` returns the counter, which must be monotonic and wide enough
+/// not to wrap during the connection.
+pub struct TickClock<F: Fn() -> u64> {
+    read_ticks: F,
+    ticks_per_sec: u64,
+}
+
+impl<F: Fn() -> u64> TickClock<F> {
+    pub fn new(ticks_per_sec: u64, read_ticks: F) -> Self {
+        TickClock {
+            read_ticks,
+            ticks_per_sec: ticks_per_sec.max(1),
+        }
+    }
+}
+
+impl<F: Fn() -> u64> Clock for TickClock<F> {
+    fn now_micros(&self) -> u64 {
+        ((self.read_ticks)() as u128 * 1_000_000 / self.ticks_per_sec as u128) as u64
+    }
+}
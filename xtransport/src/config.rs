@@ -1,20 +1,414 @@
-// Protocol constants
-pub const MAGIC: u32 = 0x58545250; // "XTRP"
-pub const VERSION: u8 = 0x01;
-pub const HEADER_SIZE: usize = 16;
-pub const MESSAGE_HEAD_SIZE: usize = 32;
+use crate::auth::Authenticator;
+use crate::capability::{Capabilities, Feature};
+use crate::clock::Clock;
+use crate::cipher::{Cipher, Encryption, Role};
+#[cfg(feature = "serde")]
+use crate::error::{Error, ErrorKind};
+use crate::journal::Journal;
+use crate::memory::MemoryBudget;
+use crate::observer::{Observer, ProgressFn, Transfer};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+#[cfg(feature = "serde")]
+use crate::Result;
+
+pub use crate::protocol::{
+    ACK_PREFIX_SIZE, GOAWAY_HEAD_SIZE, GROUP_HEAD_SIZE, HEADER_SIZE, HEADER_SIZE_V2, MAGIC, MAX_PAYLOAD_SIZE_V1, MESSAGE_DATA_HEAD_SIZE, MESSAGE_DATA_OFFSET_SIZE,
+    MESSAGE_HEAD_SIZE, PING_SIZE, PONG_SIZE, REFERENCE_SIZE, VERSION, VERSION_2,
+};
+
 const DEFAULT_MAX_FRAME_SIZE: usize = 4096; // 4KB
+const DEFAULT_REORDER_WINDOW: usize = 64; // packets
+const DEFAULT_MAX_UNACKED: u32 = 16;
+const DEFAULT_RTO_MS: u64 = 200;
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_RETRANSMIT_RATIO: u32 = 1; // retransmissions per new packet
+const DEFAULT_MIN_RTO_MS: u64 = 10;
+const DEFAULT_MAX_RTO_MS: u64 = 60_000;
+const DEFAULT_RTO_BACKOFF: f64 = 2.0;
+const DEFAULT_DUPLICATE_HISTORY: usize = 1024; // message keys
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024; // 1GB
+const DEFAULT_PROGRESS_INTERVAL: u32 = 100; // packets
+const DEFAULT_BUSY_POLL_SPIN_ROUNDS: u32 = 10; // about 2000 spins in total
+const DEFAULT_BUSY_POLL_MAX_SLEEP_US: u64 = 50;
+const DEFAULT_FRAME_GROW_AFTER: u32 = 32; // delivered packets
+const DEFAULT_FRAME_SHRINK_AFTER: u32 = 2; // lost packets
+const DEFAULT_RECV_POOL_BUFFERS: usize = 8;
+const DEFAULT_RECV_POOL_MAX_BUFFER_SIZE: usize = 256 * 1024; // 256KB
+
+/// What `recv_message` does with a message it has already seen
+///
+/// A message counts as seen if it repeats the key of a recent keyed message
+/// or the message ID of a recent MessageHead, e.g. one a sender resent in full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DuplicatePolicy {
+    /// Deliver every copy
+    Deliver,
+    /// Silently drop the repeat
+    Suppress,
+    /// Fail the receive with `DuplicateMessage`
+    Reject,
+}
+
+/// What the receive path does with a packet that fails its CRC check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CrcPolicy {
+    /// Fail the receive with `CrcMismatch`
+    Fail,
+    /// Drop the packet and continue; in ACK mode the sender's retransmission
+    /// timeout recovers it, otherwise it is lost
+    Drop,
+    /// Drop the packet and, in ACK mode, ask the peer to retransmit it at once
+    /// with a NACK (the peer must understand NACK packets)
+    Nack,
+}
+
+/// Options for the OS socket under a transport, applied by `XTransport::apply_socket_options`
+///
+/// Unset options keep the OS default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct SocketOptions {
+    /// TCP_NODELAY
+    pub nodelay: Option<bool>,
+    /// SO_RCVBUF in bytes
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF in bytes
+    pub send_buffer_size: Option<usize>,
+    /// TCP_QUICKACK (Linux only)
+    pub quickack: Option<bool>,
+    /// SO_KEEPALIVE, with the idle time in seconds before the first probe
+    pub keepalive_secs: Option<u64>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    pub fn with_buffer_sizes(mut self, recv_bytes: usize, send_bytes: usize) -> Self {
+        self.recv_buffer_size = Some(recv_bytes);
+        self.send_buffer_size = Some(send_bytes);
+        self
+    }
+
+    pub fn with_quickack(mut self, quickack: bool) -> Self {
+        self.quickack = Some(quickack);
+        self
+    }
+
+    pub fn with_keepalive(mut self, idle_secs: u64) -> Self {
+        self.keepalive_secs = Some(idle_secs);
+        self
+    }
+}
 
+/// Rate cap of one message class of the send queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClassRate {
+    pub bytes_per_sec: u64,
+    /// Bytes the class may send at once after being idle
+    pub burst_bytes: usize,
+}
+
+/// Busy-polling of a non-blocking transport instead of returning `WouldBlock`
+///
+/// Each empty read spins for twice as long as the previous one, up to
+/// `spin_rounds` rounds; after that the wait sleeps on the clock, doubling
+/// up to `max_sleep_micros`, or yields the thread when there is no clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct BusyPoll {
+    pub spin_rounds: u32,
+    pub max_sleep_micros: u64,
+    /// Give up with `WouldBlock` after this long without data (needs a clock);
+    /// ACK mode needs it for retransmissions to run
+    pub timeout_micros: Option<u64>,
+}
+
+impl BusyPoll {
+    pub fn new() -> Self {
+        BusyPoll {
+            spin_rounds: DEFAULT_BUSY_POLL_SPIN_ROUNDS,
+            max_sleep_micros: DEFAULT_BUSY_POLL_MAX_SLEEP_US,
+            timeout_micros: None,
+        }
+    }
+
+    pub fn with_spin_rounds(mut self, rounds: u32) -> Self {
+        self.spin_rounds = rounds;
+        self
+    }
+
+    pub fn with_max_sleep_micros(mut self, micros: u64) -> Self {
+        self.max_sleep_micros = micros;
+        self
+    }
+
+    pub fn with_timeout_micros(mut self, micros: u64) -> Self {
+        self.timeout_micros = Some(micros);
+        self
+    }
+
+    /// Back off after `round` consecutive empty reads
+    pub(crate) fn wait(&self, round: u32, clock: Option<&(dyn Clock + Send)>) {
+        if round < self.spin_rounds {
+            for _ in 0..1u32 << round.min(16) {
+                core::hint::spin_loop();
+            }
+            return;
+        }
+        match clock {
+            Some(clock) => {
+                let sleep = 1u64 << (round - self.spin_rounds).min(32);
+                clock.sleep_micros(sleep.min(self.max_sleep_micros));
+            }
+            #[cfg(feature = "std")]
+            None => std::thread::yield_now(),
+            #[cfg(not(feature = "std"))]
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+impl Default for BusyPoll {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frame size adapted at runtime between two bounds
+///
+/// The payload size starts at the lower bound and doubles after `grow_after`
+/// full-size packets were delivered without loss (acknowledged in ACK mode,
+/// written otherwise). `shrink_after` full-size losses (retransmission
+/// timeouts or NACKs) halve it, and each shrink doubles the deliveries needed
+/// to grow again, so a link whose limit lies between two sizes stops probing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct AdaptiveFrameSize {
+    pub min_payload_size: usize,
+    pub max_payload_size: usize,
+    pub grow_after: u32,
+    pub shrink_after: u32,
+}
+
+impl AdaptiveFrameSize {
+    /// Adapt between two frame sizes, headers included
+    pub fn new(min_frame_size: usize, max_frame_size: usize) -> Self {
+        let min_payload_size = min_frame_size.saturating_sub(HEADER_SIZE).max(1);
+        AdaptiveFrameSize {
+            min_payload_size,
+            max_payload_size: max_frame_size.saturating_sub(HEADER_SIZE).max(min_payload_size),
+            grow_after: DEFAULT_FRAME_GROW_AFTER,
+            shrink_after: DEFAULT_FRAME_SHRINK_AFTER,
+        }
+    }
+
+    pub fn with_grow_after(mut self, packets: u32) -> Self {
+        self.grow_after = packets.max(1);
+        self
+    }
+
+    pub fn with_shrink_after(mut self, losses: u32) -> Self {
+        self.shrink_after = losses.max(1);
+        self
+    }
+}
+
+/// Tuning parameters of a transport
+///
+/// With the `serde` feature the plain settings can be loaded from TOML or the
+/// environment; missing ones keep their defaults, and the clock, observer,
+/// progress callback, journal, encryption and authenticator can only be set in code.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct TransportConfig {
     pub max_payload_size: usize,
+    /// Largest message accepted from the peer; bigger MessageHeads fail with `MessageTooLarge`
+    pub max_message_size: usize,
+    /// Multi-packet messages the peer may have in reassembly at once (0 = unlimited)
+    pub max_partial_messages: usize,
+    /// Time without data after which a message in reassembly may be evicted for a new one (0 = never)
+    pub reassembly_timeout_ms: u64,
     pub wait_for_ack: bool,
+    /// Packets that may be in flight unacknowledged in ACK mode (1 = stop-and-wait)
+    pub window_size: usize,
+    /// Wire bytes that may be in flight unacknowledged in ACK mode (0 = no limit besides `window_size`)
+    pub window_bytes: usize,
+    /// Longest time a received packet may wait to be acknowledged (0 = ACK immediately)
+    ///
+    /// A delayed ACK is always sent before the receiver blocks on the stream.
+    pub ack_delay_ms: u64,
+    /// Delivered packets after which a delayed ACK is sent regardless of the timer
+    pub max_unacked: u32,
+    /// Delay ACKs only while the peer streams in bulk, ACK request/response traffic at once
+    ///
+    /// `ack_delay_ms` is then the delay used for bulk streams.
+    pub adaptive_ack: bool,
+    /// Coalesce outgoing packets into writes of up to this many bytes (0 = one write per packet)
+    pub max_burst_size: usize,
+    /// Collect small writes through the `Write` impl into packets of this many bytes (0 = one packet per write)
+    pub write_coalesce_size: usize,
+    /// Longest time a coalesced write may wait for more bytes, checked on every write (0 = no limit)
+    ///
+    /// Coalesced bytes are also sent on `flush`, before any other packet and
+    /// before the transport waits for the peer.
+    pub write_coalesce_delay_ms: u64,
+    /// Received payload buffers kept for reuse (0 = allocate one per packet)
+    pub recv_pool_buffers: usize,
+    /// Capacity above which a received payload buffer is freed instead of kept
+    pub recv_pool_max_buffer_size: usize,
+    /// Initial retransmission timeout while waiting for an ACK, backed off on every retry
+    ///
+    /// Expiry is detected when a read on the underlying transport fails with
+    /// `TimedOut` or `WouldBlock`, so it needs a read timeout or a non-blocking stream.
+    pub rto_ms: u64,
+    /// Retransmissions of one packet before giving up with `MaxRetriesExceeded`
+    pub max_retries: u32,
+    /// Expired packets retransmitted ahead of each new packet while sending (0 = only while waiting for ACKs)
+    pub retransmit_ratio: u32,
+    /// Lower bound of the retransmission timeout computed from RTT samples
+    pub min_rto_ms: u64,
+    /// Upper bound of the retransmission timeout, backoff included
+    pub max_rto_ms: u64,
+    /// Factor the retransmission timeout is multiplied by on every retry (at least 1)
+    pub rto_backoff: f64,
+    /// Fraction of itself a backed-off timeout is randomly moved by either way (0 to 1)
+    pub rto_jitter: f64,
+    /// Handling of packets that fail their CRC check
+    pub crc_policy: CrcPolicy,
+    /// Bytes that may be skipped to find the next valid packet after a malformed header (0 = fail on it)
+    pub max_resync_bytes: usize,
+    /// Time packets may be missing behind later ones before the receiver NACKs them in ACK mode (0 = never)
+    pub gap_nack_ms: u64,
+    /// How far ahead of the expected sequence a packet may arrive and still be buffered
+    pub reorder_window: usize,
+    /// Number of recently sent payloads remembered for duplicate elimination (0 = disabled)
+    pub dedup_cache_size: usize,
+    /// Handling of messages that repeat a recently seen key or message ID
+    pub duplicate_policy: DuplicatePolicy,
+    /// Number of recent message keys, and of message IDs, remembered to detect duplicates
+    pub duplicate_history: usize,
+    /// Time source for timestamps exchanged with the peer (`StdClock` by default with `std`)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub clock: Option<Box<dyn Clock + Send>>,
+    /// Features advertised to the peer by `handshake`
+    pub capabilities: Capabilities,
+    /// Socket tuning for `std` transports over OS sockets
+    pub socket: SocketOptions,
+    /// Retry empty non-blocking reads in place instead of returning `WouldBlock`
+    pub busy_poll: Option<BusyPoll>,
+    /// Grow and shrink `max_payload_size` with the delivery rate, if set
+    pub adaptive_frame_size: Option<AdaptiveFrameSize>,
+    /// Callback for packet-level events (see also the `tracing` feature)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub observer: Option<Box<dyn Observer + Send>>,
+    /// Progress of multi-packet messages, in both directions
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub progress: Option<Box<ProgressFn>>,
+    /// Packets of a message between two progress reports; the last packet is always reported
+    pub progress_interval: u32,
+    /// Write-ahead storage making `send_message` durable across restarts
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub journal: Option<Box<dyn Journal + Send>>,
+    /// Ciphers sealing every packet payload, with replay protection (see `cipher`)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub encryption: Option<Encryption>,
+    /// Credentials check run by `handshake` (see `auth`)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub authenticator: Option<Box<dyn Authenticator + Send>>,
+    /// Rate caps of message classes used with `queue_message`; other classes are uncapped
+    #[cfg_attr(feature = "serde", serde(with = "int_keys"))]
+    pub class_rates: BTreeMap<u8, ClassRate>,
+    /// Cap on all wire bytes written, in bytes per second (0 = unlimited)
+    pub max_send_rate: u64,
+    /// Bytes that may be written at once after an idle period under `max_send_rate`
+    pub max_send_burst: usize,
+    /// Cap on the bytes a connection buffers for reordering, reassembly and its send queue (0 = unlimited)
+    pub max_connection_memory: usize,
+    /// Limit shared with other connections on the same buffers (see `memory`)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub memory_budget: Option<MemoryBudget>,
+    /// Append a whole-message digest to multi-packet messages for peers that check it
+    pub message_digest: bool,
 }
 
 impl TransportConfig {
     pub fn new() -> Self {
         Self {
             max_payload_size: DEFAULT_MAX_FRAME_SIZE - HEADER_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_partial_messages: 0,
+            reassembly_timeout_ms: 0,
             wait_for_ack: false,
+            window_size: 1,
+            window_bytes: 0,
+            ack_delay_ms: 0,
+            max_unacked: DEFAULT_MAX_UNACKED,
+            adaptive_ack: false,
+            max_burst_size: 0,
+            write_coalesce_size: 0,
+            write_coalesce_delay_ms: 0,
+            recv_pool_buffers: DEFAULT_RECV_POOL_BUFFERS,
+            recv_pool_max_buffer_size: DEFAULT_RECV_POOL_MAX_BUFFER_SIZE,
+            rto_ms: DEFAULT_RTO_MS,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retransmit_ratio: DEFAULT_RETRANSMIT_RATIO,
+            min_rto_ms: DEFAULT_MIN_RTO_MS,
+            max_rto_ms: DEFAULT_MAX_RTO_MS,
+            rto_backoff: DEFAULT_RTO_BACKOFF,
+            rto_jitter: 0.0,
+            crc_policy: CrcPolicy::Fail,
+            max_resync_bytes: 0,
+            gap_nack_ms: 0,
+            reorder_window: DEFAULT_REORDER_WINDOW,
+            dedup_cache_size: 0,
+            duplicate_policy: DuplicatePolicy::Deliver,
+            duplicate_history: DEFAULT_DUPLICATE_HISTORY,
+            #[cfg(feature = "std")]
+            clock: Some(Box::new(crate::clock::StdClock::new())),
+            #[cfg(not(feature = "std"))]
+            clock: None,
+            capabilities: Capabilities::new()
+                .with_feature(Feature::Nack)
+                .with_feature(Feature::DedupCache)
+                .with_feature(Feature::Groups)
+                .with_feature(Feature::WireV2)
+                .with_feature(Feature::Goaway)
+                .with_feature(Feature::MessageDigest)
+                .with_feature(Feature::HalfClose)
+                .with_feature(Feature::FlowControl)
+                .with_feature(Feature::Batch)
+                .with_feature(Feature::MessageReset)
+                .with_feature(Feature::FragmentOffsets)
+                .with_feature(Feature::OriginalDigest),
+            socket: SocketOptions::new(),
+            busy_poll: None,
+            adaptive_frame_size: None,
+            observer: None,
+            progress: None,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            journal: None,
+            encryption: None,
+            authenticator: None,
+            class_rates: BTreeMap::new(),
+            max_send_rate: 0,
+            max_send_burst: 0,
+            max_connection_memory: 0,
+            memory_budget: None,
+            message_digest: false,
         }
     }
 
@@ -23,10 +417,402 @@ impl TransportConfig {
         self
     }
 
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Let the peer have at most `messages` multi-packet messages in reassembly at once
+    ///
+    /// The head of one more is rejected with `ReassemblyLimitExceeded` and its
+    /// body skipped, unless a stale message can be evicted to make room.
+    pub fn with_max_partial_messages(mut self, messages: usize) -> Self {
+        self.max_partial_messages = messages;
+        self
+    }
+
+    /// Treat a message in reassembly as stale once no data arrived for it in `ms`
+    ///
+    /// When the partial message limit or a memory limit is reached, the least
+    /// recently active stale message is dropped to make room. It needs a clock.
+    pub fn with_reassembly_timeout(mut self, ms: u64) -> Self {
+        self.reassembly_timeout_ms = ms;
+        self
+    }
+
     pub fn with_ack(mut self, wait_for_ack: bool) -> Self {
         self.wait_for_ack = wait_for_ack;
         self
     }
+
+    /// Keep up to `packets` unacknowledged packets in flight in ACK mode
+    pub fn with_window(mut self, packets: usize) -> Self {
+        self.window_size = packets.max(1);
+        self
+    }
+
+    /// Keep at most `bytes` of unacknowledged packets in flight in ACK mode, headers included
+    ///
+    /// Applies together with the packet count of `with_window`. A packet
+    /// larger than the limit still goes out once nothing else is in flight.
+    pub fn with_window_bytes(mut self, bytes: usize) -> Self {
+        self.window_bytes = bytes;
+        self
+    }
+
+    /// Coalesce ACKs: acknowledge after `max_unacked` packets or `delay_ms`, whichever comes first
+    ///
+    /// The delay is measured with the configured clock. A pending ACK also
+    /// goes out once a read finds nothing more from the peer (a timeout,
+    /// `WouldBlock` or the end of the stream), or piggybacked on the next
+    /// packet sent, so give a blocking stream a read timeout.
+    pub fn with_delayed_ack(mut self, delay_ms: u64, max_unacked: u32) -> Self {
+        self.ack_delay_ms = delay_ms;
+        self.max_unacked = max_unacked.max(1);
+        self
+    }
+
+    /// Like `with_delayed_ack`, but ACK immediately unless packets arrive back to back
+    ///
+    /// The send pattern is detected from packet inter-arrival times, so this needs a clock.
+    pub fn with_adaptive_ack(mut self, max_delay_ms: u64, max_unacked: u32) -> Self {
+        self = self.with_delayed_ack(max_delay_ms, max_unacked);
+        self.adaptive_ack = true;
+        self
+    }
+
+    /// Pack several packets (data and ACKs) into one write until `bytes` are queued,
+    /// the stream is flushed, or the receive path is about to wait for the peer
+    pub fn with_burst_size(mut self, bytes: usize) -> Self {
+        self.max_burst_size = bytes;
+        self
+    }
+
+    /// Batch writes smaller than `bytes` into one Data packet until `bytes` are
+    /// collected, the transport is flushed or the oldest byte is `max_delay_ms` old
+    ///
+    /// `XTransport::set_nodelay` turns it off at runtime.
+    pub fn with_write_coalescing(mut self, bytes: usize, max_delay_ms: u64) -> Self {
+        self.write_coalesce_size = bytes;
+        self.write_coalesce_delay_ms = max_delay_ms;
+        self
+    }
+
+    /// Keep up to `buffers` received payload buffers of at most `max_buffer_size`
+    /// bytes each for reuse, so a steady stream of packets needs no allocations
+    pub fn with_recv_buffer_pool(mut self, buffers: usize, max_buffer_size: usize) -> Self {
+        self.recv_pool_buffers = buffers;
+        self.recv_pool_max_buffer_size = max_buffer_size;
+        self
+    }
+
+    pub fn with_retransmit(mut self, rto_ms: u64, max_retries: u32) -> Self {
+        self.rto_ms = rto_ms;
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Keep the retransmission timeout between `min_ms` and `max_ms`
+    ///
+    /// The minimum applies to the timeout adapted from RTT samples, which
+    /// the initial `rto_ms` is not; the maximum also caps the backoff.
+    pub fn with_rto_bounds(mut self, min_ms: u64, max_ms: u64) -> Self {
+        self.min_rto_ms = min_ms;
+        self.max_rto_ms = max_ms;
+        self
+    }
+
+    /// Multiply the retransmission timeout by `factor` on every retry, e.g. 1.5
+    /// instead of doubling it, and move each backed-off timeout randomly by up
+    /// to `jitter` of itself, e.g. 0.2 for ±20 %
+    pub fn with_backoff(mut self, factor: f64, jitter: f64) -> Self {
+        self.rto_backoff = factor;
+        self.rto_jitter = jitter;
+        self
+    }
+
+    /// Retransmit up to `ratio` expired packets, oldest first, before each new packet
+    ///
+    /// Without it a sender keeping its window open only retransmits once it
+    /// has to wait for ACKs, leaving the receiver stuck behind a lost packet.
+    pub fn with_retransmit_ratio(mut self, ratio: u32) -> Self {
+        self.retransmit_ratio = ratio;
+        self
+    }
+
+    pub fn with_crc_policy(mut self, policy: CrcPolicy) -> Self {
+        self.crc_policy = policy;
+        self
+    }
+
+    /// Recover from a header with a bad magic number or version by skipping up to `max_bytes`
+    ///
+    /// The receiver drops bytes until a whole packet validates again, as
+    /// `FrameScanner` does, and fails with the header's error only if none
+    /// turns up within the limit. Packets lost in the garbage are then
+    /// handled like any other loss.
+    pub fn with_resync(mut self, max_bytes: usize) -> Self {
+        self.max_resync_bytes = max_bytes;
+        self
+    }
+
+    /// NACK packets missing behind later ones once the gap is `ms` old, and again every `ms`
+    ///
+    /// Set it below the sender's retransmission timeout to recover losses
+    /// sooner. It needs a clock and a peer that advertises `Feature::Nack`.
+    pub fn with_gap_nack(mut self, ms: u64) -> Self {
+        self.gap_nack_ms = ms;
+        self
+    }
+
+    pub fn with_reorder_window(mut self, packets: usize) -> Self {
+        self.reorder_window = packets;
+        self
+    }
+
+    /// Replace the advertised capabilities, e.g. to add `Feature::Compression`
+    /// when the application handles compressed messages
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Busy-poll non-blocking reads, e.g. over shared memory where a blocking
+    /// wait costs more than the message latency
+    pub fn with_busy_poll(mut self, busy_poll: BusyPoll) -> Self {
+        self.busy_poll = Some(busy_poll);
+        self
+    }
+
+    /// Largest payload this end sends and accepts: `max_payload_size`, or the most an adaptive frame size grows to
+    pub fn payload_ceiling(&self) -> usize {
+        self.adaptive_frame_size.map_or(self.max_payload_size, |adaptive| adaptive.max_payload_size.max(self.max_payload_size))
+    }
+
+    /// Start from a small frame size and adapt it to the link, instead of the
+    /// fixed `max_payload_size`; overrides `with_max_frame_size`
+    pub fn with_adaptive_frame_size(mut self, adaptive: AdaptiveFrameSize) -> Self {
+        self.max_payload_size = adaptive.min_payload_size;
+        self.adaptive_frame_size = Some(adaptive);
+        self
+    }
+
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket = options;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: impl Observer + Send + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Report the bytes sent or received of every multi-packet message each `every_packets` packets
+    pub fn with_progress(
+        mut self,
+        every_packets: u32,
+        progress: impl FnMut(Transfer, usize, usize) + Send + 'static,
+    ) -> Self {
+        self.progress = Some(Box::new(progress));
+        self.progress_interval = every_packets.max(1);
+        self
+    }
+
+    /// Persist every message until the peer has acknowledged it
+    ///
+    /// Journaled messages are sent keyed by their journal ID, so a receiver
+    /// using `DuplicatePolicy::Suppress` drops copies resent after a restart.
+    /// Without ACK mode an entry is removed as soon as it has been written.
+    pub fn with_journal(mut self, journal: impl Journal + Send + 'static) -> Self {
+        self.journal = Some(Box::new(journal));
+        self
+    }
+
+    /// Seal every packet with `send` and open the peer's with `recv`
+    ///
+    /// Both ends need the matching keys: one's `send` key is the other's
+    /// `recv` key. They may be the same pre-shared key, but the two ends must
+    /// then take different `role`s, which keep the nonces of the two
+    /// directions apart: one key under the same nonce twice gives up both
+    /// confidentiality and integrity. An end receiving nonces of its own role
+    /// fails with `AuthenticationFailed`.
+    /// Payloads carry a nonce and a tag on top of their data, and the header's
+    /// type and sequence number are authenticated with them, so altered,
+    /// truncated or replayed packets are rejected.
+    pub fn with_encryption(mut self, role: Role, send: impl Cipher + Send + 'static, recv: impl Cipher + Send + 'static) -> Self {
+        self.encryption = Some(Encryption::new(role, send, recv));
+        self
+    }
+
+    /// Authenticate the peer during the handshake
+    ///
+    /// The peer has to answer this end's challenge, whether this end calls
+    /// `handshake` or answers it; when the peer has an authenticator too, each
+    /// end proves itself to the other. Message data arriving before a valid
+    /// response fails with `Unauthorized`, so an unauthorized client never
+    /// reaches the message handler of an `XServer`, and the peer's
+    /// capabilities only take effect once it has authenticated.
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator + Send + 'static) -> Self {
+        self.authenticator = Some(Box::new(authenticator));
+        self
+    }
+
+    /// Pace every write to the stream to `bytes_per_sec`, with bursts of up to `burst_bytes`
+    ///
+    /// Keeps a bulk transfer from starving other traffic sharing a vsock or
+    /// serial link. Writes wait by sleeping on the clock, so it needs one.
+    pub fn with_max_send_rate(mut self, bytes_per_sec: u64, burst_bytes: usize) -> Self {
+        self.max_send_rate = bytes_per_sec;
+        self.max_send_burst = burst_bytes;
+        self
+    }
+
+    /// Cap the bytes this connection may buffer, see `memory`
+    ///
+    /// `queue_message` fails with `WouldBlock` over the cap, and a peer whose
+    /// packets would exceed it is disconnected with `MemoryLimitExceeded`.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.max_connection_memory = bytes;
+        self
+    }
+
+    /// Count this connection's buffers against `budget`, shared with other connections
+    ///
+    /// Give every session of an `XServer` a clone of one budget to bound the
+    /// memory all clients together can make the server hold.
+    pub fn with_memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// End every multi-packet message with a CRC-64 of its content, checked by the receiver
+    ///
+    /// Catches messages assembled wrongly despite intact packets. Skipped for
+    /// a peer whose handshake did not advertise `Feature::MessageDigest`.
+    pub fn with_message_digest(mut self, enabled: bool) -> Self {
+        self.message_digest = enabled;
+        self
+    }
+
+    /// Cap the wire bytes per second of queued messages in `class`
+    ///
+    /// Enforced by `poll_send` and `flush_queue`, which need a clock for it.
+    pub fn with_class_rate(mut self, class: u8, bytes_per_sec: u64, burst_bytes: usize) -> Self {
+        self.class_rates.insert(class, ClassRate { bytes_per_sec, burst_bytes });
+        self
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + Send + 'static) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Replace repeated payloads with short references to a peer-side cache
+    ///
+    /// Both peers must use the same number of entries. A payload is cached
+    /// once the peer has acknowledged its last packet, so references are only
+    /// sent in ACK mode (`with_ack`).
+    pub fn with_dedup_cache(mut self, entries: usize) -> Self {
+        self.dedup_cache_size = entries;
+        self
+    }
+
+    /// Detect repeats among the last `history` keys of messages sent with
+    /// `send_keyed_message` and the last `history` IDs of multi-packet messages
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy, history: usize) -> Self {
+        self.duplicate_policy = policy;
+        self.duplicate_history = history;
+        self
+    }
+}
+
+/// Prefix of the environment variables read by `TransportConfig::from_env`
+#[cfg(feature = "serde")]
+pub const ENV_PREFIX: &str = "XTRANSPORT_";
+
+#[cfg(feature = "serde")]
+impl TransportConfig {
+    /// Load settings from a TOML document, e.g. `max_payload_size = 2032`
+    ///
+    /// Nested settings are tables such as `[socket]` or `[class_rates.1]`.
+    pub fn from_toml_str(toml: &str) -> Result<Self> {
+        toml::from_str(toml).map_err(|e| {
+            log::warn!("Invalid transport config: {}", e);
+            Error::new(ErrorKind::InvalidInput)
+        })
+    }
+
+    /// Load settings from `XTRANSPORT_*` environment variables
+    ///
+    /// `XTRANSPORT_WINDOW_SIZE=8` sets `window_size`; a double underscore
+    /// reaches into a table, as in `XTRANSPORT_SOCKET__NODELAY=true`. Values
+    /// are TOML, and anything else is taken as a string, so
+    /// `XTRANSPORT_CRC_POLICY=Nack` needs no quotes.
+    pub fn from_env() -> Result<Self> {
+        let mut toml = alloc::string::String::new();
+        for (name, value) in std::env::vars() {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) => key.to_ascii_lowercase().replace("__", "."),
+                None => continue,
+            };
+            let value = match alloc::format!("v = {}", value).parse::<toml::Table>() {
+                Ok(_) => value,
+                Err(_) => toml::Value::String(value).to_string(),
+            };
+            toml.push_str(&alloc::format!("{} = {}\n", key, value));
+        }
+        Self::from_toml_str(&toml)
+    }
+
+    /// Load the settings of a binary: from the TOML file named by a
+    /// `--config <file>` argument, else from `XTRANSPORT_*` variables if any
+    /// is set, else `default`
+    pub fn load(default: Self) -> Result<Self> {
+        let mut args = std::env::args().skip_while(|arg| arg != "--config");
+        if args.next().is_some() {
+            let path = args.next().ok_or_else(|| {
+                log::warn!("--config needs a file");
+                Error::new(ErrorKind::InvalidInput)
+            })?;
+            let toml = std::fs::read_to_string(&path).map_err(|e| {
+                log::warn!("Cannot read config file {}: {}", path, e);
+                Error::new(ErrorKind::InvalidInput)
+            })?;
+            return Self::from_toml_str(&toml);
+        }
+        if std::env::vars().any(|(name, _)| name.starts_with(ENV_PREFIX)) {
+            return Self::from_env();
+        }
+        Ok(default)
+    }
+}
+
+/// Integer-keyed maps with string keys, as TOML tables require
+#[cfg(feature = "serde")]
+pub(crate) mod int_keys {
+    use alloc::collections::BTreeMap;
+    use alloc::string::{String, ToString};
+    use core::{fmt::Display, str::FromStr};
+    use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K: Display, V: Serialize, S: Serializer>(
+        map: &BTreeMap<K, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(key, value)| (key.to_string(), value)))
+    }
+
+    pub fn deserialize<'de, K: FromStr + Ord, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<K, V>, D::Error> {
+        BTreeMap::<String, V>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| match key.parse() {
+                Ok(parsed) => Ok((parsed, value)),
+                Err(_) => Err(D::Error::custom(alloc::format!("invalid key {:?}", key))),
+            })
+            .collect()
+    }
 }
 
 impl Default for TransportConfig {
@@ -0,0 +1,223 @@
+use crate::{
+    config::HEADER_SIZE,
+    error::{Error, ErrorKind},
+    io::Read,
+    pool::BufferPool,
+    protocol::{Packet, PacketHeader, MAX_PAYLOAD_SIZE_V1, VERSION_2},
+    scan::{FrameScanner, Scan},
+    Result,
+};
+use alloc::vec::Vec;
+
+/// Default number of bytes requested from the stream per read
+pub const DEFAULT_READ_SIZE: usize = 64 * 1024;
+
+/// Ingress decoder that reads large blocks from a stream and parses every
+/// complete packet they contain, instead of one header-then-payload read pair
+/// per packet
+pub struct PacketDecoder {
+    buf: Vec<u8>,
+    pos: usize,
+    read_size: usize,
+    /// Payload buffers handed back with `recycle`
+    pool: BufferPool,
+    /// Largest payload a header may announce, checked before any of it is buffered
+    max_payload: usize,
+    /// Whether version 2 headers are accepted, which only a negotiated `Feature::WireV2` sends
+    version_2: bool,
+    /// Keeps the buffered bytes after a malformed header, for `skip_to_packet`
+    resync: Option<FrameScanner>,
+}
+
+impl PacketDecoder {
+    pub fn new(read_size: usize) -> Self {
+        PacketDecoder {
+            buf: Vec::new(),
+            pos: 0,
+            read_size: read_size.max(HEADER_SIZE),
+            pool: BufferPool::new(0, 0),
+            max_payload: MAX_PAYLOAD_SIZE_V1,
+            version_2: false,
+            resync: None,
+        }
+    }
+
+    /// Decode payloads into buffers taken from `pool`
+    pub fn with_pool(mut self, pool: BufferPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Fail headers announcing more than `max_payload` bytes with `InvalidPacket`
+    ///
+    /// The default is the most a version 1 header can announce. Without a
+    /// bound, one forged version 2 header would have the decoder buffer up to
+    /// 4 GiB waiting for a payload that never comes.
+    pub fn with_max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = max_payload;
+    }
+
+    /// Accept version 2 headers, which otherwise fail with `InvalidVersion`
+    pub fn set_version_2(&mut self, accepted: bool) {
+        self.version_2 = accepted;
+    }
+
+    /// Recover from malformed headers with `scanner` instead of discarding everything buffered
+    pub fn with_resync(mut self, scanner: FrameScanner) -> Self {
+        self.resync = Some(scanner);
+        self
+    }
+
+    pub fn set_resync(&mut self, scanner: Option<FrameScanner>) {
+        self.resync = scanner;
+    }
+
+    /// Give back the payload of a consumed packet for a later one to reuse
+    pub fn recycle(&mut self, buf: Vec<u8>) {
+        self.pool.recycle(buf);
+    }
+
+    /// Bytes read from the stream but not yet decoded
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// True if the next packet can be decoded without reading from the stream
+    pub fn has_complete_packet(&self) -> bool {
+        match PacketHeader::parse(&self.buf[self.pos..]) {
+            Ok(header) => self.check(&header).is_err() || self.buffered() >= header.size() + header.length as usize,
+            // A malformed header is reported by `decode` without reading
+            Err(e) => e.kind() != ErrorKind::UnexpectedEof,
+        }
+    }
+
+    /// Drop all buffered bytes
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.pos = 0;
+    }
+
+    /// Perform one read of up to `read_size` bytes, returning how many arrived
+    ///
+    /// End of stream is reported as `UnexpectedEof`.
+    pub fn fill_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<usize> {
+        // Reclaim the space of already decoded packets before growing
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+
+        let start = self.buf.len();
+        self.buf.resize(start + self.read_size, 0);
+        match reader.read(&mut self.buf[start..]) {
+            Ok(0) => {
+                self.buf.truncate(start);
+                Err(Error::new(ErrorKind::UnexpectedEof))
+            }
+            Ok(n) => {
+                self.buf.truncate(start + n);
+                Ok(n)
+            }
+            Err(e) => {
+                self.buf.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
+    /// Decode the next packet from buffered bytes, or `None` if it is incomplete
+    ///
+    /// A malformed header discards everything buffered, since packet boundaries
+    /// can no longer be trusted, unless the decoder was built `with_resync`:
+    /// the bytes are then kept for `skip_to_packet`. A header announcing more
+    /// than `max_payload` counts as malformed and fails with `InvalidPacket`.
+    pub fn decode(&mut self) -> Option<Result<Packet>> {
+        if self.buffered() < HEADER_SIZE {
+            return None;
+        }
+
+        let header = match PacketHeader::parse(&self.buf[self.pos..]).and_then(|header| self.check(&header).map(|()| header)) {
+            Ok(header) => header,
+            // Version 2 headers are longer than the minimum checked above
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return None,
+            Err(e) => {
+                if self.resync.is_none() {
+                    self.clear();
+                }
+                return Some(Err(e));
+            }
+        };
+
+        let len = header.size() + header.length as usize;
+        if self.buffered() < len {
+            return None;
+        }
+        let mut data = self.pool.take();
+        data.extend_from_slice(&self.buf[self.pos + header.size()..self.pos + len]);
+        self.pos += len;
+
+        let packet = Packet { header, data };
+        if !packet.verify_crc() {
+            self.pool.recycle(packet.data);
+            return Some(Err(Error::new(ErrorKind::CrcMismatch).with_seq(header.seq)));
+        }
+        Some(Ok(packet))
+    }
+
+    /// Whether a parsed header is one this decoder takes
+    fn check(&self, header: &PacketHeader) -> Result<()> {
+        if header.version == VERSION_2 && !self.version_2 {
+            return Err(Error::new(ErrorKind::InvalidVersion).with_seq(header.seq));
+        }
+        if header.length as usize > self.max_payload {
+            return Err(Error::new(ErrorKind::InvalidPacket).with_seq(header.seq));
+        }
+        Ok(())
+    }
+
+    /// Drop the buffered bytes in front of the next valid packet, returning how many and whether one was found
+    ///
+    /// Without a valid packet, only the bytes that cannot start one are
+    /// dropped. Without `with_resync` nothing is.
+    pub fn skip_to_packet(&mut self) -> (usize, bool) {
+        let Some(scanner) = self.resync else {
+            return (0, false);
+        };
+        let (skip, found) = match scanner.scan(&self.buf[self.pos..]) {
+            Scan::Packet { skip, .. } => (skip, true),
+            Scan::Incomplete { skip } => (skip, false),
+        };
+        self.pos += skip;
+        (skip, found)
+    }
+
+    /// Read one block from `reader` and iterate over every complete packet now buffered
+    pub fn read_packets<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<Packets<'_>> {
+        self.fill_from(reader)?;
+        Ok(Packets { decoder: self })
+    }
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_READ_SIZE)
+    }
+}
+
+/// Iterator over the complete packets buffered in a `PacketDecoder`
+pub struct Packets<'a> {
+    decoder: &'a mut PacketDecoder,
+}
+
+impl Iterator for Packets<'_> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decoder.decode()
+    }
+}
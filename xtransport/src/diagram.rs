@@ -0,0 +1,158 @@
+//! Sequence diagrams of a connection, built from its observer events
+//!
+//! Attach `SequenceDiagram::observer` to a transport with
+//! `TransportConfig::with_observer`, run the exchange, then export the
+//! recorded traffic with `to_mermaid` or `to_plantuml` for a support ticket.
+
+use crate::observer::{Observer, TransportEvent};
+use crate::protocol::{PacketType, PACKET_FLAG_ACK};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use std::sync::Mutex;
+
+/// Events kept by default; a bulk transfer would otherwise produce an unreadable diagram
+const DEFAULT_EVENT_LIMIT: usize = 1000;
+
+/// Recorder of one connection's events, shared between its observer and the exporter
+#[derive(Clone)]
+pub struct SequenceDiagram {
+    events: Arc<Mutex<Vec<TransportEvent>>>,
+    dropped: Arc<Mutex<usize>>,
+    limit: usize,
+    local: String,
+    peer: String,
+}
+
+impl SequenceDiagram {
+    /// Recorder drawing the transport as `local` and the other end as `peer`
+    pub fn new(local: &str, peer: &str) -> Self {
+        SequenceDiagram {
+            events: Arc::new(Mutex::new(Vec::new())),
+            dropped: Arc::new(Mutex::new(0)),
+            limit: DEFAULT_EVENT_LIMIT,
+            local: local.into(),
+            peer: peer.into(),
+        }
+    }
+
+    /// Keep at most `events` events, counting the rest as omitted
+    pub fn with_limit(mut self, events: usize) -> Self {
+        self.limit = events;
+        self
+    }
+
+    /// Observer feeding this diagram
+    pub fn observer(&self) -> impl Observer + Send + 'static {
+        let diagram = self.clone();
+        move |event: &TransportEvent| diagram.record(event)
+    }
+
+    fn record(&self, event: &TransportEvent) {
+        let mut events = self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if events.len() < self.limit {
+            events.push(*event);
+        } else {
+            *self.dropped.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) += 1;
+        }
+    }
+
+    pub fn events(&self) -> Vec<TransportEvent> {
+        self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// Mermaid `sequenceDiagram`
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("sequenceDiagram\n");
+        let _ = writeln!(out, "    participant L as {}", self.local);
+        let _ = writeln!(out, "    participant P as {}", self.peer);
+        for line in self.lines() {
+            let _ = match line {
+                Line::Sent(text) => writeln!(out, "    L->>P: {}", text),
+                Line::Received(text) => writeln!(out, "    P->>L: {}", text),
+                Line::Retransmit(text) => writeln!(out, "    L-->>P: {}", text),
+                Line::Note(text) => writeln!(out, "    Note over L: {}", text),
+            };
+        }
+        out
+    }
+
+    /// PlantUML sequence diagram
+    pub fn to_plantuml(&self) -> String {
+        let mut out = String::from("@startuml\n");
+        let _ = writeln!(out, "participant \"{}\" as L", self.local);
+        let _ = writeln!(out, "participant \"{}\" as P", self.peer);
+        for line in self.lines() {
+            let _ = match line {
+                Line::Sent(text) => writeln!(out, "L -> P : {}", text),
+                Line::Received(text) => writeln!(out, "P -> L : {}", text),
+                Line::Retransmit(text) => writeln!(out, "L --> P : {}", text),
+                Line::Note(text) => writeln!(out, "note over L : {}", text),
+            };
+        }
+        out.push_str("@enduml\n");
+        out
+    }
+
+    fn lines(&self) -> Vec<Line> {
+        let mut lines: Vec<Line> = self.events().iter().map(Line::from_event).collect();
+        let dropped = *self.dropped.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if dropped > 0 {
+            lines.push(Line::Note(alloc::format!("{} more events omitted", dropped)));
+        }
+        lines
+    }
+}
+
+/// One arrow or note of the diagram, independent of the output syntax
+enum Line {
+    Sent(String),
+    Received(String),
+    /// Retransmission, drawn dashed to stand out from first transmissions
+    Retransmit(String),
+    Note(String),
+}
+
+impl Line {
+    fn from_event(event: &TransportEvent) -> Line {
+        match *event {
+            TransportEvent::PacketSent { pkt_type, seq, len } => {
+                Line::Sent(alloc::format!("{} seq={} len={}", type_name(pkt_type), seq, len))
+            }
+            TransportEvent::PacketReceived { pkt_type, seq, len } => {
+                Line::Received(alloc::format!("{} seq={} len={}", type_name(pkt_type), seq, len))
+            }
+            TransportEvent::CrcFailure => Line::Note("CRC mismatch, packet dropped".into()),
+            TransportEvent::AckReceived { seq, acked } => {
+                Line::Note(alloc::format!("ACK up to seq={} released {} packets", seq, acked))
+            }
+            TransportEvent::Retransmit { seq, retry } => {
+                Line::Retransmit(alloc::format!("retransmit seq={} (retry {})", seq, retry))
+            }
+            TransportEvent::Handshake { peer_features } => {
+                Line::Note(alloc::format!("handshake, peer features {:#x}", peer_features))
+            }
+            TransportEvent::FrameSizeChanged { payload_size } => {
+                Line::Note(alloc::format!("max payload now {} bytes", payload_size))
+            }
+            TransportEvent::StateChanged { state } => Line::Note(alloc::format!("connection {:?}", state)),
+            TransportEvent::DeliveryFailed(failure) => Line::Note(match failure.message_id {
+                Some(id) => alloc::format!("gave up on seq={}, message {} bytes {}..{}",
+                                           failure.seq, id, failure.offset, failure.offset + failure.len),
+                None => alloc::format!("gave up on seq={}", failure.seq),
+            }),
+        }
+    }
+}
+
+fn type_name(pkt_type: u8) -> String {
+    let mut name = match PacketType::from_u8(pkt_type & !PACKET_FLAG_ACK) {
+        Some(known) => alloc::format!("{:?}", known),
+        None => alloc::format!("type {:#04x}", pkt_type & !PACKET_FLAG_ACK),
+    };
+    if pkt_type & PACKET_FLAG_ACK != 0 {
+        name.push_str("+ACK");
+    }
+    name
+}
@@ -0,0 +1,63 @@
+//! Whole-message digest
+//!
+//! Packet CRCs cover one packet each, so a message assembled from the right
+//! packets in the wrong order, or with a chunk copied twice, passes them all.
+//! With `with_message_digest` the sender appends a CRC-64 of the whole message
+//! to its body and the receiver checks it before delivering the message.
+
+/// Reflected ECMA-182 polynomial of CRC-64/XZ
+const POLY: u64 = 0xC96C_5795_D787_0F42;
+
+const TABLE: [u64; 256] = table();
+
+const fn table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Incremental CRC-64/XZ, fed a message chunk by chunk
+#[derive(Debug, Clone, Copy)]
+pub struct Crc64 {
+    crc: u64,
+}
+
+impl Crc64 {
+    pub fn new() -> Self {
+        Crc64 { crc: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = TABLE[((self.crc ^ byte as u64) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    /// Digest of everything fed so far
+    pub fn finalize(&self) -> u64 {
+        !self.crc
+    }
+}
+
+impl Default for Crc64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-64/XZ of `data`
+pub fn crc64(data: &[u8]) -> u64 {
+    let mut crc = Crc64::new();
+    crc.update(data);
+    crc.finalize()
+}
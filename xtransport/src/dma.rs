@@ -0,0 +1,292 @@
+//! Packets in DMA memory for embedded targets
+//!
+//! A UART or SPI DMA engine moves a frame between memory and the wire on its
+//! own while the CPU works on the next one. `DmaFrames` splits a `DmaBuffer`
+//! into two halves for that: on the sending side the CPU encodes packets
+//! straight into the free half with `encode` and hands them to the engine
+//! with `start`, then `complete` gives the half back once the transfer is
+//! done. On the receiving side the engine fills the free half
+//! (`free_half_mut`), `receive` passes it to the CPU, and `frames` parses the
+//! packets in it in place. Neither direction copies the payload through an
+//! intermediate buffer.
+//!
+//! The receiving side expects every transfer to end on a packet boundary, as
+//! with idle-line detection on a UART; a packet cut off at the end of a
+//! transfer is reported as `UnexpectedEof`.
+
+use crate::{
+    error::ErrorKind,
+    io::Write,
+    irq::{Producer, QueueStorage},
+    protocol::{PacketHeader, PacketType, HEADER_SIZE_V2, MAX_PAYLOAD_SIZE_V1, VERSION_2},
+    Error, Result,
+};
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
+/// Alignment of `DmaArray`, a cache line on common MCUs and application cores
+pub const DMA_ALIGN: usize = 32;
+
+/// Memory a DMA engine can be pointed at
+///
+/// # Safety
+///
+/// `as_slice` and `as_mut_slice` must return the same memory, at the same
+/// address and with the same length, for as long as the buffer exists, and it
+/// must start at a multiple of `ALIGN`. A DMA engine keeps using the address
+/// it was given even if the value owning the memory is moved.
+pub unsafe trait DmaBuffer {
+    /// Alignment of the start of the buffer, a power of two
+    const ALIGN: usize;
+    fn as_slice(&self) -> &[u8];
+    fn as_mut_slice(&mut self) -> &mut [u8];
+}
+
+/// Byte array aligned for DMA, to be placed in a `static` or a `Box`
+#[repr(C, align(32))]
+pub struct DmaArray<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> DmaArray<N> {
+    pub const fn new() -> Self {
+        DmaArray([0; N])
+    }
+}
+
+impl<const N: usize> Default for DmaArray<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// SAFETY: a `'static` borrow never moves, and the array is aligned to `DMA_ALIGN`
+unsafe impl<const N: usize> DmaBuffer for &'static mut DmaArray<N> {
+    const ALIGN: usize = DMA_ALIGN;
+
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+// SAFETY: the heap allocation stays in place when the box is moved
+#[cfg(feature = "alloc")]
+unsafe impl<const N: usize> DmaBuffer for Box<DmaArray<N>> {
+    const ALIGN: usize = DMA_ALIGN;
+
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Packet parsed in place from received DMA memory, or borrowing the payload of one to send
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    pub header: PacketHeader,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Frame of a packet to send, with its header computed over `payload`
+    ///
+    /// Fails with `MessageTooLarge` beyond the 64 KB payload of a packet.
+    pub fn new(pkt_type: PacketType, seq: u32, payload: &'a [u8]) -> Result<Self> {
+        if payload.len() > MAX_PAYLOAD_SIZE_V1 {
+            return Err(Error::new(ErrorKind::MessageTooLarge).with_seq(seq));
+        }
+        Ok(Frame {
+            header: PacketHeader::for_parts(pkt_type, seq, &[payload]),
+            payload,
+        })
+    }
+
+    /// Bytes the frame takes on the wire
+    pub fn size(&self) -> usize {
+        self.header.size() + self.payload.len()
+    }
+
+    /// Write the header and then the payload straight to `writer`, returning the bytes written
+    ///
+    /// The payload is not copied into a frame buffer first, so frames are
+    /// not limited by the size of one.
+    pub fn serialize_to_writer<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize> {
+        let (header, len) = self.header_bytes();
+        writer.write_all(&header[..len])?;
+        writer.write_all(self.payload)?;
+        Ok(self.size())
+    }
+
+    /// Queue the whole frame on a byte ring such as a UART transmit queue, returning its size
+    ///
+    /// A frame is never queued in part: it fails with `WouldBlock` while the
+    /// ring lacks the room, and with `MessageTooLarge` if it never will have it.
+    pub fn serialize_into_ring<S: QueueStorage>(&self, ring: &mut Producer<'_, S>) -> Result<usize> {
+        let size = self.size();
+        if size > ring.capacity() {
+            return Err(Error::new(ErrorKind::MessageTooLarge).with_seq(self.header.seq));
+        }
+        if size > ring.free() {
+            return Err(Error::new(ErrorKind::WouldBlock));
+        }
+        let (header, len) = self.header_bytes();
+        ring.push_slice(&header[..len]);
+        ring.push_slice(self.payload);
+        Ok(size)
+    }
+
+    /// Header encoded for its version, in the first returned number of bytes
+    fn header_bytes(&self) -> ([u8; HEADER_SIZE_V2], usize) {
+        let mut buf = [0u8; HEADER_SIZE_V2];
+        if self.header.version == VERSION_2 {
+            buf = self.header.to_bytes_v2();
+        } else {
+            buf[..self.header.size()].copy_from_slice(&self.header.to_bytes());
+        }
+        (buf, self.header.size())
+    }
+}
+
+/// Two halves of a `DmaBuffer`: one the DMA engine owns while the CPU uses the other
+pub struct DmaFrames<B: DmaBuffer> {
+    buffer: B,
+    /// Size of each half, a multiple of the buffer's alignment so both halves are aligned
+    half: usize,
+    /// Index of the half the CPU owns
+    free: usize,
+    /// Bytes encoded into the free half
+    staged: usize,
+    /// Bytes of the other half handed over, until `complete`
+    in_flight: Option<usize>,
+}
+
+impl<B: DmaBuffer> DmaFrames<B> {
+    pub fn new(buffer: B) -> Self {
+        let half = (buffer.as_slice().len() / 2) & !(B::ALIGN - 1);
+        DmaFrames {
+            buffer,
+            half,
+            free: 0,
+            staged: 0,
+            in_flight: None,
+        }
+    }
+
+    /// Bytes each half holds
+    pub fn half_size(&self) -> usize {
+        self.half
+    }
+
+    /// Bytes encoded into the free half and not yet started
+    pub fn staged_len(&self) -> usize {
+        self.staged
+    }
+
+    /// The half handed to the DMA engine or received from it, if any
+    pub fn in_flight(&self) -> Option<&[u8]> {
+        let start = (1 - self.free) * self.half;
+        self.in_flight.map(|len| &self.buffer.as_slice()[start..start + len])
+    }
+
+    /// Append a packet with `payload` to the free half
+    ///
+    /// Several packets may be staged to go out in one transfer. Fails with
+    /// `MessageTooLarge` if the packet does not fit in what is left of the half.
+    pub fn encode(&mut self, pkt_type: PacketType, seq: u32, payload: &[u8]) -> Result<usize> {
+        let header = PacketHeader::for_parts(pkt_type, seq, &[payload]);
+        let size = header.size() + payload.len();
+        if payload.len() > u16::MAX as usize || self.staged + size > self.half {
+            return Err(Error::new(ErrorKind::MessageTooLarge).with_seq(seq));
+        }
+        let start = self.free * self.half + self.staged;
+        let frame = &mut self.buffer.as_mut_slice()[start..start + size];
+        frame[..header.size()].copy_from_slice(&header.to_bytes());
+        frame[header.size()..].copy_from_slice(payload);
+        self.staged += size;
+        Ok(size)
+    }
+
+    /// Hand the staged packets to the DMA engine, returning the memory to transfer
+    ///
+    /// The CPU then encodes into the other half. Fails with `WouldBlock` while
+    /// the previous transfer has not completed.
+    pub fn start(&mut self) -> Result<&[u8]> {
+        if self.in_flight.is_some() {
+            return Err(Error::new(ErrorKind::WouldBlock));
+        }
+        self.in_flight = Some(core::mem::take(&mut self.staged));
+        self.free = 1 - self.free;
+        Ok(self.in_flight().unwrap_or_default())
+    }
+
+    /// The free half, for the DMA engine to receive into
+    pub fn free_half_mut(&mut self) -> &mut [u8] {
+        let start = self.free * self.half;
+        &mut self.buffer.as_mut_slice()[start..start + self.half]
+    }
+
+    /// Take the `len` bytes the DMA engine received into the free half for parsing
+    ///
+    /// The engine then receives into the other half. Fails with `WouldBlock`
+    /// while the previous reception has not been completed.
+    pub fn receive(&mut self, len: usize) -> Result<()> {
+        if self.in_flight.is_some() {
+            return Err(Error::new(ErrorKind::WouldBlock));
+        }
+        if len > self.half {
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        self.in_flight = Some(len);
+        self.free = 1 - self.free;
+        Ok(())
+    }
+
+    /// Packets in the received half, parsed in place
+    pub fn frames(&self) -> Frames<'_> {
+        Frames {
+            buf: self.in_flight().unwrap_or_default(),
+        }
+    }
+
+    /// Give the in-flight half back once its transfer is done or its packets are consumed
+    pub fn complete(&mut self) {
+        self.in_flight = None;
+    }
+
+    pub fn into_inner(self) -> B {
+        self.buffer
+    }
+}
+
+/// Iterator over the packets of a received half, stopping after the first error
+pub struct Frames<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<Frame<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let result = PacketHeader::parse(self.buf).and_then(|header| {
+            let end = header.size() + header.length as usize;
+            let payload = self.buf.get(header.size()..end).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof))?;
+            if crc32fast::hash(payload) != header.crc32 {
+                return Err(Error::new(ErrorKind::CrcMismatch).with_seq(header.seq));
+            }
+            self.buf = &self.buf[end..];
+            Ok(Frame { header, payload })
+        });
+        if result.is_err() {
+            self.buf = &[];
+        }
+        Some(result)
+    }
+}
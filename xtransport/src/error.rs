@@ -1,61 +1,233 @@
 use core::fmt;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ErrorKind {
     InvalidMagic,
     InvalidVersion,
     CrcMismatch,
+    SequenceGap,
+    /// A packet of a multi-packet message did not directly follow the previous one
+    MissingPacket { expected: u32, got: u32 },
     UnexpectedEof,
     InvalidPacket,
+    InvalidInput,
+    UnknownReference,
+    Unsupported,
     WriteZero,
     Interrupted,
+    WouldBlock,
+    TimedOut,
+    MaxRetriesExceeded,
+    DuplicateMessage,
+    MessageTooLarge,
+    /// The peer closed the connection with a Goaway packet carrying this code
+    PeerGoaway { code: u32 },
+    /// An encrypted packet failed authentication: forged, altered or cut short
+    AuthenticationFailed,
+    /// The peer did not authenticate, or this end has no credentials for a peer that requires them
+    Unauthorized,
+    /// Buffering more for the connection would exceed its memory cap or the shared budget
+    MemoryLimitExceeded,
+    /// A new message would exceed the multi-packet messages the peer may have in reassembly at once
+    ReassemblyLimitExceeded,
+    /// A reassembled message does not match the digest its sender computed
+    DigestMismatch,
+    /// Sending after `shutdown_write` closed this end's direction
+    WriteShutdown,
+    /// Stopped through a `CancellationToken`, or the message being received was reset by its sender
+    Cancelled,
     Other,
 }
 
+/// Stage of the connection an error happened in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Phase {
+    Handshake,
+    Send,
+    Recv,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Handshake => write!(f, "handshake"),
+            Phase::Send => write!(f, "send"),
+            Phase::Recv => write!(f, "receive"),
+        }
+    }
+}
+
+/// Error with its kind, the context it happened in and, with `std`, the I/O error behind it
 #[derive(Debug)]
 pub struct Error {
     kind: ErrorKind,
+    phase: Option<Phase>,
+    seq: Option<u32>,
+    #[cfg(feature = "std")]
+    source: Option<std::io::Error>,
 }
 
 impl Error {
     pub fn new(kind: ErrorKind) -> Self {
-        Error { kind }
+        Error {
+            kind,
+            phase: None,
+            seq: None,
+            #[cfg(feature = "std")]
+            source: None,
+        }
     }
 
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
-}
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    /// Operation that failed, if known
+    pub fn phase(&self) -> Option<Phase> {
+        self.phase
+    }
+
+    /// Sequence number of the packet involved, if any
+    pub fn seq(&self) -> Option<u32> {
+        self.seq
+    }
+
+    /// Record the operation that failed, unless a more specific one was recorded already
+    pub fn with_phase(mut self, phase: Phase) -> Self {
+        self.phase.get_or_insert(phase);
+        self
+    }
+
+    /// Record the sequence number of the packet involved, unless one was recorded already
+    pub fn with_seq(mut self, seq: u32) -> Self {
+        self.seq.get_or_insert(seq);
+        self
+    }
+
+    /// Error of the underlying stream this one was converted from
+    #[cfg(feature = "std")]
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        self.source.as_ref()
+    }
+
+    fn describe(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.kind {
             ErrorKind::InvalidMagic => write!(f, "Invalid magic number"),
             ErrorKind::InvalidVersion => write!(f, "Invalid protocol version"),
             ErrorKind::CrcMismatch => write!(f, "CRC checksum mismatch"),
+            ErrorKind::SequenceGap => write!(f, "Unrecoverable packet sequence gap"),
+            ErrorKind::MissingPacket { expected, got } => {
+                write!(f, "Missing message packet: expected seq {}, got {}", expected, got)
+            }
             ErrorKind::UnexpectedEof => write!(f, "Unexpected end of file"),
             ErrorKind::InvalidPacket => write!(f, "Invalid packet"),
+            ErrorKind::InvalidInput => write!(f, "Invalid input"),
+            ErrorKind::UnknownReference => write!(f, "Reference to unknown cached payload"),
+            ErrorKind::Unsupported => write!(f, "Operation not supported"),
             ErrorKind::WriteZero => write!(f, "Write zero bytes"),
             ErrorKind::Interrupted => write!(f, "Operation interrupted"),
+            ErrorKind::WouldBlock => write!(f, "Operation would block"),
+            ErrorKind::TimedOut => write!(f, "Operation timed out"),
+            ErrorKind::MaxRetriesExceeded => write!(f, "Maximum retransmissions exceeded"),
+            ErrorKind::DuplicateMessage => write!(f, "Duplicate message"),
+            ErrorKind::MessageTooLarge => write!(f, "Message exceeds the maximum message size"),
+            ErrorKind::PeerGoaway { code } => write!(f, "Peer closed the connection with code {}", code),
+            ErrorKind::AuthenticationFailed => write!(f, "Packet failed authentication"),
+            ErrorKind::Unauthorized => write!(f, "Peer not authorized"),
+            ErrorKind::MemoryLimitExceeded => write!(f, "Connection memory limit exceeded"),
+            ErrorKind::ReassemblyLimitExceeded => write!(f, "Too many messages in reassembly"),
+            ErrorKind::DigestMismatch => write!(f, "Message digest mismatch"),
+            ErrorKind::WriteShutdown => write!(f, "Sending direction already shut down"),
+            ErrorKind::Cancelled => write!(f, "Operation cancelled"),
             ErrorKind::Other => write!(f, "Other error"),
         }
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.describe(f)?;
+        if let Some(phase) = self.phase {
+            write!(f, " during {}", phase)?;
+        }
+        if let Some(seq) = self.seq {
+            write!(f, " (seq {})", seq)?;
+        }
+        #[cfg(feature = "std")]
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Kind, phase and sequence number; the I/O error behind it is left out
+#[cfg(feature = "defmt")]
+impl defmt::Format for Error {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{}", self.kind);
+        if let Some(phase) = self.phase {
+            defmt::write!(f, " during {}", phase);
+        }
+        if let Some(seq) = self.seq {
+            defmt::write!(f, " (seq {})", seq);
+        }
+    }
+}
+
 #[cfg(feature = "std")]
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
 
 #[cfg(feature = "std")]
 impl From<Error> for std::io::Error {
     fn from(err: Error) -> std::io::Error {
+        // An error that came from the stream keeps the stream's kind
+        if let Some(kind) = err.source.as_ref().map(std::io::Error::kind) {
+            return std::io::Error::new(kind, err);
+        }
         let kind = match err.kind {
             ErrorKind::UnexpectedEof => std::io::ErrorKind::UnexpectedEof,
             ErrorKind::WriteZero => std::io::ErrorKind::WriteZero,
             ErrorKind::Interrupted => std::io::ErrorKind::Interrupted,
+            ErrorKind::WouldBlock => std::io::ErrorKind::WouldBlock,
+            ErrorKind::TimedOut | ErrorKind::MaxRetriesExceeded => std::io::ErrorKind::TimedOut,
+            ErrorKind::InvalidInput => std::io::ErrorKind::InvalidInput,
+            ErrorKind::Unsupported => std::io::ErrorKind::Unsupported,
+            ErrorKind::DuplicateMessage => std::io::ErrorKind::AlreadyExists,
+            ErrorKind::MessageTooLarge | ErrorKind::AuthenticationFailed | ErrorKind::DigestMismatch => {
+                std::io::ErrorKind::InvalidData
+            }
+            ErrorKind::PeerGoaway { .. } => std::io::ErrorKind::ConnectionReset,
+            ErrorKind::Unauthorized => std::io::ErrorKind::PermissionDenied,
+            ErrorKind::MemoryLimitExceeded => std::io::ErrorKind::OutOfMemory,
+            ErrorKind::WriteShutdown => std::io::ErrorKind::BrokenPipe,
             _ => std::io::ErrorKind::Other,
         };
         std::io::Error::new(kind, err)
     }
 }
 
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        let kind = match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => ErrorKind::UnexpectedEof,
+            std::io::ErrorKind::WriteZero => ErrorKind::WriteZero,
+            std::io::ErrorKind::Interrupted => ErrorKind::Interrupted,
+            std::io::ErrorKind::WouldBlock => ErrorKind::WouldBlock,
+            std::io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+            std::io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+            std::io::ErrorKind::Unsupported => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        };
+        Error { source: Some(err), ..Error::new(kind) }
+    }
+}
+
 pub type Result<T> = core::result::Result<T, Error>;
@@ -0,0 +1,178 @@
+//! One producer, many consumers: the same message sent to every registered transport
+
+use crate::{
+    error::ErrorKind,
+    io::{Read, Write},
+    transport::XTransport,
+    Error,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// What `FanoutSender::send` does for a peer whose stream has not taken the earlier messages yet
+///
+/// A peer is slow while its non-blocking stream holds back bytes already
+/// sent (`XTransport::pending_write_len`). A blocking stream is never slow:
+/// writing to it simply waits, whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowPeerPolicy {
+    /// Skip the message for that peer, counting it as dropped
+    Drop,
+    /// Wait until the peer's stream has taken the backlog, holding up the peers after it
+    Block,
+    /// Keep sending; a peer holding back more than `max_bytes` is removed
+    /// with `MemoryLimitExceeded`
+    Buffer { max_bytes: usize },
+}
+
+/// Outcome of one `FanoutSender::send` or `poll_flush`
+#[derive(Debug, Default)]
+pub struct FanoutReport {
+    /// Peers the message was handed to
+    pub sent: usize,
+    /// Peers that skipped the message for being slow (`SlowPeerPolicy::Drop`)
+    pub dropped: usize,
+    /// Peers removed after their transport failed, with the error
+    pub failed: Vec<(u64, Error)>,
+}
+
+struct Peer<S: Read + Write> {
+    transport: XTransport<S>,
+    dropped: u64,
+}
+
+/// Sender of every message to a set of transports
+///
+/// Each message is borrowed once for all peers and never copied per peer,
+/// but every connection frames it with its own sequence numbers and message
+/// IDs. A peer whose transport fails is removed and reported, without
+/// affecting the others.
+pub struct FanoutSender<S: Read + Write> {
+    peers: BTreeMap<u64, Peer<S>>,
+    next_id: u64,
+    policy: SlowPeerPolicy,
+}
+
+impl<S: Read + Write> FanoutSender<S> {
+    pub fn new(policy: SlowPeerPolicy) -> Self {
+        FanoutSender {
+            peers: BTreeMap::new(),
+            next_id: 1,
+            policy,
+        }
+    }
+
+    /// Register a transport, returning its peer ID
+    pub fn add(&mut self, transport: XTransport<S>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.peers.insert(id, Peer { transport, dropped: 0 });
+        id
+    }
+
+    /// Unregister a peer, handing back its transport
+    pub fn remove(&mut self, id: u64) -> Option<XTransport<S>> {
+        self.peers.remove(&id).map(|peer| peer.transport)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut XTransport<S>> {
+        self.peers.get_mut(&id).map(|peer| &mut peer.transport)
+    }
+
+    /// IDs of the registered peers, in the order messages are sent to them
+    pub fn peers(&self) -> impl Iterator<Item = u64> + '_ {
+        self.peers.keys().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Messages a peer has skipped for being slow, or `None` for an unknown peer
+    pub fn dropped(&self, id: u64) -> Option<u64> {
+        self.peers.get(&id).map(|peer| peer.dropped)
+    }
+
+    pub fn policy(&self) -> SlowPeerPolicy {
+        self.policy
+    }
+
+    /// Send `data` to every peer, as the slow-peer policy allows
+    pub fn send(&mut self, data: &[u8]) -> FanoutReport {
+        let policy = self.policy;
+        let mut report = FanoutReport::default();
+        for (&id, peer) in self.peers.iter_mut() {
+            let transport = &mut peer.transport;
+            let result = match catch_up(transport, policy) {
+                Ok(false) => {
+                    peer.dropped += 1;
+                    report.dropped += 1;
+                    continue;
+                }
+                Ok(true) => transport.send_message(data),
+                Err(e) => Err(e),
+            };
+            let result = result.and_then(|()| match policy {
+                SlowPeerPolicy::Buffer { max_bytes } if transport.pending_write_len() > max_bytes => {
+                    Err(Error::new(ErrorKind::MemoryLimitExceeded))
+                }
+                _ => Ok(()),
+            });
+            match result {
+                Ok(()) => report.sent += 1,
+                Err(e) => report.failed.push((id, e)),
+            }
+        }
+        self.remove_failed(&report);
+        report
+    }
+
+    /// Write out what non-blocking streams held back, without sending anything new
+    ///
+    /// Call it when streams become writable so that slow peers catch up
+    /// between messages; `sent` counts the peers left with nothing pending.
+    pub fn poll_flush(&mut self) -> FanoutReport {
+        let mut report = FanoutReport::default();
+        for (&id, peer) in self.peers.iter_mut() {
+            match peer.transport.poll_flush() {
+                Ok(()) => report.sent += 1,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => report.failed.push((id, e)),
+            }
+        }
+        self.remove_failed(&report);
+        report
+    }
+
+    fn remove_failed(&mut self, report: &FanoutReport) {
+        for (id, e) in &report.failed {
+            log::warn!("Removing fan-out peer {}: {}", id, e);
+            self.peers.remove(id);
+        }
+    }
+}
+
+impl<S: Read + Write> Default for FanoutSender<S> {
+    fn default() -> Self {
+        Self::new(SlowPeerPolicy::Block)
+    }
+}
+
+/// Write out a peer's backlog before the next message, returning false if it should skip it
+fn catch_up<S: Read + Write>(transport: &mut XTransport<S>, policy: SlowPeerPolicy) -> crate::Result<bool> {
+    loop {
+        match transport.poll_flush() {
+            Ok(()) => return Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => match policy {
+                SlowPeerPolicy::Drop => return Ok(false),
+                SlowPeerPolicy::Block => core::hint::spin_loop(),
+                SlowPeerPolicy::Buffer { .. } => return Ok(true),
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
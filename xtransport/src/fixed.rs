@@ -0,0 +1,182 @@
+//! Message exchange without a heap
+//!
+//! With the `alloc` feature off the crate keeps only what works without an
+//! allocator: the packet format, errors, statistics, clocks and the interrupt
+//! and DMA helpers. `FixedTransport` sends and receives messages of one
+//! packet each over any `Read + Write` stream, buffering received bytes in an
+//! array of `N` bytes, so it runs on targets with no allocator at all. With
+//! `alloc`, `HeapFixedTransport` is the same transport with a buffer sized at
+//! runtime, from a `TransportConfig`, so its type carries no size parameter.
+//!
+//! It speaks the same wire format as `XTransport` with ACK mode off and no
+//! handshake, as long as every message fits in one `Data` packet: messages
+//! are not split, acknowledged or retransmitted, and packets of other types
+//! are skipped.
+
+use crate::{
+    dma::Frame,
+    error::ErrorKind,
+    io::{Read, Write},
+    protocol::{PacketHeader, PacketType, HEADER_SIZE, MAX_PAYLOAD_SIZE_V1},
+    stats::Stats,
+    Error, Result,
+};
+#[cfg(feature = "alloc")]
+use crate::config::TransportConfig;
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, vec};
+
+/// Transport of single-packet messages with a receive buffer of `N` bytes
+pub type FixedTransport<S, const N: usize> = FrameTransport<S, [u8; N]>;
+
+/// Transport of single-packet messages with a receive buffer sized at runtime
+#[cfg(feature = "alloc")]
+pub type HeapFixedTransport<S> = FrameTransport<S, Box<[u8]>>;
+
+/// Transport of single-packet messages buffering received bytes in `B`
+///
+/// Use it through `FixedTransport` or `HeapFixedTransport`.
+pub struct FrameTransport<S, B> {
+    stream: S,
+    buf: B,
+    /// Bytes read into `buf`
+    filled: usize,
+    /// Bytes at the start of `buf` taken by the message last returned
+    consumed: usize,
+    /// Bytes of a packet too large for `buf` still to be discarded from the stream
+    skip: usize,
+    next_seq: u32,
+    stats: Stats,
+}
+
+impl<S, const N: usize> FrameTransport<S, [u8; N]> {
+    pub fn new(stream: S) -> Self {
+        FrameTransport::with_buffer(stream, [0; N])
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<S> FrameTransport<S, Box<[u8]>> {
+    /// Transport with a receive buffer of `capacity` bytes
+    pub fn with_capacity(stream: S, capacity: usize) -> Self {
+        FrameTransport::with_buffer(stream, vec![0; capacity].into_boxed_slice())
+    }
+
+    /// Transport whose buffer holds one packet of the configured frame size
+    ///
+    /// Payloads are capped at 64 KB, the most a single packet carries here.
+    pub fn from_config(stream: S, config: &TransportConfig) -> Self {
+        Self::with_capacity(stream, HEADER_SIZE + config.max_payload_size.min(MAX_PAYLOAD_SIZE_V1))
+    }
+}
+
+impl<S, B> FrameTransport<S, B> {
+    fn with_buffer(stream: S, buf: B) -> Self {
+        FrameTransport {
+            stream,
+            buf,
+            filled: 0,
+            consumed: 0,
+            skip: 0,
+            next_seq: 0,
+            stats: Stats::new(),
+        }
+    }
+}
+
+impl<S: Read + Write, B: AsRef<[u8]> + AsMut<[u8]>> FrameTransport<S, B> {
+    /// Largest message that can be received: the buffer less a packet header
+    pub fn max_message_size(&self) -> usize {
+        self.buf.as_ref().len().saturating_sub(HEADER_SIZE).min(MAX_PAYLOAD_SIZE_V1)
+    }
+
+    /// Send `data` as one packet, returning its sequence number
+    ///
+    /// Fails with `MessageTooLarge` beyond the 64 KB payload of a packet.
+    pub fn send_message(&mut self, data: &[u8]) -> Result<u32> {
+        let seq = self.next_seq;
+        let size = Frame::new(PacketType::Data, seq, data)?.serialize_to_writer(&mut self.stream)?;
+        self.stream.flush()?;
+        self.next_seq = seq.wrapping_add(1);
+        self.stats.record_sent(size);
+        Ok(seq)
+    }
+
+    /// Receive the next message, borrowed from the receive buffer until the next call
+    ///
+    /// A stream that would block fails with `WouldBlock`, keeping what was
+    /// read so far for the next call. A packet larger than the buffer fails
+    /// with `MessageTooLarge` and is skipped. A malformed header fails with
+    /// its parse error and discards everything buffered, since packet
+    /// boundaries are lost.
+    pub fn recv_message(&mut self) -> Result<Frame<'_>> {
+        let buf = self.buf.as_mut();
+        if self.consumed > 0 {
+            buf.copy_within(self.consumed..self.filled, 0);
+            self.filled -= self.consumed;
+            self.consumed = 0;
+        }
+        let (header, len) = loop {
+            match PacketHeader::parse(&buf[..self.filled]) {
+                Ok(header) => {
+                    let len = header.size() + header.length as usize;
+                    if len > buf.len() {
+                        self.skip = len - self.filled;
+                        self.filled = 0;
+                        return Err(Error::new(ErrorKind::MessageTooLarge).with_seq(header.seq));
+                    }
+                    if self.filled >= len {
+                        self.consumed = len;
+                        self.stats.record_received(len);
+                        if crc32fast::hash(&buf[header.size()..len]) != header.crc32 {
+                            self.stats.crc_failures += 1;
+                            return Err(Error::new(ErrorKind::CrcMismatch).with_seq(header.seq));
+                        }
+                        if header.pkt_type != PacketType::Data as u8 {
+                            log::debug!("Skipping packet of type {} (seq {})", header.pkt_type, header.seq);
+                            buf.copy_within(len..self.filled, 0);
+                            self.filled -= len;
+                            self.consumed = 0;
+                            continue;
+                        }
+                        break (header, len);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {}
+                Err(e) => {
+                    self.filled = 0;
+                    return Err(e);
+                }
+            }
+            let n = self.stream.read(&mut buf[self.filled..])?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof));
+            }
+            let skipped = n.min(self.skip);
+            buf.copy_within(self.filled + skipped..self.filled + n, self.filled);
+            self.skip -= skipped;
+            self.filled += n - skipped;
+        };
+        Ok(Frame {
+            header,
+            payload: &buf[header.size()..len],
+        })
+    }
+
+    /// Counters of packets and bytes sent and received
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
@@ -0,0 +1,169 @@
+use crate::config::AdaptiveFrameSize;
+
+/// Largest factor by which repeated shrinks stretch the growth period
+const MAX_PATIENCE: u32 = 64;
+
+/// Payload size probing driven by delivery and loss reports
+pub struct FrameSizer {
+    config: AdaptiveFrameSize,
+    current: usize,
+    delivered: u32,
+    lost: u32,
+    /// Multiplier of `grow_after`, doubled by every shrink
+    patience: u32,
+}
+
+impl FrameSizer {
+    pub fn new(config: AdaptiveFrameSize) -> Self {
+        FrameSizer {
+            config,
+            current: config.min_payload_size,
+            delivered: 0,
+            lost: 0,
+            patience: 1,
+        }
+    }
+
+    /// Current payload size
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// True if a packet with `payload_len` bytes was sent at the current size
+    ///
+    /// Only such packets are evidence for or against it: larger ones predate
+    /// a shrink, and short ones say nothing about whether full-size packets
+    /// get through.
+    pub fn is_current(&self, payload_len: usize) -> bool {
+        payload_len > self.current / 2 && payload_len <= self.current
+    }
+
+    /// Record delivered packets sent at the current size, returning the new size if it grew
+    ///
+    /// The size only grows after a whole period of deliveries without loss.
+    pub fn on_delivered(&mut self, packets: u32) -> Option<usize> {
+        self.delivered = self.delivered.saturating_add(packets);
+        if self.delivered < self.config.grow_after.saturating_mul(self.patience) {
+            return None;
+        }
+        let lossless = self.lost == 0;
+        self.delivered = 0;
+        self.lost = 0;
+        if !lossless {
+            return None;
+        }
+        let grown = self.current.saturating_mul(2).min(self.config.max_payload_size);
+        self.resize(grown)
+    }
+
+    /// Record a lost packet with `payload_len` bytes, returning the new size if it shrank
+    pub fn on_loss(&mut self, payload_len: usize) -> Option<usize> {
+        if !self.is_current(payload_len) {
+            return None;
+        }
+        self.lost += 1;
+        if self.lost < self.config.shrink_after {
+            return None;
+        }
+        self.delivered = 0;
+        self.lost = 0;
+        self.patience = (self.patience * 2).min(MAX_PATIENCE);
+        let shrunk = (self.current / 2).max(self.config.min_payload_size);
+        self.resize(shrunk)
+    }
+
+    fn resize(&mut self, size: usize) -> Option<usize> {
+        if size == self.current {
+            return None;
+        }
+        self.current = size;
+        Some(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payloads of 100 to 800 bytes, growing after 4 deliveries and shrinking after 2 losses
+    fn sizer() -> FrameSizer {
+        let config = AdaptiveFrameSize { min_payload_size: 100, max_payload_size: 800, grow_after: 4, shrink_after: 2 };
+        FrameSizer::new(config)
+    }
+
+    #[test]
+    fn grows_after_a_lossless_period_up_to_the_maximum() {
+        let mut sizer = sizer();
+        assert_eq!(sizer.current(), 100);
+        assert_eq!(sizer.on_delivered(3), None);
+        assert_eq!(sizer.on_delivered(1), Some(200));
+        assert_eq!(sizer.on_delivered(4), Some(400));
+        assert_eq!(sizer.on_delivered(4), Some(800));
+        assert_eq!(sizer.on_delivered(4), None, "already at the maximum");
+    }
+
+    #[test]
+    fn a_single_loss_holds_the_size_for_the_period() {
+        let mut sizer = sizer();
+        assert_eq!(sizer.on_loss(100), None);
+        assert_eq!(sizer.on_delivered(4), None);
+        // The next period starts clean
+        assert_eq!(sizer.on_delivered(4), Some(200));
+    }
+
+    #[test]
+    fn repeated_losses_halve_the_size_down_to_the_minimum() {
+        let mut sizer = sizer();
+        sizer.on_delivered(4);
+        sizer.on_delivered(4);
+        assert_eq!(sizer.current(), 400);
+        assert_eq!(sizer.on_loss(400), None);
+        assert_eq!(sizer.on_loss(400), Some(200));
+        sizer.on_loss(200);
+        assert_eq!(sizer.on_loss(200), Some(100));
+        sizer.on_loss(100);
+        assert_eq!(sizer.on_loss(100), None, "already at the minimum");
+    }
+
+    #[test]
+    fn every_shrink_doubles_the_period_before_growing_again() {
+        let mut sizer = sizer();
+        sizer.on_delivered(4);
+        sizer.on_loss(200);
+        sizer.on_loss(200);
+        assert_eq!(sizer.current(), 100);
+        // Twice the period now, so the size does not flap between two values
+        assert_eq!(sizer.on_delivered(4), None);
+        assert_eq!(sizer.on_delivered(4), Some(200));
+        sizer.on_loss(200);
+        sizer.on_loss(200);
+        assert_eq!(sizer.on_delivered(15), None);
+        assert_eq!(sizer.on_delivered(1), Some(200));
+    }
+
+    #[test]
+    fn patience_is_capped() {
+        let mut sizer = sizer();
+        for _ in 0..10 {
+            sizer.on_loss(100);
+            sizer.on_loss(100);
+        }
+        assert_eq!(sizer.on_delivered(4 * MAX_PATIENCE - 1), None);
+        assert_eq!(sizer.on_delivered(1), Some(200));
+    }
+
+    #[test]
+    fn only_packets_of_the_current_size_count_as_losses() {
+        let mut sizer = sizer();
+        sizer.on_delivered(4);
+        assert_eq!(sizer.current(), 200);
+        // Sent before the last resize, or too short to say anything about full-size packets
+        for len in [400, 100, 50] {
+            assert!(!sizer.is_current(len));
+            assert_eq!(sizer.on_loss(len), None);
+            assert_eq!(sizer.on_loss(len), None);
+        }
+        assert_eq!(sizer.current(), 200);
+        assert!(sizer.is_current(101) && sizer.is_current(200));
+    }
+}
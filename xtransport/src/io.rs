@@ -1,4 +1,6 @@
 use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
 
 pub trait Read {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
@@ -44,28 +46,48 @@ pub trait Write {
 #[cfg(feature = "std")]
 impl<T: std::io::Read> Read for T {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        std::io::Read::read(self, buf)
-            .map_err(|e| Error::new(match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => crate::error::ErrorKind::UnexpectedEof,
-                std::io::ErrorKind::Interrupted => crate::error::ErrorKind::Interrupted,
-                _ => crate::error::ErrorKind::Other,
-            }))
+        Ok(std::io::Read::read(self, buf)?)
     }
 }
 
 #[cfg(feature = "std")]
 impl<T: std::io::Write> Write for T {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        std::io::Write::write(self, buf)
-            .map_err(|e| Error::new(match e.kind() {
-                std::io::ErrorKind::WriteZero => crate::error::ErrorKind::WriteZero,
-                std::io::ErrorKind::Interrupted => crate::error::ErrorKind::Interrupted,
-                _ => crate::error::ErrorKind::Other,
-            }))
+        Ok(std::io::Write::write(self, buf)?)
     }
     
     fn flush(&mut self) -> Result<()> {
-        std::io::Write::flush(self)
-            .map_err(|_| Error::new(crate::error::ErrorKind::Other))
+        Ok(std::io::Write::flush(self)?)
+    }
+}
+
+/// Byte stream an `XTransport` can run over
+///
+/// Implemented for everything that is `Read + Write`. Unlike that pair it can
+/// be used as a trait object, so the stream can be chosen at runtime (see
+/// `BoxedTransport`) instead of making every caller generic over it.
+pub trait Transport: Read + Write {}
+
+impl<T: Read + Write + ?Sized> Transport for T {}
+
+/// Stream chosen at runtime, e.g. a Unix socket or a TCP connection depending on configuration
+#[cfg(feature = "alloc")]
+pub type BoxedTransport = Box<dyn Transport + Send>;
+
+#[cfg(feature = "alloc")]
+impl Read for BoxedTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).read(buf)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Write for BoxedTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
     }
 }
@@ -0,0 +1,506 @@
+//! Interrupt-driven serial links for embedded targets
+//!
+//! A UART interrupt must not run the protocol: it only moves the bytes out of
+//! the peripheral before the next one overruns it. `SpscQueue` carries them
+//! from the interrupt to the thread running the `XTransport`, lock-free with
+//! one writer on each side, so the interrupt never waits for the main loop.
+//! Split it once at start-up; the `Producer` goes to the interrupt handler and
+//! the `Consumer` is the receiving half of the transport's stream, failing
+//! reads with `WouldBlock` while no byte is queued. The transport keeps a
+//! partly received packet across such reads, so the main loop can sleep until
+//! the next interrupt and call `recv_message` again.
+//!
+//! `SpscQueue` is sized at compile time. With `alloc`, `DynSpscQueue` takes
+//! its capacity at runtime instead and can `reserve` or `shrink_to_fit`
+//! while it is not split; both are a `Queue` over their `QueueStorage`, so the
+//! ends and `IrqSerial` work with either.
+//!
+//! The `embedded-hal` feature adds the glue for `embedded-hal-nb` serial
+//! peripherals: `on_rx_interrupt` drains the receiver into the queue from the
+//! handler, `IrqSerial` pairs the consumer with the transmitter as the stream,
+//! and `IsrCell` hands the producer to a plain interrupt handler through a
+//! `critical-section` mutex where no framework such as RTIC owns it.
+//!
+//! Only atomic loads and stores are used, so the queue also works on cores
+//! without atomic read-modify-write instructions such as the Cortex-M0.
+
+use crate::{
+    error::ErrorKind,
+    io::{Read, Write},
+    Error, Result,
+};
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bytes a queue is kept in: a fixed `Array`, or a growable `HeapBuffer` with `alloc`
+///
+/// # Safety
+///
+/// `as_ptr` must point to `capacity` bytes that stay at that address while
+/// the storage is only borrowed shared, and that may be written through the
+/// pointer meanwhile.
+pub unsafe trait QueueStorage {
+    fn capacity(&self) -> usize;
+    fn as_ptr(&self) -> *mut u8;
+}
+
+/// Storage sized at compile time, for targets without an allocator
+pub struct Array<const N: usize>(UnsafeCell<[u8; N]>);
+
+// SAFETY: the array lives inside the queue, which cannot move while its ends borrow it
+unsafe impl<const N: usize> QueueStorage for Array<N> {
+    fn capacity(&self) -> usize {
+        N
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.0.get().cast()
+    }
+}
+
+/// Storage on the heap, resized with `DynSpscQueue::reserve` and `shrink_to_fit`
+#[cfg(feature = "alloc")]
+pub struct HeapBuffer(Box<[UnsafeCell<u8>]>);
+
+#[cfg(feature = "alloc")]
+impl HeapBuffer {
+    fn new(capacity: usize) -> Self {
+        HeapBuffer((0..capacity).map(|_| UnsafeCell::new(0)).collect())
+    }
+}
+
+// SAFETY: the heap allocation stays in place when the box is moved, and is
+// only replaced through `&mut` while the queue is not split
+#[cfg(feature = "alloc")]
+unsafe impl QueueStorage for HeapBuffer {
+    fn capacity(&self) -> usize {
+        self.0.len()
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        UnsafeCell::raw_get(self.0.as_ptr())
+    }
+}
+
+/// Byte queue between one interrupt handler and one thread, kept in `S`
+///
+/// Use `SpscQueue` for a fixed capacity and `DynSpscQueue` for one that can
+/// be resized between uses.
+pub struct Queue<S> {
+    buf: S,
+    /// Next byte to pop modulo twice the capacity, so a full queue differs from an empty one; written only by the consumer
+    head: AtomicUsize,
+    /// Next byte to push modulo twice the capacity, written only by the producer
+    tail: AtomicUsize,
+    /// Bytes lost to a full queue, written only by the producer
+    dropped: AtomicUsize,
+}
+
+/// Fixed-capacity byte queue between one interrupt handler and one thread
+pub type SpscQueue<const N: usize> = Queue<Array<N>>;
+
+/// Heap-backed byte queue between one interrupt handler and one thread, with a capacity chosen at runtime
+#[cfg(feature = "alloc")]
+pub type DynSpscQueue = Queue<HeapBuffer>;
+
+// SAFETY: the producer writes only slots the consumer has released and the
+// consumer reads only slots the producer has published, ordered by `head` and `tail`
+unsafe impl<S: QueueStorage + Send> Sync for Queue<S> {}
+
+impl<const N: usize> SpscQueue<N> {
+    pub const fn new() -> Self {
+        Queue {
+            buf: Array(UnsafeCell::new([0; N])),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<const N: usize> Default for SpscQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl DynSpscQueue {
+    /// Queue holding up to `capacity` bytes, at least one
+    pub fn with_capacity(capacity: usize) -> Self {
+        Queue {
+            buf: HeapBuffer::new(capacity.max(1)),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Make room for at least `additional` bytes beyond those queued
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self.len().saturating_add(additional);
+        if needed > self.capacity() {
+            self.resize(needed);
+        }
+    }
+
+    /// Shrink the storage to the bytes queued, keeping room for at least one
+    pub fn shrink_to_fit(&mut self) {
+        let len = self.len().max(1);
+        if len < self.capacity() {
+            self.resize(len);
+        }
+    }
+
+    /// Move the queued bytes to the start of new storage of `capacity` bytes
+    fn resize(&mut self, capacity: usize) {
+        let (len, old_capacity) = (self.len(), self.capacity());
+        let head = *self.head.get_mut();
+        let old = &mut self.buf.0;
+        let bytes = (0..capacity)
+            .map(|i| UnsafeCell::new(if i < len { *old[(head + i) % old_capacity].get_mut() } else { 0 }))
+            .collect();
+        self.buf = HeapBuffer(bytes);
+        *self.head.get_mut() = 0;
+        *self.tail.get_mut() = len;
+    }
+}
+
+impl<S: QueueStorage> Queue<S> {
+    /// Split into the interrupt's and the thread's end
+    ///
+    /// Borrowing the queue mutably guarantees there is only one of each; for
+    /// ends that live forever, split a queue in a `static` or one RTIC hands
+    /// to `init` as a local resource.
+    pub fn split(&mut self) -> (Producer<'_, S>, Consumer<'_, S>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+
+    /// Bytes the queue holds when full
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Bytes queued and not yet consumed
+    pub fn len(&self) -> usize {
+        let wrap = 2 * self.capacity();
+        (self.tail.load(Ordering::Acquire) + wrap - self.head.load(Ordering::Acquire)) % wrap
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes lost since creation because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Start and length in `buf` of the first of the `len` slots from `index`
+    /// modulo twice the capacity that lie before the end of `buf`
+    fn contiguous(&self, index: usize, len: usize) -> (usize, usize) {
+        let capacity = self.capacity();
+        let start = index % capacity;
+        (start, len.min(capacity - start))
+    }
+
+    /// `index` moved on by `n` slots, modulo twice the capacity
+    fn advance(&self, index: usize, n: usize) -> usize {
+        (index + n) % (2 * self.capacity())
+    }
+}
+
+/// Writing end of a queue, for the interrupt handler
+pub struct Producer<'a, S> {
+    queue: &'a Queue<S>,
+}
+
+impl<S: QueueStorage> Producer<'_, S> {
+    /// Queue `byte`, or count it as dropped and return false if the queue is full
+    pub fn push(&mut self, byte: u8) -> bool {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        if self.is_full() {
+            let dropped = self.queue.dropped.load(Ordering::Relaxed);
+            self.queue.dropped.store(dropped.wrapping_add(1), Ordering::Relaxed);
+            return false;
+        }
+        // SAFETY: the slot at `tail` is free and the consumer will not read it before `tail` moves past it
+        unsafe { *self.queue.buf.as_ptr().add(tail % self.queue.capacity()) = byte };
+        self.queue.tail.store(self.queue.advance(tail, 1), Ordering::Release);
+        true
+    }
+
+    /// Queue as much of `bytes` as fits, returning how many were queued
+    ///
+    /// The rest are counted as dropped. The bytes are copied in at most two
+    /// slices, before and after the end of the queue.
+    pub fn push_slice(&mut self, bytes: &[u8]) -> usize {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let count = bytes.len().min(self.free());
+        let (start, first) = self.queue.contiguous(tail, count);
+        let buf = self.queue.buf.as_ptr();
+        // SAFETY: the `count` slots from `tail` are free and the consumer will not read them before `tail` moves past them
+        unsafe {
+            slice::from_raw_parts_mut(buf.add(start), first).copy_from_slice(&bytes[..first]);
+            slice::from_raw_parts_mut(buf, count - first).copy_from_slice(&bytes[first..count]);
+        }
+        self.queue.tail.store(self.queue.advance(tail, count), Ordering::Release);
+        if count < bytes.len() {
+            let dropped = self.queue.dropped.load(Ordering::Relaxed);
+            self.queue.dropped.store(dropped.wrapping_add(bytes.len() - count), Ordering::Relaxed);
+        }
+        count
+    }
+
+    /// Whether another byte would be dropped
+    pub fn is_full(&self) -> bool {
+        self.queue.len() == self.queue.capacity()
+    }
+
+    /// Bytes the queue holds when full
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity()
+    }
+
+    /// Bytes that can be queued before any is dropped
+    ///
+    /// Only the consumer frees room, so the answer holds until the next push.
+    pub fn free(&self) -> usize {
+        self.queue.capacity() - self.queue.len()
+    }
+
+    /// Queue bytes read from `reader` straight into the free room, returning how many were queued
+    ///
+    /// The room may wrap around the end of the queue, so a read filling the
+    /// part before the end is followed by one into the part after it; a short
+    /// read or a full queue stops, so a blocking reader is not read again for
+    /// bytes it does not have yet. A read error is returned only if nothing
+    /// was queued.
+    pub fn write_from_reader<R: Read>(&mut self, reader: &mut R) -> Result<usize> {
+        let mut queued = 0;
+        while !self.is_full() {
+            let tail = self.queue.tail.load(Ordering::Relaxed);
+            let (start, len) = self.queue.contiguous(tail, self.free());
+            // SAFETY: the slots from `tail` are free and the consumer will not read them before `tail` moves past them
+            let room = unsafe { slice::from_raw_parts_mut(self.queue.buf.as_ptr().add(start), len) };
+            let n = match reader.read(room) {
+                Ok(0) => break,
+                Ok(n) => n.min(len),
+                Err(_) if queued > 0 => break,
+                Err(e) => return Err(e),
+            };
+            self.queue.tail.store(self.queue.advance(tail, n), Ordering::Release);
+            queued += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(queued)
+    }
+}
+
+/// Reading end of a queue, the receiving half of a transport's stream
+pub struct Consumer<'a, S> {
+    queue: &'a Queue<S>,
+}
+
+impl<S: QueueStorage> Consumer<'_, S> {
+    /// Take the oldest queued byte
+    pub fn pop(&mut self) -> Option<u8> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        if head == self.queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: the slot at `head` was published by the producer and is not reused before `head` moves
+        let byte = unsafe { *self.queue.buf.as_ptr().add(head % self.queue.capacity()) };
+        self.queue.head.store(self.queue.advance(head, 1), Ordering::Release);
+        Some(byte)
+    }
+
+    /// Bytes waiting to be read
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Bytes the producer lost because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.queue.dropped()
+    }
+
+    /// Write queued bytes to `writer` straight from the queue, returning how many it took
+    ///
+    /// The bytes may wrap around the end of the queue, so a write taking the
+    /// part before the end is followed by one of the part after it; a short
+    /// write or an empty queue stops. A write error is returned only if
+    /// nothing was written.
+    pub fn read_into_writer<W: Write>(&mut self, writer: &mut W) -> Result<usize> {
+        let mut written = 0;
+        loop {
+            let queued = self.queued();
+            if queued.is_empty() {
+                break;
+            }
+            let len = queued.len();
+            let n = match writer.write(queued) {
+                Ok(0) => break,
+                Ok(n) => n.min(len),
+                Err(_) if written > 0 => break,
+                Err(e) => return Err(e),
+            };
+            self.consume(n);
+            written += n;
+            if n < len {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    /// The oldest queued bytes up to the end of the queue; empty if none are queued
+    fn queued(&self) -> &[u8] {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let (start, len) = self.queue.contiguous(head, self.len());
+        // SAFETY: the slots from `head` were published by the producer and are not reused before `head` moves
+        unsafe { slice::from_raw_parts(self.queue.buf.as_ptr().add(start), len) }
+    }
+
+    /// Release the `n` oldest queued bytes to the producer
+    fn consume(&mut self, n: usize) {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        self.queue.head.store(self.queue.advance(head, n), Ordering::Release);
+    }
+}
+
+impl<S: QueueStorage> Read for Consumer<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            let queued = self.queued();
+            if queued.is_empty() {
+                break;
+            }
+            let len = queued.len().min(buf.len() - n);
+            buf[n..n + len].copy_from_slice(&queued[..len]);
+            self.consume(len);
+            n += len;
+        }
+        if n == 0 && !buf.is_empty() {
+            return Err(Error::new(ErrorKind::WouldBlock));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+pub use hal::{on_rx_interrupt, IrqSerial, IsrCell};
+
+#[cfg(feature = "embedded-hal")]
+mod hal {
+    use super::{Consumer, Producer, QueueStorage};
+    use crate::{error::ErrorKind, io::Read, io::Write, Error, Result};
+    use core::cell::RefCell;
+    use critical_section::Mutex;
+    use embedded_hal_nb::{nb, serial};
+
+    /// Move every byte the receiver holds into the queue; call from its interrupt handler
+    ///
+    /// Returns how many bytes were read. Receiver errors such as an overrun
+    /// are skipped: the CRC of the damaged packet fails and ACK mode resends it.
+    pub fn on_rx_interrupt<R: serial::Read<u8>, S: QueueStorage>(rx: &mut R, producer: &mut Producer<'_, S>) -> usize {
+        let mut read = 0;
+        loop {
+            match rx.read() {
+                Ok(byte) => {
+                    producer.push(byte);
+                    read += 1;
+                }
+                Err(nb::Error::WouldBlock) => return read,
+                Err(nb::Error::Other(e)) => log::debug!("Serial receive error: {:?}", serial::Error::kind(&e)),
+            }
+        }
+    }
+
+    /// Serial stream of an `XTransport`: bytes queued by the receive interrupt in, a blocking transmitter out
+    pub struct IrqSerial<'a, Tx, S> {
+        rx: Consumer<'a, S>,
+        tx: Tx,
+    }
+
+    impl<'a, Tx: serial::Write<u8>, S: QueueStorage> IrqSerial<'a, Tx, S> {
+        pub fn new(rx: Consumer<'a, S>, tx: Tx) -> Self {
+            IrqSerial { rx, tx }
+        }
+
+        /// Bytes received and not yet read by the transport
+        pub fn rx_pending(&self) -> usize {
+            self.rx.len()
+        }
+
+        /// Bytes the receive interrupt lost because the queue was full
+        pub fn rx_dropped(&self) -> usize {
+            self.rx.dropped()
+        }
+
+        pub fn into_parts(self) -> (Consumer<'a, S>, Tx) {
+            (self.rx, self.tx)
+        }
+    }
+
+    impl<Tx, S: QueueStorage> Read for IrqSerial<'_, Tx, S> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.rx.read(buf)
+        }
+    }
+
+    impl<Tx: serial::Write<u8>, S: QueueStorage> Write for IrqSerial<'_, Tx, S> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            for &byte in buf {
+                nb::block!(self.tx.write(byte)).map_err(|_| Error::new(ErrorKind::Other))?;
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            nb::block!(self.tx.flush()).map_err(|_| Error::new(ErrorKind::Other))
+        }
+    }
+
+    /// Value handed from `main` to an interrupt handler, guarded by a critical section
+    ///
+    /// For handlers that are plain functions and can only reach a `static`,
+    /// e.g. a `Producer` and the UART receiver moved in once set up.
+    pub struct IsrCell<T>(Mutex<RefCell<Option<T>>>);
+
+    impl<T> IsrCell<T> {
+        pub const fn new() -> Self {
+            IsrCell(Mutex::new(RefCell::new(None)))
+        }
+
+        /// Store `value`, returning the one stored before
+        pub fn put(&self, value: T) -> Option<T> {
+            critical_section::with(|cs| self.0.borrow_ref_mut(cs).replace(value))
+        }
+
+        /// Run `f` on the value inside a critical section, or return `None` if none is stored yet
+        pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+            critical_section::with(|cs| self.0.borrow_ref_mut(cs).as_mut().map(f))
+        }
+
+        pub fn take(&self) -> Option<T> {
+            critical_section::with(|cs| self.0.borrow_ref_mut(cs).take())
+        }
+    }
+
+    impl<T> Default for IsrCell<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::Result;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Write-ahead storage for outgoing messages
+///
+/// A message is appended before it is sent and removed once the peer has
+/// acknowledged all of it, so whatever is still pending after a crash can be
+/// sent again with `XTransport::resend_journal`.
+pub trait Journal {
+    /// Persist a message about to be sent, returning an ID never handed out before
+    fn append(&mut self, message: &[u8]) -> Result<u64>;
+    /// Forget a message the peer has acknowledged
+    fn remove(&mut self, id: u64) -> Result<()>;
+    /// Messages appended but not removed, oldest first
+    fn pending(&mut self) -> Result<Vec<(u64, Vec<u8>)>>;
+}
+
+/// Journal kept in memory, surviving reconnects but not process restarts
+pub struct MemoryJournal {
+    entries: BTreeMap<u64, Vec<u8>>,
+    next_id: u64,
+}
+
+impl MemoryJournal {
+    pub fn new() -> Self {
+        MemoryJournal {
+            entries: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+}
+
+impl Default for MemoryJournal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Journal for MemoryJournal {
+    fn append(&mut self, message: &[u8]) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.insert(id, message.to_vec());
+        Ok(id)
+    }
+
+    fn remove(&mut self, id: u64) -> Result<()> {
+        self.entries.remove(&id);
+        Ok(())
+    }
+
+    fn pending(&mut self) -> Result<Vec<(u64, Vec<u8>)>> {
+        Ok(self.entries.iter().map(|(id, message)| (*id, message.clone())).collect())
+    }
+}
+
+#[cfg(feature = "std")]
+pub use file::FileJournal;
+
+#[cfg(feature = "std")]
+mod file {
+    use super::Journal;
+    use crate::{error::Error, Result};
+    use alloc::vec::Vec;
+    use std::fs;
+    use std::io::Write as _;
+    use std::path::{Path, PathBuf};
+
+    const ENTRY_EXT: &str = "msg";
+    const NEXT_ID_FILE: &str = "next_id";
+
+    /// Journal storing one file per message in a directory
+    ///
+    /// Files are written to a temporary name, synced and renamed into place, so
+    /// a crash never leaves a partially written entry behind.
+    pub struct FileJournal {
+        dir: PathBuf,
+        next_id: u64,
+    }
+
+    impl FileJournal {
+        /// Open the journal in `dir`, creating the directory if needed
+        pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+            let dir = dir.as_ref().to_path_buf();
+            fs::create_dir_all(&dir).map_err(Error::from)?;
+
+            // IDs must stay unique across restarts, even once every entry is removed
+            let mut next_id = match fs::read_to_string(dir.join(NEXT_ID_FILE)) {
+                Ok(text) => text.trim().parse().unwrap_or(1),
+                Err(_) => 1,
+            };
+            for id in Self::entry_ids(&dir)? {
+                next_id = next_id.max(id + 1);
+            }
+            Ok(FileJournal { dir, next_id })
+        }
+
+        fn entry_ids(dir: &Path) -> Result<Vec<u64>> {
+            let mut ids = Vec::new();
+            for entry in fs::read_dir(dir).map_err(Error::from)? {
+                let path = entry.map_err(Error::from)?.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some(ENTRY_EXT) {
+                    continue;
+                }
+                if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                    ids.push(id);
+                }
+            }
+            ids.sort_unstable();
+            Ok(ids)
+        }
+
+        fn entry_path(&self, id: u64) -> PathBuf {
+            self.dir.join(format!("{:020}.{}", id, ENTRY_EXT))
+        }
+
+        fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<()> {
+            let tmp = path.with_extension("tmp");
+            let mut file = fs::File::create(&tmp).map_err(Error::from)?;
+            file.write_all(data).map_err(Error::from)?;
+            file.sync_all().map_err(Error::from)?;
+            fs::rename(&tmp, path).map_err(Error::from)
+        }
+    }
+
+    impl Journal for FileJournal {
+        fn append(&mut self, message: &[u8]) -> Result<u64> {
+            let id = self.next_id;
+            self.write_atomic(&self.dir.join(NEXT_ID_FILE), format!("{}", id + 1).as_bytes())?;
+            self.write_atomic(&self.entry_path(id), message)?;
+            self.next_id = id + 1;
+            Ok(id)
+        }
+
+        fn remove(&mut self, id: u64) -> Result<()> {
+            match fs::remove_file(self.entry_path(id)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::from(e)),
+                _ => Ok(()),
+            }
+        }
+
+        fn pending(&mut self) -> Result<Vec<(u64, Vec<u8>)>> {
+            let mut entries = Vec::new();
+            for id in Self::entry_ids(&self.dir)? {
+                entries.push((id, fs::read(self.entry_path(id)).map_err(Error::from)?));
+            }
+            Ok(entries)
+        }
+    }
+}
@@ -1,16 +1,131 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
 
+#[cfg(feature = "alloc")]
+pub mod ackdelay;
+#[cfg(feature = "alloc")]
+pub mod auth;
+#[cfg(feature = "alloc")]
+pub mod cache;
+#[cfg(feature = "alloc")]
+pub mod cancel;
+#[cfg(feature = "alloc")]
+pub mod capability;
+#[cfg(feature = "alloc")]
+pub mod capture;
+#[cfg(feature = "alloc")]
+pub mod cipher;
+pub mod clock;
+#[cfg(feature = "alloc")]
 pub mod config;
+#[cfg(feature = "alloc")]
+pub mod decoder;
+#[cfg(feature = "diagram")]
+pub mod diagram;
+pub mod digest;
+pub mod dma;
 pub mod error;
+#[cfg(feature = "alloc")]
+pub mod fanout;
+pub mod fixed;
+#[cfg(feature = "alloc")]
+pub mod framesize;
 pub mod io;
+pub mod irq;
+#[cfg(feature = "alloc")]
+pub mod journal;
+#[cfg(feature = "alloc")]
+pub mod machine;
+#[cfg(feature = "alloc")]
+pub mod memory;
+#[cfg(feature = "alloc")]
+pub mod message;
+#[cfg(feature = "noise")]
+pub mod noise;
+#[cfg(feature = "alloc")]
+pub mod observer;
+#[cfg(feature = "mio")]
+pub mod pollable;
+#[cfg(feature = "alloc")]
+pub mod pool;
 pub mod protocol;
+#[cfg(feature = "alloc")]
+pub mod pubsub;
+#[cfg(feature = "std")]
+pub mod reconnect;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "alloc")]
+pub mod retransmit;
+pub mod scan;
+#[cfg(feature = "alloc")]
+mod scheduler;
+#[cfg(feature = "alloc")]
+pub mod selftest;
+pub mod seq;
+#[cfg(feature = "std")]
+pub mod sender;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "alloc")]
+pub mod shaper;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(all(feature = "socket", unix))]
+pub mod socket;
+#[cfg(feature = "std")]
+pub mod split;
+pub mod stats;
+#[cfg(feature = "metrics")]
+pub mod telemetry;
+#[cfg(feature = "alloc")]
+pub mod timesync;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "alloc")]
 pub mod transport;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(feature = "alloc")]
+pub mod window;
 
 pub use error::{Error, Result};
-pub use io::{Read, Write};
-pub use config::{TransportConfig, MAGIC, VERSION, HEADER_SIZE, MESSAGE_HEAD_SIZE};
+#[cfg(feature = "alloc")]
+pub use auth::Authenticator;
+#[cfg(feature = "alloc")]
+pub use cancel::CancellationToken;
+#[cfg(feature = "alloc")]
+pub use capability::{Capabilities, Feature};
+#[cfg(feature = "alloc")]
+pub use cipher::{Cipher, Encryption, Role};
+pub use clock::Clock;
+pub use io::{Read, Transport, Write};
+#[cfg(feature = "alloc")]
+pub use io::BoxedTransport;
+#[cfg(feature = "alloc")]
+pub use journal::Journal;
+#[cfg(feature = "alloc")]
+pub use memory::MemoryBudget;
+#[cfg(feature = "alloc")]
+pub use message::{Message, MessageOptions, OriginalDigest};
+#[cfg(feature = "alloc")]
+pub use observer::{ConnectionState, DeliveryFailure, Observer, ProgressFn, Transfer, TransportEvent};
+#[cfg(feature = "alloc")]
+pub use config::{TransportConfig, AdaptiveFrameSize, BusyPoll, ClassRate, CrcPolicy, DuplicatePolicy, SocketOptions};
+pub use scan::{FrameScanner, Scan};
+pub use protocol::{MAGIC, VERSION, VERSION_2, HEADER_SIZE, HEADER_SIZE_V2, MAX_PAYLOAD_SIZE_V1, MESSAGE_HEAD_SIZE, MESSAGE_DATA_HEAD_SIZE, MESSAGE_DATA_OFFSET_SIZE, REFERENCE_SIZE, GROUP_HEAD_SIZE, GOAWAY_HEAD_SIZE};
+#[cfg(feature = "alloc")]
+pub use scheduler::QueuedMessageInfo;
+#[cfg(feature = "alloc")]
+pub use selftest::SelfTestReport;
+#[cfg(feature = "std")]
+pub use sender::MessageSender;
+#[cfg(feature = "std")]
+pub use split::{ReadHalf, WriteHalf};
+pub use stats::{LinkQuality, Stats};
+#[cfg(feature = "alloc")]
+pub use timesync::TimeSyncEstimate;
+#[cfg(feature = "alloc")]
 pub use transport::XTransport;
-
-
@@ -0,0 +1,387 @@
+//! Connection lifecycle as a sans-IO state machine
+//!
+//! `Protocol` runs the connection lifecycle of `XTransport` without owning a
+//! stream, a clock or a thread, so it can be driven from any async runtime,
+//! event loop or interrupt context. Pass it every frame received with
+//! `handle_frame` and call `poll_timeout` once `next_timeout` is due; both,
+//! like `connect`, `send` and `close`, return the `Action`s to carry out:
+//! frames to write, messages to deliver, and state transitions with the
+//! `Event` that caused each.
+//!
+//! ```text
+//! Idle ─Connect─▶ SynSent ─PongReceived─▶ Established ─Close/FinReceived─▶ Closing ─Close/FinReceived─▶ Closed
+//!  └──────────────HelloReceived──────────────▲
+//! ```
+//!
+//! A Goaway from the peer closes the connection from any state, and
+//! `poll_timeout` does when the handshake or the peer's Fin takes too long.
+//!
+//! It speaks the wire format of `XTransport` with ACK mode off. Its handshake
+//! advertises no optional feature but Fin packets (`Feature::HalfClose`), so
+//! the peer sends version 1 packets and messages as Data packets, or
+//! MessageHead and MessageData packets without offsets or digests. Each
+//! frame must be the whole wire bytes of one packet, in the order sent.
+
+use crate::{
+    capability::{Capabilities, Feature, HELLO_TOKEN},
+    error::ErrorKind,
+    protocol::{Goaway, MessageHead, Packet, PacketType, HEADER_SIZE, MESSAGE_DATA_HEAD_SIZE, PING_SIZE, PONG_SIZE},
+    seq, Error, Result,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Payload of a packet unless set otherwise, that of `XTransport`'s default frame size
+const DEFAULT_MAX_PAYLOAD: usize = 4096 - HEADER_SIZE;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_CLOSE_TIMEOUT_MS: u64 = 5000;
+/// Multi-packet messages the peer may have started and not finished at once
+const MAX_PARTIAL_MESSAGES: usize = 16;
+
+/// Stage of a connection driven by a `Protocol`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Nothing exchanged yet
+    Idle,
+    /// The handshake was sent and its answer is awaited
+    SynSent,
+    /// The handshake is done; messages go both ways
+    Established,
+    /// One end shut down its sending direction; the other may still send
+    Closing,
+    /// Both directions were shut down, the peer sent a Goaway or a deadline passed
+    Closed,
+}
+
+/// Cause of a state transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// `connect` sent the handshake
+    Connect,
+    /// The peer's handshake arrived and was answered
+    HelloReceived,
+    /// The peer answered this end's handshake
+    PongReceived,
+    /// `close` shut down this end's sending direction
+    Close,
+    /// The peer shut down its sending direction
+    FinReceived,
+    /// The peer ended the connection with this Goaway code
+    GoawayReceived(u32),
+    /// `poll_timeout` found the handshake or the peer's Fin overdue
+    Timeout,
+}
+
+/// What the caller of a `Protocol` has to do next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Write these bytes, one whole frame, to the peer
+    Send(Vec<u8>),
+    /// A message arrived from the peer
+    Deliver(Vec<u8>),
+    /// The connection went from one state to another
+    Transition { from: State, to: State, event: Event },
+}
+
+/// Sans-IO state machine of one connection
+pub struct Protocol {
+    state: State,
+    send_seq: u32,
+    recv_seq: u32,
+    next_message_id: u64,
+    max_payload: usize,
+    max_message_size: usize,
+    handshake_timeout_ms: u64,
+    close_timeout_ms: u64,
+    /// When `poll_timeout` gives up on the handshake or the peer's Fin, in microseconds
+    deadline: Option<u64>,
+    fin_sent: bool,
+    fin_received: bool,
+    /// Total length and bytes so far of the multi-packet messages being received
+    partial: BTreeMap<u64, (usize, Vec<u8>)>,
+}
+
+impl Protocol {
+    pub fn new() -> Self {
+        Protocol {
+            state: State::Idle,
+            send_seq: 0,
+            recv_seq: 0,
+            next_message_id: 1,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            handshake_timeout_ms: DEFAULT_HANDSHAKE_TIMEOUT_MS,
+            close_timeout_ms: DEFAULT_CLOSE_TIMEOUT_MS,
+            deadline: None,
+            fin_sent: false,
+            fin_received: false,
+            partial: BTreeMap::new(),
+        }
+    }
+
+    /// Largest payload of a packet sent; longer messages are split
+    pub fn with_max_payload(mut self, size: usize) -> Self {
+        self.max_payload = size.clamp(MESSAGE_DATA_HEAD_SIZE + 1, u16::MAX as usize);
+        self
+    }
+
+    /// Largest message accepted from the peer
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// How long `connect` waits for the peer's answer
+    pub fn with_handshake_timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.handshake_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// How long `close` waits for the peer to shut down its direction too
+    pub fn with_close_timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.close_timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// When `poll_timeout` next has something to do, in microseconds on the clock passed to it
+    pub fn next_timeout(&self) -> Option<u64> {
+        self.deadline
+    }
+
+    /// Send the handshake at `now`, in microseconds
+    ///
+    /// Fails with `InvalidInput` unless the connection is still idle.
+    pub fn connect(&mut self, now: u64) -> Result<Vec<Action>> {
+        if self.state != State::Idle {
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        let mut actions = Vec::new();
+        let mut hello = HELLO_TOKEN.to_le_bytes().to_vec();
+        hello.extend_from_slice(&local_capabilities().to_bytes());
+        self.push_frame(&mut actions, PacketType::Ping, hello);
+        self.deadline = Some(now.saturating_add(self.handshake_timeout_ms.saturating_mul(1000)));
+        self.transition(&mut actions, State::SynSent, Event::Connect);
+        Ok(actions)
+    }
+
+    /// Frames carrying `message` to the peer
+    ///
+    /// Fails with `WriteShutdown` after `close`, and with `InvalidInput`
+    /// before the handshake is done or once the connection is closed.
+    pub fn send(&mut self, message: &[u8]) -> Result<Vec<Action>> {
+        if self.fin_sent {
+            return Err(Error::new(ErrorKind::WriteShutdown));
+        }
+        if !matches!(self.state, State::Established | State::Closing) {
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        let mut actions = Vec::new();
+        if message.len() <= self.max_payload {
+            self.push_frame(&mut actions, PacketType::Data, message.to_vec());
+            return Ok(actions);
+        }
+        let chunk_size = self.max_payload - MESSAGE_DATA_HEAD_SIZE;
+        let message_id = self.next_message_id;
+        self.next_message_id += 1;
+        let packet_count = message.len().div_ceil(chunk_size) as u32;
+        let head = MessageHead::new(message.len() as u64, message_id, packet_count);
+        self.push_frame(&mut actions, PacketType::MessageHead, head.to_bytes().to_vec());
+        for chunk in message.chunks(chunk_size) {
+            let mut data = Vec::with_capacity(MESSAGE_DATA_HEAD_SIZE + chunk.len());
+            data.extend_from_slice(&message_id.to_le_bytes());
+            data.extend_from_slice(chunk);
+            self.push_frame(&mut actions, PacketType::MessageData, data);
+        }
+        Ok(actions)
+    }
+
+    /// Shut down this end's sending direction at `now`, in microseconds
+    ///
+    /// The connection is closed once the peer has shut down its direction
+    /// too, or when `poll_timeout` finds it has not in time. Closing again
+    /// does nothing.
+    pub fn close(&mut self, now: u64) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if self.fin_sent || !matches!(self.state, State::Established | State::Closing) {
+            return actions;
+        }
+        self.fin_sent = true;
+        self.push_frame(&mut actions, PacketType::Fin, Vec::new());
+        if self.fin_received {
+            self.transition(&mut actions, State::Closed, Event::Close);
+        } else {
+            self.deadline = Some(now.saturating_add(self.close_timeout_ms.saturating_mul(1000)));
+            self.transition(&mut actions, State::Closing, Event::Close);
+        }
+        actions
+    }
+
+    /// Handle one frame received from the peer
+    ///
+    /// Fails on a malformed frame or one that breaks the protocol, such as
+    /// a sequence gap; frames arriving once the connection is closed are ignored.
+    pub fn handle_frame(&mut self, frame: &[u8]) -> Result<Vec<Action>> {
+        let mut actions = Vec::new();
+        let (mut packet, _) = Packet::parse(frame)?;
+        packet.take_ack()?;
+        let pkt_type = PacketType::from_u8(packet.header.pkt_type).ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+        if self.state == State::Closed {
+            return Ok(actions);
+        }
+        match pkt_type {
+            // Control packets do not consume sequence numbers, and ACK mode is off
+            PacketType::Ack | PacketType::Nack | PacketType::WindowUpdate => return Ok(actions),
+            PacketType::Goaway => {
+                let goaway = Goaway::parse(&packet.data)?;
+                self.transition(&mut actions, State::Closed, Event::GoawayReceived(goaway.code));
+                return Ok(actions);
+            }
+            _ => {}
+        }
+
+        let seq = packet.header.seq;
+        if seq::before(seq, self.recv_seq) {
+            return Ok(actions);
+        }
+        if seq != self.recv_seq {
+            return Err(Error::new(ErrorKind::SequenceGap).with_seq(seq));
+        }
+        self.recv_seq = self.recv_seq.wrapping_add(1);
+
+        match pkt_type {
+            PacketType::Ping => self.handle_ping(&mut actions, &packet.data)?,
+            PacketType::Pong => {
+                if packet.data.len() >= PING_SIZE && packet.data[..PING_SIZE] == HELLO_TOKEN.to_le_bytes() && self.state == State::SynSent {
+                    self.deadline = None;
+                    self.transition(&mut actions, State::Established, Event::PongReceived);
+                }
+            }
+            PacketType::Fin => {
+                if !matches!(self.state, State::Established | State::Closing) {
+                    return Err(Error::new(ErrorKind::InvalidPacket).with_seq(seq));
+                }
+                self.fin_received = true;
+                if self.fin_sent {
+                    self.deadline = None;
+                    self.transition(&mut actions, State::Closed, Event::FinReceived);
+                } else {
+                    self.transition(&mut actions, State::Closing, Event::FinReceived);
+                }
+            }
+            PacketType::Data | PacketType::MessageHead | PacketType::MessageData => {
+                // Messages come after the handshake and before the peer's Fin
+                if !matches!(self.state, State::Established | State::Closing) || self.fin_received {
+                    return Err(Error::new(ErrorKind::InvalidPacket).with_seq(seq));
+                }
+                if let Some(message) = self.handle_message(pkt_type, packet.data)? {
+                    actions.push(Action::Deliver(message));
+                }
+            }
+            // Only sent to peers that advertise the matching feature, which this one does not
+            _ => return Err(Error::new(ErrorKind::Unsupported).with_seq(seq)),
+        }
+        Ok(actions)
+    }
+
+    /// Close the connection if its deadline has passed at `now`, in microseconds
+    pub fn poll_timeout(&mut self, now: u64) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if self.deadline.is_some_and(|deadline| now >= deadline) {
+            self.deadline = None;
+            self.transition(&mut actions, State::Closed, Event::Timeout);
+        }
+        actions
+    }
+
+    /// Answer a Ping with a Pong echoing its token, taking up the peer's handshake if it is one
+    fn handle_ping(&mut self, actions: &mut Vec<Action>, ping: &[u8]) -> Result<()> {
+        if ping.len() < PING_SIZE {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let mut pong = ping[..PING_SIZE].to_vec();
+        if ping[..PING_SIZE] != HELLO_TOKEN.to_le_bytes() {
+            self.push_frame(actions, PacketType::Pong, pong);
+            return Ok(());
+        }
+        // Without a clock the timestamps are left zero; the capabilities follow them
+        pong.resize(PONG_SIZE, 0);
+        pong.extend_from_slice(&local_capabilities().to_bytes());
+        self.push_frame(actions, PacketType::Pong, pong);
+        if self.state == State::Idle {
+            self.transition(actions, State::Established, Event::HelloReceived);
+        }
+        Ok(())
+    }
+
+    /// Take in a message packet, returning the message once complete
+    fn handle_message(&mut self, pkt_type: PacketType, data: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match pkt_type {
+            PacketType::Data => Ok(Some(data)),
+            PacketType::MessageHead => {
+                let head = MessageHead::parse(&data)?;
+                let total_length = head.validate()?;
+                if head.flags != 0 {
+                    return Err(Error::new(ErrorKind::Unsupported));
+                }
+                if total_length > self.max_message_size {
+                    return Err(Error::new(ErrorKind::MessageTooLarge));
+                }
+                if self.partial.len() >= MAX_PARTIAL_MESSAGES {
+                    return Err(Error::new(ErrorKind::ReassemblyLimitExceeded));
+                }
+                if total_length == 0 {
+                    return Ok(Some(Vec::new()));
+                }
+                self.partial.insert(head.message_id, (total_length, Vec::new()));
+                Ok(None)
+            }
+            _ => {
+                let id = data.get(..MESSAGE_DATA_HEAD_SIZE).ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+                let message_id = u64::from_le_bytes(id.try_into().expect("message ID"));
+                let (total_length, received) = self.partial.get_mut(&message_id)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+                let chunk = &data[MESSAGE_DATA_HEAD_SIZE..];
+                if received.len() + chunk.len() > *total_length {
+                    return Err(Error::new(ErrorKind::InvalidPacket));
+                }
+                received.extend_from_slice(chunk);
+                if received.len() < *total_length {
+                    return Ok(None);
+                }
+                Ok(self.partial.remove(&message_id).map(|(_, message)| message))
+            }
+        }
+    }
+
+    /// Queue a frame of `pkt_type` on the next sequence number
+    fn push_frame(&mut self, actions: &mut Vec<Action>, pkt_type: PacketType, payload: Vec<u8>) {
+        actions.push(Action::Send(Packet::new(pkt_type, self.send_seq, payload).to_wire()));
+        self.send_seq = self.send_seq.wrapping_add(1);
+    }
+
+    fn transition(&mut self, actions: &mut Vec<Action>, to: State, event: Event) {
+        let from = self.state;
+        self.state = to;
+        if to == State::Closed {
+            self.deadline = None;
+            self.partial.clear();
+        }
+        actions.push(Action::Transition { from, to, event });
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the handshake advertises: only the Fin packets `close` sends
+fn local_capabilities() -> Capabilities {
+    Capabilities::new().with_feature(Feature::HalfClose)
+}
@@ -0,0 +1,111 @@
+//! Accounting of the memory a connection holds on behalf of its peer
+//!
+//! A transport charges the bytes of packets held back for reordering,
+//! messages being reassembled or staged in a group, and messages waiting in
+//! the send queue to its `MemoryAccount`. The account is capped by
+//! `TransportConfig::max_connection_memory` and, through a shared
+//! `MemoryBudget`, by a limit on all connections of the process together.
+//!
+//! A queued message over either cap fails with `WouldBlock` until earlier
+//! ones are sent. A peer whose packets would take a connection over a cap is
+//! cut off with `MemoryLimitExceeded` and `GOAWAY_RESOURCE_EXHAUSTED`.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Memory limit shared by every connection built with a clone of it
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    used: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            used: Arc::new(AtomicUsize::new(0)),
+            limit,
+        }
+    }
+
+    /// Bytes held by all connections together
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Take `bytes` from the budget, or return false if that would exceed the limit
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    /// Give back `bytes` taken with `try_reserve`
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+/// Memory held by one connection, returned to the shared budget when dropped
+#[derive(Debug)]
+pub struct MemoryAccount {
+    used: usize,
+    /// Cap on `used` (0 = unlimited)
+    limit: usize,
+    budget: Option<MemoryBudget>,
+}
+
+impl MemoryAccount {
+    pub fn new(limit: usize, budget: Option<MemoryBudget>) -> Self {
+        MemoryAccount { used: 0, limit, budget }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Bytes that can still be charged before a cap is reached, or `None` without caps
+    pub fn available(&self) -> Option<usize> {
+        let own = (self.limit > 0).then(|| self.limit.saturating_sub(self.used));
+        let shared = self.budget.as_ref().map(|budget| budget.limit().saturating_sub(budget.used()));
+        match (own, shared) {
+            (Some(own), Some(shared)) => Some(own.min(shared)),
+            (own, shared) => own.or(shared),
+        }
+    }
+
+    /// Charge `bytes` to the connection, or return false if a cap would be exceeded
+    pub fn try_reserve(&mut self, bytes: usize) -> bool {
+        if self.limit > 0 && self.used.saturating_add(bytes) > self.limit {
+            return false;
+        }
+        if let Some(budget) = &self.budget
+            && !budget.try_reserve(bytes)
+        {
+            return false;
+        }
+        self.used += bytes;
+        true
+    }
+
+    /// Give back `bytes` charged with `try_reserve`
+    pub fn release(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.used);
+        self.used -= bytes;
+        if let Some(budget) = &self.budget {
+            budget.release(bytes);
+        }
+    }
+}
+
+impl Drop for MemoryAccount {
+    fn drop(&mut self) {
+        self.release(self.used);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    digest::crc64,
+    error::ErrorKind,
+    protocol::{
+        MESSAGE_CONTENT_TYPE_MASK, MESSAGE_CONTENT_TYPE_SHIFT, MESSAGE_FLAG_COMPRESSED,
+        MESSAGE_FLAG_ENCRYPTED, MESSAGE_FLAG_ORIGINAL, ORIGINAL_DIGEST_SIZE,
+    },
+    Error, Result,
+};
+use alloc::vec::Vec;
+
+/// Length and CRC-64 of a payload before the application compressed or encrypted it
+///
+/// Packet CRCs cover the payload as sent, sealed by the transport's own
+/// encryption if any, and the message digest covers the message as the
+/// application handed it over. This one covers the payload the receiver's
+/// decoding should reproduce, so a decoder bug shows up as a mismatch, and
+/// `len` bounds how far the receiver lets a decompressor expand the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OriginalDigest {
+    pub len: u64,
+    pub crc64: u64,
+}
+
+impl OriginalDigest {
+    pub fn of(original: &[u8]) -> Self {
+        OriginalDigest {
+            len: original.len() as u64,
+            crc64: crc64(original),
+        }
+    }
+
+    /// Whether `original` is the payload this digest was computed from
+    pub fn matches(&self, original: &[u8]) -> bool {
+        original.len() as u64 == self.len && crc64(original) == self.crc64
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; ORIGINAL_DIGEST_SIZE] {
+        let mut buf = [0u8; ORIGINAL_DIGEST_SIZE];
+        buf[..8].copy_from_slice(&self.len.to_le_bytes());
+        buf[8..].copy_from_slice(&self.crc64.to_le_bytes());
+        buf
+    }
+
+    pub(crate) fn from_bytes(buf: &[u8; ORIGINAL_DIGEST_SIZE]) -> Self {
+        let mut len = [0u8; 8];
+        let mut crc = [0u8; 8];
+        len.copy_from_slice(&buf[..8]);
+        crc.copy_from_slice(&buf[8..]);
+        OriginalDigest {
+            len: u64::from_le_bytes(len),
+            crc64: u64::from_le_bytes(crc),
+        }
+    }
+}
+
+/// Properties of a message payload, carried in its MessageHead flags
+///
+/// The transport does not compress or encrypt anything itself; it only tells
+/// the receiver how the sender prepared the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MessageOptions {
+    pub compressed: bool,
+    pub encrypted: bool,
+    /// Application-defined content type or codec (0 = unspecified)
+    pub content_type: u8,
+    /// Digest of the payload before the application's transforms, carried ahead of the body
+    pub original: Option<OriginalDigest>,
+}
+
+impl MessageOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_compressed(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    pub fn with_encrypted(mut self, encrypted: bool) -> Self {
+        self.encrypted = encrypted;
+        self
+    }
+
+    pub fn with_content_type(mut self, content_type: u8) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Send the digest of `original`, the payload the message body was compressed or encrypted from
+    ///
+    /// It is left out for a peer whose handshake did not advertise `Feature::OriginalDigest`.
+    pub fn with_original(mut self, original: &[u8]) -> Self {
+        self.original = Some(OriginalDigest::of(original));
+        self
+    }
+
+    pub(crate) fn to_flags(self) -> u32 {
+        let mut flags = (self.content_type as u32) << MESSAGE_CONTENT_TYPE_SHIFT;
+        if self.compressed {
+            flags |= MESSAGE_FLAG_COMPRESSED;
+        }
+        if self.encrypted {
+            flags |= MESSAGE_FLAG_ENCRYPTED;
+        }
+        if self.original.is_some() {
+            flags |= MESSAGE_FLAG_ORIGINAL;
+        }
+        flags
+    }
+
+    pub(crate) fn from_flags(flags: u32) -> Self {
+        MessageOptions {
+            compressed: flags & MESSAGE_FLAG_COMPRESSED != 0,
+            encrypted: flags & MESSAGE_FLAG_ENCRYPTED != 0,
+            content_type: ((flags & MESSAGE_CONTENT_TYPE_MASK) >> MESSAGE_CONTENT_TYPE_SHIFT) as u8,
+            original: None,
+        }
+    }
+}
+
+/// A received message together with the metadata its sender attached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub data: Vec<u8>,
+    pub options: MessageOptions,
+    /// Key given to `send_keyed_message`, if any
+    pub key: Option<u64>,
+}
+
+impl Message {
+    /// Message without metadata, as sent by `send_message`
+    pub(crate) fn plain(data: Vec<u8>) -> Self {
+        Message {
+            data,
+            options: MessageOptions::default(),
+            key: None,
+        }
+    }
+
+    /// Check the payload decoded from this message against the original digest its sender attached
+    ///
+    /// Fails with `DigestMismatch` if they differ; passes if there is no digest.
+    pub fn check_original(&self, original: &[u8]) -> Result<()> {
+        match self.options.original {
+            Some(digest) if !digest.matches(original) => Err(Error::new(ErrorKind::DigestMismatch)),
+            _ => Ok(()),
+        }
+    }
+}
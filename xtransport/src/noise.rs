@@ -0,0 +1,375 @@
+//! Noise XX key exchange: per-session keys with mutual static-key authentication
+//!
+//! Implements `Noise_XX_25519_ChaChaPoly_SHA256` from the Noise Protocol
+//! Framework (revision 34), with an empty prologue unless `with_prologue`
+//! sets one:
+//!
+//! ```text
+//! -> e
+//! <- e, ee, s, es
+//! -> s, se
+//! ```
+//!
+//! Both ends prove they hold the secret of their static X25519 key, and each
+//! learns the other's public static key to decide whether to trust it, on
+//! its own or through `verify_peer` as soon as the key arrives. The
+//! two keys of the final split become the transport's `Encryption`, one per
+//! direction. Randomness for the ephemeral keys comes from an `Rng`, so the
+//! handshake runs without `std`; `XTransport::noise_handshake` drives it
+//! over a connection.
+
+use crate::{
+    cipher::{ChaCha20Poly1305Cipher, Cipher, Encryption, Role},
+    error::ErrorKind,
+    Error, Result,
+};
+use alloc::{boxed::Box, vec::Vec};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Size of X25519 public and secret keys
+pub const KEY_LEN: usize = 32;
+const HASH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const PROTOCOL_NAME: &[u8; HASH_LEN] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+/// Handshake messages of the XX pattern
+const MESSAGE_COUNT: usize = 3;
+
+/// Source of random bytes for ephemeral keys
+///
+/// It must be a cryptographically secure generator, e.g. the platform's
+/// entropy source or a hardware RNG. Any `FnMut(&mut [u8])` filling the
+/// buffer will do.
+pub trait Rng {
+    fn fill_bytes(&mut self, buf: &mut [u8]);
+}
+
+impl<F: FnMut(&mut [u8])> Rng for F {
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        self(buf)
+    }
+}
+
+/// X25519 key pair; the secret is wiped when it is dropped
+pub struct Keypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Keypair {
+    pub fn generate(rng: &mut impl Rng) -> Self {
+        let mut secret = [0u8; KEY_LEN];
+        rng.fill_bytes(&mut secret);
+        Self::from_secret(secret)
+    }
+
+    pub fn from_secret(secret: [u8; KEY_LEN]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+
+    pub fn public(&self) -> [u8; KEY_LEN] {
+        self.public.to_bytes()
+    }
+}
+
+/// Chaining key, handshake hash and the cipher keyed from them so far
+struct SymmetricState {
+    ck: [u8; HASH_LEN],
+    h: [u8; HASH_LEN],
+    cipher: Option<ChaCha20Poly1305Cipher>,
+    n: u64,
+}
+
+impl SymmetricState {
+    fn new(prologue: &[u8]) -> Self {
+        let mut state = SymmetricState {
+            ck: *PROTOCOL_NAME,
+            h: *PROTOCOL_NAME,
+            cipher: None,
+            n: 0,
+        };
+        state.mix_hash(prologue);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = Sha256::new().chain_update(self.h).chain_update(data).finalize().into();
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let (ck, key) = hkdf(&self.ck, input);
+        self.ck = ck;
+        self.cipher = Some(ChaCha20Poly1305Cipher::new(&key));
+        self.n = 0;
+    }
+
+    /// Mix the shared secret of `local` and `remote` into the keys
+    fn mix_dh(&mut self, local: &StaticSecret, remote: &PublicKey) -> Result<()> {
+        let shared = local.diffie_hellman(remote);
+        // A low-order point from the peer would make the secret predictable
+        if !shared.was_contributory() {
+            return Err(Error::new(ErrorKind::AuthenticationFailed));
+        }
+        self.mix_key(shared.as_bytes());
+        Ok(())
+    }
+
+    /// Append `plaintext` to `out`, encrypted once a key has been mixed in
+    fn encrypt_and_hash(&mut self, plaintext: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+        if let Some(cipher) = self.cipher.as_mut() {
+            out.resize(start + plaintext.len() + TAG_LEN, 0);
+            let (body, tag) = out[start..].split_at_mut(plaintext.len());
+            cipher.seal(self.n, &self.h, body, tag)?;
+            self.n += 1;
+        }
+        self.mix_hash(&out[start..]);
+        Ok(())
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = ciphertext.to_vec();
+        if let Some(cipher) = self.cipher.as_mut() {
+            let body_len = ciphertext.len().checked_sub(TAG_LEN)
+                .ok_or_else(|| Error::new(ErrorKind::AuthenticationFailed))?;
+            let (body, tag) = plaintext.split_at_mut(body_len);
+            cipher.open(self.n, &self.h, body, tag)?;
+            self.n += 1;
+            plaintext.truncate(body_len);
+        }
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+}
+
+fn hmac(key: &[u8; HASH_LEN], parts: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// The first two outputs of the Noise HKDF
+fn hkdf(chaining_key: &[u8; HASH_LEN], input: &[u8]) -> ([u8; HASH_LEN], [u8; HASH_LEN]) {
+    let temp = hmac(chaining_key, &[input]);
+    let first = hmac(&temp, &[&[1]]);
+    let second = hmac(&temp, &[&first, &[2]]);
+    (first, second)
+}
+
+fn public_key(bytes: &[u8]) -> PublicKey {
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(bytes);
+    PublicKey::from(key)
+}
+
+/// Decides whether a peer's public static key is trusted
+type PeerCheck = Box<dyn Fn(&[u8; KEY_LEN]) -> bool + Send>;
+
+/// One side of a Noise XX handshake
+///
+/// The initiator writes the first message; after that the two sides take
+/// turns until `is_finished`. Each message may carry a payload, which is
+/// encrypted in the second and third.
+pub struct NoiseHandshake {
+    initiator: bool,
+    state: SymmetricState,
+    local_static: Keypair,
+    local_ephemeral: Keypair,
+    remote_ephemeral: Option<PublicKey>,
+    remote_static: Option<PublicKey>,
+    /// Check of the peer's static key, run as soon as it is read
+    verify: Option<PeerCheck>,
+    /// Messages written or read so far
+    step: usize,
+}
+
+impl NoiseHandshake {
+    /// Side that sends the first message, with its static key
+    pub fn initiator(local_static: Keypair, rng: &mut impl Rng) -> Self {
+        Self::new(true, local_static, rng)
+    }
+
+    /// Side that answers the first message, with its static key
+    pub fn responder(local_static: Keypair, rng: &mut impl Rng) -> Self {
+        Self::new(false, local_static, rng)
+    }
+
+    fn new(initiator: bool, local_static: Keypair, rng: &mut impl Rng) -> Self {
+        NoiseHandshake {
+            initiator,
+            state: SymmetricState::new(&[]),
+            local_static,
+            local_ephemeral: Keypair::generate(rng),
+            remote_ephemeral: None,
+            remote_static: None,
+            verify: None,
+            step: 0,
+        }
+    }
+
+    /// Bind the handshake to `prologue`, e.g. the protocol version both sides expect
+    ///
+    /// Both sides must use the same prologue, or the second message fails to
+    /// decrypt. Panics once a message has been written or read.
+    pub fn with_prologue(mut self, prologue: &[u8]) -> Self {
+        assert_eq!(self.step, 0, "prologue set after the handshake started");
+        self.state = SymmetricState::new(prologue);
+        self
+    }
+
+    /// Accept the peer only if `trusted` returns true for its public static key
+    ///
+    /// The check runs as soon as the message carrying the key is read, so an
+    /// initiator never reveals its own static key to an untrusted responder;
+    /// `read_message` fails with `Unauthorized` for a rejected key.
+    pub fn verify_peer(mut self, trusted: impl Fn(&[u8; KEY_LEN]) -> bool + Send + 'static) -> Self {
+        self.verify = Some(Box::new(trusted));
+        self
+    }
+
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.step == MESSAGE_COUNT
+    }
+
+    /// True if the next message is written by this side
+    pub fn is_write_turn(&self) -> bool {
+        !self.is_finished() && self.step.is_multiple_of(2) == self.initiator
+    }
+
+    /// Public static key of the peer, once the message carrying it has been read
+    pub fn remote_static(&self) -> Option<[u8; KEY_LEN]> {
+        self.remote_static.map(|key| key.to_bytes())
+    }
+
+    /// Next handshake message, carrying `payload`
+    ///
+    /// Fails with `InvalidInput` if it is the peer's turn.
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        if !self.is_write_turn() {
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        let mut message = Vec::new();
+        match self.step {
+            // -> e
+            0 => self.write_ephemeral(&mut message),
+            // <- e, ee, s, es
+            1 => {
+                self.write_ephemeral(&mut message);
+                let remote_ephemeral = self.remote_ephemeral.expect("read in step 0");
+                self.state.mix_dh(&self.local_ephemeral.secret, &remote_ephemeral)?;
+                self.state.encrypt_and_hash(self.local_static.public.as_bytes(), &mut message)?;
+                self.state.mix_dh(&self.local_static.secret, &remote_ephemeral)?;
+            }
+            // -> s, se
+            _ => {
+                self.state.encrypt_and_hash(self.local_static.public.as_bytes(), &mut message)?;
+                let remote_ephemeral = self.remote_ephemeral.expect("read in step 1");
+                self.state.mix_dh(&self.local_static.secret, &remote_ephemeral)?;
+            }
+        }
+        self.state.encrypt_and_hash(payload, &mut message)?;
+        self.step += 1;
+        Ok(message)
+    }
+
+    /// Process the peer's next handshake message, returning its payload
+    ///
+    /// Fails with `InvalidInput` if it is this side's turn, `InvalidPacket`
+    /// if the message is too short and `AuthenticationFailed` if it does not
+    /// decrypt; the handshake cannot continue after an error.
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        if self.is_finished() || self.is_write_turn() {
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        let sealed_key_len = KEY_LEN + TAG_LEN;
+        let rest = match self.step {
+            // -> e
+            0 => self.read_ephemeral(message)?,
+            // <- e, ee, s, es
+            1 => {
+                let rest = self.read_ephemeral(message)?;
+                let remote_ephemeral = self.remote_ephemeral.expect("just read");
+                self.state.mix_dh(&self.local_ephemeral.secret, &remote_ephemeral)?;
+                let rest = self.read_static(rest, sealed_key_len)?;
+                let remote_static = self.remote_static.expect("just read");
+                self.state.mix_dh(&self.local_ephemeral.secret, &remote_static)?;
+                rest
+            }
+            // -> s, se
+            _ => {
+                let rest = self.read_static(message, sealed_key_len)?;
+                let remote_static = self.remote_static.expect("just read");
+                self.state.mix_dh(&self.local_ephemeral.secret, &remote_static)?;
+                rest
+            }
+        };
+        let payload = self.state.decrypt_and_hash(rest)?;
+        self.step += 1;
+        Ok(payload)
+    }
+
+    fn write_ephemeral(&mut self, message: &mut Vec<u8>) {
+        let public = self.local_ephemeral.public();
+        message.extend_from_slice(&public);
+        self.state.mix_hash(&public);
+    }
+
+    /// Take the peer's ephemeral key from the start of `message`, returning the rest
+    fn read_ephemeral<'a>(&mut self, message: &'a [u8]) -> Result<&'a [u8]> {
+        if message.len() < KEY_LEN {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let (key, rest) = message.split_at(KEY_LEN);
+        self.state.mix_hash(key);
+        self.remote_ephemeral = Some(public_key(key));
+        Ok(rest)
+    }
+
+    /// Decrypt the peer's static key from the start of `message`, returning the rest
+    fn read_static<'a>(&mut self, message: &'a [u8], sealed_len: usize) -> Result<&'a [u8]> {
+        if message.len() < sealed_len {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let (sealed, rest) = message.split_at(sealed_len);
+        let key = public_key(&self.state.decrypt_and_hash(sealed)?);
+        if let Some(trusted) = self.verify.as_ref()
+            && !trusted(key.as_bytes())
+        {
+            return Err(Error::new(ErrorKind::Unauthorized));
+        }
+        self.remote_static = Some(key);
+        Ok(rest)
+    }
+
+    /// Hash of the whole handshake, the same on both sides, for channel binding
+    pub fn handshake_hash(&self) -> [u8; HASH_LEN] {
+        self.state.h
+    }
+
+    /// Transport ciphers keyed from the finished handshake
+    ///
+    /// Fails with `InvalidInput` before the last message.
+    pub fn into_encryption(self) -> Result<Encryption> {
+        if !self.is_finished() {
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        let (initiator_key, responder_key) = hkdf(&self.state.ck, &[]);
+        let initiator = ChaCha20Poly1305Cipher::new(&initiator_key);
+        let responder = ChaCha20Poly1305Cipher::new(&responder_key);
+        Ok(if self.initiator {
+            Encryption::new(Role::Initiator, initiator, responder)
+        } else {
+            Encryption::new(Role::Responder, responder, initiator)
+        })
+    }
+}
@@ -0,0 +1,119 @@
+/// Something that happened on a transport, reported to its `Observer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// A packet was queued for the wire (`len` is the payload length)
+    PacketSent { pkt_type: u8, seq: u32, len: usize },
+    /// A packet passed header and CRC checks
+    PacketReceived { pkt_type: u8, seq: u32, len: usize },
+    /// A packet failed its CRC check and was dropped
+    CrcFailure,
+    /// An ACK from the peer released `acked` packets from the send window
+    AckReceived { seq: u32, acked: usize },
+    /// The oldest unacknowledged packet was sent again
+    Retransmit { seq: u32, retry: u32 },
+    /// The peer's capabilities became known (`peer_features` are its feature bits)
+    Handshake { peer_features: u64 },
+    /// Adaptive framing changed the largest payload sent per packet
+    FrameSizeChanged { payload_size: usize },
+    /// The connection moved to another lifecycle stage
+    StateChanged { state: ConnectionState },
+    /// Retransmissions of a packet ran out and the connection was given up
+    DeliveryFailed(DeliveryFailure),
+}
+
+/// Packet a transport gave up retransmitting, and the part of a message it carried
+///
+/// The packet and everything sent after it may not have reached the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryFailure {
+    pub seq: u32,
+    /// Message of a MessageHead or MessageData packet; `None` for a
+    /// single-packet message, a Batch or a control packet
+    pub message_id: Option<u64>,
+    /// Offset of the message bytes the packet carried (the digest of a
+    /// message starts at its length)
+    pub offset: usize,
+    /// Message bytes the packet carried: 0 for a MessageHead, the payload for other packets
+    pub len: usize,
+}
+
+/// Lifecycle stage of a connection, as far as the transport can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Usable, with no handshake exchanged yet
+    Open,
+    /// A handshake has told each end the other's capabilities
+    Connected,
+    /// This end shut down its sending direction and still receives
+    Closing,
+    /// The peer shut down its sending direction and still receives
+    PeerClosing,
+    /// Both directions were shut down, or a Goaway without error ended the connection
+    Closed,
+    /// The stream ended or failed, or a Goaway reported an error
+    Reset,
+    /// The peer stopped acknowledging packets
+    TimedOut,
+}
+
+impl ConnectionState {
+    /// Whether the connection is over and nothing more can be exchanged
+    pub fn is_terminal(self) -> bool {
+        matches!(self, ConnectionState::Closed | ConnectionState::Reset | ConnectionState::TimedOut)
+    }
+}
+
+/// Direction of a message transfer reported to a progress callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    Send,
+    Receive,
+}
+
+/// Progress callback `(transfer, bytes_done, bytes_total)` for multi-packet messages
+pub type ProgressFn = dyn FnMut(Transfer, usize, usize) + Send;
+
+/// Callback receiving transport events, for targets that cannot use `tracing`
+///
+/// Called synchronously from the transport, so it should return quickly.
+pub trait Observer {
+    fn on_event(&mut self, event: &TransportEvent);
+}
+
+impl<F: FnMut(&TransportEvent)> Observer for F {
+    fn on_event(&mut self, event: &TransportEvent) {
+        self(event)
+    }
+}
+
+/// Forward an event to the `tracing` subscriber, if the feature is enabled
+#[cfg(feature = "tracing")]
+pub(crate) fn trace_event(event: &TransportEvent) {
+    match *event {
+        TransportEvent::PacketSent { pkt_type, seq, len } => {
+            tracing::trace!(pkt_type, seq, len, "packet sent");
+        }
+        TransportEvent::PacketReceived { pkt_type, seq, len } => {
+            tracing::trace!(pkt_type, seq, len, "packet received");
+        }
+        TransportEvent::CrcFailure => tracing::warn!("packet CRC mismatch"),
+        TransportEvent::AckReceived { seq, acked } => {
+            tracing::trace!(seq, acked, "ack received");
+        }
+        TransportEvent::Retransmit { seq, retry } => {
+            tracing::debug!(seq, retry, "retransmit");
+        }
+        TransportEvent::Handshake { peer_features } => {
+            tracing::debug!(peer_features, "handshake");
+        }
+        TransportEvent::FrameSizeChanged { payload_size } => {
+            tracing::debug!(payload_size, "frame size changed");
+        }
+        TransportEvent::StateChanged { state } => {
+            tracing::debug!(state = ?state, "connection state changed");
+        }
+        TransportEvent::DeliveryFailed(failure) => {
+            tracing::warn!(seq = failure.seq, message_id = failure.message_id, offset = failure.offset, len = failure.len, "delivery failed");
+        }
+    }
+}
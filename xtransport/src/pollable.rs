@@ -0,0 +1,109 @@
+//! Readiness-based event loops over mio (`mio` feature)
+//!
+//! `PollableTransport` is an `XTransport` over a non-blocking mio stream that
+//! can itself be registered with a `mio::Poll`, so one thread can serve many
+//! connections without an async runtime. On a readable event call
+//! `recv_ready`, which receives until the stream would block as
+//! edge-triggered readiness requires; messages are sent as usual, and what
+//! the socket does not take is kept and written by `flush_ready` on the next
+//! writable event. After either, `update_interest` asks for writable events
+//! only while such bytes are pending.
+//!
+//! Receives stop at `WouldBlock` instead of waiting, so leave ACK mode off:
+//! it waits for the peer's ACK after every send. TCP already delivers reliably.
+
+use crate::{config::TransportConfig, error::ErrorKind, transport::XTransport, Result};
+use alloc::vec::Vec;
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+
+/// Transport over a non-blocking mio stream, registrable with a `mio::Poll`
+pub struct PollableTransport<S: Source + std::io::Read + std::io::Write> {
+    transport: XTransport<S>,
+    /// Interest the stream is registered with, if it is
+    interest: Option<Interest>,
+}
+
+impl<S: Source + std::io::Read + std::io::Write> PollableTransport<S> {
+    pub fn new(stream: S, config: TransportConfig) -> Self {
+        PollableTransport {
+            transport: XTransport::new(stream, config),
+            interest: None,
+        }
+    }
+
+    pub fn transport(&self) -> &XTransport<S> {
+        &self.transport
+    }
+
+    /// The transport, for sending messages or reading its state
+    pub fn transport_mut(&mut self) -> &mut XTransport<S> {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> XTransport<S> {
+        self.transport
+    }
+
+    /// Readable, and writable while sent packets wait for the stream to take them
+    pub fn interest(&self) -> Interest {
+        if self.transport.pending_write_len() > 0 {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        }
+    }
+
+    /// Receive every message that completes before the stream would block
+    ///
+    /// An empty result with no error is a spurious wakeup or a message still
+    /// in progress. A stream closed by the peer fails with `UnexpectedEof`.
+    pub fn recv_ready(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut messages = Vec::new();
+        loop {
+            match self.transport.recv_message() {
+                Ok(message) => messages.push(message),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(messages),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write what the stream did not take before, as far as it takes it now
+    pub fn flush_ready(&mut self) -> Result<()> {
+        match self.transport.poll_flush() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Re-register for writable events if bytes are now pending, or stop if they were written
+    pub fn update_interest(&mut self, registry: &Registry, token: Token) -> std::io::Result<()> {
+        let interest = self.interest();
+        if self.interest.is_some() && self.interest != Some(interest) {
+            self.reregister(registry, token, interest)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Source + std::io::Read + std::io::Write> Source for PollableTransport<S> {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> std::io::Result<()> {
+        self.transport.get_mut().register(registry, token, interests)?;
+        self.interest = Some(interests);
+        Ok(())
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> std::io::Result<()> {
+        self.transport.get_mut().reregister(registry, token, interests)?;
+        self.interest = Some(interests);
+        Ok(())
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> std::io::Result<()> {
+        self.transport.get_mut().deregister(registry)?;
+        self.interest = None;
+        Ok(())
+    }
+}
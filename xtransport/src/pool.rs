@@ -0,0 +1,48 @@
+//! Recycled payload buffers for the receive path
+
+use alloc::vec::Vec;
+
+/// Free list of byte buffers, so decoding a packet reuses the allocation of
+/// one already consumed instead of making a new one
+///
+/// At most `max_buffers` are kept, and buffers that grew beyond
+/// `max_buffer_size` are freed rather than kept, which bounds the memory
+/// the pool holds on to after a burst of large packets.
+pub struct BufferPool {
+    free: Vec<Vec<u8>>,
+    max_buffers: usize,
+    max_buffer_size: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize, max_buffer_size: usize) -> Self {
+        BufferPool {
+            free: Vec::with_capacity(max_buffers),
+            max_buffers,
+            max_buffer_size,
+        }
+    }
+
+    /// An empty buffer, recycled if one is available
+    pub fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_default()
+    }
+
+    /// Return a buffer for reuse, dropping it if the pool is full or it is too large
+    pub fn recycle(&mut self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > self.max_buffer_size || self.free.len() >= self.max_buffers {
+            return;
+        }
+        buf.clear();
+        self.free.push(buf);
+    }
+
+    /// Buffers ready for reuse
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+}
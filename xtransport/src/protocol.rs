@@ -1,15 +1,50 @@
 use crate::{Error, error::ErrorKind, Result};
-use crate::config::{MAGIC, VERSION, HEADER_SIZE, MESSAGE_HEAD_SIZE};
+#[cfg(feature = "alloc")]
+use alloc::string::String;
+#[cfg(feature = "alloc")]
 use alloc::vec::Vec;
 use crc32fast::Hasher;
 
+// Protocol constants
+pub const MAGIC: u32 = 0x58545250; // "XTRP"
+pub const VERSION: u8 = 0x01;
+pub const VERSION_2: u8 = 0x02; // 4-byte length, used once both peers advertise Feature::WireV2
+pub const HEADER_SIZE: usize = 16;
+pub const HEADER_SIZE_V2: usize = 18;
+pub const MAX_PAYLOAD_SIZE_V1: usize = u16::MAX as usize;
+pub const MESSAGE_HEAD_SIZE: usize = 32;
+pub const MESSAGE_DATA_HEAD_SIZE: usize = 8; // message ID prefix of every MessageData packet
+pub const MESSAGE_DATA_OFFSET_SIZE: usize = 8; // byte offset of the chunk after the message ID, with Feature::FragmentOffsets
+pub const REFERENCE_SIZE: usize = 16; // payload hash + payload length
+pub const PING_SIZE: usize = 8; // ping send time
+pub const PONG_SIZE: usize = 24; // ping send time + ping receive time + pong send time
+pub const GROUP_HEAD_SIZE: usize = 16; // group ID + message count + reserved
+pub const ACK_PREFIX_SIZE: usize = 4; // seq of the ACK piggybacked before the payload of a packet flagged PACKET_FLAG_ACK
+pub const GOAWAY_HEAD_SIZE: usize = 4; // error code, followed by the reason
+pub const WINDOW_SIZE: usize = 4; // receive window in bytes, in WindowUpdates and after the ACKed seq
+pub const MESSAGE_RESET_SIZE: usize = 8; // ID of the abandoned message
+pub const BATCH_RECORD_HEAD_SIZE: usize = 4; // length of each message packed in a Batch packet
+pub const MESSAGE_DIGEST_SIZE: usize = 8; // CRC-64 ending the body of a message flagged MESSAGE_FLAG_DIGEST
+pub const ORIGINAL_DIGEST_SIZE: usize = 16; // original length and CRC-64 starting the body of a message flagged MESSAGE_FLAG_ORIGINAL
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum PacketType {
     Data = 0,          // Single packet message
     MessageHead = 1,   // Multi-packet message header
     MessageData = 2,   // Multi-packet message data
     Ack = 3,           // Acknowledgment packet
+    Reference = 4,     // Reference to a payload in the peer's dedup cache
+    Ping = 5,          // Timestamped probe, answered with a Pong
+    Pong = 6,          // Reply to a Ping carrying the peer's timestamps
+    GroupHead = 7,     // Start of a group of messages delivered together
+    Nack = 8,          // Request to retransmit one packet that arrived corrupted
+    Goaway = 9,        // The sender is closing the connection, with an error code and reason
+    Fin = 10,          // The sender sends nothing more but keeps receiving
+    WindowUpdate = 11, // Bytes the sender can still buffer for its peer
+    Batch = 12,        // Several small messages, each prefixed with its length
+    MessageReset = 13, // The sender abandons a multi-packet message it has started
 }
 
 impl PacketType {
@@ -19,23 +54,38 @@ impl PacketType {
             1 => Some(PacketType::MessageHead),
             2 => Some(PacketType::MessageData),
             3 => Some(PacketType::Ack),
+            4 => Some(PacketType::Reference),
+            5 => Some(PacketType::Ping),
+            6 => Some(PacketType::Pong),
+            7 => Some(PacketType::GroupHead),
+            8 => Some(PacketType::Nack),
+            9 => Some(PacketType::Goaway),
+            10 => Some(PacketType::Fin),
+            11 => Some(PacketType::WindowUpdate),
+            12 => Some(PacketType::Batch),
+            13 => Some(PacketType::MessageReset),
             _ => None,
         }
     }
 }
 
+/// Packet type flag: the payload starts with a piggybacked cumulative ACK (u32)
+pub const PACKET_FLAG_ACK: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C)]
 pub struct PacketHeader {
     pub magic: u32,      // 4 bytes
     pub version: u8,     // 1 byte
     pub pkt_type: u8,    // 1 byte - Packet type
     pub seq: u32,        // 4 bytes
-    pub length: u16,     // 2 bytes
+    pub length: u32,     // 2 bytes in version 1, 4 bytes in version 2
     pub crc32: u32,      // 4 bytes
 }
 
 impl PacketHeader {
-    pub fn new(pkt_type: PacketType, seq: u32, length: u16) -> Self {
+    pub fn new(pkt_type: PacketType, seq: u32, length: u32) -> Self {
         PacketHeader {
             magic: MAGIC,
             version: VERSION,
@@ -46,17 +96,72 @@ impl PacketHeader {
         }
     }
 
+    /// Header of a packet whose payload is `parts` back to back, without assembling it
+    pub fn for_parts(pkt_type: PacketType, seq: u32, parts: &[&[u8]]) -> Self {
+        let length = parts.iter().map(|part| part.len()).sum::<usize>() as u32;
+        let mut header = PacketHeader::new(pkt_type, seq, length);
+        let mut hasher = Hasher::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        header.crc32 = hasher.finalize();
+        header
+    }
+
+    /// Size of the header on the wire, which depends on its version
+    pub fn size(&self) -> usize {
+        if self.version == VERSION_2 { HEADER_SIZE_V2 } else { HEADER_SIZE }
+    }
+
+    /// Version 1 encoding; `length` must fit in 2 bytes
     pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
         let mut buf = [0u8; HEADER_SIZE];
         buf[0..4].copy_from_slice(&self.magic.to_le_bytes());
         buf[4] = self.version;
         buf[5] = self.pkt_type;
         buf[6..10].copy_from_slice(&self.seq.to_le_bytes());
-        buf[10..12].copy_from_slice(&self.length.to_le_bytes());
+        buf[10..12].copy_from_slice(&(self.length as u16).to_le_bytes());
         buf[12..16].copy_from_slice(&self.crc32.to_le_bytes());
         buf
     }
 
+    /// Version 2 encoding, with a 4-byte length
+    pub fn to_bytes_v2(&self) -> [u8; HEADER_SIZE_V2] {
+        let mut buf = [0u8; HEADER_SIZE_V2];
+        buf[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        buf[4] = self.version;
+        buf[5] = self.pkt_type;
+        buf[6..10].copy_from_slice(&self.seq.to_le_bytes());
+        buf[10..14].copy_from_slice(&self.length.to_le_bytes());
+        buf[14..18].copy_from_slice(&self.crc32.to_le_bytes());
+        buf
+    }
+
+    /// Append the encoding for the header's version to `buf`
+    #[cfg(feature = "alloc")]
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        if self.version == VERSION_2 {
+            buf.extend_from_slice(&self.to_bytes_v2());
+        } else {
+            buf.extend_from_slice(&self.to_bytes());
+        }
+    }
+
+    /// Parse the header of either version at the start of `buf`, which may be of any length
+    ///
+    /// Fails with `UnexpectedEof` if `buf` is shorter than the header.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(buf.get(..HEADER_SIZE).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof))?);
+        if header[4] != VERSION_2 {
+            return Self::from_bytes(&header);
+        }
+        let mut header = [0u8; HEADER_SIZE_V2];
+        header.copy_from_slice(buf.get(..HEADER_SIZE_V2).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof))?);
+        Self::from_bytes_v2(&header)
+    }
+
+    /// Parse a version 1 header
     pub fn from_bytes(buf: &[u8; HEADER_SIZE]) -> Result<Self> {
         let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
         if magic != MAGIC {
@@ -70,7 +175,7 @@ impl PacketHeader {
 
         let pkt_type = buf[5];
         let seq = u32::from_le_bytes([buf[6], buf[7], buf[8], buf[9]]);
-        let length = u16::from_le_bytes([buf[10], buf[11]]);
+        let length = u16::from_le_bytes([buf[10], buf[11]]) as u32;
         let crc32 = u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]);
 
         Ok(PacketHeader {
@@ -82,8 +187,47 @@ impl PacketHeader {
             crc32,
         })
     }
+
+    /// Parse a version 2 header
+    pub fn from_bytes_v2(buf: &[u8; HEADER_SIZE_V2]) -> Result<Self> {
+        let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        if magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidMagic));
+        }
+        if buf[4] != VERSION_2 {
+            return Err(Error::new(ErrorKind::InvalidVersion));
+        }
+
+        Ok(PacketHeader {
+            magic,
+            version: VERSION_2,
+            pkt_type: buf[5],
+            seq: u32::from_le_bytes([buf[6], buf[7], buf[8], buf[9]]),
+            length: u32::from_le_bytes([buf[10], buf[11], buf[12], buf[13]]),
+            crc32: u32::from_le_bytes([buf[14], buf[15], buf[16], buf[17]]),
+        })
+    }
 }
 
+/// MessageHead flag: the receiver stores the message in its dedup cache
+pub const MESSAGE_FLAG_CACHED: u32 = 1 << 0;
+/// MessageHead flag: the reserved field carries an application-chosen message key (u64)
+pub const MESSAGE_FLAG_KEYED: u32 = 1 << 1;
+/// MessageHead flag: the application compressed the payload
+pub const MESSAGE_FLAG_COMPRESSED: u32 = 1 << 2;
+/// MessageHead flag: the application encrypted the payload
+pub const MESSAGE_FLAG_ENCRYPTED: u32 = 1 << 3;
+/// MessageHead flag: the body ends with a CRC-64 of the message (`MESSAGE_DIGEST_SIZE` bytes), counted in its length
+pub const MESSAGE_FLAG_DIGEST: u32 = 1 << 4;
+/// MessageHead flag: the body starts with the length and CRC-64 of the payload before the
+/// application's transforms (`ORIGINAL_DIGEST_SIZE` bytes), counted in its length
+pub const MESSAGE_FLAG_ORIGINAL: u32 = 1 << 5;
+/// MessageHead flag bits holding an application-defined content type or codec (0 = unspecified)
+pub const MESSAGE_CONTENT_TYPE_MASK: u32 = 0xff << MESSAGE_CONTENT_TYPE_SHIFT;
+pub const MESSAGE_CONTENT_TYPE_SHIFT: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 #[repr(C)]
 pub struct MessageHead {
     pub total_length: u64,   // 8 bytes - Total message length
@@ -114,6 +258,29 @@ impl MessageHead {
         buf
     }
 
+    /// Parse the MessageHead at the start of `buf`, which may be of any length
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let mut head = [0u8; MESSAGE_HEAD_SIZE];
+        head.copy_from_slice(buf.get(..MESSAGE_HEAD_SIZE).ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?);
+        Self::from_bytes(&head)
+    }
+
+    /// Check that the announced length can be carried by the announced packets
+    ///
+    /// An untrusted `total_length` must pass this before anything is sized by it.
+    pub fn validate(&self) -> Result<usize> {
+        // Largest chunk a version 2 MessageData packet can carry
+        let max_chunk = u32::MAX as u64 - MESSAGE_DATA_HEAD_SIZE as u64;
+        let total_length = usize::try_from(self.total_length)
+            .map_err(|_| Error::new(ErrorKind::InvalidPacket))?;
+        if self.total_length > self.packet_count as u64 * max_chunk
+            || (self.total_length > 0 && self.packet_count == 0)
+        {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        Ok(total_length)
+    }
+
     pub fn from_bytes(buf: &[u8; MESSAGE_HEAD_SIZE]) -> Result<Self> {
         let total_length = u64::from_le_bytes([
             buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
@@ -136,22 +303,65 @@ impl MessageHead {
     }
 }
 
+#[cfg(feature = "alloc")]
 pub struct Packet {
     pub header: PacketHeader,
     pub data: Vec<u8>,
 }
 
+#[cfg(feature = "alloc")]
 impl Packet {
     pub fn new(pkt_type: PacketType, seq: u32, data: Vec<u8>) -> Self {
-        let length = data.len() as u16;
-        let mut header = PacketHeader::new(pkt_type, seq, length);
+        let header = PacketHeader::for_parts(pkt_type, seq, &[&data]);
+        Packet { header, data }
+    }
+
+    /// Parse one complete packet from the start of `buf`, returning it with its wire length
+    ///
+    /// Fails with `UnexpectedEof` if `buf` holds only part of the packet.
+    pub fn parse(buf: &[u8]) -> Result<(Self, usize)> {
+        let header = PacketHeader::parse(buf)?;
+        let len = header.size() + header.length as usize;
+        let data = buf.get(header.size()..len).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof))?.to_vec();
         
-        // Calculate CRC32
-        let mut hasher = Hasher::new();
-        hasher.update(&data);
-        header.crc32 = hasher.finalize();
+        let packet = Packet { header, data };
+        if !packet.verify_crc() {
+            return Err(Error::new(ErrorKind::CrcMismatch));
+        }
+        Ok((packet, len))
+    }
 
-        Packet { header, data }
+    /// Header and payload as sent on the wire
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut wire = Vec::with_capacity(self.header.size() + self.data.len());
+        self.header.write_to(&mut wire);
+        wire.extend_from_slice(&self.data);
+        wire
+    }
+
+    /// Packet whose payload is prefixed with a piggybacked cumulative ACK
+    pub fn with_ack(pkt_type: PacketType, seq: u32, ack_seq: u32, data: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(ACK_PREFIX_SIZE + data.len());
+        payload.extend_from_slice(&ack_seq.to_le_bytes());
+        payload.extend_from_slice(data);
+        let mut packet = Packet::new(pkt_type, seq, payload);
+        packet.header.pkt_type |= PACKET_FLAG_ACK;
+        packet
+    }
+
+    /// Strip the piggybacked ACK off the payload, if the packet carries one
+    pub fn take_ack(&mut self) -> Result<Option<u32>> {
+        if self.header.pkt_type & PACKET_FLAG_ACK == 0 {
+            return Ok(None);
+        }
+        if self.data.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let ack_seq = u32::from_le_bytes([self.data[0], self.data[1], self.data[2], self.data[3]]);
+        self.data.drain(..4);
+        self.header.pkt_type &= !PACKET_FLAG_ACK;
+        self.header.length -= 4;
+        Ok(Some(ack_seq))
     }
 
     pub fn verify_crc(&self) -> bool {
@@ -161,3 +371,65 @@ impl Packet {
         computed_crc == self.header.crc32
     }
 }
+
+/// Goaway code: orderly close, nothing went wrong
+pub const GOAWAY_NO_ERROR: u32 = 0;
+/// Goaway code: a packet broke the protocol (bad magic or version, sequence gap)
+pub const GOAWAY_PROTOCOL_ERROR: u32 = 1;
+/// Goaway code: a packet failed its CRC check under `CrcPolicy::Fail`
+pub const GOAWAY_CRC_MISMATCH: u32 = 2;
+/// Goaway code: the sender ran out of a resource such as memory or sessions
+pub const GOAWAY_RESOURCE_EXHAUSTED: u32 = 3;
+/// Goaway code: the peer stopped acknowledging packets
+pub const GOAWAY_TIMEOUT: u32 = 4;
+/// Goaway code: the peer failed authentication or sent messages before it
+pub const GOAWAY_UNAUTHORIZED: u32 = 5;
+
+/// Payload of a Goaway packet: an error code (u32) followed by a UTF-8 reason
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Goaway {
+    pub code: u32,
+    pub reason: String,
+}
+
+#[cfg(feature = "alloc")]
+impl Goaway {
+    pub fn new(code: u32, reason: &str) -> Self {
+        Goaway { code, reason: String::from(reason) }
+    }
+
+    /// Code telling the peer why a local error ends the connection
+    pub fn code_for(kind: ErrorKind) -> u32 {
+        match kind {
+            ErrorKind::CrcMismatch => GOAWAY_CRC_MISMATCH,
+            ErrorKind::MaxRetriesExceeded | ErrorKind::TimedOut => GOAWAY_TIMEOUT,
+            ErrorKind::MessageTooLarge | ErrorKind::MemoryLimitExceeded => GOAWAY_RESOURCE_EXHAUSTED,
+            ErrorKind::Unauthorized => GOAWAY_UNAUTHORIZED,
+            _ => GOAWAY_PROTOCOL_ERROR,
+        }
+    }
+
+    /// Encoding with the reason cut to at most `max_len` bytes in all, on a character boundary
+    pub fn to_bytes(&self, max_len: usize) -> Vec<u8> {
+        let mut end = self.reason.len().min(max_len.saturating_sub(GOAWAY_HEAD_SIZE));
+        while !self.reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut buf = Vec::with_capacity(GOAWAY_HEAD_SIZE + end);
+        buf.extend_from_slice(&self.code.to_le_bytes());
+        buf.extend_from_slice(&self.reason.as_bytes()[..end]);
+        buf
+    }
+
+    /// Parse a Goaway payload; a reason that is not UTF-8 is decoded lossily
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < GOAWAY_HEAD_SIZE {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        Ok(Goaway {
+            code: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            reason: String::from_utf8_lossy(&buf[GOAWAY_HEAD_SIZE..]).into_owned(),
+        })
+    }
+}
@@ -0,0 +1,196 @@
+//! Topic-based publish/subscribe over one connection
+//!
+//! Every frame is one message of the underlying `XTransport`, starting with
+//! a kind byte and a 4-byte topic ID:
+//!
+//! - `Declare` (0): maps the ID to the topic name that follows, in UTF-8
+//! - `Subscribe` (1) / `Unsubscribe` (2): the peer wants / no longer wants the topic
+//! - `Publish` (3): the rest of the message is published on the topic
+//!
+//! Each side numbers the topics it names itself and declares an ID before
+//! its first use, so a publication carries 4 bytes instead of the name.
+//! Several local consumers may subscribe to one topic; the peer sees a
+//! single subscription and sends each publication once.
+
+use crate::{
+    error::ErrorKind,
+    io::{Read, Write},
+    transport::XTransport,
+    Error, Result,
+};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Kind byte and topic ID in front of every frame
+pub const TOPIC_FRAME_HEAD_SIZE: usize = 5;
+
+const FRAME_DECLARE: u8 = 0;
+const FRAME_SUBSCRIBE: u8 = 1;
+const FRAME_UNSUBSCRIBE: u8 = 2;
+const FRAME_PUBLISH: u8 = 3;
+
+/// A message published by the peer, with the local subscribers it is for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publication {
+    pub topic: String,
+    /// IDs returned by `subscribe` for this topic
+    pub subscribers: Vec<u64>,
+    pub data: Vec<u8>,
+}
+
+/// Publish/subscribe endpoint owning a transport
+///
+/// Publications are only sent on topics the peer subscribed to. The peer's
+/// subscriptions are read by `recv`, so a side that only publishes should
+/// still call it now and then, with a read timeout on its stream.
+pub struct PubSub<S> {
+    transport: XTransport<S>,
+    /// IDs this side gave the topics it named
+    local_ids: BTreeMap<String, u32>,
+    next_topic_id: u32,
+    /// Topic names declared by the peer
+    peer_topics: BTreeMap<u32, String>,
+    /// Topics the peer subscribed to
+    peer_subscribed: BTreeSet<String>,
+    /// Local subscribers of each topic
+    subscribers: BTreeMap<String, BTreeSet<u64>>,
+    next_subscriber: u64,
+}
+
+impl<S: Read + Write> PubSub<S> {
+    pub fn new(transport: XTransport<S>) -> Self {
+        PubSub {
+            transport,
+            local_ids: BTreeMap::new(),
+            next_topic_id: 0,
+            peer_topics: BTreeMap::new(),
+            peer_subscribed: BTreeSet::new(),
+            subscribers: BTreeMap::new(),
+            next_subscriber: 1,
+        }
+    }
+
+    /// Add a consumer of `topic`, returning its subscriber ID
+    ///
+    /// The first consumer of a topic subscribes to it at the peer.
+    pub fn subscribe(&mut self, topic: &str) -> Result<u64> {
+        if !self.subscribers.contains_key(topic) {
+            let id = self.topic_id(topic)?;
+            self.send_frame(FRAME_SUBSCRIBE, id, &[])?;
+        }
+        let subscriber = self.next_subscriber;
+        self.next_subscriber += 1;
+        self.subscribers.entry(topic.into()).or_default().insert(subscriber);
+        Ok(subscriber)
+    }
+
+    /// Remove a consumer, returning false if it is unknown
+    ///
+    /// The last consumer of a topic unsubscribes from it at the peer;
+    /// publications already on their way are dropped.
+    pub fn unsubscribe(&mut self, subscriber: u64) -> Result<bool> {
+        let found = self.subscribers.iter_mut()
+            .find_map(|(topic, subs)| subs.remove(&subscriber).then_some((topic, subs)));
+        let topic = match found {
+            Some((topic, subs)) if subs.is_empty() => topic.clone(),
+            Some(_) => return Ok(true),
+            None => return Ok(false),
+        };
+        self.subscribers.remove(&topic);
+        let id = self.topic_id(&topic)?;
+        self.send_frame(FRAME_UNSUBSCRIBE, id, &[])?;
+        Ok(true)
+    }
+
+    /// Publish `data` on `topic`, returning false if the peer is not subscribed to it
+    pub fn publish(&mut self, topic: &str, data: &[u8]) -> Result<bool> {
+        if !self.peer_subscribed.contains(topic) {
+            return Ok(false);
+        }
+        let id = self.topic_id(topic)?;
+        self.send_frame(FRAME_PUBLISH, id, data)?;
+        Ok(true)
+    }
+
+    /// Whether the peer has subscribed to `topic`, as far as `recv` has seen
+    pub fn peer_subscribed(&self, topic: &str) -> bool {
+        self.peer_subscribed.contains(topic)
+    }
+
+    /// Receive the next publication for a local subscriber, handling control frames on the way
+    pub fn recv(&mut self) -> Result<Publication> {
+        loop {
+            let message = self.transport.recv_message()?;
+            if message.len() < TOPIC_FRAME_HEAD_SIZE {
+                return Err(Error::new(ErrorKind::InvalidPacket));
+            }
+            let id = u32::from_le_bytes([message[1], message[2], message[3], message[4]]);
+            let body = &message[TOPIC_FRAME_HEAD_SIZE..];
+            if message[0] == FRAME_DECLARE {
+                let name = core::str::from_utf8(body).map_err(|_| Error::new(ErrorKind::InvalidPacket))?;
+                log::debug!("Peer declared topic {} as {}", name, id);
+                self.peer_topics.insert(id, name.into());
+                continue;
+            }
+            let topic = self.peer_topics.get(&id).ok_or_else(|| {
+                log::warn!("Frame for undeclared topic {}", id);
+                Error::new(ErrorKind::InvalidPacket)
+            })?;
+            match message[0] {
+                FRAME_SUBSCRIBE => {
+                    self.peer_subscribed.insert(topic.clone());
+                }
+                FRAME_UNSUBSCRIBE => {
+                    self.peer_subscribed.remove(topic);
+                }
+                FRAME_PUBLISH => match self.subscribers.get(topic) {
+                    Some(subscribers) => {
+                        return Ok(Publication {
+                            topic: topic.clone(),
+                            subscribers: subscribers.iter().copied().collect(),
+                            data: body.to_vec(),
+                        });
+                    }
+                    None => log::debug!("Dropping publication on unsubscribed topic {}", topic),
+                },
+                kind => {
+                    log::warn!("Unknown topic frame kind {}", kind);
+                    return Err(Error::new(ErrorKind::InvalidPacket));
+                }
+            }
+        }
+    }
+
+    pub fn get_ref(&self) -> &XTransport<S> {
+        &self.transport
+    }
+
+    pub fn get_mut(&mut self) -> &mut XTransport<S> {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> XTransport<S> {
+        self.transport
+    }
+
+    /// ID of a topic this side names, declaring it to the peer the first time
+    fn topic_id(&mut self, topic: &str) -> Result<u32> {
+        if let Some(&id) = self.local_ids.get(topic) {
+            return Ok(id);
+        }
+        let id = self.next_topic_id;
+        self.send_frame(FRAME_DECLARE, id, topic.as_bytes())?;
+        self.next_topic_id += 1;
+        self.local_ids.insert(topic.into(), id);
+        Ok(id)
+    }
+
+    fn send_frame(&mut self, kind: u8, id: u32, body: &[u8]) -> Result<()> {
+        let mut frame = Vec::with_capacity(TOPIC_FRAME_HEAD_SIZE + body.len());
+        frame.push(kind);
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.extend_from_slice(body);
+        self.transport.send_message(&frame)
+    }
+}
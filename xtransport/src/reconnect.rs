@@ -0,0 +1,235 @@
+//! Transparent reconnection of a transport whose stream fails mid-session
+
+use crate::{
+    capability::Capabilities,
+    config::TransportConfig,
+    error::ErrorKind,
+    io::{Read, Write},
+    transport::XTransport,
+    Result,
+};
+use alloc::vec::Vec;
+use std::time::{Duration, Instant};
+
+const DEFAULT_INITIAL_BACKOFF_MS: u64 = 100;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 10_000;
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// When and how often `Reconnector` re-establishes the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Wait before the second connection attempt, doubled after every failure
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Connection attempts per reconnect before giving up (0 = unlimited)
+    pub max_attempts: u32,
+    /// Treat a connection that has been silent this long as dead (0 = never)
+    ///
+    /// Checked when a receive times out, so the stream needs a read timeout.
+    pub idle_timeout_ms: u64,
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        ReconnectPolicy {
+            initial_backoff_ms: DEFAULT_INITIAL_BACKOFF_MS,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            idle_timeout_ms: 0,
+        }
+    }
+
+    pub fn with_backoff(mut self, initial_ms: u64, max_ms: u64) -> Self {
+        self.initial_backoff_ms = initial_ms;
+        self.max_backoff_ms = max_ms;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout_ms: u64) -> Self {
+        self.idle_timeout_ms = timeout_ms;
+        self
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Errors after which the stream is considered broken
+fn is_disconnect(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::UnexpectedEof | ErrorKind::WriteZero | ErrorKind::Other | ErrorKind::MaxRetriesExceeded
+    )
+}
+
+/// `XTransport` that reconnects through `connect` when its stream breaks
+///
+/// Each connection starts a fresh transport, as the peer starts a fresh one
+/// for the new stream too. With a journal (`with_journal`) unacknowledged
+/// messages are resent from it after reconnecting; without one the failed
+/// `send_message` is simply sent again, so the peer may see it twice. A
+/// handshake done on the old connection is repeated on the new one.
+pub struct Reconnector<T: Read + Write, C: FnMut() -> Result<T>> {
+    transport: Option<XTransport<T>>,
+    /// Configuration kept while no connection could be established
+    detached: Option<TransportConfig>,
+    connect: C,
+    policy: ReconnectPolicy,
+    handshake: bool,
+    last_activity: Instant,
+    reconnects: u64,
+}
+
+impl<T: Read + Write, C: FnMut() -> Result<T>> Reconnector<T, C> {
+    /// Open the first connection, retrying as the policy allows
+    pub fn connect(connect: C, config: TransportConfig, policy: ReconnectPolicy) -> Result<Self> {
+        let mut reconnector = Reconnector {
+            transport: None,
+            detached: None,
+            connect,
+            policy,
+            handshake: false,
+            last_activity: Instant::now(),
+            reconnects: 0,
+        };
+        reconnector.establish(config)?;
+        Ok(reconnector)
+    }
+
+    /// The transport of the current connection, if there is one
+    pub fn transport(&mut self) -> Option<&mut XTransport<T>> {
+        self.transport.as_mut()
+    }
+
+    /// The current transport, connecting first if an earlier reconnect gave up
+    fn current(&mut self) -> Result<&mut XTransport<T>> {
+        if let Some(config) = self.detached.take() {
+            self.establish(config)?;
+        }
+        Ok(self.transport.as_mut().expect("connected above"))
+    }
+
+    /// Number of times the stream has been re-established
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Exchange capabilities now and again after every reconnect
+    pub fn handshake(&mut self) -> Result<Capabilities> {
+        self.handshake = true;
+        let caps = self.current()?.handshake()?;
+        self.last_activity = Instant::now();
+        Ok(caps)
+    }
+
+    pub fn send_message(&mut self, data: &[u8]) -> Result<()> {
+        loop {
+            match self.current()?.send_message(data) {
+                Ok(()) => {
+                    self.last_activity = Instant::now();
+                    return Ok(());
+                }
+                Err(e) if is_disconnect(e.kind()) => {
+                    log::warn!("Send failed ({}), reconnecting", e);
+                    self.reconnect()?;
+                    // The journal has already resent it
+                    if self.current()?.config().journal.is_some() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn recv_message(&mut self) -> Result<Vec<u8>> {
+        loop {
+            match self.current()?.recv_message() {
+                Ok(message) => {
+                    self.last_activity = Instant::now();
+                    return Ok(message);
+                }
+                Err(e) if is_disconnect(e.kind()) => {
+                    log::warn!("Receive failed ({}), reconnecting", e);
+                    self.reconnect()?;
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                    let idle = self.last_activity.elapsed();
+                    if self.policy.idle_timeout_ms == 0 || idle < Duration::from_millis(self.policy.idle_timeout_ms) {
+                        return Err(e);
+                    }
+                    log::warn!("Connection idle for {:?}, reconnecting", idle);
+                    self.reconnect()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Drop the current stream and establish a new one
+    pub fn reconnect(&mut self) -> Result<()> {
+        let config = match (self.transport.take(), self.detached.take()) {
+            (Some(transport), _) => transport.into_parts().1,
+            (None, Some(config)) => config,
+            (None, None) => unreachable!("Reconnector holds a transport or its config"),
+        };
+        self.establish(config)?;
+        self.reconnects += 1;
+        Ok(())
+    }
+
+    /// Connect with backoff; on failure the config is left in `detached`
+    fn establish(&mut self, config: TransportConfig) -> Result<()> {
+        self.detached = Some(config);
+        let mut backoff = self.policy.initial_backoff_ms;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.start() {
+                Ok(()) => {
+                    self.last_activity = Instant::now();
+                    return Ok(());
+                }
+                Err(e) if self.policy.max_attempts != 0 && attempt >= self.policy.max_attempts => {
+                    log::warn!("Giving up after {} connection attempts: {}", attempt, e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    log::debug!("Connection attempt {} failed ({}), retrying in {}ms", attempt, e, backoff);
+                    std::thread::sleep(Duration::from_millis(backoff));
+                    backoff = backoff.saturating_mul(2).min(self.policy.max_backoff_ms);
+                }
+            }
+        }
+    }
+
+    /// Connect, handshake and resend the journal
+    fn start(&mut self) -> Result<()> {
+        let stream = (self.connect)()?;
+        let config = self.detached.take().expect("establish keeps the config");
+        let mut transport = XTransport::new(stream, config);
+        let result = match self.handshake {
+            true => transport.handshake().and_then(|_| transport.resend_journal()),
+            false => transport.resend_journal(),
+        };
+        match result {
+            Ok(resent) => {
+                log::info!("Connected, {} journaled messages resent", resent);
+                self.transport = Some(transport);
+                Ok(())
+            }
+            Err(e) => {
+                self.detached = Some(transport.into_parts().1);
+                Err(e)
+            }
+        }
+    }
+}
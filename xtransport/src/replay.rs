@@ -0,0 +1,390 @@
+//! Recording and deterministic replay of a connection
+//!
+//! A `Recorder` around the real stream and a `RecordingClock` around the real
+//! clock capture every input `XTransport` consumes. `EventLog::replay` feeds the
+//! log back into a fresh `XTransport`, which reproduces the same internal state
+//! evolution, and any write that differs from the original run is counted as a
+//! divergence.
+//!
+//! An encoded log starts with a 24-byte header recording the settings the
+//! connection's behaviour depends on, so a replay cannot silently run with
+//! different ones:
+//! - Magic: `0x5854454c` ("XTEL"), little endian
+//! - Version: `0x01`
+//! - Wire version: highest packet header version the recorded end speaks
+//! - Flags: 1 byte (`LOG_FLAG_ACK_MODE`)
+//! - Reserved: 1 byte
+//! - Max payload size: 4 bytes
+//! - Window size: 4 bytes, packets in flight in ACK mode
+//! - Features: 8 bytes, capability bits advertised by the recorded end
+//!
+//! followed by one `tag: u8, length: u32, payload` record per event. All
+//! integers are little endian.
+
+use crate::{
+    capability::Feature,
+    clock::Clock,
+    config::{TransportConfig, VERSION, VERSION_2},
+    error::ErrorKind,
+    Error, Read, Result, Write, XTransport,
+};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+pub const LOG_MAGIC: u32 = 0x5854454c; // "XTEL"
+pub const LOG_VERSION: u8 = 0x01;
+pub const LOG_HEADER_SIZE: usize = 24;
+
+/// Header flag: the recorded end waited for ACKs
+pub const LOG_FLAG_ACK_MODE: u8 = 1 << 0;
+
+const TAG_READ: u8 = 0;
+const TAG_READ_ERROR: u8 = 1;
+const TAG_WRITE: u8 = 2;
+const TAG_CLOCK: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Bytes returned by one read of the underlying stream
+    Read(Vec<u8>),
+    /// Error returned by one read of the underlying stream
+    ReadError(ErrorKind),
+    /// Bytes accepted by one write to the underlying stream
+    Write(Vec<u8>),
+    /// One clock reading in microseconds
+    Clock(u64),
+}
+
+/// Settings of the recorded end that a replay must share
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedConfig {
+    pub max_payload_size: u32,
+    pub wait_for_ack: bool,
+    pub window_size: u32,
+    pub wire_version: u8,
+    pub features: u64,
+}
+
+impl RecordedConfig {
+    pub fn of(config: &TransportConfig) -> Self {
+        RecordedConfig {
+            max_payload_size: config.max_payload_size.min(u32::MAX as usize) as u32,
+            wait_for_ack: config.wait_for_ack,
+            window_size: config.window_size.min(u32::MAX as usize) as u32,
+            wire_version: if config.capabilities.supports(Feature::WireV2) { VERSION_2 } else { VERSION },
+            features: config.capabilities.feature_bits(),
+        }
+    }
+
+    fn encode(&self) -> [u8; LOG_HEADER_SIZE] {
+        let mut header = [0u8; LOG_HEADER_SIZE];
+        header[0..4].copy_from_slice(&LOG_MAGIC.to_le_bytes());
+        header[4] = LOG_VERSION;
+        header[5] = self.wire_version;
+        header[6] = if self.wait_for_ack { LOG_FLAG_ACK_MODE } else { 0 };
+        header[8..12].copy_from_slice(&self.max_payload_size.to_le_bytes());
+        header[12..16].copy_from_slice(&self.window_size.to_le_bytes());
+        header[16..24].copy_from_slice(&self.features.to_le_bytes());
+        header
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let header = buf.get(..LOG_HEADER_SIZE).ok_or_else(|| Error::new(ErrorKind::InvalidInput))?;
+        if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != LOG_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidMagic));
+        }
+        if header[4] != LOG_VERSION {
+            return Err(Error::new(ErrorKind::InvalidVersion));
+        }
+        let mut features = [0u8; 8];
+        features.copy_from_slice(&header[16..24]);
+        Ok(RecordedConfig {
+            max_payload_size: u32::from_le_bytes([header[8], header[9], header[10], header[11]]),
+            wait_for_ack: header[6] & LOG_FLAG_ACK_MODE != 0,
+            window_size: u32::from_le_bytes([header[12], header[13], header[14], header[15]]),
+            wire_version: header[5],
+            features: u64::from_le_bytes(features),
+        })
+    }
+}
+
+/// Shared, append-only log of connection events
+#[derive(Clone)]
+pub struct EventLog {
+    config: RecordedConfig,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl EventLog {
+    /// Empty log of a transport built with `config`
+    pub fn new(config: &TransportConfig) -> Self {
+        EventLog {
+            config: RecordedConfig::of(config),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Settings of the recorded end
+    pub fn config(&self) -> RecordedConfig {
+        self.config
+    }
+
+    pub fn push(&self, event: Event) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Serialize as the header followed by the event records
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.config.encode().to_vec();
+        for event in self.events.lock().unwrap().iter() {
+            let (tag, payload) = match event {
+                Event::Read(data) => (TAG_READ, data.clone()),
+                Event::ReadError(kind) => (TAG_READ_ERROR, vec![error_kind_to_u8(*kind)]),
+                Event::Write(data) => (TAG_WRITE, data.clone()),
+                Event::Clock(now) => (TAG_CLOCK, now.to_le_bytes().to_vec()),
+            };
+            out.push(tag);
+            out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            out.extend_from_slice(&payload);
+        }
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let config = RecordedConfig::decode(buf)?;
+        let mut buf = &buf[LOG_HEADER_SIZE..];
+        let mut events = Vec::new();
+        while !buf.is_empty() {
+            if buf.len() < 5 {
+                return Err(Error::new(ErrorKind::InvalidInput));
+            }
+            let tag = buf[0];
+            let len = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+            let payload = buf.get(5..5 + len).ok_or_else(|| Error::new(ErrorKind::InvalidInput))?;
+            let event = match tag {
+                TAG_READ => Event::Read(payload.to_vec()),
+                TAG_READ_ERROR if len == 1 => Event::ReadError(error_kind_from_u8(payload[0])),
+                TAG_WRITE => Event::Write(payload.to_vec()),
+                TAG_CLOCK if len == 8 => {
+                    let mut now = [0u8; 8];
+                    now.copy_from_slice(payload);
+                    Event::Clock(u64::from_le_bytes(now))
+                }
+                _ => return Err(Error::new(ErrorKind::InvalidInput)),
+            };
+            events.push(event);
+            buf = &buf[5 + len..];
+        }
+        Ok(EventLog {
+            config,
+            events: Arc::new(Mutex::new(events)),
+        })
+    }
+
+    /// Fresh transport playing back the recording, with the recorded clock readings in place of `config`'s clock
+    ///
+    /// Fails with `InvalidInput` if `config` differs from the recorded end in
+    /// any setting of the header, since the replay would then diverge from the start.
+    pub fn replay(&self, config: TransportConfig) -> Result<XTransport<ReplayTransport>> {
+        let replayed = RecordedConfig::of(&config);
+        if replayed != self.config {
+            log::warn!("Replay config {:?} differs from the recorded {:?}", replayed, self.config);
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        Ok(XTransport::new(self.replay_transport(), config.with_clock(self.replay_clock())))
+    }
+
+    /// Stream that plays back the recorded reads and checks writes against the recording
+    fn replay_transport(&self) -> ReplayTransport {
+        let mut reads = VecDeque::new();
+        let mut writes = VecDeque::new();
+        for event in self.events.lock().unwrap().iter() {
+            match event {
+                Event::Read(data) => reads.push_back(Ok(data.clone())),
+                Event::ReadError(kind) => reads.push_back(Err(*kind)),
+                Event::Write(data) => writes.extend(data.iter().copied()),
+                Event::Clock(_) => {}
+            }
+        }
+        ReplayTransport {
+            reads,
+            writes,
+            divergences: 0,
+        }
+    }
+
+    /// Clock that plays back the recorded readings in order
+    fn replay_clock(&self) -> ReplayClock {
+        let readings = self
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::Clock(now) => Some(*now),
+                _ => None,
+            })
+            .collect();
+        ReplayClock {
+            readings: Mutex::new(readings),
+        }
+    }
+}
+
+/// Stream wrapper that logs every read and write
+pub struct Recorder<T> {
+    inner: T,
+    log: EventLog,
+}
+
+impl<T> Recorder<T> {
+    pub fn new(inner: T, log: EventLog) -> Self {
+        Recorder { inner, log }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Recorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.log.push(Event::Read(buf[..n].to_vec()));
+                Ok(n)
+            }
+            Err(e) => {
+                self.log.push(Event::ReadError(e.kind()));
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T: Write> Write for Recorder<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.log.push(Event::Write(buf[..n].to_vec()));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Clock wrapper that logs every reading
+pub struct RecordingClock<C> {
+    inner: C,
+    log: EventLog,
+}
+
+impl<C: Clock> RecordingClock<C> {
+    pub fn new(inner: C, log: EventLog) -> Self {
+        RecordingClock { inner, log }
+    }
+}
+
+impl<C: Clock> Clock for RecordingClock<C> {
+    fn now_micros(&self) -> u64 {
+        let now = self.inner.now_micros();
+        self.log.push(Event::Clock(now));
+        now
+    }
+}
+
+/// Stream replaying recorded reads; writes are compared with the recorded ones
+pub struct ReplayTransport {
+    reads: VecDeque<core::result::Result<Vec<u8>, ErrorKind>>,
+    writes: VecDeque<u8>,
+    divergences: usize,
+}
+
+impl ReplayTransport {
+    /// Number of writes that did not match the recording
+    pub fn divergences(&self) -> usize {
+        self.divergences
+    }
+
+    /// True once every recorded read has been consumed
+    pub fn is_exhausted(&self) -> bool {
+        self.reads.is_empty()
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.reads.pop_front() {
+            Some(Ok(mut data)) => {
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                if n < data.len() {
+                    self.reads.push_front(Ok(data.split_off(n)));
+                }
+                Ok(n)
+            }
+            Some(Err(kind)) => Err(Error::new(kind)),
+            None => Err(Error::new(ErrorKind::UnexpectedEof)),
+        }
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let matches = buf.len() <= self.writes.len()
+            && buf.iter().zip(self.writes.iter()).all(|(a, b)| a == b);
+        if matches {
+            self.writes.drain(..buf.len());
+        } else {
+            self.divergences += 1;
+            log::warn!("Replay diverged: write of {} bytes differs from the recording", buf.len());
+            self.writes.clear();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Clock replaying recorded readings, repeating the last one when exhausted
+pub struct ReplayClock {
+    readings: Mutex<VecDeque<u64>>,
+}
+
+impl Clock for ReplayClock {
+    fn now_micros(&self) -> u64 {
+        let mut readings = self.readings.lock().unwrap();
+        if readings.len() > 1 {
+            readings.pop_front().unwrap_or(0)
+        } else {
+            readings.front().copied().unwrap_or(0)
+        }
+    }
+}
+
+fn error_kind_to_u8(kind: ErrorKind) -> u8 {
+    match kind {
+        ErrorKind::UnexpectedEof => 1,
+        ErrorKind::Interrupted => 2,
+        ErrorKind::WouldBlock => 3,
+        ErrorKind::TimedOut => 4,
+        _ => 0,
+    }
+}
+
+fn error_kind_from_u8(value: u8) -> ErrorKind {
+    match value {
+        1 => ErrorKind::UnexpectedEof,
+        2 => ErrorKind::Interrupted,
+        3 => ErrorKind::WouldBlock,
+        4 => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    }
+}
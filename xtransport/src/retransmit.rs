@@ -0,0 +1,264 @@
+/// Upper bound for the backed-off retransmission timeout
+const MAX_RTO_MICROS: u64 = 60_000_000;
+/// Lower bound for the timeout computed from RTT samples
+///
+/// Far below RFC 6298's one second, which suits local links where round
+/// trips take microseconds.
+const MIN_RTO_MICROS: u64 = 10_000;
+
+/// Retransmission timeout with exponential backoff for one outstanding packet
+///
+/// The timeout starts at the configured value and follows RFC 6298 once RTT
+/// samples arrive: SRTT + 4 × RTTVAR, from smoothed round-trip time and
+/// variance. Every retry multiplies it by the backoff factor, 2 unless set
+/// otherwise, and with jitter each backed-off timeout is moved up or down by
+/// a random fraction of itself, so connections that lost packets together
+/// do not all retransmit together.
+pub struct RetransmitTimer {
+    rto: u64,
+    /// Timeout before any backoff
+    base_rto: u64,
+    /// Backed-off timeout before jitter
+    backed_off: u64,
+    retries: u32,
+    srtt: Option<u64>,
+    rttvar: u64,
+    min_rto: u64,
+    max_rto: u64,
+    backoff_factor: f64,
+    jitter: f64,
+    /// xorshift64 state drawing the jitter
+    rng: u64,
+}
+
+impl RetransmitTimer {
+    pub fn new(initial_rto_micros: u64) -> Self {
+        RetransmitTimer {
+            rto: initial_rto_micros,
+            base_rto: initial_rto_micros,
+            backed_off: initial_rto_micros,
+            retries: 0,
+            srtt: None,
+            rttvar: 0,
+            min_rto: MIN_RTO_MICROS,
+            max_rto: MAX_RTO_MICROS,
+            backoff_factor: 2.0,
+            jitter: 0.0,
+            rng: 1,
+        }
+    }
+
+    /// Keep the timeout computed from RTT samples within `min_micros..=max_micros`,
+    /// and the backed-off one under `max_micros`
+    pub fn with_bounds(mut self, min_micros: u64, max_micros: u64) -> Self {
+        self.max_rto = max_micros.max(1);
+        self.min_rto = min_micros.min(self.max_rto);
+        self
+    }
+
+    /// Multiply the timeout by `factor` (at least 1) on every retry, then move it
+    /// by up to `jitter` (0 to 1) of itself either way, drawn from a generator seeded with `seed`
+    pub fn with_backoff(mut self, factor: f64, jitter: f64, seed: u64) -> Self {
+        self.backoff_factor = if factor >= 1.0 { factor } else { 1.0 };
+        self.jitter = jitter.clamp(0.0, 1.0);
+        // Spread small seeds over all bits, xorshift starts poorly from few set bits
+        self.rng = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        self
+    }
+
+    /// Current timeout in microseconds
+    pub fn rto(&self) -> u64 {
+        self.rto
+    }
+
+    /// Number of retransmissions so far
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Smoothed round-trip time, once a sample has been taken
+    pub fn srtt(&self) -> Option<u64> {
+        self.srtt
+    }
+
+    /// Round-trip time variation, once a sample has been taken
+    pub fn rttvar(&self) -> Option<u64> {
+        self.srtt.map(|_| self.rttvar)
+    }
+
+    pub fn is_expired(&self, sent_at: u64, now: u64) -> bool {
+        now.saturating_sub(sent_at) >= self.rto
+    }
+
+    /// Record a retransmission and back off the timeout
+    pub fn backoff(&mut self) {
+        self.retries += 1;
+        self.backed_off = ((self.backed_off as f64 * self.backoff_factor) as u64).min(self.max_rto);
+        self.rto = self.backed_off;
+        if self.jitter > 0.0 {
+            // Uniform in [-1, 1), from the top 53 bits
+            let unit = (self.next_random() >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+            let jittered = self.backed_off as f64 * (1.0 + self.jitter * unit);
+            self.rto = (jittered as u64).clamp(1, self.max_rto);
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Feed an RTT sample
+    ///
+    /// Following Karn's algorithm, callers only pass samples of packets that
+    /// were sent once, as the ACK of a retransmitted one is ambiguous.
+    pub fn update_rtt(&mut self, rtt_micros: u64) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt_micros);
+                self.rttvar = rtt_micros / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt_micros)) / 4;
+                self.srtt = Some((7 * srtt + rtt_micros) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(rtt_micros);
+        self.base_rto = srtt.saturating_add(4 * self.rttvar.max(1)).clamp(self.min_rto, self.max_rto);
+    }
+
+    /// Undo the backoff after an ACK, keeping the RTT estimate
+    pub fn reset(&mut self) {
+        self.retries = 0;
+        self.rto = self.base_rto;
+        self.backed_off = self.base_rto;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn backoff_doubles_up_to_the_ceiling() {
+        let mut timer = RetransmitTimer::new(20_000_000);
+        timer.backoff();
+        assert_eq!(timer.rto(), 40_000_000);
+        timer.backoff();
+        timer.backoff();
+        assert_eq!(timer.rto(), MAX_RTO_MICROS);
+        assert_eq!(timer.retries(), 3);
+    }
+
+    #[test]
+    fn reset_returns_to_the_timeout_before_backoff() {
+        let mut timer = RetransmitTimer::new(100_000);
+        timer.backoff();
+        timer.reset();
+        assert_eq!((timer.rto(), timer.retries()), (100_000, 0));
+    }
+
+    #[test]
+    fn first_sample_sets_srtt_and_half_of_it_as_variance() {
+        let mut timer = RetransmitTimer::new(1_000_000);
+        assert_eq!((timer.srtt(), timer.rttvar()), (None, None));
+        timer.update_rtt(40_000);
+        assert_eq!((timer.srtt(), timer.rttvar()), (Some(40_000), Some(20_000)));
+        // Takes effect once the backoff of the packet in flight is undone
+        assert_eq!(timer.rto(), 1_000_000);
+        timer.reset();
+        assert_eq!(timer.rto(), 40_000 + 4 * 20_000);
+    }
+
+    #[test]
+    fn later_samples_are_smoothed() {
+        let mut timer = RetransmitTimer::new(1_000_000);
+        timer.update_rtt(40_000);
+        timer.update_rtt(80_000);
+        assert_eq!(timer.srtt(), Some((7 * 40_000 + 80_000) / 8));
+        assert_eq!(timer.rttvar(), Some((3 * 20_000 + 40_000) / 4));
+    }
+
+    #[test]
+    fn computed_timeout_stays_within_bounds() {
+        let mut timer = RetransmitTimer::new(1_000_000);
+        // Steady microsecond round trips on a local link
+        for _ in 0..50 {
+            timer.update_rtt(5);
+        }
+        timer.reset();
+        assert_eq!(timer.rto(), MIN_RTO_MICROS);
+
+        timer.update_rtt(u64::MAX / 2);
+        timer.reset();
+        assert_eq!(timer.rto(), MAX_RTO_MICROS);
+    }
+
+    #[test]
+    fn expiry_survives_a_clock_going_backwards() {
+        let timer = RetransmitTimer::new(1_000);
+        assert!(!timer.is_expired(5_000, 4_000));
+        assert!(!timer.is_expired(5_000, 5_999));
+        assert!(timer.is_expired(5_000, 6_000));
+    }
+
+    #[test]
+    fn configured_bounds_replace_the_defaults() {
+        let mut timer = RetransmitTimer::new(1_000_000).with_bounds(200_000, 3_000_000);
+        timer.update_rtt(5);
+        timer.reset();
+        assert_eq!(timer.rto(), 200_000);
+        for _ in 0..4 {
+            timer.backoff();
+        }
+        assert_eq!(timer.rto(), 3_000_000);
+        // A floor above the ceiling is lowered to it
+        let timer = RetransmitTimer::new(1_000).with_bounds(5_000, 2_000);
+        assert_eq!((timer.min_rto, timer.max_rto), (2_000, 2_000));
+    }
+
+    #[test]
+    fn fractional_backoff_factor() {
+        let mut timer = RetransmitTimer::new(100_000).with_backoff(1.5, 0.0, 0);
+        timer.backoff();
+        assert_eq!(timer.rto(), 150_000);
+        timer.backoff();
+        assert_eq!(timer.rto(), 225_000);
+        // A factor below 1 would shrink the timeout on every retry
+        let mut timer = RetransmitTimer::new(100_000).with_backoff(0.5, 0.0, 0);
+        timer.backoff();
+        assert_eq!(timer.rto(), 100_000);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction_and_does_not_compound() {
+        let mut timer = RetransmitTimer::new(100_000).with_backoff(2.0, 0.25, 7);
+        let mut backed_off = 100_000;
+        for _ in 0..10 {
+            timer.backoff();
+            backed_off = (backed_off * 2).min(MAX_RTO_MICROS);
+            let spread = backed_off / 4;
+            assert!((backed_off - spread..=backed_off + spread).contains(&timer.rto()), "rto {} around {}", timer.rto(), backed_off);
+        }
+        // The jittered timeout never exceeds the ceiling
+        assert!(timer.rto() <= MAX_RTO_MICROS);
+    }
+
+    #[test]
+    fn jitter_depends_on_the_seed_only() {
+        let draws = |seed| {
+            let mut timer = RetransmitTimer::new(100_000).with_backoff(2.0, 0.5, seed);
+            (0..5)
+                .map(|_| {
+                    timer.backoff();
+                    timer.rto()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(1), draws(1));
+        assert_ne!(draws(1), draws(2));
+    }
+}
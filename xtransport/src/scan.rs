@@ -0,0 +1,105 @@
+//! Finding packet boundaries again in a stream that lost or gained bytes
+//!
+//! A serial link that drops a byte shifts every packet after it, and line
+//! noise can put bytes between two packets. Parsing headers back to back then
+//! never finds a boundary again. `FrameScanner` instead looks for the next
+//! offset where a whole packet validates: magic, version, a known type, a
+//! payload within bounds and a matching CRC. The caller drops the garbage in
+//! front of it and decodes from there. It needs no allocator.
+
+use crate::{
+    error::ErrorKind,
+    protocol::{PacketHeader, PacketType, MAGIC, PACKET_FLAG_ACK, VERSION_2},
+};
+
+/// Bytes of the magic number starting every header
+const MAGIC_SIZE: usize = 4;
+
+/// Outcome of `FrameScanner::scan`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Scan {
+    /// A valid packet of `len` bytes starts after `skip` bytes of garbage
+    Packet { skip: usize, len: usize },
+    /// No whole valid packet is buffered yet; the first `skip` bytes can never start one
+    Incomplete { skip: usize },
+}
+
+/// What `FrameScanner::check` found at one offset
+enum Check {
+    Valid(usize),
+    Incomplete,
+    Invalid,
+}
+
+/// Searcher for the next valid packet in a corrupted byte stream
+#[derive(Debug, Clone, Copy)]
+pub struct FrameScanner {
+    max_payload: usize,
+    version_2: bool,
+}
+
+impl FrameScanner {
+    /// Scanner accepting payloads of up to `max_payload` bytes
+    ///
+    /// Headers announcing more are taken for garbage, so a corrupted length
+    /// cannot make the caller wait for bytes that will never form a packet.
+    pub fn new(max_payload: usize) -> Self {
+        FrameScanner { max_payload, version_2: true }
+    }
+
+    /// Take version 2 headers for garbage unless `accepted`, for a link that has not negotiated them
+    pub fn with_version_2(mut self, accepted: bool) -> Self {
+        self.version_2 = accepted;
+        self
+    }
+
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    /// Find the first valid packet in `buf`
+    ///
+    /// A header that validates so far but whose packet is not all in `buf`
+    /// stops the search with `Incomplete`: more bytes decide whether it is real.
+    pub fn scan(&self, buf: &[u8]) -> Scan {
+        let mut offset = 0;
+        while let Some(found) = find_magic(&buf[offset..]) {
+            let start = offset + found;
+            match self.check(&buf[start..]) {
+                Check::Valid(len) => return Scan::Packet { skip: start, len },
+                Check::Incomplete => return Scan::Incomplete { skip: start },
+                Check::Invalid => offset = start + 1,
+            }
+        }
+        // The last bytes may be the start of a magic number cut by the read
+        Scan::Incomplete { skip: buf.len().saturating_sub(MAGIC_SIZE - 1).max(offset) }
+    }
+
+    /// Whether a valid packet starts at the start of `buf`
+    fn check(&self, buf: &[u8]) -> Check {
+        let header = match PacketHeader::parse(buf) {
+            Ok(header) => header,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Check::Incomplete,
+            Err(_) => return Check::Invalid,
+        };
+        if PacketType::from_u8(header.pkt_type & !PACKET_FLAG_ACK).is_none()
+            || header.length as usize > self.max_payload
+            || (header.version == VERSION_2 && !self.version_2)
+        {
+            return Check::Invalid;
+        }
+        let len = header.size() + header.length as usize;
+        match buf.get(header.size()..len) {
+            Some(payload) if crc32fast::hash(payload) == header.crc32 => Check::Valid(len),
+            Some(_) => Check::Invalid,
+            None => Check::Incomplete,
+        }
+    }
+}
+
+/// Offset of the first magic number in `buf`
+fn find_magic(buf: &[u8]) -> Option<usize> {
+    let magic = MAGIC.to_le_bytes();
+    buf.windows(MAGIC_SIZE).position(|window| window == magic)
+}
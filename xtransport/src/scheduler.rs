@@ -0,0 +1,142 @@
+use crate::config::ClassRate;
+use crate::shaper::TokenBucket;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+/// Message waiting in the send queue
+pub(crate) struct QueuedMessage {
+    pub(crate) id: u64,
+    pub(crate) data: Vec<u8>,
+    /// Bytes of `data` already sent
+    pub(crate) offset: usize,
+    /// Wire message ID, once the MessageHead has been sent
+    pub(crate) message_id: Option<u64>,
+    /// Whether the MessageHead announced a digest to send after the last chunk
+    pub(crate) digest: bool,
+    /// Clock reading when the message was queued
+    queued_at: Option<u64>,
+}
+
+/// Snapshot of a queued message, as listed by `XTransport::queued_messages`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedMessageInfo {
+    /// Queue ID returned by `queue_message`
+    pub id: u64,
+    pub class: u8,
+    /// Message size in bytes
+    pub size: usize,
+    /// Bytes already sent; only messages with none sent can be cancelled
+    pub sent: usize,
+    /// Time spent in the queue, if a clock is configured
+    pub age_micros: Option<u64>,
+}
+
+/// Per-class send queues, served in class order within each class's rate
+///
+/// Lower class numbers go first; a class whose shaper is in debt is skipped
+/// until it has budget again, letting the classes after it use the link.
+pub(crate) struct SendScheduler {
+    queues: BTreeMap<u8, VecDeque<QueuedMessage>>,
+    shapers: BTreeMap<u8, TokenBucket>,
+    next_id: u64,
+}
+
+impl SendScheduler {
+    pub(crate) fn new(rates: &BTreeMap<u8, ClassRate>) -> Self {
+        SendScheduler {
+            queues: BTreeMap::new(),
+            shapers: rates.iter()
+                .map(|(class, rate)| (*class, TokenBucket::new(rate.bytes_per_sec, rate.burst_bytes)))
+                .collect(),
+            next_id: 1,
+        }
+    }
+
+    pub(crate) fn push(&mut self, class: u8, data: Vec<u8>, now: Option<u64>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queues.entry(class).or_default().push_back(QueuedMessage {
+            id,
+            data,
+            offset: 0,
+            message_id: None,
+            digest: false,
+            queued_at: now,
+        });
+        id
+    }
+
+    /// Queued messages in the order they will be served within each class
+    pub(crate) fn list(&self, now: Option<u64>) -> Vec<QueuedMessageInfo> {
+        self.queues.iter()
+            .flat_map(|(class, queue)| queue.iter().map(move |message| (*class, message)))
+            .map(|(class, message)| QueuedMessageInfo {
+                id: message.id,
+                class,
+                size: message.data.len(),
+                sent: message.offset,
+                age_micros: message.queued_at.zip(now).map(|(queued_at, now)| now.saturating_sub(queued_at)),
+            })
+            .collect()
+    }
+
+    /// Remove a message nothing of which has been sent yet
+    pub(crate) fn cancel(&mut self, id: u64) -> Option<QueuedMessage> {
+        let (class, index) = self.queues.iter().find_map(|(class, queue)| {
+            queue.iter().position(|message| message.id == id).map(|index| (*class, index))
+        })?;
+        let queue = self.queues.get_mut(&class).expect("class found above");
+        if queue[index].message_id.is_some() || queue[index].offset > 0 {
+            return None;
+        }
+        let message = queue.remove(index);
+        if queue.is_empty() {
+            self.queues.remove(&class);
+        }
+        message
+    }
+
+    /// Class to send the next packet from, if any may be sent now
+    ///
+    /// Without a clock `now` is `None` and rates are not enforced.
+    pub(crate) fn next_class(&mut self, now: Option<u64>) -> Option<u8> {
+        let shapers = &mut self.shapers;
+        self.queues.keys().copied().find(|class| match (shapers.get_mut(class), now) {
+            (Some(shaper), Some(now)) => shaper.delay(now) == 0,
+            _ => true,
+        })
+    }
+
+    /// Microseconds until a queued packet may be sent, or `None` if nothing is queued
+    pub(crate) fn delay(&mut self, now: Option<u64>) -> Option<u64> {
+        let shapers = &mut self.shapers;
+        self.queues.keys()
+            .map(|class| match (shapers.get_mut(class), now) {
+                (Some(shaper), Some(now)) => shaper.delay(now),
+                _ => 0,
+            })
+            .min()
+    }
+
+    /// Take the message at the head of a class queue
+    pub(crate) fn pop_front(&mut self, class: u8) -> Option<QueuedMessage> {
+        let queue = self.queues.get_mut(&class)?;
+        let message = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&class);
+        }
+        message
+    }
+
+    /// Put back a partially sent message so it is continued before anything else in its class
+    pub(crate) fn push_front(&mut self, class: u8, message: QueuedMessage) {
+        self.queues.entry(class).or_default().push_front(message);
+    }
+
+    /// Charge a sent packet to its class
+    pub(crate) fn charge(&mut self, class: u8, now: Option<u64>, bytes: usize) {
+        if let (Some(shaper), Some(now)) = (self.shapers.get_mut(&class), now) {
+            shaper.consume(now, bytes);
+        }
+    }
+}
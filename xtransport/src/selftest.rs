@@ -0,0 +1,104 @@
+use crate::config::PONG_SIZE;
+use alloc::vec::Vec;
+
+/// Sequential pings measuring the round-trip time
+pub const SELF_TEST_PINGS: usize = 8;
+/// Small messages sent back to back to measure the frame rate
+pub const SELF_TEST_SMALL_FRAMES: usize = 32;
+pub const SELF_TEST_SMALL_SIZE: usize = 64;
+/// Packets the fragmented message measuring throughput spans
+pub const SELF_TEST_FRAGMENTS: usize = 16;
+
+/// Marks the PING tokens of a self-test so they cannot be mistaken for the
+/// timestamps `sync_time` uses; the low bits count the probes
+pub(crate) const SELF_TEST_TOKEN: u64 = 0x5345_4c46_0000_0000; // "SELF"
+
+/// Result of `XTransport::self_test`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    pub rtt_min_micros: u64,
+    pub rtt_avg_micros: u64,
+    pub rtt_max_micros: u64,
+    /// Small messages per second the peer echoed during a burst
+    pub frames_per_sec: f64,
+    /// Bytes per second of a fragmented message echoed by the peer
+    pub bytes_per_sec: f64,
+    /// Largest frame payload that made the round trip: the payload limit, capped by the peer's after a handshake
+    pub max_payload_size: usize,
+    /// The peer timestamps its replies, so `sync_time` will work
+    pub peer_has_clock: bool,
+    /// Every reply arrived in the order its probe or message was sent
+    pub in_order: bool,
+    /// Every echoed message came back as it was sent
+    pub echo_intact: bool,
+    /// Retransmissions needed during the test (ACK mode only)
+    pub retransmissions: u64,
+}
+
+/// Replies collected for one burst of probes
+pub(crate) struct ProbeRound {
+    first_token: u64,
+    sent_at: Vec<u64>,
+    answered: Vec<bool>,
+    pub(crate) rtts: Vec<u64>,
+    pub(crate) replies: usize,
+    pub(crate) in_order: bool,
+    pub(crate) peer_has_clock: bool,
+    next_expected: u64,
+}
+
+impl ProbeRound {
+    pub(crate) fn new(first_token: u64) -> Self {
+        ProbeRound {
+            first_token,
+            sent_at: Vec::new(),
+            answered: Vec::new(),
+            rtts: Vec::new(),
+            replies: 0,
+            in_order: true,
+            peer_has_clock: true,
+            next_expected: first_token,
+        }
+    }
+
+    /// Token of the next probe, remembering when it was sent
+    pub(crate) fn next_token(&mut self, now: u64) -> u64 {
+        self.sent_at.push(now);
+        self.answered.push(false);
+        self.first_token + self.sent_at.len() as u64 - 1
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.replies == self.sent_at.len()
+    }
+
+    /// Record a PONG, returning false if it does not answer an open probe of this round
+    pub(crate) fn on_pong(&mut self, token: u64, pong_len: usize, now: u64) -> bool {
+        let index = token.wrapping_sub(self.first_token) as usize;
+        let sent_at = match self.sent_at.get(index) {
+            Some(&sent_at) if token >= self.first_token && !self.answered[index] => sent_at,
+            _ => return false,
+        };
+        self.answered[index] = true;
+        if token != self.next_expected {
+            self.in_order = false;
+        }
+        self.next_expected = token + 1;
+        self.peer_has_clock &= pong_len >= PONG_SIZE;
+        self.rtts.push(now.saturating_sub(sent_at));
+        self.replies += 1;
+        true
+    }
+}
+
+/// Payload of the `index`th small self-test message
+pub(crate) fn small_message(index: usize) -> Vec<u8> {
+    let mut message = alloc::vec![index as u8; SELF_TEST_SMALL_SIZE];
+    message[..4].copy_from_slice(&(index as u32).to_le_bytes());
+    message
+}
+
+/// Payload of the fragmented self-test message, `len` bytes of a pattern that shows misplaced chunks
+pub(crate) fn fragmented_message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
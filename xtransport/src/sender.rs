@@ -0,0 +1,124 @@
+//! Cloneable handle for sending on one connection from many threads
+//!
+//! `MessageSender::spawn` moves the transport to a pump thread and returns a
+//! handle to a bounded queue in front of it. Producers clone the handle and
+//! queue messages; the pump sends whatever has piled up with one
+//! `send_messages` call, so a burst from many threads shares writes and
+//! Batch packets instead of contending for a lock on the transport.
+
+use crate::{
+    error::ErrorKind,
+    io::{Read, Write},
+    transport::XTransport,
+    Error, Result,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::OnceLock;
+use std::thread::JoinHandle;
+
+/// Most queued messages the pump hands to one `send_messages` call
+const MAX_PUMP_BATCH: usize = 64;
+
+/// Thread of a message pump, ending with the transport and the outcome of its last send
+pub type PumpHandle<S> = JoinHandle<(XTransport<S>, Result<()>)>;
+
+struct Request {
+    data: Vec<u8>,
+    /// Where `send_wait` expects the outcome
+    done: Option<SyncSender<Result<()>>>,
+}
+
+/// Handle queueing messages for the pump thread that owns the transport
+///
+/// The pump stops once every handle is dropped and the queue is empty, or
+/// at the first failed send; its thread then returns the transport with
+/// the outcome. After a failure, sends fail with the kind of the error that
+/// stopped the pump.
+#[derive(Clone)]
+pub struct MessageSender {
+    requests: SyncSender<Request>,
+    failure: Arc<OnceLock<ErrorKind>>,
+}
+
+impl MessageSender {
+    /// Start a pump thread sending on `transport`, with room for `capacity` queued messages
+    ///
+    /// With a capacity of 0 every `send` waits for the pump to take its message.
+    pub fn spawn<S>(transport: XTransport<S>, capacity: usize) -> (Self, PumpHandle<S>)
+    where
+        S: Read + Write + Send + 'static,
+    {
+        let (requests, queue) = mpsc::sync_channel(capacity);
+        let failure = Arc::new(OnceLock::new());
+        let pump_failure = failure.clone();
+        let thread = std::thread::spawn(move || pump(transport, queue, &pump_failure));
+        (MessageSender { requests, failure }, thread)
+    }
+
+    /// Queue a message, waiting while the queue is full
+    pub fn send(&self, data: &[u8]) -> Result<()> {
+        self.requests
+            .send(Request { data: data.to_vec(), done: None })
+            .map_err(|_| self.closed())
+    }
+
+    /// Queue a message, failing with `WouldBlock` if the queue is full
+    pub fn try_send(&self, data: &[u8]) -> Result<()> {
+        match self.requests.try_send(Request { data: data.to_vec(), done: None }) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Error::new(ErrorKind::WouldBlock)),
+            Err(TrySendError::Disconnected(_)) => Err(self.closed()),
+        }
+    }
+
+    /// Queue a message and wait until the transport has sent it
+    ///
+    /// In ACK mode that is once the peer acknowledged it. The outcome is
+    /// that of the whole batch the message went out in.
+    pub fn send_wait(&self, data: &[u8]) -> Result<()> {
+        let (done, outcome) = mpsc::sync_channel(1);
+        self.requests
+            .send(Request { data: data.to_vec(), done: Some(done) })
+            .map_err(|_| self.closed())?;
+        outcome.recv().map_err(|_| self.closed())?
+    }
+
+    /// Error for a request the pump is no longer there to take
+    fn closed(&self) -> Error {
+        Error::new(self.failure.get().copied().unwrap_or(ErrorKind::WriteShutdown))
+    }
+}
+
+/// Send queued messages until every handle is gone or a send fails
+fn pump<S: Read + Write>(
+    mut transport: XTransport<S>,
+    queue: Receiver<Request>,
+    failure: &OnceLock<ErrorKind>,
+) -> (XTransport<S>, Result<()>) {
+    let mut batch = Vec::with_capacity(MAX_PUMP_BATCH);
+    while let Ok(request) = queue.recv() {
+        batch.push(request);
+        while batch.len() < MAX_PUMP_BATCH && let Ok(request) = queue.try_recv() {
+            batch.push(request);
+        }
+        let messages: Vec<&[u8]> = batch.iter().map(|request| request.data.as_slice()).collect();
+        let result = transport.send_messages(&messages);
+        let kind = result.as_ref().err().map(Error::kind);
+        if let Some(kind) = kind {
+            log::warn!("Message pump stops after a failed send of {} messages: {:?}", batch.len(), kind);
+            let _ = failure.set(kind);
+        }
+        for request in batch.drain(..) {
+            if let Some(done) = request.done {
+                let _ = done.send(kind.map_or(Ok(()), |kind| Err(Error::new(kind))));
+            }
+        }
+        if result.is_err() {
+            return (transport, result);
+        }
+    }
+    log::debug!("Message pump stops: every sender handle is gone");
+    (transport, Ok(()))
+}
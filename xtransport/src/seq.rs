@@ -0,0 +1,78 @@
+//! Comparisons of packet sequence numbers, which wrap around after 2^32
+//!
+//! Plain `<` breaks at the wrap, where sequence number 0 follows `u32::MAX`.
+//! Sequence numbers instead compare by the signed distance between them,
+//! as in RFC 1982: `a` is before `b` when `b` is less than 2^31 ahead of it.
+//! Numbers exactly 2^31 apart are neither before nor after each other;
+//! windows stay far smaller than that, so live packets never are.
+
+/// Steps forward from `from` to `to`, wrapping
+pub fn distance(from: u32, to: u32) -> u32 {
+    to.wrapping_sub(from)
+}
+
+/// Whether `a` comes before `b`
+pub fn before(a: u32, b: u32) -> bool {
+    let ahead = distance(a, b);
+    ahead != 0 && ahead < 1 << 31
+}
+
+/// Whether `a` comes after `b`
+pub fn after(a: u32, b: u32) -> bool {
+    before(b, a)
+}
+
+/// Whether `seq` is in the range from `start` up to `end`, exclusive, going forward from `start`
+pub fn in_range(seq: u32, start: u32, end: u32) -> bool {
+    distance(start, seq) < distance(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sequence numbers where plain comparisons go wrong, or right next to them
+    const BOUNDARIES: [u32; 7] = [0, 1, (1 << 31) - 1, 1 << 31, (1 << 31) + 1, u32::MAX - 1, u32::MAX];
+
+    /// Range lengths, powers of two and others
+    const LENGTHS: [u32; 8] = [1, 2, 3, 5, 7, 8, 13, 64];
+
+    #[test]
+    fn helpers_follow_the_signed_distance() {
+        for base in BOUNDARIES {
+            for step in -1000i64..=1000 {
+                let other = base.wrapping_add(step as i32 as u32);
+                assert_eq!(distance(base, other), step as i32 as u32, "{} to {}", base, other);
+                assert_eq!(before(base, other), step > 0, "{} before {}", base, other);
+                assert_eq!(after(base, other), step < 0, "{} after {}", base, other);
+                assert_eq!(before(other, base), after(base, other));
+            }
+        }
+    }
+
+    #[test]
+    fn half_the_space_apart_is_unordered() {
+        for base in BOUNDARIES {
+            let opposite = base.wrapping_add(1 << 31);
+            assert!(!before(base, opposite) && !after(base, opposite), "{} and {}", base, opposite);
+            assert!(before(base, opposite.wrapping_sub(1)));
+            assert!(after(base, opposite.wrapping_add(1)));
+        }
+    }
+
+    #[test]
+    fn ranges_cross_the_wrap() {
+        for start in BOUNDARIES {
+            for len in LENGTHS {
+                let end = start.wrapping_add(len);
+                for step in 0..len {
+                    assert!(in_range(start.wrapping_add(step), start, end));
+                }
+                assert!(!in_range(end, start, end), "{} in {}..{}", end, start, end);
+                assert!(!in_range(start.wrapping_sub(1), start, end));
+                assert!(!in_range(start.wrapping_add(1 << 31), start, end));
+            }
+            assert!(!in_range(start, start, start), "empty range at {}", start);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Multi-client server: one thread per connection and a registry of live sessions
+
+use crate::{
+    config::TransportConfig,
+    error::ErrorKind,
+    io::{Read, Write},
+    protocol::GOAWAY_NO_ERROR,
+    transport::XTransport,
+    Result,
+};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Pause between accept attempts while the accept closure reports `WouldBlock`
+const ACCEPT_POLL_INTERVAL_MS: u64 = 10;
+
+enum Command {
+    Send(Vec<u8>),
+    Disconnect,
+}
+
+struct Session {
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+type Registry = Mutex<BTreeMap<u64, Session>>;
+
+/// Server running each accepted connection on its own thread
+///
+/// Connections come from an accept closure, so any listener works: TCP, Unix
+/// sockets or vsock. Every received message is passed to the handler with
+/// the session ID and the session's transport to reply on.
+///
+/// Messages queued with `send_to` or `broadcast` are written by the session
+/// thread between receives, so streams need a read timeout for them to go
+/// out while the peer is quiet. Likewise the accept closure should return
+/// `WouldBlock` or `TimedOut` now and then (a non-blocking listener) for
+/// `shutdown` to stop it promptly.
+pub struct XServer {
+    sessions: Arc<Registry>,
+    running: Arc<AtomicBool>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl XServer {
+    /// Start accepting connections on a background thread
+    ///
+    /// `config` builds the configuration of each new session.
+    pub fn spawn<S, A, F, H>(mut accept: A, config: F, handler: H) -> Self
+    where
+        S: Read + Write + Send + 'static,
+        A: FnMut() -> Result<S> + Send + 'static,
+        F: Fn() -> TransportConfig + Send + Sync + 'static,
+        H: Fn(u64, &mut XTransport<S>, Vec<u8>) -> Result<()> + Send + Sync + 'static,
+    {
+        Self::spawn_per_peer(move || accept().map(|stream| (stream, ())), move |_: &()| config(), handler)
+    }
+
+    /// Start accepting connections, configuring each session for its peer
+    ///
+    /// The accept closure returns what identifies the peer along with the
+    /// stream, such as its socket or vsock address, and `config` builds the
+    /// session's configuration from it. One daemon can so serve trusted and
+    /// untrusted guests with different window sizes, rate limits, memory
+    /// budgets and allowed features (`with_capabilities`).
+    pub fn spawn_per_peer<S, P, A, F, H>(mut accept: A, config: F, handler: H) -> Self
+    where
+        S: Read + Write + Send + 'static,
+        A: FnMut() -> Result<(S, P)> + Send + 'static,
+        F: Fn(&P) -> TransportConfig + Send + Sync + 'static,
+        H: Fn(u64, &mut XTransport<S>, Vec<u8>) -> Result<()> + Send + Sync + 'static,
+    {
+        let sessions: Arc<Registry> = Arc::new(Mutex::new(BTreeMap::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let handler = Arc::new(handler);
+        let next_id = AtomicU64::new(1);
+
+        let accept_sessions = sessions.clone();
+        let accept_running = running.clone();
+        let accept_thread = std::thread::spawn(move || {
+            while accept_running.load(Ordering::Acquire) {
+                let (stream, peer) = match accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                        std::thread::sleep(Duration::from_millis(ACCEPT_POLL_INTERVAL_MS));
+                        continue;
+                    }
+                    Err(e) => {
+                        log::warn!("Accept failed, server stops accepting: {}", e);
+                        break;
+                    }
+                };
+                let id = next_id.fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "metrics")]
+                crate::telemetry::record_connection();
+                let (commands, inbox) = mpsc::channel();
+                let transport = XTransport::new(stream, config(&peer));
+                let mut registry = accept_sessions.lock().expect("session registry poisoned");
+                let thread = {
+                    let sessions = accept_sessions.clone();
+                    let handler = handler.clone();
+                    std::thread::spawn(move || {
+                        run_session(id, transport, inbox, &*handler);
+                        sessions.lock().expect("session registry poisoned").remove(&id);
+                    })
+                };
+                registry.insert(id, Session { commands, thread: Some(thread) });
+                log::info!("Session {} connected, {} live", id, registry.len());
+            }
+        });
+
+        XServer {
+            sessions,
+            running,
+            accept_thread: Some(accept_thread),
+        }
+    }
+
+    /// IDs of the live sessions
+    pub fn sessions(&self) -> Vec<u64> {
+        self.sessions.lock().expect("session registry poisoned").keys().copied().collect()
+    }
+
+    /// Queue a message for one session, returning false if it is gone
+    pub fn send_to(&self, id: u64, data: &[u8]) -> bool {
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_message("sent", data.len());
+        self.command(id, Command::Send(data.to_vec()))
+    }
+
+    /// Queue a message for every live session, returning how many it was queued for
+    pub fn broadcast(&self, data: &[u8]) -> usize {
+        let registry = self.sessions.lock().expect("session registry poisoned");
+        let queued = registry.values()
+            .filter(|session| session.commands.send(Command::Send(data.to_vec())).is_ok())
+            .count();
+        #[cfg(feature = "metrics")]
+        for _ in 0..queued {
+            crate::telemetry::record_message("sent", data.len());
+        }
+        queued
+    }
+
+    /// Close a session after its queued messages, returning false if it is gone
+    pub fn disconnect(&self, id: u64) -> bool {
+        self.command(id, Command::Disconnect)
+    }
+
+    fn command(&self, id: u64, command: Command) -> bool {
+        let registry = self.sessions.lock().expect("session registry poisoned");
+        registry.get(&id).is_some_and(|session| session.commands.send(command).is_ok())
+    }
+
+    /// Block until the accept loop ends, after `shutdown` from another handle or an accept error
+    pub fn join(mut self) {
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Stop accepting, close every session after its queued messages and wait for them
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+        let threads: Vec<JoinHandle<()>> = {
+            let mut registry = self.sessions.lock().expect("session registry poisoned");
+            registry.values_mut()
+                .filter_map(|session| {
+                    let _ = session.commands.send(Command::Disconnect);
+                    session.thread.take()
+                })
+                .collect()
+        };
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for XServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Serve one connection until the peer goes away, the handler fails or a disconnect is requested
+fn run_session<S, H>(id: u64, transport: XTransport<S>, inbox: Receiver<Command>, handler: &H)
+where
+    S: Read + Write,
+    H: Fn(u64, &mut XTransport<S>, Vec<u8>) -> Result<()>,
+{
+    #[cfg(feature = "metrics")]
+    crate::telemetry::record_session_start();
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    let result = serve(id, transport, inbox, handler);
+    #[cfg(feature = "metrics")]
+    crate::telemetry::record_session_end(&result);
+}
+
+/// Session loop, ending with the error that closed the session if there was one
+fn serve<S, H>(id: u64, mut transport: XTransport<S>, inbox: Receiver<Command>, handler: &H) -> Result<()>
+where
+    S: Read + Write,
+    H: Fn(u64, &mut XTransport<S>, Vec<u8>) -> Result<()>,
+{
+    // Transport records carry the connection ID from the handshake on; tie it to the session once
+    let mut connection_logged = false;
+    loop {
+        if !connection_logged && let Some(connection) = transport.connection_id() {
+            log::info!("Session {} is connection {:016x}", id, connection);
+            connection_logged = true;
+        }
+        for command in inbox.try_iter() {
+            let result = match command {
+                Command::Send(data) => transport.send_message(&data),
+                Command::Disconnect => {
+                    log::info!("Session {} disconnected by the server", id);
+                    if let Err(e) = transport.send_goaway(GOAWAY_NO_ERROR, "disconnected by the server") {
+                        log::debug!("Session {} failed to send Goaway: {}", id, e);
+                    }
+                    return Ok(());
+                }
+            };
+            if let Err(e) = result {
+                log::warn!("Session {} send failed: {}", id, e);
+                return Err(e);
+            }
+        }
+        match transport.recv_message() {
+            Ok(message) => {
+                #[cfg(feature = "metrics")]
+                crate::telemetry::record_message("received", message.len());
+                if let Err(e) = handler(id, &mut transport, message) {
+                    log::warn!("Session {} handler failed: {}", id, e);
+                    return Err(e);
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => {
+                log::info!("Session {} closed: {}", id, e);
+                return Err(e);
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+/// Token bucket limiting a byte stream to a steady rate with bounded bursts
+///
+/// A packet may go out whenever the bucket is not in debt, and its full size
+/// is then charged, so packets larger than the burst size still get through.
+pub struct TokenBucket {
+    bytes_per_sec: u64,
+    burst: i64,
+    tokens: i64,
+    updated_at: Option<u64>,
+}
+
+impl TokenBucket {
+    /// Bucket refilled at `bytes_per_sec` (0 = unlimited), holding at most `burst_bytes`
+    pub fn new(bytes_per_sec: u64, burst_bytes: usize) -> Self {
+        let burst = burst_bytes.min(i64::MAX as usize) as i64;
+        TokenBucket {
+            bytes_per_sec,
+            burst,
+            tokens: burst,
+            updated_at: None,
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        if let Some(updated_at) = self.updated_at {
+            let earned = now.saturating_sub(updated_at) as u128 * self.bytes_per_sec as u128 / 1_000_000;
+            self.tokens = (self.tokens as i128 + earned as i128).min(self.burst as i128) as i64;
+            // Keep the fraction of a byte earned so far
+            if earned > 0 {
+                self.updated_at = Some(now);
+            }
+        } else {
+            self.updated_at = Some(now);
+        }
+    }
+
+    /// Microseconds until a packet may be sent (0 = now)
+    pub fn delay(&mut self, now: u64) -> u64 {
+        self.refill(now);
+        if self.tokens > 0 || self.bytes_per_sec == 0 {
+            return 0;
+        }
+        ((1 - self.tokens) as u128 * 1_000_000).div_ceil(self.bytes_per_sec as u128) as u64
+    }
+
+    /// Charge a packet that was sent
+    pub fn consume(&mut self, now: u64, bytes: usize) {
+        self.refill(now);
+        self.tokens = self.tokens.saturating_sub(bytes.min(i64::MAX as usize) as i64);
+    }
+}
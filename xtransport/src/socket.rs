@@ -0,0 +1,55 @@
+use crate::{
+    config::{SocketOptions, TransportConfig},
+    io::{Read, Write},
+    XTransport,
+};
+use socket2::{SockRef, TcpKeepalive};
+use std::os::fd::AsFd;
+use std::time::Duration;
+
+/// Apply the options that are set to an OS socket
+///
+/// TCP options fail on sockets of other families, such as Unix or vsock
+/// sockets; leave them unset there.
+pub fn apply_socket_options<S: AsFd>(socket: &S, options: &SocketOptions) -> std::io::Result<()> {
+    let sock = SockRef::from(socket);
+    
+    if let Some(nodelay) = options.nodelay {
+        sock.set_tcp_nodelay(nodelay)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+    }
+    if let Some(quickack) = options.quickack {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        sock.set_tcp_quickack(quickack)?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        log::debug!("TCP_QUICKACK={} not supported on this platform", quickack);
+    }
+    if let Some(idle_secs) = options.keepalive_secs {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle_secs));
+        sock.set_tcp_keepalive(&keepalive)?;
+    }
+    
+    log::debug!("Applied socket options: {:?}", options);
+    Ok(())
+}
+
+impl<T: Read + Write + AsFd> XTransport<T> {
+    /// Create a transport over an OS socket, first applying `config.socket`
+    pub fn from_socket(inner: T, config: TransportConfig) -> std::io::Result<Self> {
+        apply_socket_options(&inner, &config.socket)?;
+        Ok(XTransport::new(inner, config))
+    }
+
+    /// Apply the configured socket options to the underlying socket again
+    ///
+    /// TCP_QUICKACK is reset by the kernel after some exchanges, so
+    /// latency-sensitive users may call this periodically.
+    pub fn apply_socket_options(&self) -> std::io::Result<()> {
+        apply_socket_options(self.get_ref(), &self.config().socket)
+    }
+}
@@ -0,0 +1,101 @@
+//! Owned receive and send halves of a transport, for full-duplex use from two threads
+//!
+//! Both halves share the transport behind a mutex: sequence numbers,
+//! retransmission state, ACKs and the configuration stay in one place, and
+//! each call locks it for its duration. A receive holds the lock while it
+//! waits for the stream, so give the stream a read timeout: a waiting
+//! `ReadHalf` then lets go of the transport every time the timeout expires,
+//! failing with `TimedOut` (or `WouldBlock`), and the `WriteHalf` gets its
+//! turn. Packets read by a send, such as the messages arriving while it
+//! waits for an ACK, stay buffered for the next receive.
+
+use crate::{
+    cancel::CancellationToken,
+    io::{Read, Write},
+    message::{Message, MessageOptions},
+    transport::XTransport,
+    Result,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use std::sync::{Mutex, MutexGuard};
+
+type Shared<T> = Arc<Mutex<XTransport<T>>>;
+
+fn lock<T>(shared: &Shared<T>) -> MutexGuard<'_, XTransport<T>> {
+    shared.lock().expect("split transport poisoned")
+}
+
+/// Receiving half of a transport split with `into_split`
+pub struct ReadHalf<T> {
+    shared: Shared<T>,
+}
+
+/// Sending half of a transport split with `into_split`
+pub struct WriteHalf<T> {
+    shared: Shared<T>,
+}
+
+impl<T: Read + Write> XTransport<T> {
+    /// Split into a receiving and a sending half that can move to different threads
+    pub fn into_split(self) -> (ReadHalf<T>, WriteHalf<T>) {
+        let shared = Arc::new(Mutex::new(self));
+        (ReadHalf { shared: shared.clone() }, WriteHalf { shared })
+    }
+}
+
+impl<T: Read + Write> ReadHalf<T> {
+    pub fn recv_message(&mut self) -> Result<Vec<u8>> {
+        lock(&self.shared).recv_message()
+    }
+
+    pub fn recv_message_ext(&mut self) -> Result<Message> {
+        lock(&self.shared).recv_message_ext()
+    }
+
+    pub fn recv_message_cancellable(&mut self, token: &CancellationToken) -> Result<Vec<u8>> {
+        lock(&self.shared).recv_message_cancellable(token)
+    }
+
+    pub fn recv_messages(&mut self, max_n: usize, max_bytes: usize) -> Result<Vec<Vec<u8>>> {
+        lock(&self.shared).recv_messages(max_n, max_bytes)
+    }
+
+    /// Whether `write` was split from the same transport
+    pub fn is_pair_of(&self, write: &WriteHalf<T>) -> bool {
+        Arc::ptr_eq(&self.shared, &write.shared)
+    }
+
+    /// Put the transport back together, or hand both halves back if they were not split from it
+    pub fn reunite(self, write: WriteHalf<T>) -> core::result::Result<XTransport<T>, (ReadHalf<T>, WriteHalf<T>)> {
+        if !self.is_pair_of(&write) {
+            return Err((self, write));
+        }
+        drop(write);
+        let mutex = Arc::into_inner(self.shared).expect("both halves given back");
+        Ok(mutex.into_inner().expect("split transport poisoned"))
+    }
+}
+
+impl<T: Read + Write> WriteHalf<T> {
+    pub fn send_message(&mut self, data: &[u8]) -> Result<()> {
+        lock(&self.shared).send_message(data)
+    }
+
+    pub fn send_message_ext(&mut self, data: &[u8], options: MessageOptions) -> Result<()> {
+        lock(&self.shared).send_message_ext(data, options)
+    }
+
+    pub fn send_message_cancellable(&mut self, data: &[u8], token: &CancellationToken) -> Result<()> {
+        lock(&self.shared).send_message_cancellable(data, token)
+    }
+
+    pub fn send_messages(&mut self, messages: &[&[u8]]) -> Result<()> {
+        lock(&self.shared).send_messages(messages)
+    }
+
+    /// Signal the end of this side's messages, see `XTransport::shutdown_write`
+    pub fn shutdown_write(&mut self) -> Result<()> {
+        lock(&self.shared).shutdown_write()
+    }
+}
@@ -0,0 +1,124 @@
+/// Transport counters since creation or the last `reset_stats`
+///
+/// Byte counts are wire bytes, packet headers included.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub retransmissions: u64,
+    pub crc_failures: u64,
+    /// Retransmission requests sent for corrupted packets (`CrcPolicy::Nack`)
+    pub nacks_sent: u64,
+    /// Retransmission requests received from the peer
+    pub nacks_received: u64,
+    /// Encrypted packets dropped for repeating or predating the nonces already accepted
+    pub replays_dropped: u64,
+    /// Repeated messages suppressed or rejected under the `DuplicatePolicy`
+    pub duplicate_messages: u64,
+    /// Multi-packet messages dropped for not matching their whole-message digest
+    pub digest_failures: u64,
+    /// Probes sent while the peer's receive window was closed
+    pub window_probes: u64,
+    /// Stale partial messages dropped to make room for a new message or more data
+    pub reassembly_evictions: u64,
+    /// Malformed headers recovered from by skipping to the next valid packet
+    pub resyncs: u64,
+    /// Round-trip times measured from ACKs of packets that were sent only once
+    pub rtt_min_micros: Option<u64>,
+    pub rtt_max_micros: Option<u64>,
+    pub rtt_samples: u64,
+    rtt_total_micros: u64,
+    /// Smoothed round-trip time and its variation (RFC 6298), kept across `reset_stats`
+    pub srtt_micros: Option<u64>,
+    pub rttvar_micros: Option<u64>,
+    /// Current retransmission timeout, backoff included
+    pub rto_micros: u64,
+    /// Time covered by the counters (0 without a clock)
+    pub elapsed_micros: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rtt_avg_micros(&self) -> Option<u64> {
+        self.rtt_total_micros.checked_div(self.rtt_samples)
+    }
+
+    /// Average send rate in bytes per second
+    pub fn send_throughput(&self) -> f64 {
+        rate(self.bytes_sent, self.elapsed_micros)
+    }
+
+    /// Average receive rate in bytes per second
+    pub fn recv_throughput(&self) -> f64 {
+        rate(self.bytes_received, self.elapsed_micros)
+    }
+
+    pub(crate) fn record_sent(&mut self, bytes: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += bytes as u64;
+    }
+
+    pub(crate) fn record_received(&mut self, bytes: usize) {
+        self.packets_received += 1;
+        self.bytes_received += bytes as u64;
+    }
+
+    #[cfg(feature = "alloc")]
+    pub(crate) fn record_rtt(&mut self, rtt_micros: u64) {
+        self.rtt_min_micros = Some(self.rtt_min_micros.map_or(rtt_micros, |min| min.min(rtt_micros)));
+        self.rtt_max_micros = Some(self.rtt_max_micros.map_or(rtt_micros, |max| max.max(rtt_micros)));
+        self.rtt_samples += 1;
+        self.rtt_total_micros += rtt_micros;
+    }
+}
+
+/// Weight of each new packet in the loss and retransmit rates, which follow roughly the last 32 packets
+#[cfg(feature = "alloc")]
+const QUALITY_WEIGHT: f64 = 1.0 / 32.0;
+
+/// Recent link conditions, for applications that adapt to them
+///
+/// Unlike `Stats`, the rates follow the last few dozen packets rather than
+/// the whole connection, and are not cleared by `reset_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkQuality {
+    /// Smoothed round-trip time and its variation (RFC 6298)
+    pub srtt_micros: Option<u64>,
+    pub rttvar_micros: Option<u64>,
+    /// Latest round-trip time measured from an ACK or a heartbeat
+    pub last_rtt_micros: Option<u64>,
+    /// Share of recently acknowledged packets that had to be retransmitted (0 to 1)
+    pub loss_rate: f64,
+    /// Share of recent transmissions that were retransmissions (0 to 1)
+    pub retransmit_rate: f64,
+}
+
+#[cfg(feature = "alloc")]
+impl LinkQuality {
+    pub(crate) fn record_rtt(&mut self, rtt_micros: u64) {
+        self.last_rtt_micros = Some(rtt_micros);
+    }
+
+    /// Count an acknowledged packet, lost at least once if it was `retransmitted`
+    pub(crate) fn record_delivery(&mut self, retransmitted: bool) {
+        self.loss_rate += QUALITY_WEIGHT * (retransmitted as u8 as f64 - self.loss_rate);
+    }
+
+    pub(crate) fn record_transmission(&mut self, retransmission: bool) {
+        self.retransmit_rate += QUALITY_WEIGHT * (retransmission as u8 as f64 - self.retransmit_rate);
+    }
+}
+
+fn rate(bytes: u64, micros: u64) -> f64 {
+    if micros == 0 {
+        return 0.0;
+    }
+    bytes as f64 * 1_000_000.0 / micros as f64
+}
@@ -0,0 +1,85 @@
+//! Counters and histograms reported through the `metrics` facade
+//!
+//! With the `metrics` feature, every transport counts its packets, payload
+//! bytes and retransmissions, and `XServer` its connections, handshake
+//! failures and message sizes. Nothing is kept until the application
+//! installs a recorder, such as `metrics-exporter-prometheus` to be scraped
+//! by Prometheus; `describe` gives the metrics their units and help texts.
+
+use crate::{
+    error::{ErrorKind, Phase},
+    observer::TransportEvent,
+    Result,
+};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+
+/// Connections accepted by a server
+pub const CONNECTIONS: &str = "xtransport_connections_total";
+/// Server sessions currently running
+pub const ACTIVE_CONNECTIONS: &str = "xtransport_connections_active";
+/// Server sessions closed for failing authentication or speaking another protocol version
+pub const HANDSHAKE_FAILURES: &str = "xtransport_handshake_failures_total";
+/// Packets sent, counting each once; `RETRANSMISSIONS` counts the repeats
+pub const PACKETS_SENT: &str = "xtransport_packets_sent_total";
+pub const PACKETS_RECEIVED: &str = "xtransport_packets_received_total";
+/// Payload bytes, headers excluded, of the packets counted above
+pub const BYTES_SENT: &str = "xtransport_sent_bytes_total";
+pub const BYTES_RECEIVED: &str = "xtransport_received_bytes_total";
+pub const RETRANSMISSIONS: &str = "xtransport_retransmissions_total";
+/// Sizes of the messages server sessions received and were asked to send, labelled by `direction`
+pub const MESSAGE_SIZE: &str = "xtransport_message_size_bytes";
+
+/// Register units and help texts with the installed recorder
+///
+/// Call it once after installing the recorder; metrics are recorded without it too.
+pub fn describe() {
+    describe_counter!(CONNECTIONS, "Connections accepted by the server");
+    describe_gauge!(ACTIVE_CONNECTIONS, "Server sessions currently running");
+    describe_counter!(HANDSHAKE_FAILURES, "Server sessions that failed their handshake");
+    describe_counter!(PACKETS_SENT, "Packets sent, retransmissions excluded");
+    describe_counter!(PACKETS_RECEIVED, "Packets received that passed their checks");
+    describe_counter!(BYTES_SENT, Unit::Bytes, "Packet payload bytes sent");
+    describe_counter!(BYTES_RECEIVED, Unit::Bytes, "Packet payload bytes received");
+    describe_counter!(RETRANSMISSIONS, "Packets sent again for lack of an acknowledgement");
+    describe_histogram!(MESSAGE_SIZE, Unit::Bytes, "Sizes of the messages handled by server sessions");
+}
+
+/// Count the traffic a transport event reports
+pub(crate) fn record_event(event: &TransportEvent) {
+    match *event {
+        TransportEvent::PacketSent { len, .. } => {
+            counter!(PACKETS_SENT).increment(1);
+            counter!(BYTES_SENT).increment(len as u64);
+        }
+        TransportEvent::PacketReceived { len, .. } => {
+            counter!(PACKETS_RECEIVED).increment(1);
+            counter!(BYTES_RECEIVED).increment(len as u64);
+        }
+        TransportEvent::Retransmit { .. } => counter!(RETRANSMISSIONS).increment(1),
+        _ => {}
+    }
+}
+
+pub(crate) fn record_connection() {
+    counter!(CONNECTIONS).increment(1);
+}
+
+pub(crate) fn record_session_start() {
+    gauge!(ACTIVE_CONNECTIONS).increment(1.0);
+}
+
+/// Count a session ending, and its error if it failed the handshake
+pub(crate) fn record_session_end(result: &Result<()>) {
+    gauge!(ACTIVE_CONNECTIONS).decrement(1.0);
+    let failed_handshake = result.as_ref().is_err_and(|e| {
+        e.phase() == Some(Phase::Handshake)
+            || matches!(e.kind(), ErrorKind::Unauthorized | ErrorKind::AuthenticationFailed | ErrorKind::InvalidVersion)
+    });
+    if failed_handshake {
+        counter!(HANDSHAKE_FAILURES).increment(1);
+    }
+}
+
+pub(crate) fn record_message(direction: &'static str, len: usize) {
+    histogram!(MESSAGE_SIZE, "direction" => direction).record(len as f64);
+}
@@ -0,0 +1,326 @@
+//! Deterministic network simulator for testing transports
+//!
+//! `SimTransport::pair` creates two connected endpoints. Every write is one
+//! unit on the simulated link: it is delayed by latency, jitter and the
+//! serialization time at the configured bandwidth, and may be lost,
+//! duplicated or held back. Every direction draws these decisions from its
+//! own seeded RNG and all timing comes from a virtual clock, so a
+//! single-threaded run is fully reproducible, and with one thread per
+//! endpoint the n-th write of each side always meets the same fate.
+//!
+//! A read with nothing due waits for the next unit in flight, advancing the
+//! virtual clock to its arrival time. With nothing in flight it waits briefly
+//! for the peer thread, then advances the clock by the read timeout and fails
+//! with `TimedOut`, which drives retransmission in ACK mode.
+
+use crate::{
+    clock::Clock,
+    error::{Error, ErrorKind},
+    io::{Read, Write},
+    Result,
+};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Link characteristics, identical in both directions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimConfig {
+    pub latency_micros: u64,
+    /// Extra delay drawn uniformly from `0..=jitter_micros` for every unit
+    pub jitter_micros: u64,
+    /// Link rate in bytes per second (0 = unlimited)
+    pub bandwidth: u64,
+    /// Probability that a write is lost
+    pub loss: f64,
+    /// Probability that a write is delivered twice
+    pub duplicate: f64,
+    /// Probability that a write is held back by an extra latency, letting later writes overtake it
+    pub reorder: f64,
+    /// Virtual time a read waits for data before failing with `TimedOut`
+    pub read_timeout_micros: u64,
+    pub seed: u64,
+}
+
+impl SimConfig {
+    pub fn new() -> Self {
+        SimConfig {
+            latency_micros: 1000,
+            jitter_micros: 0,
+            bandwidth: 0,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            read_timeout_micros: 10_000,
+            seed: 1,
+        }
+    }
+
+    pub fn with_latency(mut self, latency_micros: u64, jitter_micros: u64) -> Self {
+        self.latency_micros = latency_micros;
+        self.jitter_micros = jitter_micros;
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = bytes_per_sec;
+        self
+    }
+
+    pub fn with_loss(mut self, probability: f64) -> Self {
+        self.loss = probability;
+        self
+    }
+
+    pub fn with_duplicate(mut self, probability: f64) -> Self {
+        self.duplicate = probability;
+        self
+    }
+
+    pub fn with_reorder(mut self, probability: f64) -> Self {
+        self.reorder = probability;
+        self
+    }
+
+    pub fn with_read_timeout(mut self, micros: u64) -> Self {
+        self.read_timeout_micros = micros;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Small seedable PRNG (xorshift64*), so runs do not depend on external crates
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng { state: seed.max(1) }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform value in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+/// One direction of the link
+struct Link {
+    /// Units in flight, by arrival time and then send order
+    in_flight: BTreeMap<(u64, u64), Vec<u8>>,
+    /// Bytes of an arrived unit not yet read
+    arrived: VecDeque<u8>,
+    /// When the sender side of the link is free to serialize the next unit
+    busy_until: u64,
+    rng: SimRng,
+}
+
+struct SimState {
+    config: SimConfig,
+    now: u64,
+    next_unit: u64,
+    links: [Link; 2],
+    open: [bool; 2],
+}
+
+struct SimNetwork {
+    state: Mutex<SimState>,
+    changed: Condvar,
+}
+
+impl SimNetwork {
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Virtual clock shared by both endpoints of a simulated link
+#[derive(Clone)]
+pub struct SimClock {
+    net: Arc<SimNetwork>,
+}
+
+impl SimClock {
+    /// Move virtual time forward
+    pub fn advance(&self, micros: u64) {
+        let mut state = self.net.lock();
+        state.now += micros;
+        self.net.changed.notify_all();
+    }
+}
+
+impl Clock for SimClock {
+    fn now_micros(&self) -> u64 {
+        self.net.lock().now
+    }
+
+    fn sleep_micros(&self, micros: u64) {
+        self.advance(micros);
+    }
+}
+
+/// One endpoint of a simulated link
+pub struct SimTransport {
+    net: Arc<SimNetwork>,
+    side: usize,
+}
+
+impl SimTransport {
+    /// Two connected endpoints
+    pub fn pair(config: SimConfig) -> (SimTransport, SimTransport) {
+        let link = |seed: u64| Link {
+            in_flight: BTreeMap::new(),
+            arrived: VecDeque::new(),
+            busy_until: 0,
+            rng: SimRng::new(seed),
+        };
+        let net = Arc::new(SimNetwork {
+            state: Mutex::new(SimState {
+                config,
+                now: 0,
+                next_unit: 0,
+                links: [link(config.seed), link(config.seed.rotate_left(32) ^ 0x9e37_79b9_7f4a_7c15)],
+                open: [true, true],
+            }),
+            changed: Condvar::new(),
+        });
+        (
+            SimTransport { net: net.clone(), side: 0 },
+            SimTransport { net, side: 1 },
+        )
+    }
+
+    /// The virtual clock, to be passed to `TransportConfig::with_clock`
+    pub fn clock(&self) -> SimClock {
+        SimClock { net: self.net.clone() }
+    }
+
+    fn send_unit(state: &mut SimState, side: usize, data: &[u8]) {
+        let config = state.config;
+        let now = state.now;
+        let link = &mut state.links[side];
+
+        if link.rng.chance(config.loss) {
+            log::trace!("sim: side {} lost {} bytes", side, data.len());
+            return;
+        }
+        let copies = if link.rng.chance(config.duplicate) { 2 } else { 1 };
+
+        let serialization = match config.bandwidth {
+            0 => 0,
+            bandwidth => data.len() as u64 * 1_000_000 / bandwidth,
+        };
+        link.busy_until = link.busy_until.max(now) + serialization;
+        let sent = link.busy_until;
+
+        for _ in 0..copies {
+            let mut arrival = sent + config.latency_micros;
+            if config.jitter_micros > 0 {
+                arrival += link.rng.next_u64() % (config.jitter_micros + 1);
+            }
+            if link.rng.chance(config.reorder) {
+                arrival += config.latency_micros.max(1);
+            }
+            state.next_unit += 1;
+            link.in_flight.insert((arrival, state.next_unit), data.to_vec());
+        }
+    }
+}
+
+impl Read for SimTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let incoming = 1 - self.side;
+        let mut state = self.net.lock();
+        let mut waited = false;
+
+        loop {
+            let now = state.now;
+            let link = &mut state.links[incoming];
+            if link.arrived.is_empty() {
+                // Deliver the next unit, jumping ahead in time if it is still in flight
+                if let Some(((arrival, _), data)) = link.in_flight.pop_first() {
+                    link.arrived.extend(data);
+                    if arrival > now {
+                        state.now = arrival;
+                        self.net.changed.notify_all();
+                    }
+                    continue;
+                }
+            } else {
+                let n = buf.len().min(link.arrived.len());
+                for (dst, src) in buf.iter_mut().zip(link.arrived.drain(..n)) {
+                    *dst = src;
+                }
+                return Ok(n);
+            }
+
+            if !state.open[incoming] {
+                return Ok(0);
+            }
+            if waited {
+                state.now += state.config.read_timeout_micros;
+                self.net.changed.notify_all();
+                return Err(Error::new(ErrorKind::TimedOut));
+            }
+            // Give a peer running on another thread the chance to send
+            state = self
+                .net
+                .changed
+                .wait_timeout(state, Duration::from_millis(1))
+                .map(|(guard, _)| guard)
+                .unwrap_or_else(|poisoned| poisoned.into_inner().0);
+            waited = true;
+        }
+    }
+}
+
+impl Write for SimTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut state = self.net.lock();
+        if !state.open[1 - self.side] {
+            return Err(Error::new(ErrorKind::WriteZero));
+        }
+        Self::send_unit(&mut state, self.side, buf);
+        self.net.changed.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        let mut state = self.net.lock();
+        state.open[self.side] = false;
+        self.net.changed.notify_all();
+    }
+}
@@ -0,0 +1,108 @@
+use alloc::collections::VecDeque;
+
+/// Number of PING/PONG samples kept for offset and drift estimation
+const MAX_SAMPLES: usize = 32;
+/// Samples must span at least this long before drift is estimated
+const MIN_DRIFT_SPAN_MICROS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy)]
+struct TimeSample {
+    local_time: u64,
+    offset: i64,
+    round_trip: u64,
+}
+
+/// Estimated relation between the local and the peer clock
+///
+/// `peer_time ≈ local_time + offset_micros`, with the offset changing by
+/// `drift_ppm` microseconds per second of local time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeSyncEstimate {
+    pub offset_micros: i64,
+    pub round_trip_micros: u64,
+    pub drift_ppm: f64,
+    pub samples: usize,
+}
+
+/// Clock offset and drift estimator fed by PING/PONG timestamp exchanges
+pub struct TimeSync {
+    samples: VecDeque<TimeSample>,
+}
+
+impl TimeSync {
+    pub fn new() -> Self {
+        TimeSync {
+            samples: VecDeque::with_capacity(MAX_SAMPLES),
+        }
+    }
+
+    /// Add one exchange: `t0` ping sent, `t1` ping received by the peer,
+    /// `t2` pong sent by the peer, `t3` pong received
+    pub fn add_sample(&mut self, t0: u64, t1: u64, t2: u64, t3: u64) {
+        let offset = ((t1 as i64 - t0 as i64) + (t2 as i64 - t3 as i64)) / 2;
+        let round_trip = t3.saturating_sub(t0).saturating_sub(t2.saturating_sub(t1));
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(TimeSample {
+            local_time: t3,
+            offset,
+            round_trip,
+        });
+    }
+
+    pub fn estimate(&self) -> Option<TimeSyncEstimate> {
+        let latest = self.samples.back()?;
+        let drift = self.drift();
+
+        // The sample with the shortest round trip has the least queuing error
+        let best = self.samples.iter().min_by_key(|s| s.round_trip)?;
+        let elapsed = latest.local_time.saturating_sub(best.local_time) as f64;
+        let offset = best.offset + (drift * elapsed) as i64;
+
+        Some(TimeSyncEstimate {
+            offset_micros: offset,
+            round_trip_micros: best.round_trip,
+            drift_ppm: drift * 1_000_000.0,
+            samples: self.samples.len(),
+        })
+    }
+
+    /// Least-squares slope of offset over local time
+    fn drift(&self) -> f64 {
+        let n = self.samples.len();
+        if n < 2 {
+            return 0.0;
+        }
+
+        let base = self.samples[0];
+        let span = self.samples[n - 1].local_time.saturating_sub(base.local_time);
+        if span < MIN_DRIFT_SPAN_MICROS {
+            return 0.0;
+        }
+
+        let (mut sum_x, mut sum_y) = (0.0, 0.0);
+        for s in &self.samples {
+            sum_x += s.local_time.saturating_sub(base.local_time) as f64;
+            sum_y += (s.offset - base.offset) as f64;
+        }
+        let mean_x = sum_x / n as f64;
+        let mean_y = sum_y / n as f64;
+
+        let (mut num, mut den) = (0.0, 0.0);
+        for s in &self.samples {
+            let dx = s.local_time.saturating_sub(base.local_time) as f64 - mean_x;
+            let dy = (s.offset - base.offset) as f64 - mean_y;
+            num += dx * dy;
+            den += dx * dx;
+        }
+        if den == 0.0 { 0.0 } else { num / den }
+    }
+}
+
+impl Default for TimeSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,258 @@
+//! TLS streams for running a transport over encrypted TCP (`tls` feature)
+//!
+//! `TlsTransport` wraps a rustls client or server session around any
+//! `std::io::Read + Write` stream and is itself one, so it is passed to
+//! `XTransport::new` like the plain stream, e.g. a `TcpStream` handed to
+//! `TlsTransport::connect` with a `TlsClientConfig` trusting the server's CA.
+//!
+//! Certificates and keys are given in PEM. Build a configuration once and
+//! share it between connections; `connect` and `accept` complete the TLS
+//! handshake before returning, so certificate errors surface there.
+
+use crate::{error::Phase, Error, Result};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+/// Error for a configuration rustls refuses, keeping its reason as the source
+fn invalid_input(error: impl Into<alloc::boxed::Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, error).into()
+}
+
+fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<core::result::Result<Vec<_>, _>>()
+        .map_err(invalid_input)?;
+    if certs.is_empty() {
+        return Err(invalid_input("no certificate in PEM"));
+    }
+    Ok(certs)
+}
+
+fn root_store(pems: &[Vec<u8>]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for pem in pems {
+        for cert in certificates(pem)? {
+            roots.add(cert).map_err(invalid_input)?;
+        }
+    }
+    Ok(roots)
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(invalid_input)
+}
+
+/// Settings of the connecting side
+#[derive(Clone, Default)]
+pub struct TlsClientConfig {
+    roots: Vec<Vec<u8>>,
+    alpn: Vec<Vec<u8>>,
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl TlsClientConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the CA certificates in `pem` to sign the server's certificate
+    pub fn with_root_certificates(mut self, pem: &[u8]) -> Self {
+        self.roots.push(pem.to_vec());
+        self
+    }
+
+    /// Offer an application protocol, in order of preference
+    pub fn with_alpn(mut self, protocol: &[u8]) -> Self {
+        self.alpn.push(protocol.to_vec());
+        self
+    }
+
+    /// Present a certificate chain and its key to servers that require client certificates
+    pub fn with_client_certificate(mut self, chain_pem: &[u8], key_pem: &[u8]) -> Self {
+        self.identity = Some((chain_pem.to_vec(), key_pem.to_vec()));
+        self
+    }
+
+    /// Parse the certificates and keys into a rustls configuration
+    pub fn build(&self) -> Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?
+            .with_root_certificates(root_store(&self.roots)?);
+        let mut config = match &self.identity {
+            Some((chain, key)) => builder
+                .with_client_auth_cert(certificates(chain)?, private_key(key)?)
+                .map_err(invalid_input)?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn.clone();
+        Ok(Arc::new(config))
+    }
+}
+
+/// Settings of the accepting side
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    chain: Vec<u8>,
+    key: Vec<u8>,
+    alpn: Vec<Vec<u8>>,
+    client_roots: Vec<Vec<u8>>,
+}
+
+impl TlsServerConfig {
+    /// Present the certificate chain in `chain_pem`, server certificate first, with its key
+    pub fn new(chain_pem: &[u8], key_pem: &[u8]) -> Self {
+        TlsServerConfig {
+            chain: chain_pem.to_vec(),
+            key: key_pem.to_vec(),
+            alpn: Vec::new(),
+            client_roots: Vec::new(),
+        }
+    }
+
+    /// Accept an application protocol; with any set, clients offering none of them are refused
+    pub fn with_alpn(mut self, protocol: &[u8]) -> Self {
+        self.alpn.push(protocol.to_vec());
+        self
+    }
+
+    /// Require clients to present a certificate signed by a CA in `pem`
+    pub fn with_client_roots(mut self, pem: &[u8]) -> Self {
+        self.client_roots.push(pem.to_vec());
+        self
+    }
+
+    /// Parse the certificates and keys into a rustls configuration
+    pub fn build(&self) -> Result<Arc<ServerConfig>> {
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_input)?;
+        let builder = if self.client_roots.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(&self.client_roots)?), provider)
+                .build()
+                .map_err(invalid_input)?;
+            builder.with_client_cert_verifier(verifier)
+        };
+        let mut config = builder
+            .with_single_cert(certificates(&self.chain)?, private_key(&self.key)?)
+            .map_err(invalid_input)?;
+        config.alpn_protocols = self.alpn.clone();
+        Ok(Arc::new(config))
+    }
+}
+
+/// TLS session over a byte stream, usable as the stream of an `XTransport`
+pub enum TlsTransport<S: std::io::Read + std::io::Write> {
+    Client(StreamOwned<ClientConnection, S>),
+    Server(StreamOwned<ServerConnection, S>),
+}
+
+impl<S: std::io::Read + std::io::Write> TlsTransport<S> {
+    /// Open a TLS session to `server_name`, which the server's certificate must match
+    ///
+    /// With TLS 1.3 the server checks a client certificate after the client
+    /// has finished its handshake, so a server refusing it fails the first
+    /// read or write rather than `connect`.
+    pub fn connect(mut stream: S, server_name: &str, config: Arc<ClientConfig>) -> Result<Self> {
+        let name = ServerName::try_from(server_name.to_owned()).map_err(invalid_input)?;
+        let mut conn = ClientConnection::new(config, name).map_err(invalid_input)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream).map_err(|e| Error::from(e).with_phase(Phase::Handshake))?;
+        }
+        log::debug!("TLS session to {} established, ALPN {:?}", server_name, conn.alpn_protocol());
+        Ok(TlsTransport::Client(StreamOwned::new(conn, stream)))
+    }
+
+    /// Accept a TLS session from a client connected on `stream`
+    pub fn accept(mut stream: S, config: Arc<ServerConfig>) -> Result<Self> {
+        let mut conn = ServerConnection::new(config).map_err(invalid_input)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream).map_err(|e| Error::from(e).with_phase(Phase::Handshake))?;
+        }
+        log::debug!("TLS session accepted, ALPN {:?}", conn.alpn_protocol());
+        Ok(TlsTransport::Server(StreamOwned::new(conn, stream)))
+    }
+
+    /// Application protocol both ends agreed on, if ALPN was used
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            TlsTransport::Client(stream) => stream.conn.alpn_protocol(),
+            TlsTransport::Server(stream) => stream.conn.alpn_protocol(),
+        }
+    }
+
+    /// Certificate chain the peer presented, if any
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        match self {
+            TlsTransport::Client(stream) => stream.conn.peer_certificates(),
+            TlsTransport::Server(stream) => stream.conn.peer_certificates(),
+        }
+    }
+
+    /// Reference to the underlying stream, e.g. to set a read timeout
+    pub fn get_ref(&self) -> &S {
+        match self {
+            TlsTransport::Client(stream) => stream.get_ref(),
+            TlsTransport::Server(stream) => stream.get_ref(),
+        }
+    }
+
+    /// Mutable reference to the underlying stream
+    ///
+    /// Reading or writing through it directly will corrupt the TLS session.
+    pub fn get_mut(&mut self) -> &mut S {
+        match self {
+            TlsTransport::Client(stream) => stream.get_mut(),
+            TlsTransport::Server(stream) => stream.get_mut(),
+        }
+    }
+
+    /// Send a close_notify alert, telling the peer no more data follows
+    pub fn close(&mut self) -> Result<()> {
+        match self {
+            TlsTransport::Client(stream) => {
+                stream.conn.send_close_notify();
+                std::io::Write::flush(stream)?;
+            }
+            TlsTransport::Server(stream) => {
+                stream.conn.send_close_notify();
+                std::io::Write::flush(stream)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: std::io::Read + std::io::Write> std::io::Read for TlsTransport<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            TlsTransport::Client(stream) => stream.read(buf),
+            TlsTransport::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<S: std::io::Read + std::io::Write> std::io::Write for TlsTransport<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            TlsTransport::Client(stream) => stream.write(buf),
+            TlsTransport::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TlsTransport::Client(stream) => stream.flush(),
+            TlsTransport::Server(stream) => stream.flush(),
+        }
+    }
+}
+
@@ -1,269 +1,2999 @@
 use crate::{
-    config::{TransportConfig, HEADER_SIZE, MESSAGE_HEAD_SIZE},
-    error::{Error, ErrorKind},
-    io::{Read, Write},
-    protocol::{Packet, PacketHeader, PacketType, MessageHead},
+    ackdelay::AckDelayEstimator,
+    auth::AUTH_TOKEN,
+    cache::{payload_hash, KeyHistory, PayloadCache, DEDUP_MIN_SIZE},
+    cancel::CancellationToken,
+    capability::{new_connection_id, Capabilities, Feature, HELLO_TOKEN, TLV_AUTH_CHALLENGE, TLV_CONNECTION_ID, TLV_MAX_MESSAGE_SIZE, TLV_MAX_PAYLOAD_SIZE},
+    cipher::Encryption,
+    config::{
+        CrcPolicy, DuplicatePolicy, TransportConfig, HEADER_SIZE, MESSAGE_DATA_HEAD_SIZE, MESSAGE_DATA_OFFSET_SIZE, MESSAGE_HEAD_SIZE, REFERENCE_SIZE,
+        PING_SIZE, PONG_SIZE, ACK_PREFIX_SIZE, MAX_PAYLOAD_SIZE_V1, VERSION, VERSION_2,
+    },
+    decoder::PacketDecoder,
+    digest::{crc64, Crc64},
+    error::{Error, ErrorKind, Phase},
+    framesize::FrameSizer,
+    io::{BoxedTransport, Read, Transport, Write},
+    memory::MemoryAccount,
+    message::{Message, MessageOptions, OriginalDigest},
+    observer::{ConnectionState, DeliveryFailure, Transfer, TransportEvent},
+    pool::BufferPool,
+    protocol::{Goaway, Packet, PacketHeader, PacketType, MessageHead, GOAWAY_NO_ERROR, MESSAGE_DIGEST_SIZE, MESSAGE_FLAG_CACHED, MESSAGE_FLAG_DIGEST, WINDOW_SIZE, MESSAGE_RESET_SIZE,
+               MESSAGE_FLAG_KEYED, MESSAGE_FLAG_ORIGINAL, ORIGINAL_DIGEST_SIZE, PACKET_FLAG_ACK},
+    retransmit::RetransmitTimer,
+    scan::FrameScanner,
+    scheduler::{QueuedMessageInfo, SendScheduler},
+    shaper::TokenBucket,
+    seq,
+    stats::{LinkQuality, Stats},
+    window::{InFlight, SackRanges, SendWindow},
+    timesync::{TimeSync, TimeSyncEstimate},
     Result,
 };
+#[cfg(feature = "noise")]
+use crate::noise::{NoiseHandshake, KEY_LEN as NOISE_KEY_LEN};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Receive buffer reserved upfront for a multi-packet message; it then doubles
+/// as data arrives, never beyond the announced total
+const INITIAL_REASSEMBLY_CAPACITY: usize = 64 * 1024;
+/// Rejected messages whose bodies are skipped at once; beyond it the oldest is forgotten
+const MAX_REJECTED_MESSAGES: usize = 64;
+/// Skipped ranges of sequence numbers remembered for checking message continuity
+const MAX_LOST_RANGES: usize = 16;
+
+/// Log through `log` with the connection ID in front once the handshake has set one
+///
+/// Interleaved records of a multi-client server can then be told apart by session.
+macro_rules! conn_log {
+    ($level:ident, id = $id:expr, $($arg:tt)+) => {
+        match $id {
+            Some(id) => log::$level!("[conn {:016x}] {}", id, format_args!($($arg)+)),
+            None => log::$level!($($arg)+),
+        }
+    };
+    ($level:ident, $transport:expr, $($arg:tt)+) => {
+        conn_log!($level, id = $transport.connection_id, $($arg)+)
+    };
+}
+
+mod auth;
+mod batch;
+mod group;
+mod journal;
+mod self_test;
+
+use group::StagedGroup;
+
+/// Multi-packet message whose MessageData packets are still arriving
+struct PartialMessage {
+    data: Vec<u8>,
+    total_length: usize,
+    flags: u32,
+    key: Option<u64>,
+    packet_count: u32,
+    packets_received: u32,
+    /// Repeat of a recently seen message key, dropped or rejected once complete
+    duplicate: bool,
+    /// When its head or last data arrived, for evicting stale messages
+    last_active: Option<u64>,
+    /// Sequence number of its head or last data packet
+    last_seq: u32,
+    /// Byte ranges of `data` received so far, sorted and merged; chunks
+    /// carrying their offset may leave holes to be filled by later ones
+    received: Vec<Range<usize>>,
+}
+
+impl PartialMessage {
+    /// Bytes of the body received so far
+    fn received_bytes(&self) -> usize {
+        self.received.iter().map(ExactSizeIterator::len).sum()
+    }
+
+    /// Whether every byte of the body has arrived, with no hole left
+    fn is_complete(&self) -> bool {
+        self.received_bytes() == self.total_length
+    }
+
+    /// Whether `len` bytes at `offset` lie within the body and overlap none received yet
+    fn accepts(&self, offset: usize, len: usize) -> bool {
+        let Some(end) = offset.checked_add(len).filter(|&end| end <= self.total_length) else {
+            return false;
+        };
+        !self.received.iter().any(|range| offset < range.end && range.start < end)
+    }
+
+    /// Copy `chunk` to `offset`, zero-filling any hole before it until its bytes arrive
+    fn write_at(&mut self, offset: usize, chunk: &[u8]) {
+        if chunk.is_empty() {
+            return;
+        }
+        let end = offset + chunk.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[offset..end].copy_from_slice(chunk);
+        let at = self.received.partition_point(|range| range.end < offset);
+        let mut merged = offset..end;
+        while at < self.received.len() && self.received[at].start <= end {
+            let range = self.received.remove(at);
+            merged = merged.start.min(range.start)..merged.end.max(range.end);
+        }
+        self.received.insert(at, merged);
+    }
+}
 
 pub struct XTransport<T> {
     inner: T,
     send_seq: u32,
     recv_seq: u32,
     next_message_id: u64,
+    next_group_id: u64,
+    tx_buf: Vec<u8>,
+    /// Small writes collected into one Data packet under `write_coalesce_size`
+    write_buf: Vec<u8>,
+    /// When the oldest byte in `write_buf` was written
+    write_buf_since: Option<u64>,
+    /// Send every write as its own packet, ignoring `write_coalesce_size`
+    nodelay: bool,
+    /// Bytes a non-blocking stream did not accept yet, written before anything else
+    unsent: Vec<u8>,
+    decoder: PacketDecoder,
+    ack_pending: u32,
+    ack_seq: u32,
+    ack_since: Option<u64>,
+    ack_estimator: AckDelayEstimator,
     recv_buffer: Vec<u8>,
     recv_pos: usize,
     recv_available: usize,
+    reorder: BTreeMap<u32, Packet>,
+    /// Packets were dropped for CRC errors or resynchronization outside ACK mode; skip the gap they leave
+    crc_gap: bool,
+    /// Since when `recv_seq` has been missing behind buffered packets, or was last NACKed
+    gap_since: Option<u64>,
+    pending: VecDeque<Packet>,
+    window: SendWindow,
+    rto_timer: RetransmitTimer,
+    reassembly: BTreeMap<u64, PartialMessage>,
+    /// Bytes still to be discarded of messages rejected as too large, for at most `MAX_REJECTED_MESSAGES`
+    rejected: BTreeMap<u64, usize>,
+    /// Bytes remaining, total length and running digest of messages started with `begin_message`
+    outgoing: BTreeMap<u64, (usize, usize, Option<Crc64>)>,
+    staged_group: Option<StagedGroup>,
+    /// Sequence numbers skipped as lost to corruption, as `(start, end)` with `end` exclusive, for at most `MAX_LOST_RANGES` skips
+    lost_seqs: VecDeque<(u32, u32)>,
+    scheduler: SendScheduler,
+    /// Paces all writes to `max_send_rate`, if set
+    pacer: Option<TokenBucket>,
+    /// Adapts `max_payload_size` to the link, if `adaptive_frame_size` is set
+    frame_sizer: Option<FrameSizer>,
+    ready: VecDeque<Message>,
+    send_cache: PayloadCache,
+    recv_cache: PayloadCache,
+    seen_keys: KeyHistory,
+    /// IDs of recent MessageHeads, to recognize a message sent twice
+    seen_message_ids: KeyHistory,
+    time_sync: TimeSync,
+    /// Peer capabilities, once a handshake has been exchanged in either direction
+    peer_capabilities: Option<Capabilities>,
+    /// Header version of outgoing packets, 2 once both ends advertise `Feature::WireV2`
+    wire_version: u8,
+    /// MessageData packets carry the byte offset of their chunk, once both ends advertise `Feature::FragmentOffsets`
+    fragment_offsets: bool,
+    /// Kind of the malformed header being recovered from and the bytes skipped so far
+    resync: Option<(ErrorKind, usize)>,
+    /// Reason the peer gave for closing the connection
+    peer_goaway: Option<Goaway>,
+    goaway_sent: bool,
+    state: ConnectionState,
+    /// `shutdown_write` has sent a Fin; only ACKs, NACKs and Goaways may follow
+    write_shutdown: bool,
+    /// A Fin from the peer has been received: nothing more will arrive
+    peer_write_shutdown: bool,
+    /// Shared with the peer at the handshake and put in front of every log record
+    connection_id: Option<u64>,
+    /// The peer answered the authentication challenge, or none is required of it
+    authenticated: bool,
+    /// Challenge this end sent with its handshake, until the peer answers it
+    auth_challenge: Option<Vec<u8>>,
+    /// Challenge the peer sent with its handshake, answered once it has authenticated
+    peer_challenge: Option<Vec<u8>>,
+    /// Capabilities of a peer that has yet to authenticate, applied once it has
+    pending_capabilities: Option<Capabilities>,
+    /// Bytes buffered for reordering, reassembly, a staged group and the send queue
+    memory: MemoryAccount,
+    /// Receive window the peer advertised last, `None` while it advertises none
+    peer_window: Option<u32>,
+    /// Receive window this side advertised last
+    advertised_window: Option<u32>,
+    /// Backs off zero-window probes while the peer's window stays closed
+    persist_timer: RetransmitTimer,
+    /// Probes sent since the peer last advertised its window
+    unanswered_probes: u32,
+    /// Set while `recv_messages` drains packets already read: reading the
+    /// stream fails with `WouldBlock` instead
+    buffered_only: bool,
+    /// Error met while draining a burst, returned by the next receive call
+    deferred_error: Option<Error>,
+    /// Token of the cancellable send or receive in progress
+    cancel: Option<(CancellationToken, Phase)>,
+    /// Message ID and offset of the message bytes in the next packet `send_packet` sends
+    next_chunk: Option<(u64, usize)>,
+    /// Packet whose retransmissions ran out, once the connection gave up on it
+    delivery_failure: Option<DeliveryFailure>,
+    stats: Stats,
+    stats_since: Option<u64>,
+    /// Recent RTT sample and loss and retransmit rates; the smoothed RTT lives in `rto_timer`
+    quality: LinkQuality,
     config: TransportConfig,
 }
 
-impl<T: Read + Write> XTransport<T> {
-    pub fn new(inner: T, config: TransportConfig) -> Self {
-        XTransport {
-            inner,
-            send_seq: 0,
-            recv_seq: 0,
-            next_message_id: 1,
-            recv_buffer: Vec::new(),
-            recv_pos: 0,
-            recv_available: 0,
-            config,
+impl<T: Read + Write> XTransport<T> {
+    pub fn new(inner: T, config: TransportConfig) -> Self {
+        let mut transport = XTransport {
+            inner,
+            send_seq: 0,
+            recv_seq: 0,
+            next_message_id: 1,
+            next_group_id: 1,
+            tx_buf: Vec::new(),
+            write_buf: Vec::new(),
+            write_buf_since: None,
+            nodelay: false,
+            unsent: Vec::new(),
+            decoder: PacketDecoder::default()
+                .with_pool(BufferPool::new(config.recv_pool_buffers, config.recv_pool_max_buffer_size)),
+            ack_pending: 0,
+            ack_seq: 0,
+            ack_since: None,
+            ack_estimator: AckDelayEstimator::new(),
+            recv_buffer: Vec::new(),
+            recv_pos: 0,
+            recv_available: 0,
+            reorder: BTreeMap::new(),
+            crc_gap: false,
+            gap_since: None,
+            pending: VecDeque::new(),
+            window: SendWindow::new(),
+            rto_timer: retransmit_timer(&config, 0),
+            reassembly: BTreeMap::new(),
+            rejected: BTreeMap::new(),
+            outgoing: BTreeMap::new(),
+            staged_group: None,
+            lost_seqs: VecDeque::new(),
+            scheduler: SendScheduler::new(&config.class_rates),
+            pacer: (config.max_send_rate > 0)
+                .then(|| TokenBucket::new(config.max_send_rate, config.max_send_burst)),
+            frame_sizer: config.adaptive_frame_size.map(FrameSizer::new),
+            ready: VecDeque::new(),
+            send_cache: PayloadCache::new(config.dedup_cache_size),
+            recv_cache: PayloadCache::new(config.dedup_cache_size),
+            seen_keys: KeyHistory::new(config.duplicate_history),
+            seen_message_ids: KeyHistory::new(config.duplicate_history),
+            time_sync: TimeSync::new(),
+            peer_capabilities: None,
+            wire_version: VERSION,
+            fragment_offsets: false,
+            resync: None,
+            peer_goaway: None,
+            goaway_sent: false,
+            state: ConnectionState::Open,
+            write_shutdown: false,
+            peer_write_shutdown: false,
+            connection_id: None,
+            authenticated: config.authenticator.is_none(),
+            auth_challenge: None,
+            peer_challenge: None,
+            pending_capabilities: None,
+            memory: MemoryAccount::new(config.max_connection_memory, config.memory_budget.clone()),
+            peer_window: None,
+            advertised_window: None,
+            persist_timer: retransmit_timer(&config, 1),
+            unanswered_probes: 0,
+            buffered_only: false,
+            deferred_error: None,
+            cancel: None,
+            next_chunk: None,
+            delivery_failure: None,
+            stats: Stats::new(),
+            stats_since: config.clock.as_ref().map(|clock| clock.now_micros()),
+            quality: LinkQuality::default(),
+            config,
+        };
+        transport.update_decoder_limits(false);
+        transport
+    }
+
+    /// Reference to the underlying stream
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Mutable reference to the underlying stream
+    ///
+    /// Reading or writing through it directly will corrupt the packet stream.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Take back the stream and the configuration, journal included
+    ///
+    /// Anything buffered but not yet sent or delivered is dropped.
+    pub fn into_parts(self) -> (T, TransportConfig) {
+        (self.inner, self.config)
+    }
+
+    pub fn config(&self) -> &TransportConfig {
+        &self.config
+    }
+
+    /// Traffic counters since the transport was created or `reset_stats` was called
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.clone();
+        if let (Some(since), Some(now)) = (self.stats_since, self.now()) {
+            stats.elapsed_micros = now.saturating_sub(since);
+        }
+        stats.srtt_micros = self.rto_timer.srtt();
+        stats.rttvar_micros = self.rto_timer.rttvar();
+        stats.rto_micros = self.rto_timer.rto();
+        stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::new();
+        self.stats_since = self.now();
+    }
+
+    /// Recent round-trip time, loss and retransmit rates
+    ///
+    /// ACKs keep it current in ACK mode; `heartbeat` refreshes the RTT on an
+    /// idle connection or without ACK mode.
+    pub fn link_quality(&self) -> LinkQuality {
+        LinkQuality {
+            srtt_micros: self.rto_timer.srtt(),
+            rttvar_micros: self.rto_timer.rttvar(),
+            ..self.quality
+        }
+    }
+
+    /// Exchange a Ping and a Pong with the peer to measure the round-trip time, then return the link quality
+    ///
+    /// The peer answers from inside its own receive calls; the time it held
+    /// the Ping is left out when its Pong reports it. Requires a clock.
+    pub fn heartbeat(&mut self) -> Result<LinkQuality> {
+        let sent = self.now().ok_or_else(|| Error::new(ErrorKind::Unsupported))?;
+        self.send_packet(PacketType::Ping, &sent.to_le_bytes())?;
+        self.flush_inner()?;
+        let pong = self.await_pong(sent)?;
+        let received = self.now().unwrap_or(sent);
+        self.record_pong_rtt(sent, received, &pong.data);
+        self.decoder.recycle(pong.data);
+        Ok(self.link_quality())
+    }
+
+    /// Take an RTT sample from a Pong answering a Ping sent at `sent`, less the time the peer held it
+    fn record_pong_rtt(&mut self, sent: u64, received: u64, pong: &[u8]) {
+        let held = match pong.get(8..PONG_SIZE) {
+            Some(times) => le_u64(&times[8..]).saturating_sub(le_u64(times)),
+            None => 0,
+        };
+        let rtt = received.saturating_sub(sent).saturating_sub(held);
+        self.stats.record_rtt(rtt);
+        self.rto_timer.update_rtt(rtt);
+        self.quality.record_rtt(rtt);
+    }
+
+    fn send_packet(&mut self, pkt_type: PacketType, data: &[u8]) -> Result<()> {
+        let chunk = self.next_chunk.take();
+        if self.write_shutdown {
+            return Err(Error::new(ErrorKind::WriteShutdown));
+        }
+        // Coalesced writes were issued first and must not be overtaken
+        self.flush_writes()?;
+        if self.config.wait_for_ack && pkt_type != PacketType::Ack {
+            self.wait_for_window_bytes(HEADER_SIZE + data.len())?;
+            self.wait_for_peer_window(data.len())?;
+            // Expired packets go out between new ones instead of waiting for the window to fill
+            self.retransmit_expired(self.config.retransmit_ratio as usize, false)?;
+        }
+
+        // A delayed ACK rides along with outgoing data instead of needing its own packet
+        let piggyback = self.config.wait_for_ack
+            && self.ack_pending > 0
+            && data.len() + ACK_PREFIX_SIZE <= self.max_wire_payload();
+        let ack_prefix = self.ack_seq.to_le_bytes();
+        let prefix: &[u8] = if piggyback {
+            conn_log!(trace, self, "Piggybacking ACK for seq={} covering {} packets", self.ack_seq, self.ack_pending);
+            self.ack_pending = 0;
+            self.ack_since = None;
+            &ack_prefix
+        } else {
+            &[]
+        };
+        let seq = self.send_seq;
+        let mut header = PacketHeader::for_parts(pkt_type, seq, &[prefix, data]);
+        if piggyback {
+            header.pkt_type |= PACKET_FLAG_ACK;
+        }
+        header.version = self.wire_version;
+        self.send_seq = self.send_seq.wrapping_add(1);
+        let len = prefix.len() + data.len();
+
+        let start = self.stage_packet(&header, &[prefix, data]).map_err(|e| e.with_seq(seq))?;
+        let wire_len = self.tx_buf.len() - start;
+        let tracked = self.config.wait_for_ack && pkt_type != PacketType::Ack;
+        // Only a packet that may be retransmitted needs a copy of its own;
+        // a sealed one is kept in the clear, to be sealed again under a fresh nonce
+        let retained = tracked.then(|| match self.config.encryption {
+            Some(_) => {
+                let mut wire = Vec::with_capacity(header.size() + len);
+                header.write_to(&mut wire);
+                wire.extend_from_slice(prefix);
+                wire.extend_from_slice(data);
+                wire
+            }
+            None => self.tx_buf[start..].to_vec(),
+        });
+        self.flush_full_burst().map_err(|e| e.with_seq(seq))?;
+        self.stats.record_sent(wire_len);
+        self.emit(TransportEvent::PacketSent { pkt_type: header.pkt_type, seq, len });
+        
+        conn_log!(trace, self, "Sent packet type={:?}, seq={}, len={}", pkt_type, seq, len);
+        
+        // Track the packet until it is acknowledged, if configured and not an ACK itself
+        if let Some(wire) = retained {
+            self.quality.record_transmission(false);
+            if self.window.is_empty() {
+                self.reset_rto_timer();
+            }
+            let sent_at = self.now();
+            self.window.push_chunk(seq, wire, sent_at, chunk);
+            self.wait_for_acks(self.config.window_size.saturating_sub(1))?;
+        } else if self.frame_sizer.as_ref().is_some_and(|sizer| sizer.is_current(len)) {
+            // Without ACKs a completed write is the only delivery signal
+            self.adapt_frame_size(|sizer| sizer.on_delivered(1));
+        }
+        
+        Ok(())
+    }
+
+    /// Report deliveries or a loss to the frame sizer and apply the size it picks
+    fn adapt_frame_size(&mut self, report: impl FnOnce(&mut FrameSizer) -> Option<usize>) {
+        if let Some(size) = self.frame_sizer.as_mut().and_then(report) {
+            conn_log!(debug, self, "Adapted max payload size to {} bytes", size);
+            self.config.max_payload_size = size;
+            self.emit(TransportEvent::FrameSizeChanged { payload_size: size });
+        }
+    }
+
+    /// Append a packet with the payload `parts` to the burst buffer, returning its offset there
+    ///
+    /// The burst buffer keeps its allocation between writes, so no frame is
+    /// allocated per send; `flush_full_burst` writes it out. With encryption
+    /// the payload is sealed in place.
+    fn stage_packet(&mut self, header: &PacketHeader, parts: &[&[u8]]) -> Result<usize> {
+        let start = self.tx_buf.len();
+        if let Some(encryption) = self.config.encryption.as_mut() {
+            encryption.seal_packet(header, parts, &mut self.tx_buf)?;
+            return Ok(start);
+        }
+        header.write_to(&mut self.tx_buf);
+        for part in parts {
+            self.tx_buf.extend_from_slice(part);
+        }
+        Ok(start)
+    }
+
+    /// Bytes to write to retransmit `wire`, a frame kept in the send window
+    ///
+    /// With encryption the window keeps frames in the clear and every
+    /// retransmission is sealed under a fresh nonce.
+    fn retransmit_wire(&mut self, wire: Vec<u8>) -> Result<Vec<u8>> {
+        let encryption = match self.config.encryption.as_mut() {
+            Some(encryption) => encryption,
+            None => return Ok(wire),
+        };
+        let header = PacketHeader::parse(&wire)?;
+        let mut sealed = Vec::with_capacity(wire.len() + encryption.overhead());
+        encryption.seal_packet(&header, &[&wire[header.size()..]], &mut sealed)?;
+        Ok(sealed)
+    }
+
+    /// Write out the burst buffer once it holds `max_burst_size` bytes
+    fn flush_full_burst(&mut self) -> Result<()> {
+        if self.tx_buf.len() >= self.config.max_burst_size {
+            self.flush_tx()?;
+        }
+        Ok(())
+    }
+
+    /// Wait until the send rate allows `bytes` more, then charge them
+    fn pace(&mut self, bytes: usize) {
+        let (pacer, clock) = match (self.pacer.as_mut(), self.config.clock.as_ref()) {
+            (Some(pacer), Some(clock)) => (pacer, clock),
+            _ => return,
+        };
+        let delay = pacer.delay(clock.now_micros());
+        if delay > 0 {
+            clock.sleep_micros(delay);
+        }
+        pacer.consume(clock.now_micros(), bytes);
+    }
+
+    /// Write out the coalesced burst, if any
+    fn flush_tx(&mut self) -> Result<()> {
+        if !self.tx_buf.is_empty() {
+            let burst = core::mem::take(&mut self.tx_buf);
+            let result = self.write_stream(&burst);
+            self.tx_buf = burst;
+            self.tx_buf.clear();
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Write whole packets to the stream
+    ///
+    /// What a non-blocking stream does not accept is kept and written before
+    /// anything else on a later call, so a frame is never cut off mid-way.
+    fn write_stream(&mut self, bytes: &[u8]) -> Result<()> {
+        self.pace(bytes.len());
+        if !self.unsent.is_empty() {
+            self.unsent.extend_from_slice(bytes);
+            return self.drain_unsent();
+        }
+        let mut written = 0;
+        while written < bytes.len() {
+            match self.inner.write(&bytes[written..]) {
+                Ok(0) => return Err(self.stream_failed(Error::new(ErrorKind::WriteZero))),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    conn_log!(trace, self, "Stream would block, keeping {} bytes", bytes.len() - written);
+                    self.unsent.extend_from_slice(&bytes[written..]);
+                    return Ok(());
+                }
+                Err(e) => return Err(self.stream_failed(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Write as much of the kept bytes as the stream accepts
+    fn drain_unsent(&mut self) -> Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.unsent.len() {
+                break Ok(());
+            }
+            match self.inner.write(&self.unsent[written..]) {
+                Ok(0) => break Err(Error::new(ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.unsent.drain(..written);
+        result.map_err(|e| self.stream_failed(e))
+    }
+
+    /// Send the coalesced small writes, if any, as one Data packet
+    fn flush_writes(&mut self) -> Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let data = core::mem::take(&mut self.write_buf);
+        self.write_buf_since = None;
+        conn_log!(trace, self, "Sending {} coalesced bytes", data.len());
+        self.send_packet(PacketType::Data, &data)?;
+        // Keep the allocation for the next batch
+        self.write_buf = data;
+        self.write_buf.clear();
+        Ok(())
+    }
+
+    /// Whether coalesced writes have waited `write_coalesce_delay_ms`
+    fn write_delay_expired(&self) -> bool {
+        let delay = self.config.write_coalesce_delay_ms;
+        match (self.write_buf_since, self.now()) {
+            (Some(since), Some(now)) => delay > 0 && now.saturating_sub(since) >= delay.saturating_mul(1000),
+            _ => false,
+        }
+    }
+
+    /// Send every write through the `Write` impl as its own packet, like `TCP_NODELAY`
+    ///
+    /// Turning it on sends the writes coalesced so far.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        self.nodelay = nodelay;
+        if nodelay {
+            self.flush_writes()?;
+        }
+        Ok(())
+    }
+
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    /// Body of the `Write` impl: send `buf` as a Data packet or add it to the coalesced writes
+    fn write_data(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let limit = core::cmp::min(self.config.write_coalesce_size, self.payload_size());
+        if !self.nodelay && limit > 0 && self.write_buf.len() >= limit {
+            // The payload size shrank below what is already collected
+            self.flush_writes()?;
+        }
+        if self.nodelay || limit == 0 || (self.write_buf.is_empty() && buf.len() >= limit) {
+            // Send first chunk (up to max_payload_size)
+            let to_send = core::cmp::min(buf.len(), self.payload_size());
+            self.send_packet(PacketType::Data, &buf[..to_send])?;
+            return Ok(to_send);
+        }
+
+        let to_buffer = core::cmp::min(buf.len(), limit.saturating_sub(self.write_buf.len()));
+        if self.write_buf.is_empty() {
+            self.write_buf_since = self.now();
+        }
+        self.write_buf.extend_from_slice(&buf[..to_buffer]);
+        if self.write_buf.len() >= limit || self.write_delay_expired() {
+            self.flush_writes()?;
+        }
+        Ok(to_buffer)
+    }
+
+    fn flush_inner(&mut self) -> Result<()> {
+        self.flush_writes()?;
+        self.flush_tx()?;
+        self.drain_unsent()?;
+        self.inner.flush()
+    }
+
+    /// Bytes of sent packets a non-blocking stream has not accepted yet
+    pub fn pending_write_len(&self) -> usize {
+        self.unsent.len() + self.tx_buf.len()
+    }
+
+    /// Packets sent in ACK mode and not acknowledged yet, and their wire bytes
+    pub fn in_flight(&self) -> (usize, usize) {
+        (self.window.len(), self.window.bytes())
+    }
+
+    /// Write out packets held back by a non-blocking stream
+    ///
+    /// Fails with `WouldBlock` while bytes remain; call again once the stream
+    /// is writable instead of spinning.
+    pub fn poll_flush(&mut self) -> Result<()> {
+        self.flush_inner()?;
+        if self.unsent.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::WouldBlock))
+        }
+    }
+
+    /// Flush the stream and, in ACK mode, wait until everything sent is acknowledged
+    fn flush_sent(&mut self) -> Result<()> {
+        self.flush_inner()?;
+        if self.config.wait_for_ack {
+            self.wait_for_acks(0)?;
+        }
+        Ok(())
+    }
+
+    fn reset_rto_timer(&mut self) {
+        self.rto_timer.reset();
+    }
+
+    /// Wait until at most `max_in_flight` packets are unacknowledged, retransmitting
+    /// the oldest one with exponential backoff whenever its timeout expires
+    fn wait_for_acks(&mut self, max_in_flight: usize) -> Result<()> {
+        self.wait_for_window(|window| window.len() <= max_in_flight)
+    }
+
+    /// Wait until a packet of `len` wire bytes fits in the byte limit of the send window
+    ///
+    /// However large the packet, it may go out once nothing is in flight.
+    fn wait_for_window_bytes(&mut self, len: usize) -> Result<()> {
+        let limit = self.config.window_bytes;
+        if limit == 0 {
+            return Ok(());
+        }
+        self.wait_for_window(|window| window.is_empty() || window.bytes() + len <= limit)
+    }
+
+    /// Wait for ACKs until the send window is `ready`, retransmitting as timeouts expire
+    fn wait_for_window(&mut self, ready: impl Fn(&SendWindow) -> bool) -> Result<()> {
+        while !ready(&self.window) {
+            match self.poll_packet() {
+                // Traffic from the peer while we wait; keep it for the receive path
+                Ok(Some(packet)) => self.pending.push_back(packet),
+                Ok(None) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                    self.retransmit_if_expired()?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until the peer's advertised window has room for `len` more bytes
+    ///
+    /// However small the window, a packet may go out once nothing is in
+    /// flight; only a closed window stops the sender altogether, probing the
+    /// peer until it reopens.
+    fn wait_for_peer_window(&mut self, len: usize) -> Result<()> {
+        let mut logged = false;
+        // Probes are timed from the start of the wait, then from the last probe
+        let mut last_probe = self.now();
+        while let Some(window) = self.peer_window {
+            let in_flight = self.window.payload_bytes();
+            if window > 0 && (in_flight == 0 || in_flight + len <= window as usize) {
+                break;
+            }
+            if !logged {
+                conn_log!(debug, self, "Peer window of {} bytes is full ({} in flight), waiting", window, in_flight);
+                logged = true;
+            }
+            match self.poll_packet() {
+                Ok(Some(packet)) => self.pending.push_back(packet),
+                Ok(None) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                    if window == 0 && self.window.is_empty() {
+                        self.probe_peer_window(&mut last_probe)?;
+                    } else {
+                        self.retransmit_if_expired()?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a zero-window probe once the persist timer expires, backing off after each one
+    ///
+    /// The peer answers every probe with its window, so a lost WindowUpdate
+    /// cannot stall the sender for good. A peer that leaves `max_retries`
+    /// probes in a row unanswered is given up on.
+    fn probe_peer_window(&mut self, last_probe: &mut Option<u64>) -> Result<()> {
+        let now = self.now();
+        // Without a clock every read timeout counts as an expired timer
+        let expired = match (*last_probe, now) {
+            (Some(sent), Some(now)) => self.persist_timer.is_expired(sent, now),
+            _ => true,
+        };
+        if !expired {
+            return Ok(());
+        }
+        if self.unanswered_probes >= self.config.max_retries {
+            conn_log!(warn, self, "Giving up on a closed peer window after {} unanswered probes", self.unanswered_probes);
+            self.set_state(ConnectionState::TimedOut);
+            return Err(Error::new(ErrorKind::MaxRetriesExceeded));
+        }
+        self.persist_timer.backoff();
+        self.unanswered_probes += 1;
+        conn_log!(debug, self, "Probing closed peer window (probe {}), next in {}us",
+                   self.unanswered_probes, self.persist_timer.rto());
+        self.send_window_update(&[])?;
+        self.stats.window_probes += 1;
+        *last_probe = now;
+        Ok(())
+    }
+
+    /// Retransmit the oldest packet in flight if its timeout expired, after a read timed out
+    fn retransmit_if_expired(&mut self) -> Result<()> {
+        self.retransmit_expired(1, true)
+    }
+
+    /// Retransmit up to `max` packets whose timeout expired, oldest first
+    ///
+    /// Nothing is retransmitted until the oldest packet in flight expires,
+    /// which also backs off the timeout and counts towards `max_retries`;
+    /// later packets that expired too follow it, in sequence order. Without
+    /// a clock only a read that `timed_out` tells that the oldest expired.
+    fn retransmit_expired(&mut self, max: usize, timed_out: bool) -> Result<()> {
+        let now = self.now();
+        let rto = self.rto_timer.rto();
+        let expired = |entry: &InFlight| match (entry.sent_at, now) {
+            (Some(sent), Some(now)) => Some(now.saturating_sub(sent) >= rto),
+            _ => None,
+        };
+        let oldest = match self.window.oldest() {
+            // Without a clock every read timeout counts as an expired RTO
+            Some(oldest) if max > 0 && expired(oldest).unwrap_or(timed_out) => oldest,
+            _ => return Ok(()),
+        };
+
+        let seq = oldest.seq;
+        if self.rto_timer.retries() >= self.config.max_retries {
+            conn_log!(warn, self, "Giving up on seq={} after {} retransmissions", seq, self.rto_timer.retries());
+            let failure = delivery_failure(oldest, self.data_head_size());
+            self.delivery_failure = Some(failure);
+            self.emit(TransportEvent::DeliveryFailed(failure));
+            self.set_state(ConnectionState::TimedOut);
+            return Err(Error::new(ErrorKind::MaxRetriesExceeded).with_seq(seq));
+        }
+        let seqs: Vec<u32> = core::iter::once(seq)
+            .chain(self.window.iter().skip(1).filter(|entry| expired(entry) == Some(true)).map(|entry| entry.seq))
+            .take(max)
+            .collect();
+        self.rto_timer.backoff();
+        let payload_len = oldest.wire.len().saturating_sub(HEADER_SIZE);
+        self.adapt_frame_size(|sizer| sizer.on_loss(payload_len));
+        conn_log!(debug, self, "Retransmitting {} packets from seq={} (retry {}), next rto={}us",
+                   seqs.len(), seq, self.rto_timer.retries(), self.rto_timer.rto());
+
+        self.flush_tx()?;
+        for seq in seqs {
+            self.retransmit_packet(seq, now)?;
+        }
+        self.flush_inner()
+    }
+
+    /// Write a packet in flight again, restarting its timeout
+    fn retransmit_packet(&mut self, seq: u32, now: Option<u64>) -> Result<()> {
+        let wire = match self.window.get_mut(seq) {
+            Some(entry) => {
+                entry.sent_at = now;
+                entry.retransmitted = true;
+                entry.wire.clone()
+            }
+            None => return Ok(()),
+        };
+        self.emit(TransportEvent::Retransmit { seq, retry: self.rto_timer.retries() });
+        let wire = self.retransmit_wire(wire)?;
+        self.write_stream(&wire)?;
+        self.stats.retransmissions += 1;
+        self.stats.record_sent(wire.len());
+        self.quality.record_transmission(true);
+        Ok(())
+    }
+
+    /// Apply an ACK packet to the send window
+    fn handle_ack(&mut self, packet: &Packet) -> Result<()> {
+        if packet.data.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let ack_seq = u32::from_le_bytes([packet.data[0], packet.data[1], packet.data[2], packet.data[3]]);
+        if let Some(window) = packet.data.get(4..4 + WINDOW_SIZE) {
+            self.set_peer_window(window);
+        }
+        self.apply_ack(ack_seq)
+    }
+
+    /// Record the receive window carried by an ACK or WindowUpdate
+    fn set_peer_window(&mut self, window: &[u8]) {
+        let window = u32::from_le_bytes([window[0], window[1], window[2], window[3]]);
+        if self.peer_window != Some(window) {
+            conn_log!(trace, self, "Peer window is now {} bytes", window);
+        }
+        self.peer_window = Some(window);
+        self.unanswered_probes = 0;
+        if window > 0 {
+            self.persist_timer.reset();
+        }
+    }
+
+    /// Apply a WindowUpdate packet, answering it with our window if it is an empty probe
+    fn handle_window_update(&mut self, packet: &Packet) -> Result<()> {
+        if packet.data.is_empty() {
+            conn_log!(trace, self, "Answering zero-window probe");
+            return match self.recv_window() {
+                Some(window) => self.advertise_window(window),
+                None => Ok(()),
+            };
+        }
+        if packet.data.len() < WINDOW_SIZE {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        self.set_peer_window(&packet.data[..WINDOW_SIZE]);
+        Ok(())
+    }
+
+    /// Apply a cumulative ACK, standalone or piggybacked, to the send window
+    fn apply_ack(&mut self, ack_seq: u32) -> Result<()> {
+        // Anything not yet sent cannot be acknowledged
+        if !seq::before(ack_seq, self.send_seq) {
+            conn_log!(warn, self, "ACK for unsent seq={}, next seq={}", ack_seq, self.send_seq);
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        
+        if let (Some(entry), Some(now)) = (self.window.get(ack_seq), self.now())
+            && let Some(sent_at) = entry.sent_at
+            && !entry.retransmitted
+        {
+            let rtt = now.saturating_sub(sent_at);
+            self.stats.record_rtt(rtt);
+            self.rto_timer.update_rtt(rtt);
+            self.quality.record_rtt(rtt);
+        }
+        if self.window.contains(ack_seq) {
+            for entry in self.window.iter() {
+                self.quality.record_delivery(entry.retransmitted);
+                if entry.seq == ack_seq {
+                    break;
+                }
+            }
+        }
+        
+        let full_size = self.full_size_acked(ack_seq);
+        let acked = self.window.ack(ack_seq);
+        if acked > 0 {
+            self.emit(TransportEvent::AckReceived { seq: ack_seq, acked });
+            self.adapt_frame_size(|sizer| sizer.on_delivered(full_size));
+            conn_log!(trace, self, "Received ACK up to seq={}, {} packets acknowledged", ack_seq, acked);
+            self.reset_rto_timer();
+        } else {
+            // Re-ACK of a packet we already saw acknowledged
+            conn_log!(trace, self, "Ignoring stale ACK for seq={}", ack_seq);
+        }
+        Ok(())
+    }
+
+    /// Packets up to `ack_seq` that were sent at the frame sizer's current size
+    fn full_size_acked(&self, ack_seq: u32) -> u32 {
+        let sizer = match &self.frame_sizer {
+            Some(sizer) if self.window.contains(ack_seq) => sizer,
+            _ => return 0,
+        };
+        let mut full_size = 0;
+        for entry in self.window.iter() {
+            if sizer.is_current(entry.wire.len().saturating_sub(HEADER_SIZE)) {
+                full_size += 1;
+            }
+            if entry.seq == ack_seq {
+                break;
+            }
+        }
+        full_size
+    }
+
+    fn send_ack(&mut self, seq: u32) -> Result<()> {
+        let mut ack_data = [0u8; 4 + WINDOW_SIZE];
+        ack_data[..4].copy_from_slice(&seq.to_le_bytes());
+        let window = self.recv_window();
+        if let Some(window) = window {
+            ack_data[4..].copy_from_slice(&window.to_le_bytes());
+            self.advertised_window = Some(window);
+        }
+        let ack_data = &ack_data[..if window.is_some() { 4 + WINDOW_SIZE } else { 4 }];
+        // ACKs are never retransmitted, so they carry the next sequence number
+        // without consuming it; a lost ACK must not leave a gap in the peer's order
+        let mut header = PacketHeader::for_parts(PacketType::Ack, self.send_seq, &[ack_data]);
+        header.version = self.wire_version;
+        
+        let start = self.stage_packet(&header, &[ack_data])?;
+        let wire_len = self.tx_buf.len() - start;
+        self.flush_full_burst()?;
+        self.stats.record_sent(wire_len);
+        self.emit(TransportEvent::PacketSent { pkt_type: PacketType::Ack as u8, seq: header.seq, len: ack_data.len() });
+        
+        // A cumulative ACK covers everything delivered so far
+        self.ack_pending = 0;
+        self.ack_since = None;
+        
+        conn_log!(trace, self, "Sent ACK for seq={}", seq);
+        Ok(())
+    }
+
+    /// Receive window to advertise: the bytes the memory caps still allow,
+    /// plus the room left in reassembly buffers already charged
+    ///
+    /// `None` without caps, or unless both ends advertise `Feature::FlowControl`.
+    fn recv_window(&self) -> Option<u32> {
+        if !self.config.capabilities.supports(Feature::FlowControl) || !self.peer_supports(Feature::FlowControl) {
+            return None;
+        }
+        let reserved: usize = self.reassembly.values()
+            .map(|partial| partial.data.capacity() - partial.data.len())
+            .sum();
+        self.memory.available().map(|bytes| bytes.saturating_add(reserved).min(u32::MAX as usize) as u32)
+    }
+
+    /// Tell the peer its window has opened, once it has at least doubled since the last advertisement
+    fn update_window(&mut self) -> Result<()> {
+        let (Some(advertised), Some(window)) = (self.advertised_window, self.recv_window()) else {
+            return Ok(());
+        };
+        if window <= advertised.saturating_mul(2) {
+            return Ok(());
+        }
+        self.advertise_window(window)
+    }
+
+    fn advertise_window(&mut self, window: u32) -> Result<()> {
+        self.send_window_update(&window.to_le_bytes())?;
+        self.advertised_window = Some(window);
+        conn_log!(trace, self, "Sent WindowUpdate: {} bytes", window);
+        Ok(())
+    }
+
+    /// Send a WindowUpdate carrying `payload`: our window, or nothing for a probe
+    fn send_window_update(&mut self, payload: &[u8]) -> Result<()> {
+        let mut header = PacketHeader::for_parts(PacketType::WindowUpdate, self.send_seq, &[payload]);
+        header.version = self.wire_version;
+        
+        let start = self.stage_packet(&header, &[payload])?;
+        let wire_len = self.tx_buf.len() - start;
+        self.flush_full_burst()?;
+        self.stats.record_sent(wire_len);
+        self.emit(TransportEvent::PacketSent { pkt_type: PacketType::WindowUpdate as u8, seq: header.seq, len: payload.len() });
+        self.flush_tx()
+    }
+
+    /// Acknowledge a delivered packet, delaying the ACK if configured
+    fn ack_delivered(&mut self, seq: u32) -> Result<()> {
+        self.ack_seq = seq;
+        self.ack_pending += 1;
+        let now = self.now();
+        if self.ack_since.is_none() {
+            self.ack_since = now;
+        }
+        
+        let mut delay_ms = self.config.ack_delay_ms;
+        if self.config.adaptive_ack && let Some(now) = now {
+            self.ack_estimator.on_arrival(now);
+            if !self.ack_estimator.is_bulk() {
+                delay_ms = 0;
+            }
+        }
+        
+        if delay_ms == 0 || self.ack_pending >= self.config.max_unacked || self.ack_delay_expired(now, delay_ms) {
+            self.send_ack(seq)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the oldest packet a pending ACK covers has waited `delay_ms`; never without a clock
+    fn ack_delay_expired(&self, now: Option<u64>, delay_ms: u64) -> bool {
+        match (self.ack_since, now) {
+            (Some(since), Some(now)) => now.saturating_sub(since) >= delay_ms.saturating_mul(1000),
+            _ => false,
+        }
+    }
+
+    /// Send the delayed ACK if it has waited its delay
+    fn send_due_ack(&mut self) -> Result<()> {
+        if self.ack_pending > 0 && self.ack_delay_expired(self.now(), self.config.ack_delay_ms) {
+            self.send_pending_ack()?;
+        }
+        Ok(())
+    }
+
+    /// Send the delayed ACK, if any
+    fn send_pending_ack(&mut self) -> Result<()> {
+        if self.ack_pending > 0 {
+            conn_log!(trace, self, "Flushing delayed ACK covering {} packets", self.ack_pending);
+            self.send_ack(self.ack_seq)?;
+        }
+        Ok(())
+    }
+
+    fn recv_packet_internal(&mut self) -> Result<Packet> {
+        // Consecutive empty reads while busy-polling, and when they started
+        let mut idle_rounds = 0u32;
+        let mut idle_since = None;
+        loop {
+            let synced = self.resync.is_none() || self.resync_step()?;
+            if synced && let Some(result) = self.decoder.decode() {
+                let mut packet = match result {
+                    Ok(packet) => packet,
+                    Err(e) if e.kind() == ErrorKind::CrcMismatch => {
+                        self.stats.crc_failures += 1;
+                        self.emit(TransportEvent::CrcFailure);
+                        self.handle_crc_failure(e)?;
+                        continue;
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::InvalidMagic | ErrorKind::InvalidVersion | ErrorKind::InvalidPacket)
+                        && self.config.max_resync_bytes > 0 =>
+                    {
+                        conn_log!(warn, self, "Malformed header, resynchronizing: {}", e);
+                        self.resync = Some((e.kind(), 0));
+                        continue;
+                    }
+                    // Packet boundaries are lost with a malformed header
+                    Err(e) => return Err(self.abort(e)),
+                };
+                self.stats.record_received(packet.header.size() + packet.data.len());
+                if let Some(encryption) = self.config.encryption.as_mut() {
+                    match encryption.open_payload(&packet.header, &mut packet.data) {
+                        Ok(true) => packet.header.length = packet.data.len() as u32,
+                        Ok(false) => {
+                            conn_log!(warn, self, "Dropping replayed packet seq={}", packet.header.seq);
+                            self.stats.replays_dropped += 1;
+                            self.decoder.recycle(packet.data);
+                            continue;
+                        }
+                        Err(e) => return Err(self.abort(e)),
+                    }
+                }
+                self.emit(TransportEvent::PacketReceived {
+                    pkt_type: packet.header.pkt_type,
+                    seq: packet.header.seq,
+                    len: packet.data.len(),
+                });
+                // Piggybacked ACKs are applied on arrival, even for duplicates
+                // and out-of-order packets
+                if let Some(ack_seq) = packet.take_ack()? {
+                    self.apply_ack(ack_seq)?;
+                }
+                conn_log!(trace, self, "Received packet seq={}, len={}", packet.header.seq, packet.data.len());
+                return Ok(packet);
+            }
+            
+            if self.buffered_only {
+                return Err(Error::new(ErrorKind::WouldBlock));
+            }
+            if self.cancelled(Phase::Recv) {
+                return Err(Error::new(ErrorKind::Cancelled));
+            }
+            // Never sit on coalesced writes, an overdue ACK or a partial burst while waiting for the peer
+            self.flush_writes()?;
+            self.send_due_ack()?;
+            self.flush_tx()?;
+            self.drain_unsent()?;
+            
+            let filled = self.decoder.fill_from(&mut self.inner);
+            // Nothing more to read for now: the delayed ACK covers all the peer sent
+            if let Err(e) = &filled
+                && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::UnexpectedEof)
+                && self.ack_pending > 0
+            {
+                self.send_pending_ack()?;
+                self.flush_tx()?;
+            }
+            match filled {
+                Err(e) if e.kind() == ErrorKind::WouldBlock && self.config.busy_poll.is_some() => {
+                    let poll = self.config.busy_poll.expect("checked above");
+                    let now = self.now();
+                    let since = *idle_since.get_or_insert(now);
+                    if let (Some(timeout), Some(now), Some(since)) = (poll.timeout_micros, now, since)
+                        && now.saturating_sub(since) >= timeout
+                    {
+                        return Err(e);
+                    }
+                    poll.wait(idle_rounds, self.config.clock.as_deref());
+                    idle_rounds = idle_rounds.saturating_add(1);
+                }
+                Err(e) if !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                    return Err(self.stream_failed(e));
+                }
+                result => {
+                    result?;
+                }
+            }
+        }
+    }
+
+    /// Drop buffered bytes that cannot start a packet, returning true once a valid packet is next
+    ///
+    /// Fails with the kind of the malformed header once more than
+    /// `max_resync_bytes` have been dropped.
+    fn resync_step(&mut self) -> Result<bool> {
+        let Some((kind, skipped)) = self.resync else {
+            return Ok(true);
+        };
+        let (skip, found) = self.decoder.skip_to_packet();
+        let skipped = skipped + skip;
+        if skipped > self.config.max_resync_bytes {
+            conn_log!(warn, self, "No valid packet within {} bytes after a malformed header", skipped);
+            self.resync = None;
+            self.decoder.clear();
+            return Err(self.abort(Error::new(kind)));
+        }
+        if !found {
+            self.resync = Some((kind, skipped));
+            return Ok(false);
+        }
+        conn_log!(warn, self, "Resynchronized after skipping {} bytes", skipped);
+        self.resync = None;
+        self.stats.resyncs += 1;
+        // Packets lost in the garbage are retransmitted in ACK mode, skipped otherwise
+        if !self.config.wait_for_ack {
+            self.crc_gap = true;
+        }
+        Ok(true)
+    }
+
+    /// Record the peer's reason for closing the connection and turn it into an error
+    fn handle_goaway(&mut self, data: &[u8]) -> Error {
+        let goaway = match Goaway::parse(data) {
+            Ok(goaway) => goaway,
+            Err(e) => return e,
+        };
+        conn_log!(warn, self, "Peer closed the connection with code {}: {}", goaway.code, goaway.reason);
+        self.set_state(if goaway.code == GOAWAY_NO_ERROR { ConnectionState::Closed } else { ConnectionState::Reset });
+        let error = Error::new(ErrorKind::PeerGoaway { code: goaway.code });
+        self.peer_goaway = Some(goaway);
+        error
+    }
+
+    /// Reason the peer gave for closing the connection, once a Goaway has arrived
+    pub fn peer_goaway(&self) -> Option<&Goaway> {
+        self.peer_goaway.as_ref()
+    }
+
+    /// Tell the peer the connection is being closed and why
+    ///
+    /// The Goaway packet does not consume a sequence number and is written out
+    /// at once, with the reason cut to fit one packet; closing the stream is
+    /// left to the caller. Does nothing if a handshake showed the peer does not
+    /// understand Goaway packets.
+    pub fn send_goaway(&mut self, code: u32, reason: &str) -> Result<()> {
+        self.set_state(if code == GOAWAY_NO_ERROR { ConnectionState::Closed } else { ConnectionState::Reset });
+        if self.peer_capabilities.as_ref().is_some_and(|peer| !peer.supports(Feature::Goaway)) {
+            return Ok(());
+        }
+        self.flush_writes()?;
+        let payload = Goaway::new(code, reason).to_bytes(self.max_wire_payload());
+        let mut header = PacketHeader::for_parts(PacketType::Goaway, self.send_seq, &[&payload]);
+        header.version = self.wire_version;
+        let start = self.stage_packet(&header, &[&payload])?;
+        self.stats.record_sent(self.tx_buf.len() - start);
+        self.emit(TransportEvent::PacketSent { pkt_type: PacketType::Goaway as u8, seq: header.seq, len: payload.len() });
+        self.goaway_sent = true;
+        conn_log!(debug, self, "Sent Goaway code={}: {}", code, reason);
+        self.flush_inner()
+    }
+
+    /// Stop sending but keep receiving, like `shutdown(SHUT_WR)` on a TCP socket
+    ///
+    /// Queued and coalesced data is sent first, then a Fin packet, which the
+    /// peer reads as the end of the stream once everything before it has
+    /// arrived; in ACK mode this waits until the peer has acknowledged it.
+    /// Afterwards sending fails with `WriteShutdown` while ACKs still go out
+    /// for what is received. Fails with `Unsupported` if a handshake showed
+    /// the peer does not understand Fin packets.
+    pub fn shutdown_write(&mut self) -> Result<()> {
+        self.shutdown_write_inner().map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn shutdown_write_inner(&mut self) -> Result<()> {
+        if self.write_shutdown {
+            return Ok(());
+        }
+        if self.peer_capabilities.as_ref().is_some_and(|peer| !peer.supports(Feature::HalfClose)) {
+            return Err(Error::new(ErrorKind::Unsupported));
+        }
+        self.flush_queue_inner()?;
+        self.send_packet(PacketType::Fin, &[])?;
+        self.write_shutdown = true;
+        self.set_state(if self.peer_write_shutdown { ConnectionState::Closed } else { ConnectionState::Closing });
+        conn_log!(debug, self, "Sending direction shut down");
+        self.flush_sent()
+    }
+
+    /// Packet the connection gave up on after `max_retries` retransmissions, if it did
+    ///
+    /// Set when a send fails with `MaxRetriesExceeded` and also reported as
+    /// `TransportEvent::DeliveryFailed`, so the application can resend from
+    /// that message on or drop the peer.
+    pub fn delivery_failure(&self) -> Option<DeliveryFailure> {
+        self.delivery_failure
+    }
+
+    /// Lifecycle stage of the connection, also reported as `TransportEvent::StateChanged`
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Move to `state` unless the connection is already over
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state == state || self.state.is_terminal() {
+            return;
+        }
+        conn_log!(debug, self, "Connection state {:?} -> {:?}", self.state, state);
+        self.state = state;
+        self.emit(TransportEvent::StateChanged { state });
+    }
+
+    /// Mark the connection as reset by a failure of the stream itself
+    fn stream_failed(&mut self, error: Error) -> Error {
+        self.set_state(ConnectionState::Reset);
+        error
+    }
+
+    /// Whether `shutdown_write` has closed this end's sending direction
+    pub fn is_write_shutdown(&self) -> bool {
+        self.write_shutdown
+    }
+
+    /// Whether the peer has shut down its sending direction
+    ///
+    /// Once its Fin has been read, receiving fails with `UnexpectedEof`.
+    pub fn is_peer_write_shutdown(&self) -> bool {
+        self.peer_write_shutdown
+    }
+
+    /// Tell the peer, once, that `error` ends the connection, and return the error
+    fn abort(&mut self, error: Error) -> Error {
+        if !self.goaway_sent {
+            let reason = alloc::format!("{}", error);
+            if let Err(e) = self.send_goaway(Goaway::code_for(error.kind()), &reason) {
+                conn_log!(debug, self, "Failed to send Goaway: {}", e);
+            }
+        }
+        error
+    }
+
+    /// Charge `bytes` the peer made us buffer, disconnecting it if a memory cap would be exceeded
+    fn reserve_recv_memory(&mut self, bytes: usize) -> Result<()> {
+        if self.memory.try_reserve(bytes) {
+            return Ok(());
+        }
+        conn_log!(warn, self, "Peer exceeds the memory limit: {} bytes held, {} more requested", self.memory.used(), bytes);
+        Err(self.abort(Error::new(ErrorKind::MemoryLimitExceeded)))
+    }
+
+    /// Charge `bytes` for reassembly, evicting stale partial messages while a memory cap is in the way
+    fn reserve_reassembly_memory(&mut self, bytes: usize) -> Result<()> {
+        while !self.memory.try_reserve(bytes) {
+            if !self.evict_stale_partial() {
+                return self.reserve_recv_memory(bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the least recently active message in reassembly if it is stale, returning whether one was
+    ///
+    /// The rest of its body is skipped as it arrives.
+    fn evict_stale_partial(&mut self) -> bool {
+        let timeout = self.config.reassembly_timeout_ms.saturating_mul(1000);
+        let now = match self.now() {
+            Some(now) if timeout > 0 => now,
+            _ => return false,
+        };
+        let stale = self.reassembly.iter()
+            .filter_map(|(&id, partial)| partial.last_active.map(|active| (active, id)))
+            .filter(|&(active, _)| now.saturating_sub(active) >= timeout)
+            .min();
+        let Some((active, message_id)) = stale else {
+            return false;
+        };
+        let partial = self.reassembly.remove(&message_id).expect("message found above");
+        self.memory.release(partial.data.capacity());
+        let remaining = partial.total_length - partial.received_bytes();
+        self.rejected.insert(message_id, remaining);
+        self.stats.reassembly_evictions += 1;
+        conn_log!(warn, self, "Evicting message id={} idle for {} ms after {} of {} bytes",
+                  message_id, now.saturating_sub(active) / 1000, partial.received_bytes(), partial.total_length);
+        true
+    }
+
+    /// Apply the CRC policy to a corrupted packet the decoder has skipped
+    fn handle_crc_failure(&mut self, error: Error) -> Result<()> {
+        match self.config.crc_policy {
+            CrcPolicy::Fail => Err(self.abort(error)),
+            CrcPolicy::Drop | CrcPolicy::Nack => {
+                conn_log!(warn, self, "Dropping corrupted packet, expected seq={}", self.recv_seq);
+                if !self.config.wait_for_ack {
+                    self.crc_gap = true;
+                } else if self.config.crc_policy == CrcPolicy::Nack {
+                    self.send_nack(self.recv_seq, 1)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Ask the peer to retransmit `count` packets from `seq` on without waiting for its timeout
+    ///
+    /// A NACK for more than one packet appends the count to the sequence
+    /// number; peers that only read the sequence number retransmit that one.
+    fn send_nack(&mut self, seq: u32, count: u32) -> Result<()> {
+        // Like ACKs, NACKs carry the next sequence number without consuming it
+        let mut nack_data = [0u8; 8];
+        nack_data[..4].copy_from_slice(&seq.to_le_bytes());
+        nack_data[4..].copy_from_slice(&count.to_le_bytes());
+        let nack_data = if count > 1 { &nack_data[..] } else { &nack_data[..4] };
+        let mut header = PacketHeader::for_parts(PacketType::Nack, self.send_seq, &[nack_data]);
+        header.version = self.wire_version;
+        let start = self.stage_packet(&header, &[nack_data])?;
+        self.stats.record_sent(self.tx_buf.len() - start);
+        self.flush_tx()?;
+        self.stats.nacks_sent += 1;
+        self.emit(TransportEvent::PacketSent { pkt_type: PacketType::Nack as u8, seq: header.seq, len: nack_data.len() });
+        conn_log!(debug, self, "Sent NACK for {} packets from seq={}", count, seq);
+        Ok(())
+    }
+
+    /// Retransmit the packets a NACK asks for that are still in flight, oldest first
+    fn handle_nack(&mut self, packet: &Packet) -> Result<()> {
+        if packet.data.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let seq = u32::from_le_bytes([packet.data[0], packet.data[1], packet.data[2], packet.data[3]]);
+        let count = match packet.data.get(4..8) {
+            Some(count) => u32::from_le_bytes([count[0], count[1], count[2], count[3]]).max(1),
+            None => 1,
+        };
+        self.stats.nacks_received += 1;
+
+        let entry = match self.window.get(seq) {
+            Some(entry) => entry,
+            None => {
+                conn_log!(trace, self, "Ignoring NACK for seq={} not in flight", seq);
+                return Ok(());
+            }
+        };
+        conn_log!(debug, self, "Retransmitting {} packets from seq={} on NACK", count, seq);
+        let payload_len = entry.wire.len().saturating_sub(HEADER_SIZE);
+        self.adapt_frame_size(|sizer| sizer.on_loss(payload_len));
+        let seqs: Vec<u32> = self.window.iter()
+            .map(|entry| entry.seq)
+            .filter(|&entry_seq| seq::distance(seq, entry_seq) < count)
+            .collect();
+        let now = self.now();
+        for seq in seqs {
+            self.retransmit_packet(seq, now)?;
+        }
+        self.flush_inner()
+    }
+
+    /// NACK the packets missing before the buffered ones once they have been missing for `gap_nack_ms`
+    ///
+    /// Repeated every `gap_nack_ms` while the gap stays open, so the sender
+    /// need not wait out its retransmission timeout.
+    fn nack_stale_gap(&mut self) -> Result<()> {
+        if self.reorder.is_empty() || self.config.gap_nack_ms == 0 || !self.config.wait_for_ack
+            || !self.peer_capabilities.as_ref().is_none_or(|peer| peer.supports(Feature::Nack))
+        {
+            self.gap_since = None;
+            return Ok(());
+        }
+        let now = match self.now() {
+            Some(now) => now,
+            None => return Ok(()),
+        };
+        let since = *self.gap_since.get_or_insert(now);
+        if now.saturating_sub(since) < self.config.gap_nack_ms.saturating_mul(1000) {
+            return Ok(());
+        }
+        let recv_seq = self.recv_seq;
+        let missing = self.sack_ranges().first_gap(recv_seq).max(1);
+        conn_log!(debug, self, "Packets seq={}.. missing for {}us, sending NACK", recv_seq, now - since);
+        self.gap_since = Some(now);
+        self.send_nack(recv_seq, missing)
+    }
+
+    /// Packets held back for reordering, as ranges after the next sequence number expected
+    ///
+    /// Empty while packets arrive in order.
+    pub fn sack_ranges(&self) -> SackRanges {
+        let buffered = self.reorder.range(self.recv_seq..).chain(self.reorder.range(..self.recv_seq));
+        SackRanges::from_received(self.recv_seq, buffered.map(|(&seq, _)| seq))
+    }
+
+    /// Receive the next packet in sequence order
+    ///
+    /// Duplicates are dropped and packets arriving ahead of `recv_seq` are held
+    /// back until the gap before them is filled. ACKs do not consume sequence
+    /// numbers and are returned as soon as they arrive.
+    fn recv_ordered_packet(&mut self) -> Result<Packet> {
+        loop {
+            if let Some(packet) = self.reorder.remove(&self.recv_seq) {
+                self.memory.release(packet.data.len());
+                self.recv_seq = self.recv_seq.wrapping_add(1);
+                self.gap_since = None;
+                return Ok(packet);
+            }
+            self.nack_stale_gap()?;
+            
+            let packet = self.recv_packet_internal()?;
+            // Control packets do not consume sequence numbers
+            if [PacketType::Ack, PacketType::Nack, PacketType::Goaway, PacketType::WindowUpdate].iter().any(|&t| packet.header.pkt_type == t as u8) {
+                return Ok(packet);
+            }
+            let seq = packet.header.seq;
+            let offset = seq::distance(self.recv_seq, seq);
+            
+            if offset == 0 {
+                self.recv_seq = self.recv_seq.wrapping_add(1);
+                self.gap_since = None;
+                return Ok(packet);
+            }
+            
+            if seq::before(seq, self.recv_seq) || self.reorder.contains_key(&seq) {
+                conn_log!(trace, self, "Dropping duplicate packet seq={}, expected={}", seq, self.recv_seq);
+                self.decoder.recycle(packet.data);
+                // The peer may be retransmitting because our ACK was lost
+                if self.config.wait_for_ack {
+                    self.send_ack(self.recv_seq.wrapping_sub(1))?;
+                }
+                continue;
+            }
+            
+            if self.crc_gap {
+                // Without ACK mode the packets dropped for corruption never come;
+                // resume at the oldest packet that did arrive
+                let recv_seq = self.recv_seq;
+                let next = self.reorder.keys().copied()
+                    .chain(core::iter::once(seq))
+                    .min_by_key(|&next| seq::distance(recv_seq, next))
+                    .unwrap_or(seq);
+                conn_log!(warn, self, "Skipping seq={}..{} lost to corruption", recv_seq, next);
+                self.crc_gap = false;
+                if self.lost_seqs.len() == MAX_LOST_RANGES {
+                    self.lost_seqs.pop_front();
+                }
+                self.lost_seqs.push_back((recv_seq, next));
+                self.recv_seq = next;
+                self.reserve_recv_memory(packet.data.len())?;
+                self.reorder.insert(seq, packet);
+                continue;
+            }
+            
+            if offset as usize > self.config.reorder_window {
+                conn_log!(warn, self, "Sequence gap: expected={}, got={}", self.recv_seq, seq);
+                return Err(self.abort(Error::new(ErrorKind::SequenceGap).with_seq(seq)));
+            }
+            
+            conn_log!(trace, self, "Buffering out-of-order packet seq={}, expected={}", seq, self.recv_seq);
+            self.reserve_recv_memory(packet.data.len())?;
+            self.reorder.insert(seq, packet);
+        }
+    }
+
+    /// Receive the next packet in sequence order and acknowledge it if configured
+    ///
+    /// ACKs from the peer are applied to the send window and reported as `None`.
+    fn poll_packet(&mut self) -> Result<Option<Packet>> {
+        let packet = self.recv_ordered_packet()?;
+        
+        let pkt_type = PacketType::from_u8(packet.header.pkt_type)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+        
+        if pkt_type == PacketType::Ack {
+            self.handle_ack(&packet)?;
+            self.decoder.recycle(packet.data);
+            return Ok(None);
+        }
+        if pkt_type == PacketType::Nack {
+            self.handle_nack(&packet)?;
+            self.decoder.recycle(packet.data);
+            return Ok(None);
+        }
+        if pkt_type == PacketType::WindowUpdate {
+            self.handle_window_update(&packet)?;
+            self.decoder.recycle(packet.data);
+            return Ok(None);
+        }
+        if pkt_type == PacketType::Goaway {
+            let error = self.handle_goaway(&packet.data);
+            self.decoder.recycle(packet.data);
+            return Err(error);
+        }
+        
+        if self.config.wait_for_ack {
+            self.ack_delivered(packet.header.seq)?;
+        }
+
+        Ok(Some(packet))
+    }
+
+    fn read_packet(&mut self) -> Result<Packet> {
+        loop {
+            if let Some(packet) = self.poll_packet()? {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// Next packet for the receive path, answering control packets on the way
+    fn recv_packet(&mut self) -> Result<Packet> {
+        if let Some(error) = self.deferred_error.take() {
+            return Err(error);
+        }
+        loop {
+            let packet = match self.pending.pop_front() {
+                Some(packet) => packet,
+                None if self.peer_write_shutdown => return Err(Error::new(ErrorKind::UnexpectedEof)),
+                None => self.read_packet()?,
+            };
+            if let Some(packet) = self.screen_packet(packet)? {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// Answer a control packet of the receive path, passing on the others
+    fn screen_packet(&mut self, packet: Packet) -> Result<Option<Packet>> {
+        match PacketType::from_u8(packet.header.pkt_type) {
+            Some(PacketType::Ping) if self.write_shutdown => conn_log!(debug, self, "Not answering Ping after shutdown_write"),
+            Some(PacketType::Ping) => self.send_pong(&packet.data)?,
+            Some(PacketType::Pong) => conn_log!(trace, self, "Ignoring unsolicited Pong"),
+            _ if !self.authenticated => {
+                conn_log!(warn, self, "Rejecting packet seq={} from a peer that has not authenticated", packet.header.seq);
+                self.decoder.recycle(packet.data);
+                return Err(self.abort(Error::new(ErrorKind::Unauthorized)));
+            }
+            Some(PacketType::Fin) => {
+                conn_log!(debug, self, "Peer shut down its sending direction");
+                self.peer_write_shutdown = true;
+                self.set_state(if self.write_shutdown { ConnectionState::Closed } else { ConnectionState::PeerClosing });
+                self.decoder.recycle(packet.data);
+                // Acknowledge the Fin itself before reporting the end of the stream
+                self.send_pending_ack()?;
+                self.flush_tx()?;
+                return Err(Error::new(ErrorKind::UnexpectedEof));
+            }
+            _ => return Ok(Some(packet)),
+        }
+        self.decoder.recycle(packet.data);
+        Ok(None)
+    }
+
+    /// Report an event to the configured observer and the `tracing` subscriber
+    fn emit(&mut self, event: TransportEvent) {
+        #[cfg(feature = "tracing")]
+        crate::observer::trace_event(&event);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_event(&event);
+        if let Some(observer) = self.config.observer.as_mut() {
+            observer.on_event(&event);
+        }
+    }
+
+    fn now(&self) -> Option<u64> {
+        self.config.clock.as_ref().map(|clock| clock.now_micros())
+    }
+
+    /// Answer a Ping with the local receive and send timestamps
+    ///
+    /// An authentication Ping is answered only if its response is accepted.
+    fn send_pong(&mut self, ping: &[u8]) -> Result<()> {
+        if ping.len() < PING_SIZE {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let token = le_u64(ping);
+        if token == AUTH_TOKEN {
+            self.verify_peer(&ping[PING_SIZE..])?;
+        }
+        
+        let mut pong = Vec::with_capacity(PONG_SIZE);
+        pong.extend_from_slice(&ping[0..8]);
+        if let Some(received) = self.now() {
+            pong.extend_from_slice(&received.to_le_bytes());
+            pong.extend_from_slice(&self.now().unwrap_or(received).to_le_bytes());
+        }
+        // Without a clock only the ping time is echoed back, unless capabilities or a response follow
+        if token == HELLO_TOKEN {
+            let capabilities = self.parse_capabilities(&ping[PING_SIZE..]);
+            self.receive_peer_capabilities(capabilities);
+            pong.resize(PONG_SIZE, 0);
+            pong.extend_from_slice(&self.local_capabilities().to_bytes());
+        } else if token == AUTH_TOKEN
+            && let (Some(authenticator), Some(challenge)) = (self.config.authenticator.as_mut(), self.peer_challenge.as_ref())
+        {
+            // The peer proved itself first, so a plain token is only revealed to one that knows it
+            let response = authenticator.respond(challenge);
+            pong.resize(PONG_SIZE, 0);
+            pong.extend_from_slice(&response);
+        }
+        
+        self.send_packet(PacketType::Pong, &pong)?;
+        self.flush_inner()
+    }
+
+    /// Exchange capabilities with the peer
+    ///
+    /// Only one side needs to call this; the other learns the capabilities
+    /// while answering from its receive path. A peer that predates the
+    /// handshake answers with no capabilities, so optional features can be
+    /// switched off for it.
+    pub fn handshake(&mut self) -> Result<Capabilities> {
+        self.handshake_inner().map_err(|e| e.with_phase(Phase::Handshake))
+    }
+
+    fn handshake_inner(&mut self) -> Result<Capabilities> {
+        if self.connection_id.is_none() {
+            self.connection_id = Some(new_connection_id(self.now().unwrap_or(0)));
+        }
+        // A peer taking up the offer of version 2 headers answers with one
+        self.update_decoder_limits(self.config.capabilities.supports(Feature::WireV2));
+        let mut hello = HELLO_TOKEN.to_le_bytes().to_vec();
+        hello.extend_from_slice(&self.local_capabilities().to_bytes());
+        self.send_packet(PacketType::Ping, &hello)?;
+        self.flush_inner()?;
+        
+        let pong = self.await_pong(HELLO_TOKEN)?;
+        let capabilities = self.parse_capabilities(pong.data.get(PONG_SIZE..).unwrap_or(&[]));
+        self.decoder.recycle(pong.data);
+        self.peer_challenge = capabilities.tlv(TLV_AUTH_CHALLENGE).map(<[u8]>::to_vec);
+        // Version 2 headers and the other capabilities wait until no more handshake packets go to the peer
+        self.pending_capabilities = Some(capabilities);
+        if self.peer_challenge.is_some() || !self.authenticated {
+            self.authenticate()?;
+        }
+        if let Some(capabilities) = self.pending_capabilities.take() {
+            self.record_peer_capabilities(capabilities);
+        }
+        Ok(self.peer_capabilities.clone().unwrap_or_default())
+    }
+
+    /// Wait for the Pong echoing `token`, answering Pings and keeping other packets for later
+    fn await_pong(&mut self, token: u64) -> Result<Packet> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(packet) => packet,
+                Err(e) if self.config.wait_for_ack && !self.window.is_empty()
+                    && matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+                {
+                    self.retransmit_if_expired()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match PacketType::from_u8(packet.header.pkt_type) {
+                Some(PacketType::Pong) if packet.data.len() >= PING_SIZE && le_u64(&packet.data) == token => {
+                    return Ok(packet);
+                }
+                Some(PacketType::Ping) => self.send_pong(&packet.data)?,
+                _ => self.pending.push_back(packet),
+            }
+        }
+    }
+
+    /// Establish session keys with a Noise XX handshake and encrypt everything after it
+    ///
+    /// Each handshake message travels as a single-packet message in the
+    /// clear, so run it first on a fresh connection, with a transport
+    /// configured without encryption on both ends. Returns the peer's public
+    /// static key, which the caller must check against the keys it trusts
+    /// before sending anything sensitive, unless `NoiseHandshake::verify_peer`
+    /// already did; a handshake that fails tells the peer with a Goaway.
+    #[cfg(feature = "noise")]
+    pub fn noise_handshake(&mut self, handshake: NoiseHandshake) -> Result<[u8; NOISE_KEY_LEN]> {
+        self.noise_handshake_inner(handshake).map_err(|e| e.with_phase(Phase::Handshake))
+    }
+
+    #[cfg(feature = "noise")]
+    fn noise_handshake_inner(&mut self, mut handshake: NoiseHandshake) -> Result<[u8; NOISE_KEY_LEN]> {
+        while !handshake.is_finished() {
+            if handshake.is_write_turn() {
+                let message = handshake.write_message(&[])?;
+                self.send_packet(PacketType::Data, &message)?;
+                self.flush_sent()?;
+            } else {
+                let message = self.recv_message_ext_inner()?;
+                // A peer failing the handshake learns why, e.g. that its static key is not trusted
+                if let Err(e) = handshake.read_message(&message.data) {
+                    return Err(self.abort(e));
+                }
+            }
+        }
+        // The ACK of the last message still goes out in the clear
+        self.send_pending_ack()?;
+        self.flush_inner()?;
+        let remote_static = handshake.remote_static().expect("known once the handshake is finished");
+        self.config.encryption = Some(handshake.into_encryption()?);
+        self.update_decoder_limits(self.wire_version == VERSION_2);
+        conn_log!(debug, self, "Noise handshake complete, traffic is now encrypted");
+        Ok(remote_static)
+    }
+
+    /// Capabilities sent to the peer: the configured ones plus this end's size limits
+    ///
+    /// Until the peer has authenticated, they carry a challenge for it.
+    fn local_capabilities(&mut self) -> Capabilities {
+        let mut capabilities = self.config.capabilities.clone()
+            .with_tlv(TLV_MAX_PAYLOAD_SIZE, &(self.config.payload_ceiling().min(u32::MAX as usize) as u32).to_le_bytes())
+            .with_tlv(TLV_MAX_MESSAGE_SIZE, &(self.config.max_message_size as u64).to_le_bytes());
+        // One challenge per connection, so handshakes started from both ends expect the same response
+        if !self.authenticated && let Some(authenticator) = self.config.authenticator.as_mut() {
+            let challenge = self.auth_challenge.get_or_insert_with(|| authenticator.challenge());
+            capabilities = capabilities.with_tlv(TLV_AUTH_CHALLENGE, challenge);
+        }
+        match self.connection_id {
+            Some(id) => capabilities.with_tlv(TLV_CONNECTION_ID, &id.to_le_bytes()),
+            None => capabilities,
+        }
+    }
+
+    fn parse_capabilities(&self, block: &[u8]) -> Capabilities {
+        // An empty block comes from a peer that predates the handshake
+        match block {
+            [] => Capabilities::new(),
+            block => Capabilities::parse(block).unwrap_or_else(|_| {
+                conn_log!(warn, self, "Ignoring malformed peer capabilities");
+                Capabilities::new()
+            }),
+        }
+    }
+
+    fn record_peer_capabilities(&mut self, capabilities: Capabilities) {
+        // Take the connecting side's ID, or make one up for a peer that sent none
+        let peer_id = capabilities.tlv(TLV_CONNECTION_ID)
+            .and_then(|id| <[u8; 8]>::try_from(id).ok())
+            .map(u64::from_le_bytes);
+        self.connection_id = peer_id.or(self.connection_id).or_else(|| Some(new_connection_id(self.now().unwrap_or(0))));
+        conn_log!(debug, self, "Peer capabilities: features={:#x}", capabilities.feature_bits());
+        self.emit(TransportEvent::Handshake { peer_features: capabilities.feature_bits() });
+        // Fall back to version 1 headers unless both ends parse version 2
+        self.wire_version = if capabilities.supports(Feature::WireV2) && self.config.capabilities.supports(Feature::WireV2) {
+            VERSION_2
+        } else {
+            VERSION
+        };
+        self.fragment_offsets = capabilities.supports(Feature::FragmentOffsets)
+            && self.config.capabilities.supports(Feature::FragmentOffsets);
+        self.peer_capabilities = Some(capabilities);
+        self.update_decoder_limits(self.wire_version == VERSION_2);
+        if self.state == ConnectionState::Open {
+            self.set_state(ConnectionState::Connected);
+        }
+    }
+
+    /// Bound the headers the decoder takes, and the packets resynchronizing takes for real
+    ///
+    /// A payload may be as large as this end's payload ceiling, which the
+    /// peer learns from the handshake, plus a piggybacked ACK and what sealing
+    /// adds. Version 2 headers are taken only once `version_2` says the
+    /// handshake negotiated them.
+    fn update_decoder_limits(&mut self, version_2: bool) {
+        let overhead = self.config.encryption.as_ref().map_or(0, Encryption::overhead);
+        let max_payload = self.config.payload_ceiling() + ACK_PREFIX_SIZE + overhead;
+        self.decoder.set_max_payload(max_payload);
+        self.decoder.set_version_2(version_2);
+        if self.config.max_resync_bytes > 0 {
+            self.decoder.set_resync(Some(FrameScanner::new(max_payload).with_version_2(version_2)));
+        }
+    }
+
+    /// Largest payload the peer advertised it accepts, if it did
+    fn peer_max_payload(&self) -> Option<usize> {
+        self.peer_capabilities.as_ref()
+            .and_then(|caps| caps.tlv(TLV_MAX_PAYLOAD_SIZE))
+            .and_then(|value| value.try_into().ok())
+            .map(|value| u32::from_le_bytes(value) as usize)
+    }
+
+    /// Header version of outgoing packets: 1 until a handshake negotiates 2
+    pub fn wire_version(&self) -> u8 {
+        self.wire_version
+    }
+
+    /// Largest payload the length field of the current header version can describe, less what sealing adds
+    fn max_wire_payload(&self) -> usize {
+        let limit = if self.wire_version == VERSION_2 { u32::MAX as usize } else { MAX_PAYLOAD_SIZE_V1 };
+        limit - self.config.encryption.as_ref().map_or(0, Encryption::overhead)
+    }
+
+    /// Configured payload size less what sealing adds, capped by the current header version and the peer's limit
+    fn payload_size(&self) -> usize {
+        let peer_limit = self.peer_max_payload().unwrap_or(usize::MAX);
+        let overhead = self.config.encryption.as_ref().map_or(0, Encryption::overhead);
+        self.config.max_payload_size.min(peer_limit).saturating_sub(overhead).min(self.max_wire_payload())
+    }
+
+    /// ID the handshake gave this connection, the same at both ends when both set one
+    ///
+    /// Log records of the transport start with it in hex, as `[conn 0123456789abcdef]`.
+    pub fn connection_id(&self) -> Option<u64> {
+        self.connection_id
+    }
+
+    /// Capabilities of the peer, if a handshake has taken place
+    pub fn peer_capabilities(&self) -> Option<&Capabilities> {
+        self.peer_capabilities.as_ref()
+    }
+
+    /// True if the peer advertised `feature`; false before any handshake
+    pub fn peer_supports(&self, feature: Feature) -> bool {
+        self.peer_capabilities.as_ref().is_some_and(|caps| caps.supports(feature))
+    }
+
+    /// Exchange one PING/PONG with the peer and refine the clock offset estimate
+    ///
+    /// The peer answers pings from inside its own receive calls. Call this
+    /// periodically to let the drift estimate converge.
+    pub fn sync_time(&mut self) -> Result<TimeSyncEstimate> {
+        let sent = self.now().ok_or_else(|| Error::new(ErrorKind::Unsupported))?;
+        self.send_packet(PacketType::Ping, &sent.to_le_bytes())?;
+        self.flush_inner()?;
+        
+        loop {
+            let packet = self.read_packet()?;
+            match PacketType::from_u8(packet.header.pkt_type) {
+                Some(PacketType::Pong) => {
+                    let received = self.now().unwrap_or(sent);
+                    if packet.data.len() < PING_SIZE || le_u64(&packet.data[0..8]) != sent {
+                        conn_log!(trace, self, "Ignoring stale Pong");
+                        continue;
+                    }
+                    if packet.data.len() < PONG_SIZE {
+                        conn_log!(warn, self, "Peer has no clock to answer time sync");
+                        return Err(Error::new(ErrorKind::Unsupported));
+                    }
+                    let peer_received = le_u64(&packet.data[8..16]);
+                    let peer_sent = le_u64(&packet.data[16..24]);
+                    self.time_sync.add_sample(sent, peer_received, peer_sent, received);
+                    self.record_pong_rtt(sent, received, &packet.data);
+                    break;
+                }
+                Some(PacketType::Ping) => self.send_pong(&packet.data)?,
+                _ => self.pending.push_back(packet),
+            }
+        }
+        
+        let estimate = self.time_sync.estimate()
+            .ok_or_else(|| Error::new(ErrorKind::Other))?;
+        conn_log!(debug, self, "Time sync: offset={}us, rtt={}us, drift={:.3}ppm", 
+                   estimate.offset_micros, estimate.round_trip_micros, estimate.drift_ppm);
+        Ok(estimate)
+    }
+
+    /// Current clock offset estimate, if `sync_time` has completed at least once
+    pub fn time_sync(&self) -> Option<TimeSyncEstimate> {
+        self.time_sync.estimate()
+    }
+
+    /// Payload bytes carried by one MessageData packet after its message ID prefix
+    fn chunk_size(&self) -> usize {
+        self.payload_size().saturating_sub(self.data_head_size()).max(1)
+    }
+
+    /// Bytes in front of the chunk of a MessageData packet: the message ID, then the offset if negotiated
+    fn data_head_size(&self) -> usize {
+        MESSAGE_DATA_HEAD_SIZE + if self.fragment_offsets { MESSAGE_DATA_OFFSET_SIZE } else { 0 }
+    }
+
+    /// Split a MessageData payload into its message ID, the offset of its chunk if it carries one, and the chunk
+    fn split_message_data<'a>(&self, data: &'a [u8]) -> Result<(u64, Option<usize>, &'a [u8])> {
+        let head = self.data_head_size();
+        if data.len() < head {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let offset = self.fragment_offsets
+            .then(|| usize::try_from(le_u64(&data[MESSAGE_DATA_HEAD_SIZE..])).unwrap_or(usize::MAX));
+        Ok((le_u64(data), offset, &data[head..]))
+    }
+
+    /// Send a complete message (automatically handles fragmentation)
+    pub fn send_message(&mut self, data: &[u8]) -> Result<()> {
+        self.send_message_inner(data).map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn send_message_inner(&mut self, data: &[u8]) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("send_message", conn = self.connection_id, len = data.len()).entered();
+        
+        if self.cancelled(Phase::Send) {
+            return Err(Error::new(ErrorKind::Cancelled));
+        }
+        if self.config.journal.is_some() {
+            return self.send_journaled(data);
+        }
+        if self.send_cache.is_enabled() && data.len() >= DEDUP_MIN_SIZE {
+            return self.send_cached_message(data);
+        }
+        
+        if data.len() <= self.payload_size() {
+            // Small message: single Data packet
+            self.send_packet(PacketType::Data, data)?;
+            conn_log!(debug, self, "Sent single-packet message: {} bytes", data.len());
+        } else {
+            // Large message: MessageHead + multiple MessageData packets
+            let message_id = self.begin_message(data.len())?;
+            self.send_message_data(message_id, data)?;
+        }
+        
+        self.flush_sent()?;
+        Ok(())
+    }
+
+    /// Send a message unless `token` is cancelled before it is through
+    ///
+    /// The token is checked before the first packet and before every
+    /// MessageData packet; waiting for ACKs is not interrupted. Packets always
+    /// go out whole, so a cancelled send leaves no torn frame on the stream.
+    /// A multi-packet message cut off midway is abandoned with a MessageReset
+    /// packet and the peer drops what it received of it, unless the peer
+    /// does not advertise `Feature::MessageReset`: then only a message that
+    /// has not started can be cancelled. Fails with `Cancelled`.
+    pub fn send_message_cancellable(&mut self, data: &[u8], token: &CancellationToken) -> Result<()> {
+        self.cancel = Some((token.clone(), Phase::Send));
+        let result = self.send_message_inner(data);
+        self.cancel = None;
+        result.map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn cancelled(&self, phase: Phase) -> bool {
+        self.cancel.as_ref().is_some_and(|(token, cancel_phase)| *cancel_phase == phase && token.is_cancelled())
+    }
+
+    /// Abandon a message cut off by a cancellation, telling the peer to drop what it has of it
+    fn reset_message(&mut self, message_id: u64) -> Result<Error> {
+        self.outgoing.remove(&message_id);
+        self.send_packet(PacketType::MessageReset, &message_id.to_le_bytes())?;
+        self.flush_sent()?;
+        conn_log!(debug, self, "Reset cancelled message id={}", message_id);
+        Ok(Error::new(ErrorKind::Cancelled))
+    }
+
+    /// Send a message with metadata the receiver gets from `recv_message_ext`
+    ///
+    /// A message with options always goes out with a MessageHead, bypassing the
+    /// journal and the dedup cache.
+    pub fn send_message_ext(&mut self, data: &[u8], options: MessageOptions) -> Result<()> {
+        self.send_message_ext_inner(data, options).map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn send_message_ext_inner(&mut self, data: &[u8], options: MessageOptions) -> Result<()> {
+        if options == MessageOptions::default() {
+            return self.send_message(data);
         }
+        let original = options.original
+            .filter(|_| self.peer_capabilities.as_ref().is_none_or(|peer| peer.supports(Feature::OriginalDigest)));
+        let Some(original) = original else {
+            let message_id = self.start_message(data.len(), options.to_flags() & !MESSAGE_FLAG_ORIGINAL, None)?;
+            if !data.is_empty() {
+                self.send_message_data(message_id, data)?;
+            }
+            return Ok(());
+        };
+        let mut body = Vec::with_capacity(ORIGINAL_DIGEST_SIZE + data.len());
+        body.extend_from_slice(&original.to_bytes());
+        body.extend_from_slice(data);
+        let message_id = self.start_message(body.len(), options.to_flags(), None)?;
+        self.send_message_data(message_id, &body)
     }
 
-    fn send_packet(&mut self, pkt_type: PacketType, data: &[u8]) -> Result<()> {
-        let packet = Packet::new(pkt_type, self.send_seq, data.to_vec());
-        let seq = packet.header.seq;
-        self.send_seq = self.send_seq.wrapping_add(1);
+    /// Send a message through the dedup cache, replacing a repeated payload with a reference
+    fn send_cached_message(&mut self, data: &[u8]) -> Result<()> {
+        let hash = payload_hash(data);
+        if self.send_cache.get(hash) == Some(data) {
+            let mut reference = [0u8; REFERENCE_SIZE];
+            reference[0..8].copy_from_slice(&hash.to_le_bytes());
+            reference[8..16].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            self.send_packet(PacketType::Reference, &reference)?;
+            self.flush_sent()?;
+            conn_log!(debug, self, "Sent cached message reference: hash={:016x}, {} bytes", hash, data.len());
+            return Ok(());
+        }
+        
+        // First transfer goes out in full, flagged so the receiver caches it too
+        let message_id = self.start_message(data.len(), MESSAGE_FLAG_CACHED, None)?;
+        self.send_message_data(message_id, data)?;
+        // Refer to the payload only once the peer is known to hold all of it:
+        // in ACK mode every packet has been acknowledged by now
+        if self.config.wait_for_ack {
+            self.send_cache.insert(hash, data.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Start a multi-packet message by sending its MessageHead, returning the message ID
+    ///
+    /// The body is sent with `send_message_data`, and may be interleaved with the
+    /// data of other messages started the same way.
+    pub fn begin_message(&mut self, total_length: usize) -> Result<u64> {
+        self.start_message(total_length, 0, None).map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    /// Send a message identified by an application-chosen key
+    ///
+    /// A receiver with a `DuplicatePolicy` other than `Deliver` recognizes a
+    /// message resent with the same key, e.g. by a retrying upstream, and drops
+    /// or rejects it.
+    pub fn send_keyed_message(&mut self, key: u64, data: &[u8]) -> Result<()> {
+        self.send_keyed_message_inner(key, data).map_err(|e| e.with_phase(Phase::Send))
+    }
 
-        // Combine header and data into a single buffer for atomic send
-        let header_bytes = packet.header.to_bytes();
-        let mut combined = Vec::with_capacity(header_bytes.len() + packet.data.len());
-        combined.extend_from_slice(&header_bytes);
-        combined.extend_from_slice(&packet.data);
+    fn send_keyed_message_inner(&mut self, key: u64, data: &[u8]) -> Result<()> {
+        let message_id = self.start_message(data.len(), 0, Some(key))?;
+        if !data.is_empty() {
+            self.send_message_data(message_id, data)?;
+        }
+        Ok(())
+    }
+
+    fn start_message(&mut self, total_length: usize, flags: u32, key: Option<u64>) -> Result<u64> {
+        let digest = total_length > 0 && self.digest_enabled();
+        let flags = if digest { flags | MESSAGE_FLAG_DIGEST } else { flags };
+        let message_id = self.send_message_head(total_length, flags, key)?;
+        self.flush_sent()?;
+        if total_length > 0 {
+            self.outgoing.insert(message_id, (total_length, total_length, digest.then(Crc64::new)));
+        }
+        Ok(message_id)
+    }
+
+    /// Whether multi-packet messages end with a digest: if configured and the peer did not say it cannot check it
+    fn digest_enabled(&self) -> bool {
+        self.config.message_digest
+            && self.peer_capabilities.as_ref().is_none_or(|peer| peer.supports(Feature::MessageDigest))
+    }
+
+    /// Send the MessageHead of a message of `total_length` bytes, plus its digest if flagged
+    fn send_message_head(&mut self, total_length: usize, flags: u32, key: Option<u64>) -> Result<u64> {
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
         
-        // Send combined buffer in one write call
-        self.inner.write_all(&combined)?;
+        let mut packet_count = total_length.div_ceil(self.chunk_size()) as u32;
+        let mut wire_length = total_length;
+        if flags & MESSAGE_FLAG_DIGEST != 0 {
+            packet_count += 1;
+            wire_length += MESSAGE_DIGEST_SIZE;
+        }
         
-        log::trace!("Sent packet type={:?}, seq={}, len={}", pkt_type, seq, packet.data.len());
+        let mut head = MessageHead::new(wire_length as u64, message_id, packet_count);
+        head.flags = flags;
+        if let Some(key) = key {
+            head.flags |= MESSAGE_FLAG_KEYED;
+            head.reserved = key.to_le_bytes();
+        }
+        self.next_chunk = Some((message_id, 0));
+        self.send_packet(PacketType::MessageHead, &head.to_bytes())?;
         
-        // Wait for ACK if configured and not sending an ACK itself
-        if self.config.wait_for_ack && pkt_type != PacketType::Ack {
-            let ack_packet = self.recv_packet_internal()?;
-            if ack_packet.header.pkt_type != PacketType::Ack as u8 {
-                return Err(Error::new(ErrorKind::InvalidPacket));
-            }
-            if ack_packet.data.len() < 4 {
-                return Err(Error::new(ErrorKind::InvalidPacket));
+        conn_log!(debug, self, "Sending large message: id={}, total={} bytes, packets={}", 
+                   message_id, total_length, packet_count);
+        Ok(message_id)
+    }
+
+    /// Send the next part of a message started with `begin_message`
+    pub fn send_message_data(&mut self, message_id: u64, data: &[u8]) -> Result<()> {
+        self.send_message_data_inner(message_id, data).map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn send_message_data_inner(&mut self, message_id: u64, data: &[u8]) -> Result<()> {
+        let (remaining, total, mut digest) = *self.outgoing.get(&message_id)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput))?;
+        if data.len() > remaining {
+            return Err(Error::new(ErrorKind::InvalidInput));
+        }
+        
+        let mut done = total - remaining;
+        let mut offset = 0;
+        let resettable = self.peer_capabilities.as_ref().is_none_or(|peer| peer.supports(Feature::MessageReset));
+        while offset < data.len() {
+            if resettable && self.cancelled(Phase::Send) {
+                return Err(self.reset_message(message_id)?);
             }
-            let ack_seq = u32::from_le_bytes([ack_packet.data[0], ack_packet.data[1], ack_packet.data[2], ack_packet.data[3]]);
-            if ack_seq != seq {
-                log::warn!("ACK seq mismatch: expected {}, got {}", seq, ack_seq);
-                return Err(Error::new(ErrorKind::InvalidPacket));
+            // Adaptive framing may change the chunk size from one packet to the next
+            let end = data.len().min(offset + self.chunk_size());
+            self.send_data_packet(message_id, done, &data[offset..end])?;
+            if let Some(digest) = digest.as_mut() {
+                digest.update(&data[offset..end]);
             }
-            log::trace!("Received ACK for seq={}", seq);
+            done += end - offset;
+            offset = end;
+            self.report_progress(Transfer::Send, done.div_ceil(self.chunk_size()) as u32, done, total);
         }
+        self.flush_sent()?;
         
+        if data.len() == remaining {
+            self.outgoing.remove(&message_id);
+            if let Some(digest) = digest {
+                self.send_data_packet(message_id, total, &digest.finalize().to_le_bytes())?;
+                self.flush_sent()?;
+            }
+            conn_log!(debug, self, "Large message sent: id={}", message_id);
+        } else {
+            self.outgoing.insert(message_id, (remaining - data.len(), total, digest));
+        }
         Ok(())
     }
 
-    fn send_ack(&mut self, seq: u32) -> Result<()> {
-        let ack_data = seq.to_le_bytes();
-        let ack_packet = Packet::new(PacketType::Ack, self.send_seq, ack_data.to_vec());
-        self.send_seq = self.send_seq.wrapping_add(1);
-        
-        let header_bytes = ack_packet.header.to_bytes();
-        let mut combined = Vec::with_capacity(header_bytes.len() + ack_packet.data.len());
-        combined.extend_from_slice(&header_bytes);
-        combined.extend_from_slice(&ack_packet.data);
-        self.inner.write_all(&combined)?;
-        
-        log::trace!("Sent ACK for seq={}", seq);
-        Ok(())
+    /// Call the progress callback every `progress_interval` packets and on the last one
+    fn report_progress(&mut self, transfer: Transfer, packets: u32, done: usize, total: usize) {
+        let interval = self.config.progress_interval.max(1);
+        if let Some(progress) = self.config.progress.as_mut()
+            && (done == total || packets.is_multiple_of(interval))
+        {
+            progress(transfer, done, total);
+        }
     }
 
-    fn recv_packet_internal(&mut self) -> Result<Packet> {
-        // Read header
-        let mut header_buf = [0u8; HEADER_SIZE];
-        self.inner.read_exact(&mut header_buf)?;
-        let header = PacketHeader::from_bytes(&header_buf)?;
+    /// Send `chunk`, the bytes of a message from `offset` on, in a MessageData packet
+    fn send_data_packet(&mut self, message_id: u64, offset: usize, chunk: &[u8]) -> Result<()> {
+        // Every MessageData packet is prefixed with its message ID, and the
+        // offset of its chunk if the peer reads it
+        let mut payload = Vec::with_capacity(self.data_head_size() + chunk.len());
+        payload.extend_from_slice(&message_id.to_le_bytes());
+        if self.fragment_offsets {
+            payload.extend_from_slice(&(offset as u64).to_le_bytes());
+        }
+        payload.extend_from_slice(chunk);
+        self.next_chunk = Some((message_id, offset));
+        self.send_packet(PacketType::MessageData, &payload)
+    }
+
+    /// Add a message to the send queue of `class`, returning its queue ID
+    ///
+    /// Queued messages are sent by `poll_send` or `flush_queue`, packet by
+    /// packet, lowest class first and each class within its `with_class_rate`
+    /// cap, so a capped bulk class cannot crowd out the other classes.
+    ///
+    /// Fails with `WouldBlock` while the message would take the connection
+    /// over its memory cap or the shared budget, until queued messages are
+    /// sent, and with `MemoryLimitExceeded` if it alone exceeds the cap.
+    pub fn queue_message(&mut self, class: u8, data: &[u8]) -> Result<u64> {
+        if self.config.max_connection_memory > 0 && data.len() > self.config.max_connection_memory {
+            return Err(Error::new(ErrorKind::MemoryLimitExceeded).with_phase(Phase::Send));
+        }
+        if !self.memory.try_reserve(data.len()) {
+            return Err(Error::new(ErrorKind::WouldBlock).with_phase(Phase::Send));
+        }
+        let now = self.now();
+        Ok(self.scheduler.push(class, data.to_vec(), now))
+    }
 
-        // Read data
-        let mut data = alloc::vec![0u8; header.length as usize];
-        self.inner.read_exact(&mut data)?;
+    /// Bytes this connection holds in reordering, reassembly and send queue buffers
+    pub fn memory_used(&self) -> usize {
+        self.memory.used()
+    }
 
-        let packet = Packet { header, data };
+    /// Messages in the send queue that have not been completely sent
+    pub fn queued_messages(&self) -> Vec<QueuedMessageInfo> {
+        self.scheduler.list(self.now())
+    }
 
-        // Verify CRC
-        if !packet.verify_crc() {
-            return Err(Error::new(ErrorKind::CrcMismatch));
+    /// Drop a queued message before any of it is sent, e.g. a superseded snapshot
+    ///
+    /// Returns false if the message is unknown, already sent, or partially
+    /// sent: once its first packet is on the wire it has to be completed.
+    pub fn cancel_queued(&mut self, id: u64) -> bool {
+        match self.scheduler.cancel(id) {
+            Some(message) => {
+                self.memory.release(message.data.len());
+                conn_log!(debug, self, "Queued message {} cancelled", id);
+                true
+            }
+            None => false,
         }
+    }
 
-        log::trace!("Received packet seq={}, len={}", packet.header.seq, packet.data.len());
-
-        Ok(packet)
+    /// Send queued packets as far as the class rates allow, returning how many were sent
+    pub fn poll_send(&mut self) -> Result<usize> {
+        self.poll_send_inner().map_err(|e| e.with_phase(Phase::Send))
     }
 
-    fn recv_packet(&mut self) -> Result<Packet> {
-        let packet = self.recv_packet_internal()?;
-        
-        // Send ACK if configured and not receiving an ACK itself
-        let pkt_type = PacketType::from_u8(packet.header.pkt_type)
-            .ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
-        
-        if self.config.wait_for_ack && pkt_type != PacketType::Ack {
-            self.send_ack(packet.header.seq)?;
+    fn poll_send_inner(&mut self) -> Result<usize> {
+        let mut sent = 0;
+        while let Some(class) = self.scheduler.next_class(self.now()) {
+            self.send_queued_packet(class)?;
+            sent += 1;
         }
-        
-        // Update receive sequence
-        self.recv_seq = packet.header.seq.wrapping_add(1);
+        if sent > 0 {
+            self.flush_inner()?;
+        }
+        Ok(sent)
+    }
 
-        Ok(packet)
+    /// Microseconds until `poll_send` can send more, or `None` with nothing queued
+    pub fn send_delay(&mut self) -> Option<u64> {
+        let now = self.now();
+        self.scheduler.delay(now)
     }
 
-    /// Send a complete message (automatically handles fragmentation)
-    pub fn send_message(&mut self, data: &[u8]) -> Result<()> {
-        if data.len() <= self.config.max_payload_size {
-            // Small message: single Data packet
-            self.send_packet(PacketType::Data, data)?;
-            log::debug!("Sent single-packet message: {} bytes", data.len());
-        } else {
-            // Large message: MessageHead + multiple MessageData packets
-            let message_id = self.next_message_id;
-            self.next_message_id = self.next_message_id.wrapping_add(1);
-            
-            let packet_count = ((data.len() + self.config.max_payload_size - 1) / self.config.max_payload_size) as u32;
-            
-            // Send MessageHead
-            let head = MessageHead::new(data.len() as u64, message_id, packet_count);
-            self.send_packet(PacketType::MessageHead, &head.to_bytes())?;
-            
-            log::debug!("Sending large message: id={}, total={} bytes, packets={}", 
-                       message_id, data.len(), packet_count);
-            
-            // Send MessageData packets
-            for chunk in data.chunks(self.config.max_payload_size) {
-                self.send_packet(PacketType::MessageData, chunk)?;
+    /// Send everything queued, sleeping on the clock while every class is over its rate
+    pub fn flush_queue(&mut self) -> Result<()> {
+        self.flush_queue_inner().map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn flush_queue_inner(&mut self) -> Result<()> {
+        loop {
+            self.poll_send()?;
+            match self.send_delay() {
+                None => break,
+                Some(0) => {}
+                Some(delay) => {
+                    if let Some(clock) = self.config.clock.as_ref() {
+                        clock.sleep_micros(delay);
+                    }
+                }
             }
-            
-            log::debug!("Large message sent: id={}", message_id);
         }
+        // Sending the queue frees the memory it was charged
+        self.update_window()?;
+        self.flush_sent()
+    }
+
+    /// Send the next packet of the message at the head of a class queue
+    fn send_queued_packet(&mut self, class: u8) -> Result<()> {
+        let mut queued = match self.scheduler.pop_front(class) {
+            Some(queued) => queued,
+            None => return Ok(()),
+        };
+        let total = queued.data.len();
+        
+        let wire_len = match queued.message_id {
+            None if total <= self.payload_size() => {
+                self.send_packet(PacketType::Data, &queued.data)?;
+                queued.offset = total;
+                HEADER_SIZE + total
+            }
+            None => {
+                queued.digest = self.digest_enabled();
+                let flags = if queued.digest { MESSAGE_FLAG_DIGEST } else { 0 };
+                queued.message_id = Some(self.send_message_head(total, flags, None)?);
+                HEADER_SIZE + MESSAGE_HEAD_SIZE
+            }
+            Some(message_id) => {
+                let end = total.min(queued.offset + self.chunk_size());
+                self.send_data_packet(message_id, queued.offset, &queued.data[queued.offset..end])?;
+                let mut len = end - queued.offset;
+                queued.offset = end;
+                self.report_progress(Transfer::Send, end.div_ceil(self.chunk_size()) as u32, end, total);
+                if end == total && queued.digest {
+                    self.send_data_packet(message_id, queued.data.len(), &crc64(&queued.data).to_le_bytes())?;
+                    len += HEADER_SIZE + self.data_head_size() + MESSAGE_DIGEST_SIZE;
+                }
+                HEADER_SIZE + self.data_head_size() + len
+            }
+        };
+        self.scheduler.charge(class, self.now(), wire_len);
         
-        self.inner.flush()?;
+        if queued.offset < total {
+            self.scheduler.push_front(class, queued);
+        } else {
+            self.memory.release(total);
+            conn_log!(debug, self, "Queued message {} sent: {} bytes, class {}", queued.id, total, class);
+        }
         Ok(())
     }
 
     /// Receive a complete message (automatically handles reassembly)
+    ///
+    /// MessageData packets of different messages may arrive interleaved; each
+    /// message is returned as soon as its last packet has been received. The
+    /// messages of a group are returned one by one once the whole group has arrived.
     pub fn recv_message(&mut self) -> Result<Vec<u8>> {
-        // Read first packet to determine type
-        let mut header_buf = [0u8; HEADER_SIZE];
-        self.inner.read_exact(&mut header_buf)?;
-        let header = PacketHeader::from_bytes(&header_buf)?;
+        self.recv_message_ext().map(|message| message.data)
+    }
+
+    /// Receive a complete message along with the options and key it was sent with
+    pub fn recv_message_ext(&mut self) -> Result<Message> {
+        self.recv_message_ext_inner().map_err(|e| e.with_phase(Phase::Recv))
+    }
+
+    fn recv_message_ext_inner(&mut self) -> Result<Message> {
+        if let Some(message) = self.ready.pop_front() {
+            return Ok(message);
+        }
+        let mut messages = self.recv_delivery()?.into_iter();
+        let first = messages.next().ok_or_else(|| Error::new(ErrorKind::Other))?;
+        self.ready.extend(messages);
+        Ok(first)
+    }
+
+    /// Receive a message unless `token` is cancelled first
+    ///
+    /// The token is checked before every read of the stream, so a read that
+    /// blocks is only interrupted by data or its read timeout. A message
+    /// partly received stays buffered for the next receive call. Fails with
+    /// `Cancelled`.
+    pub fn recv_message_cancellable(&mut self, token: &CancellationToken) -> Result<Vec<u8>> {
+        self.cancel = Some((token.clone(), Phase::Recv));
+        let result = self.recv_message_ext_inner();
+        self.cancel = None;
+        result.map(|message| message.data).map_err(|e| e.with_phase(Phase::Recv))
+    }
+
+    /// Receive a burst: the next message and the complete messages already read after it
+    ///
+    /// Only the first message may wait for the stream. The others come from
+    /// packets already read, up to `max_n` messages in all and without going
+    /// over `max_bytes`, though the first is returned whatever its size. An
+    /// error met after the first message ends the burst and is returned by
+    /// the next receive call.
+    pub fn recv_messages(&mut self, max_n: usize, max_bytes: usize) -> Result<Vec<Vec<u8>>> {
+        self.recv_messages_inner(max_n, max_bytes).map_err(|e| e.with_phase(Phase::Recv))
+    }
+
+    fn recv_messages_inner(&mut self, max_n: usize, max_bytes: usize) -> Result<Vec<Vec<u8>>> {
+        if max_n == 0 {
+            return Ok(Vec::new());
+        }
+        let first = self.recv_message_ext_inner()?.data;
+        let mut bytes = first.len();
+        let mut messages = alloc::vec![first];
+        while messages.len() < max_n {
+            let Some(next) = self.ready.front() else {
+                match self.recv_buffered_delivery() {
+                    Ok(Some(delivery)) => self.ready.extend(delivery),
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.deferred_error = Some(e);
+                        break;
+                    }
+                }
+                continue;
+            };
+            if bytes + next.data.len() > max_bytes {
+                break;
+            }
+            bytes += next.data.len();
+            messages.extend(self.ready.pop_front().map(|message| message.data));
+        }
+        conn_log!(trace, self, "Received burst of {} messages, {} bytes", messages.len(), bytes);
+        Ok(messages)
+    }
+
+    /// Next delivery that packets already read complete, or `None` if it would take reading the stream
+    fn recv_buffered_delivery(&mut self) -> Result<Option<Vec<Message>>> {
+        loop {
+            let packet = match self.pending.pop_front() {
+                Some(packet) => packet,
+                None if self.peer_write_shutdown => return Ok(None),
+                None => {
+                    self.buffered_only = true;
+                    let result = self.poll_packet();
+                    self.buffered_only = false;
+                    match result {
+                        Ok(Some(packet)) => packet,
+                        Ok(None) => continue,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                        Err(e) => return Err(e),
+                    }
+                }
+            };
+            let Some(packet) = self.screen_packet(packet)? else {
+                continue;
+            };
+            if let Some(delivery) = self.handle_delivery_packet(packet)? {
+                self.finish_delivery()?;
+                return Ok(Some(delivery));
+            }
+        }
+    }
+
+    /// Receive the next message, or the next whole group of messages
+    fn recv_delivery(&mut self) -> Result<Vec<Message>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("recv_message", conn = self.connection_id).entered();
         
-        let pkt_type = PacketType::from_u8(header.pkt_type)
+        loop {
+            let packet = self.recv_packet()?;
+            if let Some(delivery) = self.handle_delivery_packet(packet)? {
+                self.finish_delivery()?;
+                return Ok(delivery);
+            }
+        }
+    }
+
+    /// Feed one packet of the receive path, returning a delivery once it completes one
+    fn handle_delivery_packet(&mut self, mut packet: Packet) -> Result<Option<Vec<Message>>> {
+        let pkt_type = PacketType::from_u8(packet.header.pkt_type)
             .ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+        let seq = packet.header.seq;
         
-        match pkt_type {
+        let message = match pkt_type {
+            PacketType::Batch => {
+                let messages = self.handle_batch(&packet.data);
+                self.decoder.recycle(packet.data);
+                let mut delivery = Vec::new();
+                for message in messages? {
+                    delivery.extend(self.stage_or_deliver(message)?.into_iter().flatten());
+                }
+                return Ok((!delivery.is_empty()).then_some(delivery));
+            }
             PacketType::Data => {
-                // Single packet message
-                let mut data = alloc::vec![0u8; header.length as usize];
-                self.inner.read_exact(&mut data)?;
-                
-                let packet = Packet { header, data };
-                if !packet.verify_crc() {
-                    return Err(Error::new(ErrorKind::CrcMismatch));
+                conn_log!(debug, self, "Received single-packet message: {} bytes", packet.data.len());
+                Some(Message::plain(core::mem::take(&mut packet.data)))
+            }
+            PacketType::MessageHead => self.handle_message_head(seq, &packet.data)?,
+            PacketType::MessageData => self.handle_message_data(seq, &packet.data)?,
+            PacketType::Reference => Some(Message::plain(self.handle_reference(&packet.data)?)),
+            PacketType::GroupHead => {
+                self.handle_group_head(&packet.data)?;
+                None
+            }
+            PacketType::MessageReset => {
+                self.handle_message_reset(&packet.data)?;
+                None
+            }
+            // Control packets are consumed by `recv_packet`
+            PacketType::Ack | PacketType::Nack | PacketType::Ping | PacketType::Pong | PacketType::Goaway | PacketType::Fin
+            | PacketType::WindowUpdate => None,
+        };
+        // Everything but a Data payload has been copied out of the packet
+        self.decoder.recycle(packet.data);
+        match message {
+            Some(message) => self.stage_or_deliver(message),
+            None => Ok(None),
+        }
+    }
+
+    /// Drop what was received of a message its sender abandoned
+    fn handle_message_reset(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() < MESSAGE_RESET_SIZE {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let message_id = le_u64(data);
+        self.rejected.remove(&message_id);
+        match self.reassembly.remove(&message_id) {
+            Some(partial) => {
+                self.memory.release(partial.data.capacity());
+                conn_log!(debug, self, "Message id={} reset by the peer after {} of {} bytes",
+                           message_id, partial.received_bytes(), partial.total_length);
+            }
+            None => conn_log!(debug, self, "Ignoring reset of message id={} not being received", message_id),
+        }
+        Ok(())
+    }
+
+    /// Acknowledge what was received before handing a delivery to the application
+    fn finish_delivery(&mut self) -> Result<()> {
+        // A delayed ACK waits for the next receive to run dry, unless it is
+        // already overdue; a coalesced one must not sit in the burst buffer
+        self.send_due_ack()?;
+        self.flush_tx()?;
+        self.update_window()
+    }
+
+    /// Receive the next message piece by piece, returning its total length
+    ///
+    /// The body of a multi-packet message is passed to `on_chunk` straight
+    /// from each MessageData packet as it arrives, without being reassembled,
+    /// so a large message can be hashed or written to disk incrementally.
+    /// Other messages (single-packet, cached, keyed repeats, group members)
+    /// are passed as one chunk once complete. If `on_chunk` fails, the rest of
+    /// the message is discarded and the error returned.
+    pub fn recv_message_chunks<F>(&mut self, on_chunk: F) -> Result<usize>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        self.recv_message_chunks_inner(on_chunk).map_err(|e| e.with_phase(Phase::Recv))
+    }
+
+    fn recv_message_chunks_inner<F>(&mut self, mut on_chunk: F) -> Result<usize>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        if let Some(message) = self.ready.pop_front() {
+            on_chunk(&message.data)?;
+            return Ok(message.data.len());
+        }
+        
+        // Message being streamed: ID, total length, bytes passed on so far and sequence number of its last packet
+        let mut streaming: Option<(u64, usize, usize, u32)> = None;
+        let mut packets = 0;
+        loop {
+            let packet = self.recv_packet()?;
+            let pkt_type = PacketType::from_u8(packet.header.pkt_type);
+            
+            if let Some((message_id, ..)) = streaming
+                && pkt_type == Some(PacketType::MessageReset)
+                && packet.data.len() >= MESSAGE_RESET_SIZE
+                && le_u64(&packet.data) == message_id
+            {
+                conn_log!(debug, self, "Streamed message id={} reset by the peer", message_id);
+                self.decoder.recycle(packet.data);
+                return Err(Error::new(ErrorKind::Cancelled));
+            }
+            if let Some((message_id, total, done, last_seq)) = streaming
+                && pkt_type == Some(PacketType::MessageData)
+                && let Ok((id, offset, chunk)) = self.split_message_data(&packet.data)
+                && id == message_id
+            {
+                let seq = packet.header.seq;
+                let continuity = match offset {
+                    Some(_) => check_offset(self.connection_id, message_id, offset, done),
+                    None => self.check_continuity(message_id, last_seq, seq),
+                };
+                if let Err(e) = continuity {
+                    if done + chunk.len() < total {
+                        self.rejected.insert(message_id, total - done - chunk.len());
+                    }
+                    return Err(e);
                 }
-                
-                // Send ACK if configured
-                if self.config.wait_for_ack {
-                    self.send_ack(packet.header.seq)?;
+                let done = done + chunk.len();
+                if done > total {
+                    return Err(Error::new(ErrorKind::InvalidPacket));
                 }
-                
-                log::debug!("Received single-packet message: {} bytes", packet.data.len());
-                Ok(packet.data)
-            }
-            PacketType::MessageHead => {
-                // Multi-packet message
-                let mut head_data = alloc::vec![0u8; header.length as usize];
-                self.inner.read_exact(&mut head_data)?;
-                
-                let packet = Packet { header, data: head_data };
-                if !packet.verify_crc() {
-                    return Err(Error::new(ErrorKind::CrcMismatch));
+                streaming = Some((message_id, total, done, seq));
+                packets += 1;
+                self.report_progress(Transfer::Receive, packets, done, total);
+                let result = on_chunk(chunk);
+                self.decoder.recycle(packet.data);
+                if let Err(e) = result {
+                    if done < total {
+                        self.rejected.insert(message_id, total - done);
+                    }
+                    return Err(e);
                 }
-                
-                // Send ACK for MessageHead if configured
-                if self.config.wait_for_ack {
-                    self.send_ack(packet.header.seq)?;
+                if done == total {
+                    conn_log!(debug, self, "Large message streamed: id={}, {} bytes", message_id, total);
+                    self.finish_delivery()?;
+                    return Ok(total);
                 }
-                
-                if packet.data.len() < MESSAGE_HEAD_SIZE {
-                    return Err(Error::new(ErrorKind::InvalidPacket));
+                continue;
+            }
+            
+            if streaming.is_none() && self.staged_group.is_none() && pkt_type == Some(PacketType::MessageHead) {
+                streaming = self.stream_message_head(packet.header.seq, &packet.data)?;
+                self.decoder.recycle(packet.data);
+            } else if let Some(delivery) = self.handle_delivery_packet(packet)? {
+                self.ready.extend(delivery);
+            }
+            
+            // Messages completed while streaming wait for the next receive call
+            if streaming.is_none() && let Some(message) = self.ready.pop_front() {
+                self.finish_delivery()?;
+                on_chunk(&message.data)?;
+                return Ok(message.data.len());
+            }
+        }
+    }
+
+    /// Accept a MessageHead for streaming, or return `None` to reassemble it as usual
+    fn stream_message_head(&mut self, seq: u32, data: &[u8]) -> Result<Option<(u64, usize, usize, u32)>> {
+        let msg_head = MessageHead::parse(data)?;
+        let total_length = msg_head.validate()?;
+        let tracked = self.config.duplicate_policy != DuplicatePolicy::Deliver;
+        // Cached payloads are needed whole, keyed ones and repeated IDs may be
+        // duplicates, digests can only be checked once the whole message is in
+        // and an original digest is delivered with the message
+        let streamable = total_length > 0
+            && total_length <= self.config.max_message_size
+            && msg_head.flags & (MESSAGE_FLAG_CACHED | MESSAGE_FLAG_KEYED | MESSAGE_FLAG_DIGEST | MESSAGE_FLAG_ORIGINAL) == 0
+            && !self.reassembly.contains_key(&msg_head.message_id)
+            && !(tracked && self.seen_message_ids.contains(msg_head.message_id));
+        if !streamable {
+            return self.handle_message_head(seq, data).map(|message| {
+                if let Some(message) = message {
+                    self.ready.push_back(message);
                 }
-                
-                let mut head_bytes = [0u8; MESSAGE_HEAD_SIZE];
-                head_bytes.copy_from_slice(&packet.data[..MESSAGE_HEAD_SIZE]);
-                let msg_head = MessageHead::from_bytes(&head_bytes)?;
-                
-                log::debug!("Receiving large message: id={}, total={} bytes, packets={}", 
-                           msg_head.message_id, msg_head.total_length, msg_head.packet_count);
-                
-                // Receive all data packets
-                let mut result = alloc::vec![0u8; msg_head.total_length as usize];
-                let mut offset = 0;
-                
-                for i in 0..msg_head.packet_count {
-                    let mut data_header_buf = [0u8; HEADER_SIZE];
-                    self.inner.read_exact(&mut data_header_buf)?;
-                    let data_header = PacketHeader::from_bytes(&data_header_buf)?;
-                    
-                    let data_type = PacketType::from_u8(data_header.pkt_type)
-                        .ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
-                    
-                    if data_type != PacketType::MessageData {
-                        return Err(Error::new(ErrorKind::InvalidPacket));
-                    }
-                    
-                    let mut chunk = alloc::vec![0u8; data_header.length as usize];
-                    self.inner.read_exact(&mut chunk)?;
-                    
-                    let data_packet = Packet { header: data_header, data: chunk };
-                    if !data_packet.verify_crc() {
-                        return Err(Error::new(ErrorKind::CrcMismatch));
-                    }
-                    
-                    // Send ACK for each MessageData if configured
-                    if self.config.wait_for_ack {
-                        self.send_ack(data_packet.header.seq)?;
-                    }
-                    
-                    let to_copy = core::cmp::min(data_packet.data.len(), result.len() - offset);
-                    result[offset..offset + to_copy].copy_from_slice(&data_packet.data[..to_copy]);
-                    offset += to_copy;
-                    
-                    if (i + 1) % 100 == 0 || i + 1 == msg_head.packet_count {
-                        log::debug!("Progress: {}/{} packets received", i + 1, msg_head.packet_count);
-                    }
+                None
+            });
+        }
+        if tracked {
+            self.seen_message_ids.insert(msg_head.message_id);
+        }
+        conn_log!(debug, self, "Streaming large message: id={}, total={} bytes, packets={}", 
+                   msg_head.message_id, total_length, msg_head.packet_count);
+        Ok(Some((msg_head.message_id, total_length, 0, seq)))
+    }
+
+    /// Register a new in-flight message, returning it directly if it has no body
+    fn handle_message_head(&mut self, seq: u32, data: &[u8]) -> Result<Option<Message>> {
+        let msg_head = MessageHead::parse(data)?;
+        let total_length = msg_head.validate()?;
+        let mut digest_size = if msg_head.flags & MESSAGE_FLAG_DIGEST != 0 { MESSAGE_DIGEST_SIZE } else { 0 };
+        if msg_head.flags & MESSAGE_FLAG_ORIGINAL != 0 {
+            digest_size += ORIGINAL_DIGEST_SIZE;
+        }
+        if total_length < digest_size {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        if total_length - digest_size > self.config.max_message_size {
+            conn_log!(warn, self, "Rejecting message id={}: {} bytes exceeds limit of {}", 
+                      msg_head.message_id, total_length, self.config.max_message_size);
+            // Skip its body so the connection stays usable
+            self.reject_message(msg_head.message_id, total_length);
+            return Err(Error::new(ErrorKind::MessageTooLarge));
+        }
+        
+        conn_log!(debug, self, "Receiving large message: id={}, total={} bytes, packets={}", 
+                   msg_head.message_id, msg_head.total_length, msg_head.packet_count);
+        
+        let key = (msg_head.flags & MESSAGE_FLAG_KEYED != 0).then(|| u64::from_le_bytes(msg_head.reserved));
+        let tracked = self.config.duplicate_policy != DuplicatePolicy::Deliver;
+        let repeated_key = match key {
+            Some(key) if tracked => !self.seen_keys.insert(key),
+            _ => false,
+        };
+        // A second head for a message still in flight is rejected below instead
+        let repeated_id = tracked
+            && !self.reassembly.contains_key(&msg_head.message_id)
+            && !self.seen_message_ids.insert(msg_head.message_id);
+        if repeated_key {
+            conn_log!(debug, self, "Message id={} repeats key={:?}", msg_head.message_id, key);
+        }
+        if repeated_id {
+            conn_log!(debug, self, "Message id={} was already received", msg_head.message_id);
+        }
+        let duplicate = repeated_key || repeated_id;
+        
+        if total_length == 0 {
+            let message = Message {
+                data: Vec::new(),
+                options: MessageOptions::from_flags(msg_head.flags),
+                key,
+            };
+            return self.deliver(message, duplicate);
+        }
+        if self.reassembly.contains_key(&msg_head.message_id) {
+            conn_log!(warn, self, "Duplicate MessageHead for in-flight message id={}", msg_head.message_id);
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let limit = self.config.max_partial_messages;
+        if limit > 0 && self.reassembly.len() >= limit && !self.evict_stale_partial() {
+            conn_log!(warn, self, "Rejecting message id={}: {} messages already in reassembly",
+                      msg_head.message_id, self.reassembly.len());
+            self.rejected.insert(msg_head.message_id, total_length);
+            return Err(Error::new(ErrorKind::ReassemblyLimitExceeded));
+        }
+        
+        // Grow with the data actually received rather than trusting the head
+        let capacity = total_length.min(INITIAL_REASSEMBLY_CAPACITY);
+        self.reserve_reassembly_memory(capacity)?;
+        self.reassembly.insert(msg_head.message_id, PartialMessage {
+            data: Vec::with_capacity(capacity),
+            total_length,
+            flags: msg_head.flags,
+            key,
+            packet_count: msg_head.packet_count,
+            packets_received: 0,
+            duplicate,
+            last_active: self.now(),
+            last_seq: seq,
+            received: Vec::new(),
+        });
+        Ok(None)
+    }
+
+    /// Skip the remaining body of a rejected message, forgetting the lowest (oldest) message ID beyond the limit
+    ///
+    /// A peer can send heads without ever sending their bodies, so the list
+    /// is bounded. MessageData of a forgotten message fails like that of an
+    /// unknown one.
+    fn reject_message(&mut self, message_id: u64, remaining: usize) {
+        self.rejected.insert(message_id, remaining);
+        if self.rejected.len() > MAX_REJECTED_MESSAGES
+            && let Some((oldest, _)) = self.rejected.pop_first()
+        {
+            conn_log!(debug, self, "No longer skipping the body of rejected message id={}", oldest);
+        }
+    }
+
+    /// Place a MessageData packet in its message, returning the message once complete
+    ///
+    /// Chunks without an offset are appended. One carrying its offset may land
+    /// ahead of the bytes received so far and is placed there, the hole before
+    /// it tracked until filled; the message is delivered only once none is left.
+    fn handle_message_data(&mut self, seq: u32, data: &[u8]) -> Result<Option<Message>> {
+        let (message_id, offset, chunk) = self.split_message_data(data)?;
+        
+        if let Some(remaining) = self.rejected.get_mut(&message_id) {
+            *remaining = remaining.saturating_sub(chunk.len());
+            if *remaining == 0 {
+                self.rejected.remove(&message_id);
+            }
+            return Ok(None);
+        }
+        let continuity = match self.reassembly.get(&message_id) {
+            Some(partial) => {
+                let at = offset.unwrap_or(partial.data.len());
+                if !partial.accepts(at, chunk.len()) {
+                    conn_log!(warn, self, "MessageData for id={} at offset {} overlaps or overruns the {} bytes received", 
+                              message_id, at, partial.received_bytes());
+                    Err(Error::new(ErrorKind::InvalidPacket))
+                } else if offset.is_none() || at > partial.data.len() {
+                    // A hole left by packets skipped as lost would never be filled
+                    self.check_continuity(message_id, partial.last_seq, seq)
+                } else {
+                    Ok(())
+                }
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = continuity {
+            // The message cannot be completed; drop it and skip the rest of its body
+            if let Some(partial) = self.reassembly.remove(&message_id) {
+                self.memory.release(partial.data.capacity());
+                let remaining = partial.total_length.saturating_sub(partial.received_bytes() + chunk.len());
+                if remaining > 0 {
+                    self.rejected.insert(message_id, remaining);
                 }
-                
-                log::debug!("Large message received: id={}, {} bytes", msg_head.message_id, result.len());
-                Ok(result)
             }
-            PacketType::MessageData | PacketType::Ack => {
-                // Unexpected: should not receive MessageData or Ack as first packet
-                Err(Error::new(ErrorKind::InvalidPacket))
+            return Err(e);
+        }
+        
+        let now = self.now();
+        let partial = self.reassembly.get_mut(&message_id).ok_or_else(|| {
+            conn_log!(warn, self, "MessageData for unknown message id={}", message_id);
+            Error::new(ErrorKind::InvalidPacket)
+        })?;
+        partial.last_active = now;
+        
+        let at = offset.unwrap_or(partial.data.len());
+        let needed = partial.data.len().max(at + chunk.len());
+        if needed > partial.data.capacity() {
+            let capacity = needed.max(partial.data.capacity() * 2).min(partial.total_length);
+            let growth = capacity - partial.data.capacity();
+            self.reserve_reassembly_memory(growth)?;
+            let partial = self.reassembly.get_mut(&message_id).expect("message found above");
+            partial.data.reserve_exact(capacity - partial.data.len());
+        }
+        let partial = self.reassembly.get_mut(&message_id).expect("message found above");
+        partial.write_at(at, chunk);
+        partial.packets_received += 1;
+        partial.last_seq = seq;
+        
+        if partial.packets_received.is_multiple_of(100) || partial.is_complete() {
+            conn_log!(debug, self, "Progress: id={}, {}/{} packets received", 
+                       message_id, partial.packets_received, partial.packet_count);
+        }
+        let (packets, done, total) = (partial.packets_received, partial.received_bytes(), partial.total_length);
+        self.report_progress(Transfer::Receive, packets, done, total);
+        
+        if done < total {
+            return Ok(None);
+        }
+        
+        let mut partial = match self.reassembly.remove(&message_id) {
+            Some(partial) => partial,
+            None => return Ok(None),
+        };
+        self.memory.release(partial.data.capacity());
+        conn_log!(debug, self, "Large message received: id={}, {} bytes", message_id, partial.data.len());
+        
+        if partial.flags & MESSAGE_FLAG_DIGEST != 0 {
+            let body_length = partial.data.len() - MESSAGE_DIGEST_SIZE;
+            let expected = le_u64(&partial.data[body_length..]);
+            partial.data.truncate(body_length);
+            if crc64(&partial.data) != expected {
+                conn_log!(warn, self, "Message id={} does not match its digest", message_id);
+                self.stats.digest_failures += 1;
+                return Err(Error::new(ErrorKind::DigestMismatch));
+            }
+        }
+        let mut options = MessageOptions::from_flags(partial.flags);
+        if partial.flags & MESSAGE_FLAG_ORIGINAL != 0 {
+            let original = self.take_original(&mut partial.data)?;
+            options.original = Some(original);
+        }
+        if partial.flags & MESSAGE_FLAG_CACHED != 0 {
+            self.recv_cache.insert(payload_hash(&partial.data), partial.data.clone());
+        }
+        let message = Message {
+            data: partial.data,
+            options,
+            key: partial.key,
+        };
+        self.deliver(message, partial.duplicate)
+    }
+
+    /// Remove the original-payload digest from the front of a message body
+    ///
+    /// An original payload above `max_message_size` fails with `MessageTooLarge`,
+    /// before the application gets to expand the body into it.
+    fn take_original(&self, data: &mut Vec<u8>) -> Result<OriginalDigest> {
+        let mut head = [0u8; ORIGINAL_DIGEST_SIZE];
+        head.copy_from_slice(data.get(..ORIGINAL_DIGEST_SIZE).ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?);
+        let original = OriginalDigest::from_bytes(&head);
+        if original.len > self.config.max_message_size as u64 {
+            conn_log!(warn, self, "Rejecting message of {} bytes that expands to {}", data.len(), original.len);
+            return Err(Error::new(ErrorKind::MessageTooLarge));
+        }
+        data.drain(..ORIGINAL_DIGEST_SIZE);
+        Ok(original)
+    }
+
+    /// Check that no packet was lost between `last`, the head or previous data
+    /// packet of `message_id`, and its next packet `seq`
+    ///
+    /// Bodies of different messages may be interleaved, so the sequence numbers
+    /// in between may belong to other messages; only those skipped as lost leave
+    /// a hole. Without chunk offsets there is no telling whose packet was lost,
+    /// so every message spanning it fails.
+    fn check_continuity(&self, message_id: u64, last: u32, seq: u32) -> Result<()> {
+        let expected = last.wrapping_add(1);
+        let lost = self.lost_seqs.iter()
+            .any(|&(start, end)| seq::in_range(start, expected, seq) || seq::in_range(expected, start, end));
+        if !lost {
+            return Ok(());
+        }
+        conn_log!(warn, self, "Message id={} is missing packets: expected seq={}, got seq={}", message_id, expected, seq);
+        Err(Error::new(ErrorKind::MissingPacket { expected, got: seq }).with_seq(seq))
+    }
+
+    /// Hand a completed message to the application, applying the duplicate policy
+    fn deliver(&mut self, message: Message, duplicate: bool) -> Result<Option<Message>> {
+        if !duplicate {
+            return Ok(Some(message));
+        }
+        if self.config.duplicate_policy != DuplicatePolicy::Deliver {
+            self.stats.duplicate_messages += 1;
+        }
+        match self.config.duplicate_policy {
+            DuplicatePolicy::Deliver => Ok(Some(message)),
+            DuplicatePolicy::Suppress => Ok(None),
+            DuplicatePolicy::Reject => Err(Error::new(ErrorKind::DuplicateMessage)),
+        }
+    }
+
+    /// Resolve a Reference packet against the receive-side dedup cache
+    fn handle_reference(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < REFERENCE_SIZE {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        
+        let mut hash_bytes = [0u8; 8];
+        hash_bytes.copy_from_slice(&data[0..8]);
+        let hash = u64::from_le_bytes(hash_bytes);
+        let mut length_bytes = [0u8; 8];
+        length_bytes.copy_from_slice(&data[8..16]);
+        let length = u64::from_le_bytes(length_bytes);
+        
+        match self.recv_cache.get(hash) {
+            Some(payload) if payload.len() as u64 == length => {
+                conn_log!(debug, self, "Received cached message reference: hash={:016x}, {} bytes", hash, length);
+                Ok(payload.to_vec())
+            }
+            _ => {
+                conn_log!(warn, self, "Reference to unknown cached payload: hash={:016x}", hash);
+                Err(Error::new(ErrorKind::UnknownReference))
             }
         }
     }
 }
 
+/// Retransmission timer set up from the configuration; `stream` tells the timers of a connection apart
+fn retransmit_timer(config: &TransportConfig, stream: u64) -> RetransmitTimer {
+    // Seeded from the clock so that connections opened together jitter differently
+    let seed = config.clock.as_ref().map_or(0, |clock| clock.now_micros()) ^ (stream << 32);
+    RetransmitTimer::new(config.rto_ms.saturating_mul(1000))
+        .with_bounds(config.min_rto_ms.saturating_mul(1000), config.max_rto_ms.saturating_mul(1000))
+        .with_backoff(config.rto_backoff, config.rto_jitter, seed)
+}
+
+/// What a packet in flight carried, for reporting that it could not be delivered
+fn delivery_failure(entry: &InFlight, data_head_size: usize) -> DeliveryFailure {
+    let (pkt_type, mut payload) = match PacketHeader::parse(&entry.wire) {
+        Ok(header) => (header.pkt_type, header.length as usize),
+        Err(_) => (0, entry.wire.len().saturating_sub(HEADER_SIZE)),
+    };
+    if pkt_type & PACKET_FLAG_ACK != 0 {
+        payload = payload.saturating_sub(4);
+    }
+    let len = match PacketType::from_u8(pkt_type & !PACKET_FLAG_ACK) {
+        Some(PacketType::MessageHead) => 0,
+        Some(PacketType::MessageData) => payload.saturating_sub(data_head_size),
+        _ => payload,
+    };
+    DeliveryFailure {
+        seq: entry.seq,
+        message_id: entry.chunk.map(|(message_id, _)| message_id),
+        offset: entry.chunk.map_or(0, |(_, offset)| offset),
+        len,
+    }
+}
+
+/// Check that a chunk carrying its offset starts where the `received` bytes of its message end
+fn check_offset(connection_id: Option<u64>, message_id: u64, offset: Option<usize>, received: usize) -> Result<()> {
+    match offset {
+        Some(offset) if offset != received => {
+            conn_log!(warn, id = connection_id, "MessageData for id={} at offset {}, {} bytes received", message_id, offset, received);
+            Err(Error::new(ErrorKind::InvalidPacket))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+impl XTransport<BoxedTransport> {
+    /// Transport over a stream picked at runtime, so callers need not be generic over its type
+    pub fn boxed(inner: impl Transport + Send + 'static, config: TransportConfig) -> Self {
+        XTransport::new(Box::new(inner), config)
+    }
+}
+
 impl<T: Read + Write> Read for XTransport<T> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         if self.recv_pos >= self.recv_available {
             // Need to receive a new packet
-            let packet = self.recv_packet()?;
-            self.recv_buffer = packet.data;
+            let packet = self.recv_packet().map_err(|e| e.with_phase(Phase::Recv))?;
+            let consumed = core::mem::replace(&mut self.recv_buffer, packet.data);
+            self.decoder.recycle(consumed);
             self.recv_pos = 0;
             self.recv_available = self.recv_buffer.len();
         }
@@ -279,18 +3009,10 @@ impl<T: Read + Write> Read for XTransport<T> {
 
 impl<T: Read + Write> Write for XTransport<T> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        if buf.is_empty() {
-            return Ok(0);
-        }
-
-        // Send first chunk (up to max_payload_size)
-        let to_send = core::cmp::min(buf.len(), self.config.max_payload_size);
-        self.send_packet(PacketType::Data, &buf[..to_send])?;
-
-        Ok(to_send)
+        self.write_data(buf).map_err(|e| e.with_phase(Phase::Send))
     }
 
     fn flush(&mut self) -> Result<()> {
-        self.inner.flush()
+        self.flush_sent().map_err(|e| e.with_phase(Phase::Send))
     }
 }
@@ -0,0 +1,74 @@
+//! The authentication exchange that follows the handshake when either end has an `Authenticator`
+
+use super::XTransport;
+use crate::{
+    auth::AUTH_TOKEN,
+    capability::{Capabilities, TLV_AUTH_CHALLENGE},
+    config::PONG_SIZE,
+    error::{Error, ErrorKind},
+    io::{Read, Write},
+    protocol::PacketType,
+    Result,
+};
+use alloc::vec::Vec;
+
+impl<T: Read + Write> XTransport<T> {
+    /// Answer the peer's authentication challenge, and have the peer answer this end's
+    ///
+    /// The Pong accepting this end's response carries the peer's response to
+    /// the challenge this end sent with its handshake.
+    pub(super) fn authenticate(&mut self) -> Result<()> {
+        let response = match (self.peer_challenge.as_ref(), self.config.authenticator.as_mut()) {
+            (Some(challenge), Some(authenticator)) => authenticator.respond(challenge),
+            (Some(_), None) => {
+                conn_log!(warn, self, "Peer requires authentication, but no authenticator is configured");
+                return Err(Error::new(ErrorKind::Unauthorized));
+            }
+            // Only the peer has to prove itself
+            (None, _) => Vec::new(),
+        };
+        let mut ping = AUTH_TOKEN.to_le_bytes().to_vec();
+        ping.extend_from_slice(&response);
+        self.send_packet(PacketType::Ping, &ping)?;
+        self.flush_inner()?;
+        let pong = self.await_pong(AUTH_TOKEN)?;
+        let verified = self.verify_peer(pong.data.get(PONG_SIZE..).unwrap_or(&[]));
+        self.decoder.recycle(pong.data);
+        verified?;
+        conn_log!(debug, self, "Authenticated with the peer");
+        Ok(())
+    }
+
+    /// Check the peer's response to the challenge sent with this end's handshake
+    ///
+    /// Once the peer is authenticated, the capabilities it sent are applied.
+    pub(super) fn verify_peer(&mut self, response: &[u8]) -> Result<()> {
+        if !self.authenticated {
+            let accepted = match (self.config.authenticator.as_mut(), self.auth_challenge.as_ref()) {
+                (Some(authenticator), Some(challenge)) => authenticator.verify(challenge, response),
+                // A response without a challenge outstanding
+                _ => false,
+            };
+            if !accepted {
+                conn_log!(warn, self, "Peer failed authentication");
+                return Err(self.abort(Error::new(ErrorKind::Unauthorized)));
+            }
+            self.authenticated = true;
+            self.auth_challenge = None;
+        }
+        if let Some(capabilities) = self.pending_capabilities.take() {
+            self.record_peer_capabilities(capabilities);
+        }
+        Ok(())
+    }
+
+    /// Apply the capabilities of an authenticated peer, or keep them until it authenticates
+    pub(super) fn receive_peer_capabilities(&mut self, capabilities: Capabilities) {
+        self.peer_challenge = capabilities.tlv(TLV_AUTH_CHALLENGE).map(<[u8]>::to_vec);
+        if self.authenticated {
+            self.record_peer_capabilities(capabilities);
+        } else {
+            self.pending_capabilities = Some(capabilities);
+        }
+    }
+}
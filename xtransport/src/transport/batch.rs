@@ -0,0 +1,87 @@
+//! Batch packets packing several small messages, sent by `XTransport::send_messages`
+
+use super::XTransport;
+use crate::{
+    capability::Feature,
+    error::{Error, ErrorKind, Phase},
+    io::{Read, Write},
+    message::Message,
+    protocol::{PacketType, BATCH_RECORD_HEAD_SIZE},
+    Result,
+};
+use alloc::vec::Vec;
+
+impl<T: Read + Write> XTransport<T> {
+    /// Send several messages with as few writes and ACK round trips as possible
+    ///
+    /// Nothing is flushed until the last message has been staged. Once the
+    /// handshake shows the peer understands them, consecutive small messages
+    /// are packed into Batch packets, so they share one header and one ACK;
+    /// the receiver still gets them one by one, in order.
+    pub fn send_messages(&mut self, messages: &[&[u8]]) -> Result<()> {
+        self.send_messages_inner(messages).map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn send_messages_inner(&mut self, messages: &[&[u8]]) -> Result<()> {
+        // The journal and the dedup cache take each message on its own
+        if self.config.journal.is_some() || self.send_cache.is_enabled() {
+            return messages.iter().try_for_each(|message| self.send_message_inner(message));
+        }
+        let batching = self.peer_supports(Feature::Batch);
+        let mut batch = Vec::new();
+        let mut count = 0;
+        for message in messages {
+            let record_len = BATCH_RECORD_HEAD_SIZE + message.len();
+            if batching && record_len <= self.payload_size() {
+                if batch.len() + record_len > self.payload_size() {
+                    self.send_batch(&mut batch, &mut count)?;
+                }
+                batch.extend_from_slice(&(message.len() as u32).to_le_bytes());
+                batch.extend_from_slice(message);
+                count += 1;
+                continue;
+            }
+            self.send_batch(&mut batch, &mut count)?;
+            if message.len() <= self.payload_size() {
+                self.send_packet(PacketType::Data, message)?;
+            } else {
+                let message_id = self.begin_message(message.len())?;
+                self.send_message_data(message_id, message)?;
+            }
+        }
+        self.send_batch(&mut batch, &mut count)?;
+        conn_log!(debug, self, "Sent {} messages", messages.len());
+        self.flush_sent()
+    }
+
+    /// Send the `count` messages packed in `batch`, a lone one as a plain Data packet
+    fn send_batch(&mut self, batch: &mut Vec<u8>, count: &mut usize) -> Result<()> {
+        match *count {
+            0 => return Ok(()),
+            1 => self.send_packet(PacketType::Data, &batch[BATCH_RECORD_HEAD_SIZE..])?,
+            _ => {
+                conn_log!(trace, self, "Sending batch of {} messages, {} bytes", count, batch.len());
+                self.send_packet(PacketType::Batch, batch)?;
+            }
+        }
+        batch.clear();
+        *count = 0;
+        Ok(())
+    }
+
+    /// Split the payload of a Batch packet into its messages
+    pub(super) fn handle_batch(&mut self, mut data: &[u8]) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        while !data.is_empty() {
+            let (head, rest) = data.split_at_checked(BATCH_RECORD_HEAD_SIZE)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+            let len = u32::from_le_bytes([head[0], head[1], head[2], head[3]]) as usize;
+            let (message, rest) = rest.split_at_checked(len)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidPacket))?;
+            messages.push(Message::plain(message.to_vec()));
+            data = rest;
+        }
+        conn_log!(debug, self, "Received batch of {} messages", messages.len());
+        Ok(messages)
+    }
+}
@@ -0,0 +1,108 @@
+//! Message groups sent by `XTransport::send_group` and staged by the receiver until all of them have arrived
+
+use super::{le_u64, XTransport};
+use crate::{
+    config::GROUP_HEAD_SIZE,
+    error::{Error, ErrorKind, Phase},
+    io::{Read, Write},
+    message::Message,
+    protocol::PacketType,
+    Result,
+};
+use alloc::vec::Vec;
+
+/// Messages of a group held back until the whole group has arrived
+pub(super) struct StagedGroup {
+    group_id: u64,
+    message_count: u32,
+    messages: Vec<Message>,
+}
+
+impl<T: Read + Write> XTransport<T> {
+    /// Send messages that the receiver delivers together, once all have arrived
+    ///
+    /// Messages started with `begin_message` must not complete while the group
+    /// is being sent, or the receiver counts them towards the group.
+    pub fn send_group(&mut self, messages: &[&[u8]]) -> Result<()> {
+        self.send_group_inner(messages).map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn send_group_inner(&mut self, messages: &[&[u8]]) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let group_id = self.next_group_id;
+        self.next_group_id = self.next_group_id.wrapping_add(1);
+        
+        let mut head = [0u8; GROUP_HEAD_SIZE];
+        head[0..8].copy_from_slice(&group_id.to_le_bytes());
+        head[8..12].copy_from_slice(&(messages.len() as u32).to_le_bytes());
+        self.send_packet(PacketType::GroupHead, &head)?;
+        conn_log!(debug, self, "Sending message group: id={}, {} messages", group_id, messages.len());
+        
+        for message in messages {
+            self.send_message(message)?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next group sent with `send_group`, all messages at once
+    ///
+    /// A message sent on its own is returned as a group of one. If `recv_message`
+    /// already returned part of a group, the rest of it is returned.
+    pub fn recv_group(&mut self) -> Result<Vec<Vec<u8>>> {
+        self.recv_group_inner().map_err(|e| e.with_phase(Phase::Recv))
+    }
+
+    fn recv_group_inner(&mut self) -> Result<Vec<Vec<u8>>> {
+        let messages = if self.ready.is_empty() {
+            self.recv_delivery()?
+        } else {
+            self.ready.drain(..).collect()
+        };
+        Ok(messages.into_iter().map(|message| message.data).collect())
+    }
+
+    /// Add a received message to the staged group, or deliver it on its own
+    pub(super) fn stage_or_deliver(&mut self, message: Message) -> Result<Option<Vec<Message>>> {
+        if self.staged_group.is_none() {
+            return Ok(Some(alloc::vec![message]));
+        }
+        self.reserve_recv_memory(message.data.len())?;
+        let group = self.staged_group.as_mut().expect("group staged above");
+        group.messages.push(message);
+        if group.messages.len() < group.message_count as usize {
+            return Ok(None);
+        }
+        Ok(self.staged_group.take().map(|group| {
+            self.memory.release(group.messages.iter().map(|message| message.data.len()).sum());
+            conn_log!(debug, self, "Message group received: id={}, {} messages", group.group_id, group.messages.len());
+            group.messages
+        }))
+    }
+
+    /// Start staging the messages of a group until all of them have arrived
+    pub(super) fn handle_group_head(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() < GROUP_HEAD_SIZE {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        let group_id = le_u64(&data[0..8]);
+        let message_count = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        
+        if let Some(staged) = &self.staged_group {
+            conn_log!(warn, self, "Group id={} started before group id={} completed", group_id, staged.group_id);
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        if message_count == 0 {
+            return Err(Error::new(ErrorKind::InvalidPacket));
+        }
+        
+        conn_log!(debug, self, "Receiving message group: id={}, {} messages", group_id, message_count);
+        self.staged_group = Some(StagedGroup {
+            group_id,
+            message_count,
+            messages: Vec::new(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! Sending through the write-ahead journal of `TransportConfig::journal`
+
+use super::XTransport;
+use crate::{
+    error::{Error, ErrorKind, Phase},
+    io::{Read, Write},
+    Result,
+};
+
+impl<T: Read + Write> XTransport<T> {
+    /// Journal a message, send it and drop it from the journal once acknowledged
+    pub(super) fn send_journaled(&mut self, data: &[u8]) -> Result<()> {
+        let id = match self.config.journal.as_mut() {
+            Some(journal) => journal.append(data)?,
+            None => return Err(Error::new(ErrorKind::Unsupported)),
+        };
+        self.send_journal_entry(id, data)
+    }
+
+    fn send_journal_entry(&mut self, id: u64, data: &[u8]) -> Result<()> {
+        self.send_keyed_message(id, data)?;
+        self.flush_sent()?;
+        if let Some(journal) = self.config.journal.as_mut() {
+            journal.remove(id)?;
+        }
+        conn_log!(debug, self, "Journaled message id={} delivered", id);
+        Ok(())
+    }
+
+    /// Send every message left in the journal by an earlier run, returning how many
+    ///
+    /// Call this after connecting, before sending new messages.
+    pub fn resend_journal(&mut self) -> Result<usize> {
+        self.resend_journal_inner().map_err(|e| e.with_phase(Phase::Send))
+    }
+
+    fn resend_journal_inner(&mut self) -> Result<usize> {
+        let pending = match self.config.journal.as_mut() {
+            Some(journal) => journal.pending()?,
+            None => return Err(Error::new(ErrorKind::Unsupported)),
+        };
+        let count = pending.len();
+        if count > 0 {
+            conn_log!(info, self, "Resending {} journaled messages", count);
+        }
+        for (id, data) in pending {
+            self.send_journal_entry(id, &data)?;
+        }
+        Ok(count)
+    }
+}
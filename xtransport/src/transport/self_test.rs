@@ -0,0 +1,186 @@
+//! The channel self-test run by `XTransport::self_test` and answered by `XTransport::answer_self_test`
+
+use super::{le_u64, XTransport};
+use crate::{
+    config::PING_SIZE,
+    error::{Error, ErrorKind},
+    io::{Read, Write},
+    protocol::PacketType,
+    selftest::{
+        fragmented_message, small_message, ProbeRound, SelfTestReport, SELF_TEST_FRAGMENTS, SELF_TEST_PINGS, SELF_TEST_SMALL_FRAMES,
+        SELF_TEST_SMALL_SIZE, SELF_TEST_TOKEN,
+    },
+    Result,
+};
+
+impl<T: Read + Write> XTransport<T> {
+    /// Validate the channel with a short scripted exchange before real traffic
+    ///
+    /// The script runs against a peer inside `answer_self_test`:
+    ///
+    /// 1. sequential pings measure the round-trip time;
+    /// 2. pings padded to doubling sizes, up to the payload limit (the peer's
+    ///    if lower, after a handshake), check that frames of every size make
+    ///    the round trip;
+    /// 3. a burst of small messages, echoed by the peer, measures the frame rate;
+    /// 4. a message spanning `SELF_TEST_FRAGMENTS` packets, echoed as well,
+    ///    measures the throughput of reassembled data;
+    /// 5. this end shuts down its sending direction, and the peer answers
+    ///    with its own shutdown once it has echoed everything, closing the
+    ///    script on both ends.
+    ///
+    /// Requires a clock; a reply that never arrives fails the test with the
+    /// read timeout.
+    pub fn self_test(&mut self) -> Result<SelfTestReport> {
+        let start = self.now().ok_or_else(|| Error::new(ErrorKind::Unsupported))?;
+        let retransmissions = self.stats.retransmissions;
+        // Tokens differ between runs, so replies to an aborted test are ignored
+        let base = SELF_TEST_TOKEN | ((start & 0x00ff_ffff) << 8);
+        
+        let mut pings = ProbeRound::new(base);
+        for _ in 0..SELF_TEST_PINGS {
+            self.send_probe(&mut pings, PING_SIZE)?;
+            self.flush_inner()?;
+            self.await_probes(&mut pings)?;
+        }
+        let max_payload_size = self.probe_max_payload(base + SELF_TEST_PINGS as u64)?;
+        
+        let started = self.now().unwrap_or(0);
+        for index in 0..SELF_TEST_SMALL_FRAMES {
+            self.send_message(&small_message(index))?;
+        }
+        self.flush_sent()?;
+        let mut in_order = pings.in_order;
+        let mut echo_intact = true;
+        for index in 0..SELF_TEST_SMALL_FRAMES {
+            let echo = self.recv_message()?;
+            if echo != small_message(index) {
+                // A message of the burst arriving early is out of order, anything else is damaged
+                match (0..SELF_TEST_SMALL_FRAMES).any(|other| echo == small_message(other)) {
+                    true => in_order = false,
+                    false => echo_intact = false,
+                }
+            }
+        }
+        let small_micros = self.now().unwrap_or(0).saturating_sub(started).max(1);
+        
+        let fragmented = fragmented_message(SELF_TEST_FRAGMENTS * self.chunk_size());
+        let started = self.now().unwrap_or(0);
+        self.send_message(&fragmented)?;
+        self.flush_sent()?;
+        echo_intact &= self.recv_message()? == fragmented;
+        let fragmented_micros = self.now().unwrap_or(0).saturating_sub(started).max(1);
+        
+        // The peer answers the Fin with its own once it has echoed everything
+        self.shutdown_write()?;
+        match self.recv_message() {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.peer_write_shutdown => {}
+            Err(e) => return Err(e),
+            Ok(_) => echo_intact = false,
+        }
+        
+        let rtts = &pings.rtts;
+        let report = SelfTestReport {
+            rtt_min_micros: rtts.iter().copied().min().unwrap_or(0),
+            rtt_avg_micros: rtts.iter().sum::<u64>() / rtts.len().max(1) as u64,
+            rtt_max_micros: rtts.iter().copied().max().unwrap_or(0),
+            frames_per_sec: SELF_TEST_SMALL_FRAMES as f64 * 1_000_000.0 / small_micros as f64,
+            bytes_per_sec: fragmented.len() as f64 * 1_000_000.0 / fragmented_micros as f64,
+            max_payload_size,
+            peer_has_clock: pings.peer_has_clock,
+            in_order,
+            echo_intact,
+            retransmissions: self.stats.retransmissions - retransmissions,
+        };
+        conn_log!(debug, self, "Self-test: rtt={}us, {:.0} frames/s, {:.0} bytes/s, max payload {} bytes", 
+                   report.rtt_avg_micros, report.frames_per_sec, report.bytes_per_sec, report.max_payload_size);
+        Ok(report)
+    }
+
+    /// Answer the peer's `self_test`: echo every message until the peer shuts down its sending direction
+    ///
+    /// Pings are answered as in any receive. Returns the number of messages
+    /// echoed, after shutting down this end's sending direction in turn.
+    pub fn answer_self_test(&mut self) -> Result<usize> {
+        let mut echoed = 0;
+        loop {
+            match self.recv_message() {
+                Ok(message) => {
+                    self.send_message(&message)?;
+                    echoed += 1;
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.peer_write_shutdown => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.shutdown_write()?;
+        Ok(echoed)
+    }
+
+    /// Send padded PINGs doubling from the small message size up to the payload limit, returning the largest
+    ///
+    /// A probe the link loses fails like any other unanswered PING: it has
+    /// consumed a sequence number, so the connection cannot go on without it.
+    fn probe_max_payload(&mut self, first_token: u64) -> Result<usize> {
+        let limit = self.payload_size().max(PING_SIZE);
+        let mut round = ProbeRound::new(first_token);
+        let mut size = SELF_TEST_SMALL_SIZE.min(limit);
+        loop {
+            self.send_probe(&mut round, size)?;
+            self.flush_inner()?;
+            if let Err(e) = self.await_probes(&mut round) {
+                conn_log!(warn, self, "Self-test probe of {} bytes got no reply", size);
+                return Err(e);
+            }
+            if size == limit {
+                return Ok(size);
+            }
+            size = size.saturating_mul(2).min(limit);
+        }
+    }
+
+    /// Send one self-test PING of `size` bytes
+    fn send_probe(&mut self, round: &mut ProbeRound, size: usize) -> Result<()> {
+        let mut probe = alloc::vec![0u8; size.max(PING_SIZE)];
+        let token = round.next_token(self.now().unwrap_or(0));
+        probe[0..8].copy_from_slice(&token.to_le_bytes());
+        self.send_packet(PacketType::Ping, &probe)
+    }
+
+    /// Collect the PONGs answering every probe of `round`
+    fn await_probes(&mut self, round: &mut ProbeRound) -> Result<()> {
+        while !round.is_complete() {
+            // Replies may have been queued while waiting for ACKs in `send_packet`
+            let queued = self.pending.iter()
+                .position(|packet| packet.header.pkt_type == PacketType::Pong as u8)
+                .and_then(|index| self.pending.remove(index));
+            let packet = match queued {
+                Some(packet) => packet,
+                None => match self.read_packet() {
+                    Ok(packet) => packet,
+                    // A lost probe is recovered like any other unacknowledged packet
+                    Err(e) if self.config.wait_for_ack && !self.window.is_empty()
+                        && matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+                    {
+                        self.retransmit_if_expired()?;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                },
+            };
+            match PacketType::from_u8(packet.header.pkt_type) {
+                Some(PacketType::Pong) => {
+                    let now = self.now().unwrap_or(0);
+                    if packet.data.len() < PING_SIZE
+                        || !round.on_pong(le_u64(&packet.data[0..8]), packet.data.len(), now)
+                    {
+                        conn_log!(trace, self, "Ignoring stale Pong");
+                    }
+                }
+                Some(PacketType::Ping) => self.send_pong(&packet.data)?,
+                _ => self.pending.push_back(packet),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+//! io_uring backed stream for Linux sockets (`uring` feature)
+//!
+//! `UringTransport` owns a small io_uring instance with two buffers
+//! registered with the kernel, one for each direction. Writes are collected
+//! in the send buffer and go out as one `WRITE_FIXED`, and a read that has to
+//! wait for the socket submits the pending writes and the `READ_FIXED` with
+//! a single `io_uring_enter`, so a burst of packets followed by a wait for
+//! the ACK costs one system call instead of one per packet.
+//!
+//! io_uring does not honour `SO_RCVTIMEO`; use `set_read_timeout`, which
+//! links a timeout to every read, for the retransmission timer of ACK mode.
+
+use rustix::io_uring::{
+    addr_or_splice_off_in_union, buf_union, io_uring_cqe, io_uring_enter, io_uring_params, io_uring_ptr,
+    io_uring_register, io_uring_setup, io_uring_sqe, io_uring_user_data, iovec, len_union, IoringEnterFlags, IoringOp, IoringRegisterOp, IoringSqeFlags, Timespec, IORING_OFF_CQ_RING,
+    IORING_OFF_SQES, IORING_OFF_SQ_RING,
+};
+use rustix::mm::{mmap, munmap, MapFlags, ProtFlags};
+use std::ffi::c_void;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Size of each of the two registered buffers unless given
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+/// Submission queue entries: a write, a read and its timeout are the most in flight
+const QUEUE_DEPTH: u32 = 4;
+
+/// `user_data` of the operations, to tell their completions apart
+const OP_READ: u64 = 1;
+const OP_WRITE: u64 = 2;
+const OP_TIMEOUT: u64 = 3;
+/// Indices of the registered buffers
+const READ_BUFFER: u16 = 0;
+const WRITE_BUFFER: u16 = 1;
+
+/// Shared memory mapped from the ring's file descriptor
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize, offset: u64) -> io::Result<Self> {
+        // SAFETY: a fresh shared mapping of the ring, unmapped only on drop
+        let ptr = unsafe {
+            mmap(
+                core::ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED | MapFlags::POPULATE,
+                fd,
+                offset,
+            )?
+        };
+        Ok(Mapping { ptr, len })
+    }
+
+    /// Pointer to the `T` at byte offset `offset`
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!(offset as usize + core::mem::size_of::<T>() <= self.len);
+        // SAFETY: in bounds of the mapping, at offsets the kernel reported
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `new` and not referenced past the ring's lifetime
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+/// Submission and completion queues of one io_uring instance
+struct Ring {
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut io_uring_sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+    /// Entries queued but not yet passed to the kernel
+    unsubmitted: u32,
+    _sq_ring: Mapping,
+    _cq_ring: Mapping,
+    _sqe_array: Mapping,
+    fd: OwnedFd,
+}
+
+// SAFETY: the pointers refer to mappings the ring owns, used only through `&mut self`
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = io_uring_params::default();
+        // SAFETY: no flags that require other fields of `params`
+        let fd = unsafe { io_uring_setup(entries, &mut params)? };
+        let (sq, cq) = (params.sq_off, params.cq_off);
+        let sq_ring = Mapping::new(&fd, sq.array as usize + params.sq_entries as usize * 4, IORING_OFF_SQ_RING)?;
+        let cq_ring = Mapping::new(
+            &fd,
+            cq.cqes as usize + params.cq_entries as usize * core::mem::size_of::<io_uring_cqe>(),
+            IORING_OFF_CQ_RING,
+        )?;
+        let sqe_array = Mapping::new(
+            &fd,
+            params.sq_entries as usize * core::mem::size_of::<io_uring_sqe>(),
+            IORING_OFF_SQES,
+        )?;
+        // SAFETY: the masks are written by the kernel during setup and never change
+        let (sq_mask, cq_mask) = unsafe { (*sq_ring.at::<u32>(sq.ring_mask), *cq_ring.at::<u32>(cq.ring_mask)) };
+        Ok(Ring {
+            sq_head: sq_ring.at(sq.head),
+            sq_tail: sq_ring.at(sq.tail),
+            sq_mask,
+            sq_entries: params.sq_entries,
+            sq_array: sq_ring.at(sq.array),
+            sqes: sqe_array.at(0),
+            cq_head: cq_ring.at(cq.head),
+            cq_tail: cq_ring.at(cq.tail),
+            cq_mask,
+            cqes: cq_ring.at(cq.cqes),
+            unsubmitted: 0,
+            _sq_ring: sq_ring,
+            _cq_ring: cq_ring,
+            _sqe_array: sqe_array,
+            fd,
+        })
+    }
+
+    /// Queue `sqe` for the next `submit`
+    fn push(&mut self, sqe: io_uring_sqe) {
+        // SAFETY: head and tail point into the SQ ring mapping; only this side writes the tail
+        unsafe {
+            let tail = (*self.sq_tail).load(Ordering::Relaxed);
+            let head = (*self.sq_head).load(Ordering::Acquire);
+            assert!(tail.wrapping_sub(head) < self.sq_entries, "io_uring submission queue overflow");
+            let index = tail & self.sq_mask;
+            self.sqes.add(index as usize).write(sqe);
+            self.sq_array.add(index as usize).write(index);
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.unsubmitted += 1;
+    }
+
+    /// Pass the queued entries to the kernel and wait until at least `wait` completions are ready
+    fn submit(&mut self, wait: u32) -> io::Result<()> {
+        loop {
+            // SAFETY: every queued entry refers to memory that outlives its completion
+            match unsafe { io_uring_enter(&self.fd, self.unsubmitted, wait, IoringEnterFlags::GETEVENTS) } {
+                Ok(submitted) => {
+                    self.unsubmitted -= submitted.min(self.unsubmitted);
+                    return Ok(());
+                }
+                Err(rustix::io::Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Take the next completion: its `user_data` and result
+    fn pop(&mut self) -> Option<(u64, i32)> {
+        // SAFETY: head and tail point into the CQ ring mapping; only this side writes the head
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            if head == (*self.cq_tail).load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+            let completion = (cqe.user_data.u64_(), cqe.res);
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            Some(completion)
+        }
+    }
+}
+
+/// Socket read and written through io_uring with registered buffers
+///
+/// Works with any stream socket, such as a `TcpStream` or `UnixStream`, and
+/// is passed to `XTransport::new` like the socket itself. Written bytes stay
+/// in the send buffer until it is full, `flush` is called or a read has to
+/// wait; `XTransport` flushes after every packet it must get out.
+pub struct UringTransport<S: AsFd> {
+    // Dropped first, so the kernel releases the buffers before they are freed
+    ring: Ring,
+    stream: S,
+    read_buf: Box<[u8]>,
+    read_pos: usize,
+    read_end: usize,
+    write_buf: Box<[u8]>,
+    write_len: usize,
+    read_timeout: Option<Timespec>,
+}
+
+impl<S: AsFd> UringTransport<S> {
+    pub fn new(stream: S) -> io::Result<Self> {
+        Self::with_buffer_size(stream, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Register send and receive buffers of `bytes` each
+    ///
+    /// Larger buffers batch more packets per system call; the kernel pins
+    /// them in memory, which counts against `RLIMIT_MEMLOCK` on older kernels.
+    pub fn with_buffer_size(stream: S, bytes: usize) -> io::Result<Self> {
+        if bytes == 0 || bytes > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid io_uring buffer size"));
+        }
+        let ring = Ring::new(QUEUE_DEPTH)?;
+        let mut read_buf = vec![0u8; bytes].into_boxed_slice();
+        let mut write_buf = vec![0u8; bytes].into_boxed_slice();
+        let buffers = [
+            iovec { iov_base: read_buf.as_mut_ptr().cast(), iov_len: bytes },
+            iovec { iov_base: write_buf.as_mut_ptr().cast(), iov_len: bytes },
+        ];
+        // SAFETY: the buffers live on the heap as long as the ring they are registered with
+        unsafe {
+            io_uring_register(&ring.fd, IoringRegisterOp::RegisterBuffers, buffers.as_ptr().cast(), buffers.len() as u32)?;
+        }
+        log::debug!("io_uring transport ready: 2 registered buffers of {} bytes", bytes);
+        Ok(UringTransport {
+            ring,
+            stream,
+            read_buf,
+            read_pos: 0,
+            read_end: 0,
+            write_buf,
+            write_len: 0,
+            read_timeout: None,
+        })
+    }
+
+    /// Fail reads that get no data within `timeout` with `TimedOut` (`None` waits forever)
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout.map(|timeout| Timespec {
+            tv_sec: timeout.as_secs() as _,
+            tv_nsec: timeout.subsec_nanos() as _,
+        });
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout.map(|ts| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    fn fixed_sqe(&self, op: IoringOp, user_data: u64, buf_index: u16, addr: *mut u8, len: usize) -> io_uring_sqe {
+        io_uring_sqe {
+            opcode: op,
+            fd: self.stream.as_fd().as_raw_fd(),
+            addr_or_splice_off_in: addr_or_splice_off_in_union { addr: io_uring_ptr::new(addr.cast()) },
+            len: len_union { len: len as u32 },
+            buf: buf_union { buf_index },
+            user_data: io_uring_user_data { u64_: user_data },
+            ..Default::default()
+        }
+    }
+
+    fn push_write(&mut self) {
+        let addr = self.write_buf.as_mut_ptr();
+        let sqe = self.fixed_sqe(IoringOp::WriteFixed, OP_WRITE, WRITE_BUFFER, addr, self.write_len);
+        self.ring.push(sqe);
+    }
+
+    /// Account for a completed write, returning true once the send buffer is empty
+    fn write_done(&mut self, res: i32) -> io::Result<bool> {
+        if res < 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        if res == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let written = res as usize;
+        self.write_buf.copy_within(written..self.write_len, 0);
+        self.write_len -= written;
+        Ok(self.write_len == 0)
+    }
+
+    /// Write out the send buffer, waiting for every write to complete
+    fn drain_writes(&mut self) -> io::Result<()> {
+        while self.write_len > 0 {
+            self.push_write();
+            self.ring.submit(1)?;
+            while let Some((op, res)) = self.ring.pop() {
+                if op == OP_WRITE {
+                    self.write_done(res)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Refill the receive buffer, sending any pending writes with the same submission
+    fn fill_read_buf(&mut self) -> io::Result<()> {
+        let mut writing = self.write_len > 0;
+        if writing {
+            self.push_write();
+        }
+        let addr = self.read_buf.as_mut_ptr();
+        let mut read = self.fixed_sqe(IoringOp::ReadFixed, OP_READ, READ_BUFFER, addr, self.read_buf.len());
+        let mut timing = false;
+        if let Some(timeout) = self.read_timeout.as_ref() {
+            read.flags = IoringSqeFlags::IO_LINK;
+            self.ring.push(read);
+            let timer = io_uring_sqe {
+                opcode: IoringOp::LinkTimeout,
+                fd: -1,
+                addr_or_splice_off_in: addr_or_splice_off_in_union {
+                    addr: io_uring_ptr::new((timeout as *const Timespec).cast_mut().cast()),
+                },
+                len: len_union { len: 1 },
+                user_data: io_uring_user_data { u64_: OP_TIMEOUT },
+                ..Default::default()
+            };
+            self.ring.push(timer);
+            timing = true;
+        } else {
+            self.ring.push(read);
+        }
+
+        let mut reading = true;
+        let mut result = Ok(());
+        while reading || writing || timing {
+            self.ring.submit(1)?;
+            while let Some((op, res)) = self.ring.pop() {
+                match op {
+                    OP_READ => {
+                        reading = false;
+                        if res >= 0 {
+                            self.read_pos = 0;
+                            self.read_end = res as usize;
+                        } else if res == -(rustix::io::Errno::CANCELED.raw_os_error()) {
+                            result = Err(io::ErrorKind::TimedOut.into());
+                        } else {
+                            result = Err(io::Error::from_raw_os_error(-res));
+                        }
+                    }
+                    OP_WRITE => match self.write_done(res) {
+                        // The rest of a short write goes out while the read waits
+                        Ok(false) => self.push_write(),
+                        Ok(true) => writing = false,
+                        Err(e) => {
+                            writing = false;
+                            result = Err(e);
+                        }
+                    },
+                    _ => timing = false,
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsFd> io::Read for UringTransport<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos == self.read_end {
+            self.fill_read_buf()?;
+        }
+        let n = buf.len().min(self.read_end - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl<S: AsFd> io::Write for UringTransport<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_len == self.write_buf.len() {
+            self.drain_writes()?;
+        }
+        let n = buf.len().min(self.write_buf.len() - self.write_len);
+        self.write_buf[self.write_len..self.write_len + n].copy_from_slice(&buf[..n]);
+        self.write_len += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain_writes()
+    }
+}
+
+impl<S: AsFd> Drop for UringTransport<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.drain_writes() {
+            log::debug!("io_uring transport dropped {} unsent bytes: {}", self.write_len, e);
+        }
+    }
+}
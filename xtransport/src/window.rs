@@ -0,0 +1,257 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::{protocol::HEADER_SIZE, seq};
+
+/// Most ranges a `SackRanges` holds, few enough to fit a small control packet
+pub const MAX_SACK_RANGES: usize = 4;
+
+/// A sent packet that has not been acknowledged yet
+pub struct InFlight {
+    pub seq: u32,
+    /// Serialized header and payload, ready for retransmission
+    pub wire: Vec<u8>,
+    pub sent_at: Option<u64>,
+    /// Sent more than once, so an ACK for it gives no reliable round-trip time
+    pub retransmitted: bool,
+    /// Message ID and offset of the message bytes carried, for MessageHead and MessageData packets
+    pub chunk: Option<(u64, usize)>,
+}
+
+/// Packets in flight in ACK mode, oldest first
+pub struct SendWindow {
+    entries: VecDeque<InFlight>,
+    /// Wire bytes of `entries`
+    bytes: usize,
+}
+
+impl SendWindow {
+    pub fn new() -> Self {
+        SendWindow {
+            entries: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Wire bytes of the packets in flight, headers included
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Payload bytes of the packets in flight, headers excluded
+    pub fn payload_bytes(&self) -> usize {
+        // Every entry holds at least a header
+        self.bytes - self.entries.len() * HEADER_SIZE
+    }
+
+    pub fn push(&mut self, seq: u32, wire: Vec<u8>, sent_at: Option<u64>) {
+        self.push_chunk(seq, wire, sent_at, None);
+    }
+
+    /// Add a packet carrying the part of message `chunk.0` starting at offset `chunk.1`
+    pub fn push_chunk(&mut self, seq: u32, wire: Vec<u8>, sent_at: Option<u64>, chunk: Option<(u64, usize)>) {
+        self.bytes += wire.len();
+        self.entries.push_back(InFlight { seq, wire, sent_at, retransmitted: false, chunk });
+    }
+
+    pub fn oldest(&self) -> Option<&InFlight> {
+        self.entries.front()
+    }
+
+    pub fn oldest_mut(&mut self) -> Option<&mut InFlight> {
+        self.entries.front_mut()
+    }
+
+    /// Packets in flight, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &InFlight> {
+        self.entries.iter()
+    }
+
+    pub fn get(&self, seq: u32) -> Option<&InFlight> {
+        self.entries.iter().find(|entry| entry.seq == seq)
+    }
+
+    pub fn get_mut(&mut self, seq: u32) -> Option<&mut InFlight> {
+        self.entries.iter_mut().find(|entry| entry.seq == seq)
+    }
+
+    /// True if `seq` is one of the packets in flight
+    pub fn contains(&self, seq: u32) -> bool {
+        match (self.entries.front(), self.entries.back()) {
+            (Some(oldest), Some(newest)) => {
+                seq::in_range(seq, oldest.seq, newest.seq.wrapping_add(1))
+            }
+            _ => false,
+        }
+    }
+
+    /// Apply a cumulative ACK, removing every packet up to and including `seq`
+    ///
+    /// Returns the number of packets acknowledged.
+    pub fn ack(&mut self, seq: u32) -> usize {
+        if !self.contains(seq) {
+            return 0;
+        }
+        let mut acked = 0;
+        while let Some(entry) = self.entries.pop_front() {
+            acked += 1;
+            self.bytes -= entry.wire.len();
+            if entry.seq == seq {
+                break;
+            }
+        }
+        acked
+    }
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Packets received beyond a gap, as ranges of consecutive sequence numbers for a selective ACK
+///
+/// Each range is `(start, end)` with `end` exclusive, in wrapping sequence
+/// space; ranges come nearest the gap first, and those past the first
+/// `MAX_SACK_RANGES` are left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SackRanges {
+    ranges: [(u32, u32); MAX_SACK_RANGES],
+    len: usize,
+}
+
+impl SackRanges {
+    /// Ranges of the `received` sequence numbers, given in order from `next`, the first one missing
+    pub fn from_received(next: u32, received: impl IntoIterator<Item = u32>) -> Self {
+        let mut sack = SackRanges::default();
+        for received in received {
+            if !seq::after(received, next) {
+                continue;
+            }
+            match sack.len.checked_sub(1).map(|last| &mut sack.ranges[last]) {
+                Some((_, end)) if *end == received => *end = received.wrapping_add(1),
+                _ if sack.len == MAX_SACK_RANGES => break,
+                _ => {
+                    sack.ranges[sack.len] = (received, received.wrapping_add(1));
+                    sack.len += 1;
+                }
+            }
+        }
+        sack
+    }
+
+    pub fn as_slice(&self) -> &[(u32, u32)] {
+        &self.ranges[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Packets missing before the first range, counted from `next`; 0 without a gap
+    pub fn first_gap(&self, next: u32) -> u32 {
+        self.as_slice().first().map_or(0, |&(start, _)| seq::distance(next, start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn window(seqs: impl IntoIterator<Item = u32>) -> SendWindow {
+        let mut window = SendWindow::new();
+        for seq in seqs {
+            window.push(seq, Vec::new(), None);
+        }
+        window
+    }
+
+    #[test]
+    fn cumulative_ack_removes_everything_up_to_seq() {
+        let mut window = window(10..15);
+        assert_eq!(window.ack(12), 3);
+        assert_eq!(window.oldest().map(|entry| entry.seq), Some(13));
+        assert_eq!(window.ack(14), 2);
+        assert!(window.is_empty());
+    }
+
+    #[test]
+    fn ack_outside_the_window_is_ignored() {
+        let mut window = window(10..15);
+        assert_eq!(window.ack(9), 0, "already acknowledged");
+        assert_eq!(window.ack(15), 0, "never sent");
+        assert_eq!(window.len(), 5);
+        assert_eq!(SendWindow::new().ack(0), 0);
+    }
+
+    #[test]
+    fn window_spans_the_sequence_wrap() {
+        let mut window = window([u32::MAX - 1, u32::MAX, 0, 1]);
+        assert!(window.contains(u32::MAX));
+        assert!(window.contains(1));
+        assert!(!window.contains(2));
+        assert!(!window.contains(u32::MAX - 2));
+        assert_eq!(window.ack(0), 3);
+        assert_eq!(window.oldest().map(|entry| entry.seq), Some(1));
+    }
+
+    #[test]
+    fn bytes_follow_pushes_and_acks() {
+        let mut window = SendWindow::new();
+        window.push(1, vec![0; HEADER_SIZE + 100], None);
+        window.push_chunk(2, vec![0; HEADER_SIZE + 20], None, Some((7, 100)));
+        window.push(3, vec![0; HEADER_SIZE], None);
+        assert_eq!(window.bytes(), 3 * HEADER_SIZE + 120);
+        assert_eq!(window.payload_bytes(), 120);
+
+        assert_eq!(window.ack(9), 0);
+        assert_eq!(window.bytes(), 3 * HEADER_SIZE + 120);
+        window.ack(2);
+        assert_eq!((window.bytes(), window.payload_bytes()), (HEADER_SIZE, 0));
+        window.ack(3);
+        assert_eq!(window.bytes(), 0);
+    }
+
+    #[test]
+    fn consecutive_packets_merge_into_ranges() {
+        let sack = SackRanges::from_received(10, [12, 13, 14, 16, 17]);
+        assert_eq!(sack.as_slice(), [(12, 15), (16, 18)]);
+        assert_eq!(sack.first_gap(10), 2);
+    }
+
+    #[test]
+    fn packets_up_to_the_gap_are_left_out() {
+        let sack = SackRanges::from_received(10, [8, 9, 10, 11]);
+        assert_eq!(sack.as_slice(), [(11, 12)]);
+        let sack = SackRanges::from_received(10, [9, 10]);
+        assert!(sack.is_empty());
+        assert_eq!(sack.first_gap(10), 0);
+    }
+
+    #[test]
+    fn ranges_span_the_sequence_wrap() {
+        let sack = SackRanges::from_received(u32::MAX - 2, [u32::MAX, 0, 1, 3]);
+        assert_eq!(sack.as_slice(), [(u32::MAX, 2), (3, 4)]);
+        assert_eq!(sack.first_gap(u32::MAX - 2), 2);
+    }
+
+    #[test]
+    fn ranges_beyond_the_limit_are_dropped_farthest_first() {
+        let received = (0..MAX_SACK_RANGES as u32 + 2).map(|i| 2 + 2 * i);
+        let sack = SackRanges::from_received(1, received);
+        assert_eq!(sack.as_slice().len(), MAX_SACK_RANGES);
+        assert_eq!(sack.as_slice()[0], (2, 3));
+        let last = 2 * MAX_SACK_RANGES as u32;
+        assert_eq!(sack.as_slice()[MAX_SACK_RANGES - 1], (last, last + 1));
+    }
+}
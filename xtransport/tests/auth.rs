@@ -0,0 +1,113 @@
+//! Authentication during the handshake, from either end of the connection
+
+mod common;
+
+use common::{packets, pair, Peer};
+use std::thread;
+use xtransport::auth::TokenAuthenticator;
+use xtransport::error::ErrorKind;
+use xtransport::config::{VERSION, VERSION_2};
+use xtransport::protocol::{Goaway, Packet, PacketType, GOAWAY_UNAUTHORIZED};
+use xtransport::{TransportConfig, XTransport};
+
+/// Frames large enough that the handshake negotiates version 2 headers, a capability to watch
+fn config() -> TransportConfig {
+    TransportConfig::default().with_max_frame_size(128 * 1024)
+}
+
+fn with_token(token: &[u8]) -> TransportConfig {
+    config().with_authenticator(TokenAuthenticator::new(token))
+}
+
+/// Wire bytes of the HELLO a connecting side without an authenticator sends
+fn hello() -> Vec<u8> {
+    let mut client = XTransport::new(Peer::new(Vec::new()), config());
+    assert!(client.handshake().is_err(), "no peer to answer");
+    client.into_parts().0.output
+}
+
+/// Goaway codes among the packets written to `peer`
+fn goaway_codes(peer: &Peer) -> Vec<u32> {
+    packets(&peer.output)
+        .iter()
+        .filter(|packet| packet.header.pkt_type == PacketType::Goaway as u8)
+        .map(|packet| Goaway::parse(&packet.data).expect("goaway").code)
+        .collect()
+}
+
+#[test]
+fn matching_tokens_authenticate_both_ends() {
+    let (a, b) = pair();
+    let server = thread::spawn(move || {
+        let mut transport = XTransport::new(b, with_token(b"secret"));
+        let message = transport.recv_message().expect("receive");
+        (message, transport.peer_capabilities().is_some(), transport.wire_version())
+    });
+    let mut client = XTransport::new(a, with_token(b"secret"));
+    client.handshake().expect("handshake");
+    client.send_message(b"hello").expect("send");
+
+    let (message, recorded, version) = server.join().expect("server");
+    assert_eq!(message, b"hello");
+    assert!(recorded, "capabilities of an authenticated peer not applied");
+    assert_eq!((version, client.wire_version()), (VERSION_2, VERSION_2));
+}
+
+#[test]
+fn wrong_token_is_rejected_with_goaway() {
+    let (a, b) = pair();
+    let server = thread::spawn(move || {
+        let mut transport = XTransport::new(b, with_token(b"secret"));
+        let error = transport.recv_message().expect_err("message from an unauthenticated peer");
+        (error.kind(), transport.peer_capabilities().is_some())
+    });
+    let mut client = XTransport::new(a, with_token(b"guess"));
+    let error = client.handshake().expect_err("wrong token accepted");
+    assert_eq!(error.kind(), ErrorKind::PeerGoaway { code: GOAWAY_UNAUTHORIZED });
+
+    let (kind, recorded) = server.join().expect("server");
+    assert_eq!(kind, ErrorKind::Unauthorized);
+    assert!(!recorded, "capabilities of a rejected peer applied");
+}
+
+#[test]
+fn connecting_side_requires_the_peer_to_authenticate() {
+    // Calling `handshake` does not excuse the peer from answering this end's challenge
+    let (a, b) = pair();
+    let peer = thread::spawn(move || {
+        let mut transport = XTransport::new(b, config());
+        transport.recv_message().expect_err("message after a failed handshake").kind()
+    });
+    let mut client = XTransport::new(a, with_token(b"secret"));
+    let error = client.handshake().expect_err("peer without a token accepted");
+    assert_eq!(error.kind(), ErrorKind::Unauthorized);
+    assert_eq!(client.wire_version(), VERSION, "capabilities of a rejected peer applied");
+    assert_eq!(peer.join().expect("peer"), ErrorKind::PeerGoaway { code: GOAWAY_UNAUTHORIZED });
+}
+
+#[test]
+fn data_before_authentication_is_rejected() {
+    let data = |seq| Packet::new(PacketType::Data, seq, b"sneaky".to_vec()).to_wire();
+    // Alone, and in sequence after a HELLO the server answered
+    for input in [data(0), [hello(), data(1)].concat()] {
+        let mut server = XTransport::new(Peer::new(input), with_token(b"secret"));
+        let error = server.recv_message().expect_err("unauthenticated data delivered");
+        assert_eq!(error.kind(), ErrorKind::Unauthorized);
+        let (peer, _) = server.into_parts();
+        assert_eq!(goaway_codes(&peer), [GOAWAY_UNAUTHORIZED]);
+    }
+}
+
+#[test]
+fn capabilities_wait_for_authentication() {
+    let mut server = XTransport::new(Peer::new(hello()), with_token(b"secret"));
+    assert_eq!(server.recv_message().expect_err("end of stream").kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(server.wire_version(), VERSION);
+    assert!(server.peer_capabilities().is_none());
+
+    // Without an authenticator the same HELLO takes effect at once
+    let mut open = XTransport::new(Peer::new(hello()), config());
+    assert_eq!(open.recv_message().expect_err("end of stream").kind(), ErrorKind::UnexpectedEof);
+    assert!(open.peer_capabilities().is_some());
+    assert_eq!(open.wire_version(), VERSION_2);
+}
@@ -0,0 +1,104 @@
+//! Small messages packed into Batch packets once the peer has shown it unpacks them
+
+mod common;
+
+use common::{packets, pair, Peer, Tap};
+use std::thread;
+use xtransport::capability::Feature;
+use xtransport::error::ErrorKind;
+use xtransport::protocol::{Packet, PacketType, HEADER_SIZE};
+use xtransport::{TransportConfig, XTransport};
+
+fn messages() -> Vec<Vec<u8>> {
+    (0..10u8).map(|i| vec![i; 20 + i as usize]).collect()
+}
+
+fn types(packets: &[Packet]) -> Vec<u8> {
+    packets.iter().map(|packet| packet.header.pkt_type).filter(|&t| t != PacketType::Ping as u8).collect()
+}
+
+fn count(packets: &[Packet], pkt_type: PacketType) -> usize {
+    packets.iter().filter(|packet| packet.header.pkt_type == pkt_type as u8).count()
+}
+
+/// Send `messages` with `send_messages` after a handshake, returning what arrived and the packets written
+fn exchange(sender: TransportConfig, receiver: TransportConfig, messages: &[Vec<u8>]) -> (Vec<Vec<u8>>, Vec<Packet>) {
+    let expected = messages.len();
+    let (a, b) = pair();
+    let receiver = thread::spawn(move || {
+        let mut transport = XTransport::new(b, receiver);
+        (0..expected).map(|_| transport.recv_message().expect("message")).collect::<Vec<_>>()
+    });
+    let mut transport = XTransport::new(Tap::new(a), sender);
+    transport.handshake().expect("handshake");
+    let borrowed: Vec<&[u8]> = messages.iter().map(Vec::as_slice).collect();
+    transport.send_messages(&borrowed).expect("send");
+    (receiver.join().expect("receiver"), transport.get_ref().packets())
+}
+
+#[test]
+fn batched_messages_arrive_one_by_one() {
+    let expected = messages();
+    let (received, written) = exchange(TransportConfig::default(), TransportConfig::default(), &expected);
+    assert_eq!(received, expected);
+    assert_eq!(count(&written, PacketType::Batch), 1);
+    assert_eq!(count(&written, PacketType::Data), 0);
+}
+
+#[test]
+fn batches_never_exceed_the_payload_size() {
+    // Room for two 30-byte messages and their 4-byte lengths, not three
+    let config = || TransportConfig::default().with_max_frame_size(HEADER_SIZE + 100);
+    let expected: Vec<Vec<u8>> = (0..9u8).map(|i| vec![i; 30]).collect();
+    let (received, written) = exchange(config(), config(), &expected);
+    assert_eq!(received, expected);
+    // Four full batches and the odd message out on its own
+    assert_eq!(count(&written, PacketType::Batch), 4);
+    assert_eq!(count(&written, PacketType::Data), 1);
+    assert!(written.iter().all(|packet| packet.data.len() <= 100));
+}
+
+#[test]
+fn large_message_ends_the_batch_and_keeps_its_place() {
+    let config = || TransportConfig::default().with_max_frame_size(HEADER_SIZE + 100);
+    let expected = [vec![1; 10], vec![2; 10], vec![3; 250], vec![4; 10], Vec::new(), vec![5; 10]];
+    let (received, written) = exchange(config(), config(), &expected);
+    assert_eq!(received, expected);
+    let (batch, head, data) = (PacketType::Batch as u8, PacketType::MessageHead as u8, PacketType::MessageData as u8);
+    // The empty message is a record like any other
+    assert_eq!(types(&written), [batch, head, data, data, data, batch]);
+}
+
+#[test]
+fn no_batches_before_a_handshake() {
+    let expected = messages();
+    let borrowed: Vec<&[u8]> = expected.iter().map(Vec::as_slice).collect();
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default());
+    sender.send_messages(&borrowed).expect("send");
+    let written = packets(&sender.into_parts().0.output);
+    assert_eq!(count(&written, PacketType::Batch), 0);
+    assert_eq!(count(&written, PacketType::Data), expected.len());
+}
+
+#[test]
+fn no_batches_for_a_peer_without_the_feature() {
+    let expected = messages();
+    let capabilities = TransportConfig::default().capabilities.without_feature(Feature::Batch);
+    let without_batch = TransportConfig::default().with_capabilities(capabilities);
+    let (received, written) = exchange(TransportConfig::default(), without_batch, &expected);
+    assert_eq!(received, expected);
+    assert_eq!(count(&written, PacketType::Batch), 0);
+    assert_eq!(count(&written, PacketType::Data), expected.len());
+}
+
+#[test]
+fn malformed_batch_records_fail() {
+    // A record announcing 10 bytes followed by 3, and a length cut short
+    let truncated = [&10u32.to_le_bytes()[..], b"abc"].concat();
+    let short_length = [&1u32.to_le_bytes()[..], b"a", &[0, 0]].concat();
+    for batch in [truncated, short_length] {
+        let wire = Packet::new(PacketType::Batch, 0, batch).to_wire();
+        let mut receiver = XTransport::new(Peer::new(wire), TransportConfig::default());
+        assert_eq!(receiver.recv_message().expect_err("malformed record delivered").kind(), ErrorKind::InvalidPacket);
+    }
+}
@@ -0,0 +1,129 @@
+//! Sends and receives given up through a cancellation token
+
+mod common;
+
+use common::{packets, pair, Peer, Tap};
+use std::thread;
+use xtransport::capability::Feature;
+use xtransport::error::ErrorKind;
+use xtransport::observer::Transfer;
+use xtransport::protocol::{Packet, PacketType, HEADER_SIZE};
+use xtransport::{CancellationToken, TransportConfig, XTransport};
+
+fn small_frames() -> TransportConfig {
+    TransportConfig::default().with_max_frame_size(HEADER_SIZE + 100)
+}
+
+/// Config cancelling `token` once `after` bytes of a message have been sent
+fn cancelling(config: TransportConfig, token: &CancellationToken, after: usize) -> TransportConfig {
+    let token = token.clone();
+    config.with_progress(1, move |transfer, done, _| {
+        if transfer == Transfer::Send && done >= after {
+            token.cancel();
+        }
+    })
+}
+
+fn count(packets: &[Packet], pkt_type: PacketType) -> usize {
+    packets.iter().filter(|packet| packet.header.pkt_type == pkt_type as u8).count()
+}
+
+/// Wire of a 1000-byte message cut off after 200 bytes, followed by a short message
+fn reset_wire() -> Vec<u8> {
+    let token = CancellationToken::new();
+    let mut sender = XTransport::new(Peer::new(Vec::new()), cancelling(small_frames(), &token, 200));
+    let error = sender.send_message_cancellable(&[7; 1000], &token).expect_err("cancelled send finished");
+    assert_eq!(error.kind(), ErrorKind::Cancelled);
+    sender.send_message(b"after").expect("send");
+    sender.into_parts().0.output
+}
+
+#[test]
+fn live_token_lets_messages_through() {
+    let token = CancellationToken::new();
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default());
+    sender.send_message_cancellable(b"hello", &token).expect("send");
+
+    let wire = sender.into_parts().0.output;
+    let mut receiver = XTransport::new(Peer::new(wire), TransportConfig::default());
+    assert_eq!(receiver.recv_message_cancellable(&token).expect("receive"), b"hello");
+}
+
+#[test]
+fn cancelled_send_writes_nothing() {
+    let token = CancellationToken::new();
+    token.clone().cancel();
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default());
+    let error = sender.send_message_cancellable(&[7; 4096], &token).expect_err("cancelled send went out");
+    assert_eq!(error.kind(), ErrorKind::Cancelled);
+
+    // The token only applies to the call it was passed to
+    sender.send_message(b"after").expect("send");
+    let written = packets(&sender.into_parts().0.output);
+    assert_eq!(written.len(), 1);
+    assert_eq!(written[0].data, b"after");
+}
+
+#[test]
+fn send_cancelled_midway_resets_the_message() {
+    let written = packets(&reset_wire());
+    let head = &written[0];
+    assert_eq!(head.header.pkt_type, PacketType::MessageHead as u8);
+    // 92 bytes of message per packet: the token is seen before the fourth
+    assert_eq!(count(&written, PacketType::MessageData), 3);
+
+    let reset = &written[written.len() - 2];
+    assert_eq!(reset.header.pkt_type, PacketType::MessageReset as u8);
+    assert_eq!(reset.data, written[1].data[..8]);
+    assert_eq!(written[written.len() - 1].data, b"after");
+}
+
+#[test]
+fn reset_message_is_dropped_by_the_receiver() {
+    let mut receiver = XTransport::new(Peer::new(reset_wire()), small_frames());
+    assert_eq!(receiver.recv_message().expect("receive"), b"after");
+}
+
+#[test]
+fn reset_fails_a_streamed_receive() {
+    let mut receiver = XTransport::new(Peer::new(reset_wire()), small_frames());
+    let mut streamed = 0;
+    let error = receiver
+        .recv_message_chunks(|chunk| {
+            streamed += chunk.len();
+            Ok(())
+        })
+        .expect_err("reset message streamed");
+    assert_eq!(error.kind(), ErrorKind::Cancelled);
+    assert_eq!(streamed, 3 * 92);
+    // The stream carries on after the reset message
+    assert_eq!(receiver.recv_message().expect("receive"), b"after");
+}
+
+#[test]
+fn started_message_is_finished_for_a_peer_without_resets() {
+    let (a, b) = pair();
+    let receiver = thread::spawn(move || {
+        let mut config = small_frames();
+        config.capabilities = config.capabilities.without_feature(Feature::MessageReset);
+        XTransport::new(b, config).recv_message().expect("message")
+    });
+    let token = CancellationToken::new();
+    let mut sender = XTransport::new(Tap::new(a), cancelling(small_frames(), &token, 200));
+    sender.handshake().expect("handshake");
+    sender.send_message_cancellable(&[7; 1000], &token).expect("send");
+
+    assert_eq!(receiver.join().expect("receiver"), [7; 1000]);
+    assert_eq!(count(&sender.get_ref().packets(), PacketType::MessageReset), 0);
+}
+
+#[test]
+fn cancelled_receive_keeps_the_message() {
+    let token = CancellationToken::new();
+    token.cancel();
+    let wire = Packet::new(PacketType::Data, 0, b"waiting".to_vec()).to_wire();
+    let mut receiver = XTransport::new(Peer::new(wire), TransportConfig::default());
+    let error = receiver.recv_message_cancellable(&token).expect_err("cancelled receive returned");
+    assert_eq!(error.kind(), ErrorKind::Cancelled);
+    assert_eq!(receiver.recv_message().expect("receive"), b"waiting");
+}
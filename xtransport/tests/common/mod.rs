@@ -0,0 +1,121 @@
+//! In-memory peers shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::io::{Cursor, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use xtransport::error::ErrorKind;
+use xtransport::protocol::Packet;
+use xtransport::XTransport;
+
+/// Stream that replays fixed input and records everything written to it
+pub struct Peer {
+    input: Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+}
+
+impl Peer {
+    pub fn new(input: Vec<u8>) -> Self {
+        Peer { input: Cursor::new(input), output: Vec::new() }
+    }
+}
+
+impl Read for Peer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Peer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Split a byte stream into its packets
+pub fn packets(mut stream: &[u8]) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    while !stream.is_empty() {
+        let (packet, len) = Packet::parse(stream).expect("valid packet");
+        packets.push(packet);
+        stream = &stream[len..];
+    }
+    packets
+}
+
+/// Hex string of `bytes`, lowercase and without separators
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Bytes of a hex string, ignoring whitespace
+pub fn from_hex(hex: &str) -> Vec<u8> {
+    let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    assert!(digits.len().is_multiple_of(2), "odd number of hex digits");
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).expect("ascii"), 16).expect("hex digit"))
+        .collect()
+}
+
+/// Receive the next message, retrying reads that time out
+pub fn recv<S: xtransport::io::Read + xtransport::io::Write>(transport: &mut XTransport<S>) -> Vec<u8> {
+    loop {
+        match transport.recv_message() {
+            Ok(message) => return message,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => panic!("receive failed: {}", e),
+        }
+    }
+}
+
+/// Connected blocking streams, so a test never hangs on a lost reply
+pub fn pair() -> (UnixStream, UnixStream) {
+    let (a, b) = UnixStream::pair().expect("socket pair");
+    for stream in [&a, &b] {
+        stream.set_read_timeout(Some(Duration::from_secs(10))).expect("read timeout");
+    }
+    (a, b)
+}
+
+/// Socket that keeps a copy of everything written to it
+pub struct Tap {
+    inner: UnixStream,
+    pub written: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Tap {
+    pub fn new(inner: UnixStream) -> Self {
+        Tap { inner, written: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// Packets written so far
+    pub fn packets(&self) -> Vec<Packet> {
+        packets(&self.written.lock().expect("tap"))
+    }
+}
+
+impl Read for Tap {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for Tap {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.lock().expect("tap").extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
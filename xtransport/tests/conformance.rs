@@ -0,0 +1,117 @@
+//! Runs the documented wire vectors in `tests/conformance/vectors.txt`
+
+mod common;
+
+use common::{from_hex, to_hex, Peer};
+use std::collections::BTreeMap;
+use xtransport::protocol::{MessageHead, Packet, PacketType};
+use xtransport::{TransportConfig, XTransport};
+
+const VECTORS: &str = include_str!("conformance/vectors.txt");
+
+struct Vector {
+    name: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl Vector {
+    fn get(&self, key: &str) -> &str {
+        self.fields.get(key).unwrap_or_else(|| panic!("{}: missing {}", self.name, key))
+    }
+
+    fn number<N: std::str::FromStr>(&self, key: &str) -> N {
+        self.get(key).parse().unwrap_or_else(|_| panic!("{}: invalid {}", self.name, key))
+    }
+
+    fn bytes(&self, key: &str) -> Vec<u8> {
+        from_hex(self.get(key))
+    }
+}
+
+fn vectors() -> Vec<Vector> {
+    let mut vectors: Vec<Vector> = Vec::new();
+    for line in VECTORS.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            vectors.push(Vector { name: name.to_string(), fields: BTreeMap::new() });
+            continue;
+        }
+        let (key, value) = line.split_once('=').unwrap_or_else(|| panic!("malformed line {:?}", line));
+        let vector = vectors.last_mut().expect("field before the first vector");
+        vector.fields.insert(key.trim().to_string(), value.trim().to_string());
+    }
+    vectors
+}
+
+fn of_kind(kind: &str) -> Vec<Vector> {
+    let vectors: Vec<Vector> = vectors().into_iter().filter(|vector| vector.get("kind") == kind).collect();
+    assert!(!vectors.is_empty(), "no {} vectors", kind);
+    vectors
+}
+
+#[test]
+fn packets() {
+    for vector in of_kind("packet") {
+        let pkt_type = PacketType::from_u8(vector.number("type")).expect("known type");
+        let seq = vector.number("seq");
+        let payload = vector.bytes("payload");
+        let ack = vector.fields.get("ack").map(|_| vector.number::<u32>("ack"));
+        let mut packet = match ack {
+            Some(ack_seq) => Packet::with_ack(pkt_type, seq, ack_seq, &payload),
+            None => Packet::new(pkt_type, seq, payload.clone()),
+        };
+        if let Some(version) = vector.fields.get("version") {
+            packet.header.version = version.parse().expect("valid version");
+        }
+        assert_eq!(to_hex(&packet.to_wire()), vector.get("wire"), "{}: encoding", vector.name);
+
+        let bytes = vector.bytes("wire");
+        let (mut parsed, len) = Packet::parse(&bytes).unwrap_or_else(|e| panic!("{}: {}", vector.name, e));
+        assert_eq!(len, bytes.len(), "{}: wire length", vector.name);
+        assert_eq!(parsed.take_ack().expect("valid ack"), ack, "{}: ack", vector.name);
+        assert_eq!(parsed.header.pkt_type, pkt_type as u8, "{}: type", vector.name);
+        assert_eq!(parsed.header.seq, seq, "{}: seq", vector.name);
+        assert_eq!(parsed.data, payload, "{}: payload", vector.name);
+    }
+}
+
+#[test]
+fn message_heads() {
+    for vector in of_kind("head") {
+        let mut head = MessageHead::new(vector.number("total_length"), vector.number("message_id"), vector.number("packet_count"));
+        head.flags = vector.number("flags");
+        assert_eq!(to_hex(&head.to_bytes()), vector.get("wire"), "{}: encoding", vector.name);
+        assert_eq!(MessageHead::parse(&vector.bytes("wire")).expect("valid head"), head, "{}: parsing", vector.name);
+    }
+}
+
+#[test]
+fn invalid_packets() {
+    for vector in of_kind("invalid") {
+        let error = match Packet::parse(&vector.bytes("wire")) {
+            Ok(_) => panic!("{}: parsed an invalid packet", vector.name),
+            Err(e) => e,
+        };
+        assert_eq!(format!("{:?}", error.kind()), vector.get("error"), "{}: error", vector.name);
+    }
+}
+
+#[test]
+fn messages() {
+    for vector in of_kind("message") {
+        let config = || TransportConfig::default().with_max_frame_size(vector.number("frame_size"));
+        let message = vector.bytes("message");
+
+        let mut sender = XTransport::new(Peer::new(Vec::new()), config());
+        sender.send_message(&message).expect("send");
+        let (peer, _) = sender.into_parts();
+        assert_eq!(to_hex(&peer.output), vector.get("wire"), "{}: sent", vector.name);
+
+        let mut receiver = XTransport::new(Peer::new(vector.bytes("wire")), config().with_ack(vector.number("ack")));
+        assert_eq!(receiver.recv_message().expect("receive"), message, "{}: received", vector.name);
+        let (peer, _) = receiver.into_parts();
+        assert_eq!(to_hex(&peer.output), vector.get("reply"), "{}: reply", vector.name);
+    }
+}
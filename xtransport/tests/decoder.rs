@@ -0,0 +1,120 @@
+//! Header checks that run before the decoder buffers a payload
+
+mod common;
+
+use common::pair;
+use std::thread;
+use xtransport::decoder::PacketDecoder;
+use xtransport::error::ErrorKind;
+use xtransport::io::Read;
+use xtransport::protocol::{PacketHeader, PacketType};
+use xtransport::{Result, TransportConfig, XTransport, VERSION, VERSION_2};
+
+/// Length announced by the forged headers, close to the 4 GiB a version 2 header can describe
+const FORGED_LENGTH: u32 = u32::MAX - 16;
+
+/// Stream sending one header and then zeros forever, as a peer announcing a huge payload would
+struct Forged {
+    header: Vec<u8>,
+    sent: usize,
+}
+
+impl Forged {
+    fn new(version: u8, length: u32) -> Self {
+        let mut header = PacketHeader::new(PacketType::Data, 0, length);
+        header.version = version;
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes);
+        Forged { header: bytes, sent: 0 }
+    }
+}
+
+impl Read for Forged {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let header = &self.header[self.sent.min(self.header.len())..];
+        let n = header.len().min(buf.len());
+        buf[..n].copy_from_slice(&header[..n]);
+        buf[n..].fill(0);
+        self.sent += buf.len();
+        Ok(buf.len())
+    }
+}
+
+impl xtransport::io::Write for Forged {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Fill `decoder` from `stream` until it reports something, failing the test if it keeps buffering
+fn decode_one(decoder: &mut PacketDecoder, stream: &mut Forged) -> ErrorKind {
+    for _ in 0..16 {
+        match decoder.decode() {
+            Some(Ok(_)) => panic!("forged header decoded"),
+            Some(Err(e)) => return e.kind(),
+            None => {}
+        }
+        decoder.fill_from(stream).expect("read");
+    }
+    panic!("decoder buffered {} bytes for a forged header", decoder.buffered());
+}
+
+#[test]
+fn version_2_header_fails_unless_negotiated() {
+    let mut decoder = PacketDecoder::new(1024);
+    let kind = decode_one(&mut decoder, &mut Forged::new(VERSION_2, 100));
+    assert_eq!(kind, ErrorKind::InvalidVersion);
+    assert_eq!(decoder.buffered(), 0, "a malformed header drops the buffer");
+}
+
+#[test]
+fn oversized_header_fails_before_its_payload_is_buffered() {
+    let mut decoder = PacketDecoder::new(1024).with_max_payload(4096);
+    decoder.set_version_2(true);
+    let kind = decode_one(&mut decoder, &mut Forged::new(VERSION_2, FORGED_LENGTH));
+    assert_eq!(kind, ErrorKind::InvalidPacket);
+
+    let mut decoder = PacketDecoder::new(1024).with_max_payload(4096);
+    let kind = decode_one(&mut decoder, &mut Forged::new(VERSION, 8192));
+    assert_eq!(kind, ErrorKind::InvalidPacket);
+}
+
+#[test]
+fn transport_rejects_forged_headers_without_resync() {
+    for (version, length, expected) in [
+        (VERSION_2, FORGED_LENGTH, ErrorKind::InvalidVersion),
+        (VERSION, 60_000, ErrorKind::InvalidPacket),
+    ] {
+        let config = TransportConfig::default().with_max_frame_size(1024);
+        let mut transport = XTransport::new(Forged::new(version, length), config);
+        let error = transport.recv_message().expect_err("forged header accepted");
+        assert_eq!(error.kind(), expected, "version {} announcing {} bytes", version, length);
+    }
+}
+
+#[test]
+fn peers_with_different_payload_limits_interoperate() {
+    let (a, b) = pair();
+    let large = TransportConfig::default().with_max_frame_size(256 * 1024);
+    let small = TransportConfig::default().with_max_frame_size(4096);
+    let message: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+    let expected = message.clone();
+
+    let receiver = thread::spawn(move || {
+        let mut transport = XTransport::new(b, small);
+        let received = transport.recv_message().expect("receive");
+        (transport.wire_version(), received)
+    });
+    let mut sender = XTransport::new(a, large);
+    sender.handshake().expect("handshake");
+    assert_eq!(sender.wire_version(), VERSION_2);
+    sender.send_message(&message).expect("send");
+
+    let (version, received) = receiver.join().expect("receiver");
+    assert_eq!(version, VERSION_2);
+    assert_eq!(received, expected);
+}
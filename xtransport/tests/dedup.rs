@@ -0,0 +1,119 @@
+//! Repeated payloads sent as references once the peer has acknowledged them
+
+mod common;
+
+use common::{pair, Peer, Tap};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use xtransport::cache::payload_hash;
+use xtransport::error::ErrorKind;
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::{TransportConfig, XTransport};
+
+fn config(entries: usize) -> TransportConfig {
+    TransportConfig::new().with_dedup_cache(entries).with_ack(true)
+}
+
+/// Send `messages` to an ACKing receiver, returning what it got and the sender's wire bytes
+fn exchange(config: fn() -> TransportConfig, messages: &[Vec<u8>]) -> (Vec<Vec<u8>>, Arc<Mutex<Vec<u8>>>) {
+    let (a, b) = pair();
+    let count = messages.len();
+    let receiver = thread::spawn(move || {
+        let mut transport = XTransport::new(b, config());
+        (0..count).map(|_| transport.recv_message().expect("message")).collect::<Vec<_>>()
+    });
+    let tap = Tap::new(a);
+    let written = Arc::clone(&tap.written);
+    let mut sender = XTransport::new(tap, config());
+    for message in messages {
+        sender.send_message(message).expect("send");
+    }
+    (receiver.join().expect("receiver"), written)
+}
+
+fn references(written: &Mutex<Vec<u8>>) -> usize {
+    common::packets(&written.lock().expect("tap"))
+        .iter()
+        .filter(|packet| packet.header.pkt_type == PacketType::Reference as u8)
+        .count()
+}
+
+#[test]
+fn acknowledged_payload_is_sent_as_a_reference() {
+    let blob = vec![0x5a; 4096];
+    let other = vec![0xa5; 4096];
+    let messages = [blob.clone(), other, blob.clone(), blob];
+    let (received, written) = exchange(|| config(8), &messages);
+    assert_eq!(received, messages);
+    assert_eq!(references(&written), 2, "the two repeats of the first payload");
+}
+
+#[test]
+fn evicted_payload_goes_out_in_full_again() {
+    // A one-entry cache forgets the first payload when the second one is sent
+    let first = vec![1u8; 512];
+    let second = vec![2u8; 512];
+    let messages = [first.clone(), second, first.clone(), first];
+    let (received, written) = exchange(|| config(1), &messages);
+    assert_eq!(received, messages);
+    assert_eq!(references(&written), 1, "only the last repeat finds the payload cached");
+}
+
+#[test]
+fn small_payloads_are_never_referenced() {
+    let messages = vec![vec![7u8; 32]; 3];
+    let (received, written) = exchange(|| config(8), &messages);
+    assert_eq!(received, messages);
+    assert_eq!(references(&written), 0);
+}
+
+#[test]
+fn no_reference_without_ack_mode() {
+    // Without ACK mode nothing confirms the peer cached the payload
+    let blob = vec![0x5a; 4096];
+    let (a, b) = pair();
+    let tap = Tap::new(a);
+    let written = Arc::clone(&tap.written);
+    let config = || TransportConfig::new().with_dedup_cache(8);
+    let mut sender = XTransport::new(tap, config());
+    for _ in 0..3 {
+        sender.send_message(&blob).expect("send");
+    }
+    assert_eq!(references(&written), 0);
+
+    let mut receiver = XTransport::new(b, config());
+    for _ in 0..3 {
+        assert_eq!(receiver.recv_message().expect("message"), blob);
+    }
+}
+
+#[test]
+fn unacknowledged_payload_stays_uncached() {
+    // An ACK for the MessageHead but not for the body leaves the payload uncached
+    let blob = vec![0x5a; 4096];
+    let ack_head = Packet::new(PacketType::Ack, 0, 0u32.to_le_bytes().to_vec()).to_wire();
+    let config = config(8).with_max_frame_size(1024).with_retransmit(1, 1);
+    let mut sender = XTransport::new(Peer::new(ack_head), config);
+    assert!(sender.send_message(&blob).is_err(), "the body was never acknowledged");
+    let error = sender.send_message(&blob).expect_err("no peer left");
+    assert_ne!(error.kind(), ErrorKind::UnknownReference);
+    let references = common::packets(&sender.get_ref().output)
+        .iter()
+        .filter(|packet| packet.header.pkt_type == PacketType::Reference as u8)
+        .count();
+    assert_eq!(references, 0);
+}
+
+#[test]
+fn unknown_reference_fails() {
+    let mut reference = payload_hash(b"never sent").to_le_bytes().to_vec();
+    reference.extend_from_slice(&10u64.to_le_bytes());
+    let packet = Packet::new(PacketType::Reference, 0, reference);
+    let (mut a, b) = pair();
+    a.write_all(&packet.header.to_bytes()).expect("header");
+    a.write_all(&packet.data).expect("payload");
+
+    let mut receiver = XTransport::new(b, TransportConfig::new().with_dedup_cache(8));
+    assert_eq!(receiver.recv_message().expect_err("unknown reference resolved").kind(), ErrorKind::UnknownReference);
+}
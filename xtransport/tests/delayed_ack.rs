@@ -0,0 +1,123 @@
+//! Delayed ACKs cover runs of packets instead of going out one per read
+
+mod common;
+
+use common::packets;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use xtransport::error::{Error, ErrorKind};
+use xtransport::io::{Read, Write};
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::{Result, TransportConfig, XTransport};
+
+const PACKETS: u32 = 20;
+
+/// Stream returning one packet per read, as a socket does when the peer sends them apart,
+/// and timing out once they run out
+struct Trickle {
+    input: VecDeque<Vec<u8>>,
+    output: Vec<u8>,
+    /// Time in microseconds, advanced by `step` on every read
+    now: Arc<AtomicU64>,
+    step: u64,
+}
+
+impl Trickle {
+    fn new(step_micros: u64) -> Self {
+        let input = (0..PACKETS).map(|seq| Packet::new(PacketType::Data, seq, vec![seq as u8; 100]).to_wire()).collect();
+        Trickle { input, output: Vec::new(), now: Arc::new(AtomicU64::new(1_000_000)), step: step_micros }
+    }
+
+    fn clock(&self) -> impl Fn() -> u64 + Send + 'static {
+        let now = Arc::clone(&self.now);
+        move || now.load(Ordering::Relaxed)
+    }
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.now.fetch_add(self.step, Ordering::Relaxed);
+        let packet = self.input.pop_front().ok_or_else(|| Error::new(ErrorKind::TimedOut))?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+}
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Receive every packet of `stream`, then the timeout, returning the sequence numbers ACKed
+fn acks(stream: Trickle, config: TransportConfig) -> Vec<u32> {
+    let config = config.with_ack(true).with_clock(stream.clock());
+    let mut receiver = XTransport::new(stream, config);
+    for seq in 0..PACKETS {
+        assert_eq!(receiver.recv_message().expect("message"), vec![seq as u8; 100]);
+    }
+    assert_eq!(receiver.recv_message().expect_err("no more packets").kind(), ErrorKind::TimedOut);
+    packets(&receiver.get_ref().output)
+        .iter()
+        .filter(|packet| packet.header.pkt_type == PacketType::Ack as u8)
+        .map(|packet| u32::from_le_bytes(packet.data[..4].try_into().expect("ACK sequence number")))
+        .collect()
+}
+
+#[test]
+fn one_ack_once_the_stream_runs_dry() {
+    let acked = acks(Trickle::new(10), TransportConfig::default().with_delayed_ack(50, 64));
+    assert_eq!(acked, [PACKETS - 1]);
+}
+
+#[test]
+fn ack_after_max_unacked_packets() {
+    let acked = acks(Trickle::new(10), TransportConfig::default().with_delayed_ack(50, 8));
+    assert_eq!(acked, [7, 15, PACKETS - 1]);
+}
+
+#[test]
+fn no_delay_acks_every_packet() {
+    let acked = acks(Trickle::new(10), TransportConfig::default());
+    assert_eq!(acked, (0..PACKETS).collect::<Vec<_>>());
+}
+
+#[test]
+fn without_a_clock_only_the_count_and_the_dry_stream_send_acks() {
+    // The delay cannot be measured, so it never expires however slow the packets are
+    let stream = Trickle::new(20_000);
+    let config = TransportConfig { clock: None, ..TransportConfig::default() }.with_ack(true).with_delayed_ack(50, 8);
+    let mut receiver = XTransport::new(stream, config);
+    for seq in 0..PACKETS {
+        assert_eq!(receiver.recv_message().expect("message"), vec![seq as u8; 100]);
+    }
+    assert_eq!(receiver.recv_message().expect_err("no more packets").kind(), ErrorKind::TimedOut);
+    let acked: Vec<u32> = packets(&receiver.get_ref().output)
+        .iter()
+        .map(|packet| u32::from_le_bytes(packet.data[..4].try_into().expect("ACK sequence number")))
+        .collect();
+    assert_eq!(acked, [7, 15, PACKETS - 1]);
+}
+
+#[test]
+fn ack_once_the_delay_expires() {
+    // 20 ms between packets and a 50 ms delay: the third packet after the oldest unacknowledged one is overdue
+    let acked = acks(Trickle::new(20_000), TransportConfig::default().with_delayed_ack(50, 64));
+    assert_eq!(acked, [3, 7, 11, 15, 19]);
+}
+
+#[test]
+fn adaptive_acks_are_delayed_only_for_bulk_traffic() {
+    // The first packet is ACKed at once, before there is a gap to measure
+    let acked = acks(Trickle::new(10), TransportConfig::default().with_adaptive_ack(50, 64));
+    assert_eq!(acked, [0, PACKETS - 1], "back-to-back packets");
+
+    let acked = acks(Trickle::new(20_000), TransportConfig::default().with_adaptive_ack(50, 64));
+    assert_eq!(acked, (0..PACKETS).collect::<Vec<_>>(), "sparse packets");
+}
@@ -0,0 +1,150 @@
+//! One message sent to every registered transport, with slow and failed peers handled per policy
+
+mod common;
+
+use common::packets;
+use std::io::{Read, Write};
+use xtransport::error::ErrorKind;
+use xtransport::fanout::{FanoutSender, SlowPeerPolicy};
+use xtransport::{TransportConfig, XTransport};
+
+/// Stream recording what it takes, refusing writes while stalled or failing them once broken
+struct Link {
+    written: Vec<u8>,
+    /// Writes still to refuse with `WouldBlock`; `u32::MAX` refuses them until changed
+    stalled: u32,
+    broken: bool,
+}
+
+impl Link {
+    fn open() -> Self {
+        Link { written: Vec::new(), stalled: 0, broken: false }
+    }
+
+    fn stalled(writes: u32) -> Self {
+        Link { stalled: writes, ..Link::open() }
+    }
+
+    fn broken() -> Self {
+        Link { broken: true, ..Link::open() }
+    }
+}
+
+impl Read for Link {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Write for Link {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.broken {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        if self.stalled > 0 {
+            if self.stalled != u32::MAX {
+                self.stalled -= 1;
+            }
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn transport(link: Link) -> XTransport<Link> {
+    XTransport::new(link, TransportConfig::default())
+}
+
+fn received(fanout: &mut FanoutSender<Link>, id: u64) -> Vec<Vec<u8>> {
+    let link = fanout.get_mut(id).expect("registered peer").get_ref();
+    packets(&link.written).into_iter().map(|packet| packet.data).collect()
+}
+
+#[test]
+fn every_peer_gets_every_message() {
+    let mut fanout = FanoutSender::new(SlowPeerPolicy::Block);
+    let ids: Vec<u64> = (0..3).map(|_| fanout.add(transport(Link::open()))).collect();
+    for message in [&b"first"[..], b"second"] {
+        let report = fanout.send(message);
+        assert_eq!((report.sent, report.dropped, report.failed.len()), (3, 0, 0));
+    }
+    for id in ids {
+        assert_eq!(received(&mut fanout, id), [b"first".to_vec(), b"second".to_vec()]);
+    }
+}
+
+#[test]
+fn failed_peer_is_removed_and_its_id_not_reused() {
+    let mut fanout = FanoutSender::new(SlowPeerPolicy::Block);
+    let good = fanout.add(transport(Link::open()));
+    let broken = fanout.add(transport(Link::broken()));
+    let report = fanout.send(b"hello");
+    assert_eq!(report.sent, 1);
+    assert_eq!(report.failed.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [broken]);
+    assert_eq!(report.failed[0].1.kind(), ErrorKind::Other);
+    assert_eq!(fanout.peers().collect::<Vec<_>>(), [good]);
+    assert_eq!(fanout.dropped(broken), None);
+    assert_eq!(received(&mut fanout, good), [b"hello".to_vec()]);
+
+    let later = fanout.add(transport(Link::open()));
+    assert!(later != broken && later != good);
+    // A removed peer takes its transport along, messages so far included
+    let transport = fanout.remove(good).expect("registered peer");
+    assert_eq!(packets(&transport.get_ref().written).len(), 1);
+    assert_eq!(fanout.peers().collect::<Vec<_>>(), [later]);
+}
+
+#[test]
+fn blocking_policy_waits_for_a_slow_peer() {
+    let mut fanout = FanoutSender::new(SlowPeerPolicy::Block);
+    let slow = fanout.add(transport(Link::stalled(3)));
+    // The refused bytes of the first message hold up the second until the stream takes them
+    assert_eq!(fanout.send(b"one").sent, 1);
+    let report = fanout.send(b"two");
+    assert_eq!((report.sent, report.dropped), (1, 0));
+    assert_eq!(received(&mut fanout, slow), [b"one".to_vec(), b"two".to_vec()]);
+}
+
+#[test]
+fn dropping_policy_skips_messages_until_the_peer_catches_up() {
+    let mut fanout = FanoutSender::new(SlowPeerPolicy::Drop);
+    let good = fanout.add(transport(Link::open()));
+    let slow = fanout.add(transport(Link::stalled(u32::MAX)));
+    // The first message is held back by the stalled stream, the next ones skipped
+    assert_eq!(fanout.send(b"one").sent, 2);
+    for message in [&b"two"[..], b"three"] {
+        let report = fanout.send(message);
+        assert_eq!((report.sent, report.dropped), (1, 1));
+    }
+    assert_eq!(fanout.dropped(slow), Some(2));
+    assert_eq!(fanout.dropped(good), Some(0));
+
+    // Once writable again, poll_flush delivers the backlog and later messages reach it
+    assert_eq!(fanout.poll_flush().sent, 1, "stalled peer still pending");
+    fanout.get_mut(slow).expect("registered peer").get_mut().stalled = 0;
+    assert_eq!(fanout.poll_flush().sent, 2);
+    assert_eq!(fanout.send(b"four").sent, 2);
+    assert_eq!(received(&mut fanout, slow), [b"one".to_vec(), b"four".to_vec()]);
+    assert_eq!(received(&mut fanout, good).len(), 4);
+}
+
+#[test]
+fn buffering_policy_removes_a_peer_over_its_limit() {
+    let mut fanout = FanoutSender::new(SlowPeerPolicy::Buffer { max_bytes: 100 });
+    let slow = fanout.add(transport(Link::stalled(u32::MAX)));
+    // Under the limit the backlog grows and nothing is skipped
+    let report = fanout.send(&[0; 20]);
+    assert_eq!((report.sent, report.dropped, report.failed.len()), (1, 0, 0));
+    assert!(fanout.get_mut(slow).expect("registered peer").pending_write_len() <= 100);
+
+    let report = fanout.send(&[0; 100]);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, slow);
+    assert_eq!(report.failed[0].1.kind(), ErrorKind::MemoryLimitExceeded);
+    assert!(fanout.is_empty());
+}
@@ -0,0 +1,124 @@
+//! Heap-free single-packet transport talking to `XTransport`
+
+mod common;
+
+use common::Peer;
+use std::collections::VecDeque;
+use xtransport::error::ErrorKind;
+use xtransport::fixed::FixedTransport;
+use xtransport::protocol::{Packet, PacketHeader, PacketType, HEADER_SIZE, MAX_PAYLOAD_SIZE_V1, VERSION_2};
+use xtransport::{Error, Read, Result, TransportConfig, Write, XTransport};
+
+/// Stream handing out one chunk per read, and `WouldBlock` for an empty chunk
+struct Chunks(VecDeque<Vec<u8>>);
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.0.pop_front() {
+            None => Ok(0),
+            Some(chunk) if chunk.is_empty() => Err(Error::new(ErrorKind::WouldBlock)),
+            Some(chunk) => {
+                assert!(chunk.len() <= buf.len(), "chunk larger than the free buffer");
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            }
+        }
+    }
+}
+
+impl Write for Chunks {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn data(seq: u32, payload: &[u8]) -> Vec<u8> {
+    Packet::new(PacketType::Data, seq, payload.to_vec()).to_wire()
+}
+
+#[test]
+fn interoperates_with_xtransport() {
+    let mut fixed: FixedTransport<_, 256> = FixedTransport::new(Peer::new(Vec::new()));
+    assert_eq!(fixed.send_message(b"from fixed").expect("send"), 0);
+    assert_eq!(fixed.send_message(b"again").expect("send"), 1);
+    let mut receiver = XTransport::new(Peer::new(fixed.into_inner().output), TransportConfig::default());
+    assert_eq!(receiver.recv_message().expect("first"), b"from fixed");
+    assert_eq!(receiver.recv_message().expect("second"), b"again");
+
+    // A Ping ahead of the message is skipped
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default());
+    sender.send_message(b"to fixed").expect("send");
+    let wire = [Packet::new(PacketType::Ping, 0, vec![0; 8]).to_wire(), sender.into_parts().0.output].concat();
+    let mut fixed: FixedTransport<_, 128> = FixedTransport::new(Peer::new(wire));
+    assert_eq!(fixed.recv_message().expect("message").payload, b"to fixed");
+    assert_eq!(fixed.recv_message().expect_err("end of stream").kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn version_2_headers_are_read() {
+    let payload = b"longer header";
+    let mut header = PacketHeader::for_parts(PacketType::Data, 7, &[payload]);
+    header.version = VERSION_2;
+    let mut wire = Vec::new();
+    header.write_to(&mut wire);
+    wire.extend_from_slice(payload);
+
+    let mut fixed: FixedTransport<_, 64> = FixedTransport::new(Peer::new(wire));
+    let frame = fixed.recv_message().expect("message");
+    assert_eq!((frame.header.seq, frame.payload), (7, &payload[..]));
+}
+
+#[test]
+fn oversized_and_corrupt_packets_are_skipped() {
+    let mut corrupt = data(1, b"flipped");
+    *corrupt.last_mut().expect("payload") ^= 0xff;
+    // The oversized packet spans several reads of the 64-byte buffer
+    let wire = [data(0, &[1; 200]), corrupt, data(2, b"fits")].concat();
+
+    let mut fixed: FixedTransport<_, 64> = FixedTransport::new(Peer::new(wire));
+    assert_eq!(fixed.max_message_size(), 64 - HEADER_SIZE);
+    let error = fixed.recv_message().expect_err("packet larger than the buffer");
+    assert_eq!((error.kind(), error.seq()), (ErrorKind::MessageTooLarge, Some(0)));
+    let error = fixed.recv_message().expect_err("bad checksum");
+    assert_eq!((error.kind(), error.seq()), (ErrorKind::CrcMismatch, Some(1)));
+    // Neither cost the packet after it
+    assert_eq!(fixed.recv_message().expect("message").payload, b"fits");
+    assert_eq!(fixed.stats().crc_failures, 1);
+}
+
+#[test]
+fn partial_packet_is_kept_across_would_block() {
+    let wire = data(0, b"arrives in three pieces");
+    let chunks = [wire[..6].to_vec(), Vec::new(), wire[6..20].to_vec(), Vec::new(), wire[20..].to_vec()];
+    let mut fixed: FixedTransport<_, 64> = FixedTransport::new(Chunks(chunks.into()));
+    assert_eq!(fixed.recv_message().expect_err("header cut short").kind(), ErrorKind::WouldBlock);
+    assert_eq!(fixed.recv_message().expect_err("payload cut short").kind(), ErrorKind::WouldBlock);
+    assert_eq!(fixed.recv_message().expect("message").payload, b"arrives in three pieces");
+}
+
+#[test]
+fn malformed_header_discards_what_was_buffered() {
+    // The garbage shares a read with the start of a packet, which goes with it
+    let good = data(1, b"after resync");
+    let chunks = [[vec![0xaa; HEADER_SIZE], data(0, b"lost")[..8].to_vec()].concat(), Vec::new(), good];
+    let mut fixed: FixedTransport<_, 64> = FixedTransport::new(Chunks(chunks.into()));
+    assert_eq!(fixed.recv_message().expect_err("bad magic").kind(), ErrorKind::InvalidMagic);
+    assert_eq!(fixed.recv_message().expect_err("nothing buffered").kind(), ErrorKind::WouldBlock);
+    let frame = fixed.recv_message().expect("message");
+    assert_eq!((frame.header.seq, frame.payload), (1, &b"after resync"[..]));
+}
+
+#[test]
+fn message_beyond_one_packet_is_not_sent() {
+    let mut fixed: FixedTransport<_, 64> = FixedTransport::new(Peer::new(Vec::new()));
+    let error = fixed.send_message(&vec![0; MAX_PAYLOAD_SIZE_V1 + 1]).expect_err("too large");
+    assert_eq!(error.kind(), ErrorKind::MessageTooLarge);
+    assert!(fixed.get_ref().output.is_empty());
+    // The sequence number was not used up
+    assert_eq!(fixed.send_message(&[0; MAX_PAYLOAD_SIZE_V1]).expect("largest packet"), 0);
+    assert_eq!(fixed.stats().packets_sent, 1);
+}
@@ -0,0 +1,112 @@
+//! Receive-window flow control over the simulated link
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use xtransport::error::ErrorKind;
+use xtransport::io::{Read, Write};
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::testing::{SimConfig, SimTransport};
+use xtransport::{Result, TransportConfig, XTransport};
+
+const MEMORY_LIMIT: usize = 64 * 1024;
+const MESSAGES: u8 = 5;
+
+/// Link endpoint that loses the first WindowUpdate reopening a window
+struct LoseWindowUpdate {
+    inner: SimTransport,
+    lost: Arc<AtomicBool>,
+}
+
+impl Read for LoseWindowUpdate {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for LoseWindowUpdate {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut kept = Vec::with_capacity(buf.len());
+        let mut rest = buf;
+        while let Ok((packet, len)) = Packet::parse(rest) {
+            let reopens = packet.header.pkt_type == PacketType::WindowUpdate as u8 && !packet.data.is_empty();
+            if !reopens || self.lost.swap(true, Ordering::Relaxed) {
+                kept.extend_from_slice(&rest[..len]);
+            }
+            rest = &rest[len..];
+        }
+        kept.extend_from_slice(rest);
+        self.inner.write_all(&kept)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn config(link: &SimTransport) -> TransportConfig {
+    TransportConfig::default()
+        .with_ack(true)
+        .with_retransmit(10, 5)
+        .with_clock(link.clock())
+}
+
+fn recv<S: Read + Write>(transport: &mut XTransport<S>) -> Vec<u8> {
+    loop {
+        match transport.recv_message() {
+            Ok(message) => return message,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => panic!("receive failed: {}", e),
+        }
+    }
+}
+
+#[test]
+fn zero_window_probe_recovers_lost_window_update() {
+    let (a, b) = SimTransport::pair(SimConfig::new().with_latency(1_000, 0).with_seed(7));
+    let lost = Arc::new(AtomicBool::new(false));
+    let receiver_config = config(&b).with_memory_limit(MEMORY_LIMIT);
+    let link = LoseWindowUpdate { inner: b, lost: lost.clone() };
+
+    // The receiver fills its memory with a queued reply, so its ACK of the
+    // second message closes the window; sending the reply reopens it, but
+    // the WindowUpdate saying so is lost
+    let receiver = thread::spawn(move || {
+        let mut transport = XTransport::new(link, receiver_config);
+        let mut received = vec![recv(&mut transport)];
+        transport.queue_message(0, &[0xCD; MEMORY_LIMIT]).expect("queue reply");
+        received.push(recv(&mut transport));
+        transport.flush_queue().expect("send reply");
+        while received.len() < MESSAGES as usize {
+            received.push(recv(&mut transport));
+        }
+        received
+    });
+
+    let sender_config = config(&a);
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let mut transport = XTransport::new(a, sender_config);
+        let result = (|| -> Result<Vec<u8>> {
+            transport.handshake()?;
+            for i in 0..MESSAGES {
+                transport.send_message(&[i; 100])?;
+            }
+            Ok(recv(&mut transport))
+        })();
+        let _ = done.send((result, transport.stats().window_probes));
+    });
+
+    let (reply, probes) = finished
+        .recv_timeout(Duration::from_secs(30))
+        .expect("sender stalled on a closed window");
+    assert_eq!(reply.expect("sender failed"), vec![0xCD; MEMORY_LIMIT]);
+    assert!(lost.load(Ordering::Relaxed), "no WindowUpdate was lost");
+    assert!(probes > 0);
+
+    let received = receiver.join().expect("receiver panicked");
+    let expected: Vec<Vec<u8>> = (0..MESSAGES).map(|i| vec![i; 100]).collect();
+    assert_eq!(received, expected);
+}
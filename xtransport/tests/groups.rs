@@ -0,0 +1,72 @@
+//! Message groups delivered together once every member has arrived
+
+mod common;
+
+use common::Peer;
+use xtransport::error::ErrorKind;
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::{TransportConfig, XTransport};
+
+fn config() -> TransportConfig {
+    TransportConfig::default().with_max_frame_size(256)
+}
+
+/// Wire bytes of what `send` writes
+fn sent(send: impl FnOnce(&mut XTransport<Peer>)) -> Vec<u8> {
+    let mut sender = XTransport::new(Peer::new(Vec::new()), config());
+    send(&mut sender);
+    sender.get_ref().output.clone()
+}
+
+fn group_head(seq: u32, id: u64, count: u32) -> Vec<u8> {
+    let mut data = id.to_le_bytes().to_vec();
+    data.extend_from_slice(&count.to_le_bytes());
+    Packet::new(PacketType::GroupHead, seq, data).to_wire()
+}
+
+#[test]
+fn group_is_delivered_whole() {
+    // The middle member spans several packets
+    let members = [b"head".to_vec(), vec![9; 1000], b"tail".to_vec()];
+    let wire = sent(|sender| {
+        sender.send_message(b"alone").expect("send");
+        sender.send_group(&[&members[0], &members[1], &members[2]]).expect("send group");
+        sender.send_group(&[b"one", b"two"]).expect("send group");
+    });
+
+    let mut receiver = XTransport::new(Peer::new(wire), config());
+    assert_eq!(receiver.recv_group().expect("lone message"), [b"alone".to_vec()]);
+    assert_eq!(receiver.recv_group().expect("group"), members);
+    // Read one by one, the members of a group still arrive together
+    assert_eq!(receiver.recv_message().expect("first member"), b"one");
+    assert_eq!(receiver.recv_group().expect("rest of the group"), [b"two".to_vec()]);
+}
+
+#[test]
+fn empty_group_sends_nothing() {
+    assert!(sent(|sender| sender.send_group(&[]).expect("send group")).is_empty());
+}
+
+#[test]
+fn incomplete_group_is_not_delivered() {
+    let wire = sent(|sender| sender.send_group(&[b"one", b"two", b"three"]).expect("send group"));
+    // Cut off before the last member
+    let cut = wire.len() - Packet::new(PacketType::Data, 0, b"three".to_vec()).to_wire().len();
+    let mut receiver = XTransport::new(Peer::new(wire[..cut].to_vec()), config());
+    assert_eq!(receiver.recv_group().expect_err("partial group delivered").kind(), ErrorKind::UnexpectedEof);
+
+    // Neither are members read one at a time
+    let mut receiver = XTransport::new(Peer::new(wire[..cut].to_vec()), config());
+    assert_eq!(receiver.recv_message().expect_err("member of a partial group delivered").kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn malformed_group_heads_fail() {
+    let data = |seq| Packet::new(PacketType::Data, seq, b"member".to_vec()).to_wire();
+    let short = Packet::new(PacketType::GroupHead, 0, 1u64.to_le_bytes().to_vec()).to_wire();
+    // A truncated head, an empty group, and a group started inside another
+    for wire in [short, group_head(0, 1, 0), [group_head(0, 1, 2), data(1), group_head(2, 2, 1)].concat()] {
+        let mut receiver = XTransport::new(Peer::new(wire), config());
+        assert_eq!(receiver.recv_group().expect_err("malformed group accepted").kind(), ErrorKind::InvalidPacket);
+    }
+}
@@ -0,0 +1,322 @@
+//! Bytes handed from an interrupt handler to the transport through `SpscQueue`
+
+use std::thread;
+use xtransport::error::ErrorKind;
+use xtransport::irq::{Consumer, DynSpscQueue, QueueStorage, SpscQueue};
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::{Read, Result, TransportConfig, Write, XTransport};
+
+/// Serial port whose receiver is fed by the interrupt and whose transmitter is discarded
+struct Serial<'a, S> {
+    rx: Consumer<'a, S>,
+}
+
+impl<S: QueueStorage> Read for Serial<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.rx.read(buf)
+    }
+}
+
+impl<S> Write for Serial<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn transport_reads_what_the_interrupt_queues() {
+    // Packets larger than the queue only ever arrive in pieces
+    let messages: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 10 + 7 * i as usize]).collect();
+    let wire: Vec<u8> = messages.iter().enumerate()
+        .flat_map(|(seq, message)| Packet::new(PacketType::Data, seq as u32, message.clone()).to_wire())
+        .collect();
+
+    let mut queue = SpscQueue::<64>::new();
+    let (mut producer, rx) = queue.split();
+    let received = thread::scope(|scope| {
+        // The "interrupt" queues one byte at a time, waiting while the queue is full
+        scope.spawn(move || {
+            for &byte in &wire {
+                while producer.is_full() {
+                    thread::yield_now();
+                }
+                assert!(producer.push(byte));
+            }
+        });
+        let mut transport = XTransport::new(Serial { rx }, TransportConfig::default());
+        let mut received = Vec::new();
+        while received.len() < messages.len() {
+            match transport.recv_message() {
+                Ok(message) => received.push(message),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::yield_now(),
+                Err(e) => panic!("receive failed: {}", e),
+            }
+        }
+        received
+    });
+    assert_eq!(received, messages);
+    assert_eq!(queue.dropped(), 0);
+}
+
+#[test]
+fn partial_packet_is_kept_while_the_queue_is_empty() {
+    let wire = Packet::new(PacketType::Data, 0, b"split by the interrupt".to_vec()).to_wire();
+    let mut queue = SpscQueue::<128>::new();
+    let (mut producer, rx) = queue.split();
+    let mut transport = XTransport::new(Serial { rx }, TransportConfig::default());
+
+    assert_eq!(producer.push_slice(&wire[..5]), 5);
+    assert_eq!(transport.recv_message().expect_err("header cut short").kind(), ErrorKind::WouldBlock);
+    assert_eq!(producer.push_slice(&wire[5..wire.len() - 1]), wire.len() - 6);
+    assert_eq!(transport.recv_message().expect_err("payload cut short").kind(), ErrorKind::WouldBlock);
+    assert!(producer.push(wire[wire.len() - 1]));
+    assert_eq!(transport.recv_message().expect("message"), b"split by the interrupt");
+}
+
+#[test]
+fn full_and_empty_are_told_apart_after_wrapping() {
+    let mut queue = SpscQueue::<4>::new();
+    let (mut producer, mut consumer) = queue.split();
+    // Both indices run modulo twice the capacity; go round that several times
+    for round in 0..10u8 {
+        assert!(consumer.is_empty());
+        assert!(!producer.is_full());
+        for i in 0..4 {
+            assert!(producer.push(round * 4 + i));
+        }
+        assert!(producer.is_full());
+        assert_eq!(consumer.len(), 4);
+        for i in 0..4 {
+            assert_eq!(consumer.pop(), Some(round * 4 + i));
+        }
+        assert_eq!(consumer.pop(), None);
+    }
+
+    // Offsets that straddle the end of the buffer
+    assert!(producer.push(1) && producer.push(2) && producer.push(3));
+    assert_eq!(consumer.pop(), Some(1));
+    assert_eq!(producer.push_slice(b"ab"), 2);
+    let mut buf = [0; 8];
+    assert_eq!(consumer.read(&mut buf).expect("read"), 4);
+    assert_eq!(&buf[..4], &[2, 3, b'a', b'b']);
+    assert_eq!(consumer.dropped(), 0);
+}
+
+#[test]
+fn full_queue_drops_bytes() {
+    let mut queue = SpscQueue::<8>::new();
+    let (mut producer, mut consumer) = queue.split();
+    assert_eq!(producer.push_slice(b"0123456789ab"), 8);
+    assert!(producer.is_full());
+    assert!(!producer.push(b'c'));
+    assert_eq!(consumer.dropped(), 5);
+
+    // The oldest bytes are kept, the overflow is lost
+    let mut buf = [0; 16];
+    assert_eq!(consumer.read(&mut buf).expect("read"), 8);
+    assert_eq!(&buf[..8], b"01234567");
+    assert_eq!(consumer.read(&mut buf).expect_err("queue empty").kind(), ErrorKind::WouldBlock);
+    assert_eq!(consumer.read(&mut []).expect("empty buffer"), 0);
+}
+
+/// Writer taking at most `limit` bytes per call and recording each call, failing once `fail_after` calls are used up
+struct Port {
+    writes: Vec<Vec<u8>>,
+    limit: usize,
+    fail_after: usize,
+}
+
+impl Port {
+    fn new(limit: usize) -> Self {
+        Port { writes: Vec::new(), limit, fail_after: usize::MAX }
+    }
+}
+
+impl std::io::Write for Port {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.writes.len() == self.fail_after {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        let n = buf.len().min(self.limit);
+        self.writes.push(buf[..n].to_vec());
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader handing out `chunks` one per call and counting the calls
+struct Uart {
+    chunks: Vec<Vec<u8>>,
+    reads: usize,
+}
+
+impl std::io::Read for Uart {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        if self.chunks.is_empty() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        let chunk = self.chunks.remove(0);
+        let n = chunk.len().min(buf.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        if n < chunk.len() {
+            self.chunks.insert(0, chunk[n..].to_vec());
+        }
+        Ok(n)
+    }
+}
+
+#[test]
+fn read_into_writer_drains_both_sides_of_the_wrap() {
+    let mut queue = SpscQueue::<8>::new();
+    let (mut producer, mut consumer) = queue.split();
+    assert_eq!(producer.push_slice(b"012345"), 6);
+    let mut buf = [0; 6];
+    assert_eq!(consumer.read(&mut buf).expect("read"), 6);
+
+    assert_eq!(producer.push_slice(b"wrapped"), 7);
+    let mut port = Port::new(usize::MAX);
+    assert_eq!(consumer.read_into_writer(&mut port).expect("drain"), 7);
+    // One write per contiguous part, no bounce buffer in between
+    assert_eq!(port.writes, [b"wr".to_vec(), b"apped".to_vec()]);
+    assert!(consumer.is_empty());
+    assert_eq!(consumer.read_into_writer(&mut port).expect("empty queue"), 0);
+    assert_eq!(port.writes.len(), 2, "writer called for an empty queue");
+}
+
+#[test]
+fn read_into_writer_stops_at_a_short_write() {
+    let mut queue = SpscQueue::<8>::new();
+    let (mut producer, mut consumer) = queue.split();
+    assert_eq!(producer.push_slice(b"abcdef"), 6);
+    let mut port = Port::new(4);
+    assert_eq!(consumer.read_into_writer(&mut port).expect("drain"), 4);
+    assert_eq!(port.writes.len(), 1);
+    // What the writer did not take stays queued
+    assert_eq!(consumer.len(), 2);
+    assert_eq!(consumer.pop(), Some(b'e'));
+}
+
+#[test]
+fn read_into_writer_fails_only_if_nothing_was_written() {
+    let mut queue = SpscQueue::<8>::new();
+    let (mut producer, mut consumer) = queue.split();
+    assert_eq!(producer.push_slice(b"012345"), 6);
+    let mut buf = [0; 6];
+    assert_eq!(consumer.read(&mut buf).expect("read"), 6);
+    assert_eq!(producer.push_slice(b"wrapped"), 7);
+
+    let mut port = Port { fail_after: 0, ..Port::new(usize::MAX) };
+    assert!(consumer.read_into_writer(&mut port).is_err());
+    assert_eq!(consumer.len(), 7);
+    // A failure after the part before the wrap reports that part
+    port.fail_after = 1;
+    assert_eq!(consumer.read_into_writer(&mut port).expect("first part written"), 2);
+    assert_eq!(consumer.len(), 5);
+}
+
+#[test]
+fn write_from_reader_fills_both_sides_of_the_wrap() {
+    let mut queue = SpscQueue::<8>::new();
+    let (mut producer, mut consumer) = queue.split();
+    assert_eq!(producer.push_slice(b"0123456"), 7);
+    let mut buf = [0; 7];
+    assert_eq!(consumer.read(&mut buf).expect("read"), 7);
+
+    let mut uart = Uart { chunks: vec![b"abcdefghij".to_vec()], reads: 0 };
+    // One byte of room before the end of the buffer and seven after it
+    assert_eq!(producer.write_from_reader(&mut uart).expect("fill"), 8);
+    assert_eq!(uart.reads, 2);
+    assert!(producer.is_full());
+    assert_eq!(producer.write_from_reader(&mut uart).expect("full queue"), 0);
+    assert_eq!(uart.reads, 2, "reader called with no room");
+
+    let mut out = [0; 8];
+    assert_eq!(consumer.read(&mut out).expect("read"), 8);
+    assert_eq!(&out, b"abcdefgh");
+    assert_eq!(consumer.dropped(), 0);
+}
+
+#[test]
+fn write_from_reader_stops_at_a_short_read() {
+    let mut queue = SpscQueue::<16>::new();
+    let (mut producer, consumer) = queue.split();
+    let mut uart = Uart { chunks: vec![b"abc".to_vec(), b"def".to_vec()], reads: 0 };
+    // A blocking reader is not asked again for bytes it may not have yet
+    assert_eq!(producer.write_from_reader(&mut uart).expect("fill"), 3);
+    assert_eq!(uart.reads, 1);
+    assert_eq!(producer.write_from_reader(&mut uart).expect("fill"), 3);
+    assert_eq!(consumer.len(), 6);
+    assert_eq!(producer.write_from_reader(&mut uart).expect_err("nothing to read").kind(), ErrorKind::WouldBlock);
+}
+
+#[test]
+fn heap_queue_grows_and_shrinks_around_queued_bytes() {
+    let mut queue = DynSpscQueue::with_capacity(8);
+    {
+        let (mut producer, mut consumer) = queue.split();
+        assert_eq!(producer.push_slice(b"012345"), 6);
+        let mut buf = [0; 4];
+        assert_eq!(consumer.read(&mut buf).expect("read"), 4);
+        // Queued bytes now wrap around the end of the storage
+        assert_eq!(producer.push_slice(b"abcdefgh"), 6);
+        assert!(producer.is_full());
+    }
+    assert_eq!(queue.dropped(), 2);
+
+    queue.reserve(8);
+    assert_eq!(queue.capacity(), 16);
+    {
+        let (mut producer, mut consumer) = queue.split();
+        assert_eq!(producer.push_slice(b"ghij"), 4);
+        let mut buf = [0; 16];
+        assert_eq!(consumer.read(&mut buf).expect("read"), 12);
+        assert_eq!(&buf[..12], b"45abcdefghij");
+        assert_eq!(producer.push_slice(b"xyz"), 3);
+    }
+
+    queue.shrink_to_fit();
+    assert_eq!((queue.capacity(), queue.len()), (3, 3));
+    let (mut producer, mut consumer) = queue.split();
+    assert!(producer.is_full());
+    assert_eq!(consumer.pop(), Some(b'x'));
+    assert!(producer.push(b'!'));
+    let mut buf = [0; 4];
+    assert_eq!(consumer.read(&mut buf).expect("read"), 3);
+    assert_eq!(&buf[..3], b"yz!");
+}
+
+#[test]
+fn transport_reads_through_a_heap_queue() {
+    let wire = Packet::new(PacketType::Data, 0, b"sized at runtime".to_vec()).to_wire();
+    let mut queue = DynSpscQueue::with_capacity(wire.len());
+    let (mut producer, rx) = queue.split();
+    assert_eq!(producer.push_slice(&wire), wire.len());
+    let mut transport = XTransport::new(Serial { rx }, TransportConfig::default());
+    assert_eq!(transport.recv_message().expect("message"), b"sized at runtime");
+}
+
+#[test]
+fn push_slice_copies_both_sides_of_the_wrap() {
+    let mut queue = SpscQueue::<8>::new();
+    let (mut producer, mut consumer) = queue.split();
+    assert_eq!(producer.push_slice(b"012345"), 6);
+    let mut buf = [0; 5];
+    assert_eq!(consumer.read(&mut buf).expect("read"), 5);
+
+    // Two slots before the end of the buffer, five after it, the rest dropped
+    assert_eq!(producer.push_slice(b"abcdefghij"), 7);
+    assert_eq!(consumer.dropped(), 3);
+    let mut out = [0; 8];
+    assert_eq!(consumer.read(&mut out).expect("read"), 8);
+    assert_eq!(&out, b"5abcdefg");
+    assert_eq!(producer.push_slice(b""), 0);
+    assert_eq!(consumer.dropped(), 3);
+}
@@ -0,0 +1,123 @@
+//! Messages kept on disk until delivered, and resent after a restart
+
+mod common;
+
+use common::Peer;
+use std::fs;
+use std::path::PathBuf;
+use xtransport::error::ErrorKind;
+use xtransport::journal::{FileJournal, MemoryJournal};
+use xtransport::{DuplicatePolicy, Journal, Result, TransportConfig, XTransport};
+
+/// Empty directory for one test's journal
+fn journal_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("xtransport-journal-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn pending_messages(journal: &mut impl Journal) -> Vec<Vec<u8>> {
+    journal.pending().expect("pending").into_iter().map(|(_, message)| message).collect()
+}
+
+/// Journal that loses its removals, like a sender crashing right after the peer's ACK
+struct NeverRemoves(MemoryJournal);
+
+impl Journal for NeverRemoves {
+    fn append(&mut self, message: &[u8]) -> Result<u64> {
+        self.0.append(message)
+    }
+
+    fn remove(&mut self, _id: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn pending(&mut self) -> Result<Vec<(u64, Vec<u8>)>> {
+        self.0.pending()
+    }
+}
+
+#[test]
+fn unacknowledged_message_is_resent_after_a_restart() {
+    let dir = journal_dir("resend");
+    // The peer never acknowledges, so the message stays journaled
+    let config = TransportConfig::default()
+        .with_ack(true)
+        .with_retransmit(1, 1)
+        .with_journal(FileJournal::open(&dir).expect("journal"));
+    let mut sender = XTransport::new(Peer::new(Vec::new()), config);
+    assert!(sender.send_message(b"durable").is_err());
+    drop(sender);
+
+    let mut journal = FileJournal::open(&dir).expect("reopen");
+    assert_eq!(pending_messages(&mut journal), [b"durable".to_vec()]);
+    let config = TransportConfig::default().with_journal(journal);
+    let mut sender = XTransport::new(Peer::new(Vec::new()), config);
+    assert_eq!(sender.resend_journal().expect("resend"), 1);
+    assert_eq!(sender.resend_journal().expect("resend"), 0, "delivered entries are removed");
+
+    let mut receiver = XTransport::new(Peer::new(sender.get_ref().output.clone()), TransportConfig::default());
+    assert_eq!(receiver.recv_message().expect("resent message"), b"durable");
+    fs::remove_dir_all(&dir).expect("cleanup");
+}
+
+#[test]
+fn resent_copy_is_suppressed_by_its_journal_id() {
+    // The same entry goes out twice; the receiver recognizes it by the journal ID it is keyed with
+    let mut journal = NeverRemoves(MemoryJournal::new());
+    journal.append(b"once").expect("append");
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default().with_journal(journal));
+    assert_eq!(sender.resend_journal().expect("resend"), 1);
+    assert_eq!(sender.resend_journal().expect("resend"), 1);
+    sender.send_message(b"next").expect("send");
+
+    let config = TransportConfig::default().with_duplicate_policy(DuplicatePolicy::Suppress, 16);
+    let mut receiver = XTransport::new(Peer::new(sender.get_ref().output.clone()), config);
+    assert_eq!(receiver.recv_message().expect("first copy"), b"once");
+    assert_eq!(receiver.recv_message().expect("next message"), b"next");
+}
+
+#[test]
+fn ids_stay_unique_across_restarts() {
+    let dir = journal_dir("ids");
+    let mut journal = FileJournal::open(&dir).expect("journal");
+    let first = journal.append(b"one").expect("append");
+    journal.remove(first).expect("remove");
+    // Every entry is gone, yet a reopened journal does not hand out the ID again
+    let mut journal = FileJournal::open(&dir).expect("reopen");
+    assert!(journal.append(b"two").expect("append") > first);
+    fs::remove_dir_all(&dir).expect("cleanup");
+
+    let mut memory = MemoryJournal::new();
+    let id = memory.append(b"x").expect("append");
+    memory.remove(id).expect("remove");
+    assert!(memory.append(b"y").expect("append") > id);
+}
+
+#[test]
+fn stray_files_are_not_entries() {
+    let dir = journal_dir("stray");
+    let mut journal = FileJournal::open(&dir).expect("journal");
+    journal.append(b"entry").expect("append");
+    // Left over from an interrupted write, and a file someone else put there
+    fs::write(dir.join("00000000000000000099.tmp"), b"partial").expect("temp file");
+    fs::write(dir.join("notes.msg"), b"not an entry").expect("foreign file");
+    assert_eq!(pending_messages(&mut FileJournal::open(&dir).expect("reopen")), [b"entry".to_vec()]);
+    // Removing an entry twice is not an error
+    journal.remove(1).expect("remove");
+    journal.remove(1).expect("remove again");
+    fs::remove_dir_all(&dir).expect("cleanup");
+}
+
+#[test]
+fn without_ack_mode_entries_go_once_written() {
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default().with_journal(MemoryJournal::new()));
+    sender.send_message(b"fire and forget").expect("send");
+    assert_eq!(sender.resend_journal().expect("resend"), 0);
+}
+
+#[test]
+fn resend_needs_a_journal() {
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default());
+    assert_eq!(sender.resend_journal().expect_err("no journal").kind(), ErrorKind::Unsupported);
+}
@@ -0,0 +1,150 @@
+//! The sans-IO `Protocol` driven by hand, against itself and against an `XTransport`
+
+mod common;
+
+use common::pair;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use xtransport::error::ErrorKind;
+use xtransport::machine::{Action, Event, Protocol, State};
+use xtransport::protocol::{Packet, PacketHeader, PacketType};
+use xtransport::{TransportConfig, XTransport, HEADER_SIZE};
+
+/// Frames among `actions`
+fn frames(actions: &[Action]) -> Vec<Vec<u8>> {
+    actions.iter()
+        .filter_map(|action| match action {
+            Action::Send(frame) => Some(frame.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Transitions among `actions`, as the state reached and its cause
+fn transitions(actions: &[Action]) -> Vec<(State, Event)> {
+    actions.iter()
+        .filter_map(|action| match action {
+            Action::Transition { to, event, .. } => Some((*to, *event)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Hand every frame among `actions` to `peer`, returning what it does in turn
+fn deliver(actions: &[Action], peer: &mut Protocol) -> Vec<Action> {
+    frames(actions).iter().flat_map(|frame| peer.handle_frame(frame).expect("frame")).collect()
+}
+
+#[test]
+fn two_protocols_connect_exchange_and_close() {
+    let (mut client, mut server) = (Protocol::new().with_max_payload(64), Protocol::new());
+    let hello = client.connect(0).expect("connect");
+    assert_eq!(transitions(&hello), [(State::SynSent, Event::Connect)]);
+    assert_eq!(client.next_timeout(), Some(5_000_000));
+
+    let pong = deliver(&hello, &mut server);
+    assert_eq!(transitions(&pong), [(State::Established, Event::HelloReceived)]);
+    let established = deliver(&pong, &mut client);
+    assert_eq!(transitions(&established), [(State::Established, Event::PongReceived)]);
+    assert_eq!(client.next_timeout(), None);
+
+    // Longer than a packet, so split into a head and data packets
+    let message: Vec<u8> = (0..200u8).collect();
+    let sent = client.send(&message).expect("send");
+    assert_eq!(frames(&sent).len(), 5);
+    let received = deliver(&sent, &mut server);
+    assert_eq!(received, [Action::Deliver(message)]);
+
+    let fin = client.close(1_000);
+    assert_eq!(transitions(&fin), [(State::Closing, Event::Close)]);
+    assert_eq!(client.send(b"late").expect_err("sent after close").kind(), ErrorKind::WriteShutdown);
+    assert_eq!(transitions(&deliver(&fin, &mut server)), [(State::Closing, Event::FinReceived)]);
+    // The server can still reply before closing its side
+    let reply = server.send(b"bye").expect("reply");
+    let mut actions = deliver(&reply, &mut client);
+    actions.extend(deliver(&server.close(2_000), &mut client));
+    assert_eq!(actions, [
+        Action::Deliver(b"bye".to_vec()),
+        Action::Transition { from: State::Closing, to: State::Closed, event: Event::FinReceived },
+    ]);
+    assert_eq!(server.state(), State::Closed);
+}
+
+#[test]
+fn deadlines_close_the_connection() {
+    let mut client = Protocol::new().with_handshake_timeout_ms(100);
+    client.connect(1_000).expect("connect");
+    assert!(client.poll_timeout(100_999).is_empty());
+    assert_eq!(transitions(&client.poll_timeout(101_000)), [(State::Closed, Event::Timeout)]);
+    assert_eq!(client.next_timeout(), None);
+    assert_eq!(client.connect(200_000).expect_err("connected twice").kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn frames_must_arrive_in_order() {
+    let mut server = Protocol::new();
+    let mut client = Protocol::new();
+    let hello = client.connect(0).expect("connect");
+    deliver(&hello, &mut server);
+    // A message before the handshake, and one skipping a sequence number
+    let early = Packet::new(PacketType::Data, 0, b"early".to_vec()).to_wire();
+    assert_eq!(Protocol::new().handle_frame(&early).expect_err("message before handshake").kind(), ErrorKind::InvalidPacket);
+    let skipped = Packet::new(PacketType::Data, 2, b"skipped".to_vec()).to_wire();
+    assert_eq!(server.handle_frame(&skipped).expect_err("gap accepted").kind(), ErrorKind::SequenceGap);
+    // A repeat of the handshake is dropped
+    assert!(server.handle_frame(&frames(&hello)[0]).expect("duplicate").is_empty());
+}
+
+/// Read one whole version 1 frame off `stream`
+fn read_frame(stream: &mut UnixStream) -> Vec<u8> {
+    let mut frame = vec![0; HEADER_SIZE];
+    stream.read_exact(&mut frame).expect("header");
+    let header = PacketHeader::parse(&frame).expect("valid header");
+    frame.resize(HEADER_SIZE + header.length as usize, 0);
+    stream.read_exact(&mut frame[HEADER_SIZE..]).expect("payload");
+    frame
+}
+
+/// Write the frames among `actions` to `stream`, returning the rest
+fn perform(actions: Vec<Action>, stream: &mut UnixStream) -> Vec<Action> {
+    actions.into_iter()
+        .filter(|action| match action {
+            Action::Send(frame) => {
+                stream.write_all(frame).expect("write");
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+#[test]
+fn protocol_talks_to_an_xtransport() {
+    let (mut stream, peer) = pair();
+    let echo = thread::spawn(move || {
+        let mut transport = XTransport::new(peer, TransportConfig::default());
+        let message = transport.recv_message().expect("message");
+        transport.send_message(&message).expect("echo");
+        assert_eq!(transport.recv_message().expect_err("peer closed").kind(), ErrorKind::UnexpectedEof);
+        transport.shutdown_write().expect("shutdown");
+    });
+
+    let mut protocol = Protocol::new();
+    perform(protocol.connect(0).expect("connect"), &mut stream);
+    let answer = protocol.handle_frame(&read_frame(&mut stream)).expect("pong");
+    assert_eq!(transitions(&answer), [(State::Established, Event::PongReceived)]);
+
+    let message: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+    perform(protocol.send(&message).expect("send"), &mut stream);
+    let mut delivered = Vec::new();
+    while delivered.is_empty() {
+        delivered = perform(protocol.handle_frame(&read_frame(&mut stream)).expect("frame"), &mut stream);
+    }
+    assert_eq!(delivered, [Action::Deliver(message)]);
+
+    perform(protocol.close(0), &mut stream);
+    let closed = protocol.handle_frame(&read_frame(&mut stream)).expect("fin");
+    assert_eq!(transitions(&closed), [(State::Closed, Event::FinReceived)]);
+    echo.join().expect("echo");
+}
@@ -0,0 +1,199 @@
+//! Noise XX handshake: published test vectors, a run over a connection and rejected peers
+
+mod common;
+
+use common::{from_hex, pair, to_hex};
+use std::thread;
+use xtransport::error::ErrorKind;
+use xtransport::noise::{Keypair, NoiseHandshake, KEY_LEN};
+use xtransport::protocol::GOAWAY_UNAUTHORIZED;
+use xtransport::{TransportConfig, XTransport};
+
+/// One `Noise_XX_25519_ChaChaPoly_SHA256` vector in the cacophony format
+struct Vector {
+    prologue: &'static str,
+    init_static: &'static str,
+    init_ephemeral: &'static str,
+    resp_static: &'static str,
+    resp_ephemeral: &'static str,
+    handshake_hash: Option<&'static str>,
+    /// Payload and ciphertext of the three handshake messages
+    messages: [(&'static str, &'static str); 3],
+}
+
+/// From cacophony's vectors, as shipped in snow's tests/vectors/cacophony.txt
+const CACOPHONY: Vector = Vector {
+    prologue: "4a6f686e2047616c74",
+    init_static: "e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1",
+    init_ephemeral: "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a",
+    resp_static: "4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893",
+    resp_ephemeral: "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b",
+    handshake_hash: Some("c8e5f64e846193be2a834104c2a009868d6c9f3bd3c186299888b488b2f1f58e"),
+    messages: [
+        (
+            "4c756477696720766f6e204d69736573",
+            "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573",
+        ),
+        (
+            "4d757272617920526f746862617264",
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f14480884381cbad1f276e038c48378ffce2b65285e08d6b68aaa3629a5a8639392490e5b9bd5269c2f1e4f488ed8831161f19b7815528f8982ffe09be9b5c412f8a0db50f8814c7194e83f23dbd8d162c9326ad",
+        ),
+        (
+            "462e20412e20486179656b",
+            "c7195ffacac1307ff99046f219750fc47693e23c3cb08b89c2af808b444850a80ae475b9df0f169ae80a89be0865b57f58c9fea0d4ec82a286427402f113e4b6ae769a1d95941d49b25030",
+        ),
+    ],
+};
+
+/// From snow's own vectors, tests/vectors/snow.txt
+const SNOW: Vector = Vector {
+    prologue: "5468657265206973206e6f20726967687420616e642077726f6e672e2054686572652773206f6e6c792066756e20616e6420626f72696e672e",
+    init_static: "dab4be55bd94d644dd4a7e19cf956bb943d0ec2b14f5b94fa4d095401d2ee59a",
+    init_ephemeral: "06382acb02bb823f8bd44c48b31d73d985162d540e38f86edd24fea02d0ff6e2",
+    resp_static: "899abec47f5fdbc165af6e9b79540dc1f87561a494e86e7cb72b9ff6100be08c",
+    resp_ephemeral: "e49eb9324a4d4c090fc939018574e5be3fde515fca0ba14701357084dd9c2cd9",
+    handshake_hash: None,
+    messages: [
+        (
+            "acdcb64d3e73672d8aaefbaf9837d3e5900989081c653a6d7c65033002427ac3",
+            "27ad4035b31f8e7a1782a2627aa5dedb7b855542cce2a4be40982c151606f77cacdcb64d3e73672d8aaefbaf9837d3e5900989081c653a6d7c65033002427ac3",
+        ),
+        (
+            "ca9d4a467552befa1a173c0683d3f9f98067be5c09ac7b9e98af2df186a5ba42",
+            "9bdcb63322dd83aada19cf3b6efd6d37efbc016d9ac0d3657b24669f27a86972363a0124a9876858dd41790463c85b4f4232ec234a84fc7c27239c962287da1b0d9f2a6240059fa23d8465ce72b308a505b88673953a0dae230f4f1b95725fefde6a47b08ab66ff36dcaccc4634b204128340f0bad4d5500ce4c314b97a8c87d",
+        ),
+        (
+            "4e04af6fda545c2b76e0e1146e9b2bdb3d5b469ec31bfde34a79d52224a65f2d",
+            "af67c1f4fef00fdd8432b05897091e8c88ebffe1d3450762fb504941699a188855d7ca26d16ac0120f8a6048ccd26f08ce74c74a86c4e6031593b44e9328775b842ab7ddffed63909c0af61c33a0f2aa5af926a4dedaff35f806ceb8e45dfcab",
+        ),
+    ],
+};
+
+fn key(hex: &str) -> [u8; KEY_LEN] {
+    from_hex(hex).try_into().expect("32-byte key")
+}
+
+/// Initiator and responder of `vector`, drawing the vector's ephemeral keys
+fn sides(vector: &Vector) -> (NoiseHandshake, NoiseHandshake) {
+    let prologue = from_hex(vector.prologue);
+    let ephemeral = |hex: &str| {
+        let key = key(hex);
+        move |buf: &mut [u8]| buf.copy_from_slice(&key)
+    };
+    let initiator = NoiseHandshake::initiator(Keypair::from_secret(key(vector.init_static)), &mut ephemeral(vector.init_ephemeral));
+    let responder = NoiseHandshake::responder(Keypair::from_secret(key(vector.resp_static)), &mut ephemeral(vector.resp_ephemeral));
+    (initiator.with_prologue(&prologue), responder.with_prologue(&prologue))
+}
+
+/// The first `count` messages of `vector`, written by the side whose turn it is and read by the other
+fn exchange(initiator: &mut NoiseHandshake, responder: &mut NoiseHandshake, vector: &Vector, count: usize) {
+    for (step, (payload, ciphertext)) in vector.messages.iter().take(count).enumerate() {
+        let (writer, reader) = if step % 2 == 0 { (&mut *initiator, &mut *responder) } else { (&mut *responder, &mut *initiator) };
+        let message = writer.write_message(&from_hex(payload)).expect("write");
+        assert_eq!(to_hex(&message), *ciphertext, "message {}", step);
+        assert_eq!(to_hex(&reader.read_message(&message).expect("read")), *payload, "payload {}", step);
+    }
+}
+
+#[test]
+fn published_vectors() {
+    for vector in [CACOPHONY, SNOW] {
+        let (mut initiator, mut responder) = sides(&vector);
+        exchange(&mut initiator, &mut responder, &vector, 3);
+        assert!(initiator.is_finished() && responder.is_finished());
+        assert_eq!(initiator.remote_static(), Some(Keypair::from_secret(key(vector.resp_static)).public()));
+        assert_eq!(responder.remote_static(), Some(Keypair::from_secret(key(vector.init_static)).public()));
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+        if let Some(hash) = vector.handshake_hash {
+            assert_eq!(to_hex(&initiator.handshake_hash()), hash);
+        }
+    }
+}
+
+#[test]
+fn different_prologues_fail() {
+    let (mut initiator, responder) = sides(&CACOPHONY);
+    let mut responder = responder.with_prologue(b"another protocol");
+    let first = initiator.write_message(&[]).expect("write");
+    responder.read_message(&first).expect("the first message is not encrypted");
+    let second = responder.write_message(&[]).expect("write");
+    assert_eq!(initiator.read_message(&second).expect_err("prologues differ").kind(), ErrorKind::AuthenticationFailed);
+}
+
+#[test]
+fn tampered_messages_fail() {
+    // Every byte of the second and third messages is covered by a tag or feeds a key exchange
+    for step in 1..3 {
+        let message = from_hex(CACOPHONY.messages[step].1);
+        for offset in 0..message.len() {
+            let (mut initiator, mut responder) = sides(&CACOPHONY);
+            exchange(&mut initiator, &mut responder, &CACOPHONY, step);
+            let reader = if step == 1 { &mut initiator } else { &mut responder };
+            let mut tampered = message.clone();
+            tampered[offset] ^= 0x01;
+            let error = reader.read_message(&tampered).expect_err("tampered message accepted");
+            assert_eq!(error.kind(), ErrorKind::AuthenticationFailed, "message {} byte {}", step, offset);
+        }
+    }
+}
+
+#[test]
+fn untrusted_static_key_is_rejected_when_read() {
+    let trusted = Keypair::from_secret([9; KEY_LEN]).public();
+    for rejecting_initiator in [true, false] {
+        let (mut initiator, mut responder) = sides(&CACOPHONY);
+        let step = if rejecting_initiator {
+            initiator = initiator.verify_peer(move |key| *key == trusted);
+            1
+        } else {
+            responder = responder.verify_peer(move |key| *key == trusted);
+            2
+        };
+        exchange(&mut initiator, &mut responder, &CACOPHONY, step);
+        let message = from_hex(CACOPHONY.messages[step].1);
+        let reader = if rejecting_initiator { &mut initiator } else { &mut responder };
+        assert_eq!(reader.read_message(&message).expect_err("untrusted key accepted").kind(), ErrorKind::Unauthorized);
+        assert_eq!(reader.remote_static(), None);
+    }
+}
+
+#[test]
+fn handshake_over_a_connection_keys_both_directions() {
+    let mut rng = |buf: &mut [u8]| buf.fill(3);
+    let client_key = Keypair::from_secret([1; KEY_LEN]);
+    let server_key = Keypair::from_secret([2; KEY_LEN]);
+    let (client_public, server_public) = (client_key.public(), server_key.public());
+    let client_handshake = NoiseHandshake::initiator(client_key, &mut rng).verify_peer(move |key| *key == server_public);
+    let server_handshake = NoiseHandshake::responder(server_key, &mut |buf: &mut [u8]| buf.fill(4));
+
+    let (a, b) = pair();
+    let server = thread::spawn(move || {
+        let mut transport = XTransport::new(b, TransportConfig::default());
+        let peer = transport.noise_handshake(server_handshake).expect("server handshake");
+        let request = transport.recv_message().expect("request");
+        transport.send_message(&[request.as_slice(), b" back"].concat()).expect("reply");
+        peer
+    });
+    let mut client = XTransport::new(a, TransportConfig::default());
+    assert_eq!(client.noise_handshake(client_handshake).expect("client handshake"), server_public);
+    client.send_message(b"ping").expect("send");
+    assert_eq!(client.recv_message().expect("reply"), b"ping back");
+    assert_eq!(server.join().expect("server"), client_public);
+}
+
+#[test]
+fn untrusted_server_gets_goaway() {
+    let client_handshake = NoiseHandshake::initiator(Keypair::from_secret([1; KEY_LEN]), &mut |buf: &mut [u8]| buf.fill(3))
+        .verify_peer(|_| false);
+    let server_handshake = NoiseHandshake::responder(Keypair::from_secret([2; KEY_LEN]), &mut |buf: &mut [u8]| buf.fill(4));
+
+    let (a, b) = pair();
+    let server = thread::spawn(move || {
+        let mut transport = XTransport::new(b, TransportConfig::default());
+        transport.noise_handshake(server_handshake).expect_err("handshake finished without the client").kind()
+    });
+    let mut client = XTransport::new(a, TransportConfig::default());
+    let error = client.noise_handshake(client_handshake).expect_err("untrusted server accepted");
+    assert_eq!(error.kind(), ErrorKind::Unauthorized);
+    assert_eq!(server.join().expect("server"), ErrorKind::PeerGoaway { code: GOAWAY_UNAUTHORIZED });
+}
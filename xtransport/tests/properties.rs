@@ -0,0 +1,170 @@
+//! Invariants checked over generated inputs
+
+mod common;
+
+use common::{packets, Peer};
+use proptest::prelude::*;
+use xtransport::decoder::PacketDecoder;
+use xtransport::protocol::{MessageHead, Packet, PacketType};
+use xtransport::window::SendWindow;
+use xtransport::{TransportConfig, XTransport, VERSION, VERSION_2};
+
+fn packet_type() -> impl Strategy<Value = PacketType> {
+    (0u8..=13).prop_map(|value| PacketType::from_u8(value).expect("known type"))
+}
+
+/// Reader returning at most the next of `sizes` bytes per call
+struct Chunked<'a> {
+    data: &'a [u8],
+    sizes: std::iter::Cycle<std::vec::IntoIter<usize>>,
+}
+
+impl std::io::Read for Chunked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.sizes.next().unwrap_or(1).min(buf.len()).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+proptest! {
+    #[test]
+    fn packet_round_trips(
+        pkt_type in packet_type(),
+        seq in any::<u32>(),
+        ack in proptest::option::of(any::<u32>()),
+        version in prop_oneof![Just(VERSION), Just(VERSION_2)],
+        payload in proptest::collection::vec(any::<u8>(), 0..4096),
+    ) {
+        let mut packet = match ack {
+            Some(ack_seq) => Packet::with_ack(pkt_type, seq, ack_seq, &payload),
+            None => Packet::new(pkt_type, seq, payload.clone()),
+        };
+        packet.header.version = version;
+        let bytes = packet.to_wire();
+        let (mut parsed, len) = Packet::parse(&bytes).expect("valid packet");
+        prop_assert_eq!(len, bytes.len());
+        prop_assert_eq!(parsed.header, packet.header);
+        prop_assert_eq!(parsed.take_ack().expect("valid ack"), ack);
+        prop_assert_eq!(PacketType::from_u8(parsed.header.pkt_type), Some(pkt_type));
+        prop_assert_eq!(parsed.header.seq, seq);
+        prop_assert_eq!(parsed.data, payload);
+    }
+
+    #[test]
+    fn corrupted_payload_fails_crc(
+        payload in proptest::collection::vec(any::<u8>(), 1..1024),
+        index in any::<prop::sample::Index>(),
+        flip in 1u8..=255,
+    ) {
+        let mut bytes = Packet::new(PacketType::Data, 0, payload).to_wire();
+        let offset = xtransport::HEADER_SIZE + index.index(bytes.len() - xtransport::HEADER_SIZE);
+        bytes[offset] ^= flip;
+        prop_assert!(Packet::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn message_head_round_trips(
+        total_length in any::<u64>(),
+        message_id in any::<u64>(),
+        packet_count in any::<u32>(),
+        flags in any::<u32>(),
+        reserved in any::<[u8; 8]>(),
+    ) {
+        let mut head = MessageHead::new(total_length, message_id, packet_count);
+        head.flags = flags;
+        head.reserved = reserved;
+        prop_assert_eq!(MessageHead::parse(&head.to_bytes()).expect("valid head"), head);
+    }
+
+    /// However the stream is split into reads, the decoder yields the same packets
+    #[test]
+    fn decoder_ignores_read_boundaries(
+        payloads in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..512), 1..16),
+        sizes in proptest::collection::vec(1usize..700, 1..8),
+    ) {
+        let stream: Vec<u8> = payloads.iter()
+            .enumerate()
+            .flat_map(|(seq, payload)| {
+                let mut packet = Packet::new(PacketType::Data, seq as u32, payload.clone());
+                // Mix header versions, as after a handshake
+                packet.header.version = if seq % 2 == 0 { VERSION } else { VERSION_2 };
+                packet.to_wire()
+            })
+            .collect();
+        let mut reader = Chunked { data: &stream, sizes: sizes.into_iter().cycle() };
+        let mut decoder = PacketDecoder::new(1024);
+        decoder.set_version_2(true);
+        let mut decoded = Vec::new();
+        while decoder.fill_from(&mut reader).is_ok() {
+            while let Some(packet) = decoder.decode() {
+                decoded.push(packet.expect("valid packet").data);
+            }
+        }
+        prop_assert_eq!(decoder.buffered(), 0);
+        prop_assert_eq!(decoded, payloads);
+    }
+
+    /// Cumulative ACKs in any order remove exactly a prefix of the window, once
+    #[test]
+    fn window_never_loses_or_duplicates(
+        start in any::<u32>(),
+        count in 1u32..64,
+        acks in proptest::collection::vec(0u32..96, 0..32),
+    ) {
+        let mut window = SendWindow::new();
+        for offset in 0..count {
+            window.push(start.wrapping_add(offset), vec![offset as u8], None);
+        }
+        let mut acked = 0u32;
+        for offset in acks {
+            let newly = window.ack(start.wrapping_add(offset)) as u32;
+            if offset >= acked && offset < count {
+                prop_assert_eq!(newly, offset + 1 - acked);
+                acked = offset + 1;
+            } else {
+                prop_assert_eq!(newly, 0);
+            }
+            prop_assert_eq!(window.len() as u32, count - acked);
+            for remaining in acked..count {
+                let seq = start.wrapping_add(remaining);
+                prop_assert!(window.contains(seq));
+                prop_assert_eq!(&window.get(seq).expect("in flight").wire, &vec![remaining as u8]);
+            }
+            prop_assert_eq!(window.oldest().map(|entry| entry.seq), (acked < count).then(|| start.wrapping_add(acked)));
+        }
+    }
+
+    /// A fragmented message is reassembled whatever order its packets arrive in
+    #[test]
+    fn reassembly_handles_any_fragment_order(
+        message in proptest::collection::vec(any::<u8>(), 1..4096),
+        frame_size in 64usize..512,
+        order in Just(()).prop_perturb(|_, mut rng| rng.next_u64()),
+    ) {
+        let config = || TransportConfig::default().with_max_frame_size(frame_size).with_reorder_window(1024);
+        let mut sender = XTransport::new(Peer::new(Vec::new()), config());
+        sender.send_message(&message).expect("send");
+        let (peer, _) = sender.into_parts();
+
+        let mut fragments = packets(&peer.output);
+        shuffle(&mut fragments, order);
+        let stream: Vec<u8> = fragments.iter().flat_map(Packet::to_wire).collect();
+
+        let mut receiver = XTransport::new(Peer::new(stream), config());
+        prop_assert_eq!(receiver.recv_message().expect("reassembled"), message);
+    }
+}
+
+/// Fisher-Yates shuffle driven by a seed
+fn shuffle<T>(items: &mut [T], mut seed: u64) {
+    for i in (1..items.len()).rev() {
+        // xorshift64*
+        seed ^= seed >> 12;
+        seed ^= seed << 25;
+        seed ^= seed >> 27;
+        let j = (seed.wrapping_mul(0x2545_f491_4f6c_dd1d) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
@@ -0,0 +1,117 @@
+//! Topic subscriptions and publications between two ends of a connection
+
+mod common;
+
+use common::{packets, pair, Peer};
+use std::time::Duration;
+use xtransport::error::ErrorKind;
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::pubsub::PubSub;
+use xtransport::{TransportConfig, XTransport};
+
+/// Read the control frames the peer has sent, until the short read timeout expires
+fn drain<S: xtransport::Read + xtransport::Write>(end: &mut PubSub<S>) {
+    let error = end.recv().expect_err("no publication was sent");
+    assert!(matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut), "{}", error);
+}
+
+/// Wire bytes of topic frames sent by a peer, as consecutive packets
+fn frames(frames: &[(u8, u32, &[u8])]) -> Vec<u8> {
+    frames.iter().enumerate()
+        .flat_map(|(seq, (kind, id, body))| {
+            let data = [&[*kind][..], &id.to_le_bytes(), body].concat();
+            Packet::new(PacketType::Data, seq as u32, data).to_wire()
+        })
+        .collect()
+}
+
+#[test]
+fn publications_reach_every_local_subscriber() {
+    let (a, b) = pair();
+    b.set_read_timeout(Some(Duration::from_millis(50))).expect("read timeout");
+    let mut subscriber = PubSub::new(XTransport::new(a, TransportConfig::default()));
+    let mut publisher = PubSub::new(XTransport::new(b, TransportConfig::default()));
+
+    let first = subscriber.subscribe("temperature").expect("subscribe");
+    let second = subscriber.subscribe("temperature").expect("subscribe");
+    drain(&mut publisher);
+    assert!(publisher.peer_subscribed("temperature"));
+    assert!(!publisher.publish("wind", b"12 kn").expect("publish"), "nobody subscribed to wind");
+    assert!(publisher.publish("temperature", b"21 C").expect("publish"));
+
+    let publication = subscriber.recv().expect("publication");
+    assert_eq!(publication.topic, "temperature");
+    assert_eq!(publication.subscribers, [first, second]);
+    assert_eq!(publication.data, b"21 C");
+
+    // The peer keeps the subscription until the last local consumer leaves
+    assert!(subscriber.unsubscribe(first).expect("unsubscribe"));
+    assert!(!subscriber.unsubscribe(first).expect("unsubscribe"), "already gone");
+    drain(&mut publisher);
+    assert!(publisher.peer_subscribed("temperature"));
+    assert!(subscriber.unsubscribe(second).expect("unsubscribe"));
+    drain(&mut publisher);
+    assert!(!publisher.peer_subscribed("temperature"));
+    assert!(!publisher.publish("temperature", b"22 C").expect("publish"));
+}
+
+#[test]
+fn each_side_numbers_its_own_topics() {
+    let (a, b) = pair();
+    for stream in [&a, &b] {
+        stream.set_read_timeout(Some(Duration::from_millis(50))).expect("read timeout");
+    }
+    let mut left = PubSub::new(XTransport::new(a, TransportConfig::default()));
+    let mut right = PubSub::new(XTransport::new(b, TransportConfig::default()));
+
+    // Both ends give their first topic ID 0, for different names
+    let on_left = left.subscribe("left").expect("subscribe");
+    let on_right = right.subscribe("right").expect("subscribe");
+    drain(&mut left);
+    drain(&mut right);
+    assert!(right.publish("left", b"to the left").expect("publish"));
+    assert!(left.publish("right", b"to the right").expect("publish"));
+
+    let publication = left.recv().expect("publication");
+    assert_eq!((publication.topic.as_str(), publication.subscribers), ("left", vec![on_left]));
+    let publication = right.recv().expect("publication");
+    assert_eq!((publication.topic.as_str(), publication.subscribers), ("right", vec![on_right]));
+}
+
+#[test]
+fn topic_is_declared_once() {
+    let mut end = PubSub::new(XTransport::new(Peer::new(Vec::new()), TransportConfig::default()));
+    let subscriber = end.subscribe("news").expect("subscribe");
+    assert!(end.unsubscribe(subscriber).expect("unsubscribe"));
+    end.subscribe("news").expect("subscribe again");
+
+    let kinds: Vec<u8> = packets(&end.get_ref().get_ref().output).iter().map(|packet| packet.data[0]).collect();
+    // Declare, subscribe, unsubscribe, subscribe
+    assert_eq!(kinds, [0, 1, 2, 1]);
+}
+
+#[test]
+fn publication_in_flight_after_unsubscribing_is_dropped() {
+    let wire = frames(&[(0, 0, b"old"), (0, 1, b"new"), (3, 0, b"late"), (3, 1, b"kept")]);
+    let mut end = PubSub::new(XTransport::new(Peer::new(wire), TransportConfig::default()));
+    let gone = end.subscribe("old").expect("subscribe");
+    assert!(end.unsubscribe(gone).expect("unsubscribe"));
+    let current = end.subscribe("new").expect("subscribe");
+
+    let publication = end.recv().expect("publication");
+    assert_eq!((publication.topic.as_str(), publication.data), ("new", b"kept".to_vec()));
+    assert_eq!(publication.subscribers, [current]);
+    assert_eq!(end.recv().expect_err("end of stream").kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn malformed_frames_fail() {
+    let short = Packet::new(PacketType::Data, 0, vec![3, 0]).to_wire();
+    let undeclared = frames(&[(3, 7, b"data")]);
+    let not_utf8 = frames(&[(0, 0, &[0xff, 0xfe])]);
+    let unknown_kind = frames(&[(0, 0, b"topic"), (9, 0, b"")]);
+    for wire in [short, undeclared, not_utf8, unknown_kind] {
+        let mut end = PubSub::new(XTransport::new(Peer::new(wire), TransportConfig::default()));
+        assert_eq!(end.recv().expect_err("malformed frame accepted").kind(), ErrorKind::InvalidPacket);
+    }
+}
@@ -0,0 +1,132 @@
+//! Reassembly of multi-packet messages: bodies rejected as too large are
+//! skipped for a bounded number of messages, lost packets are detected
+//! across interleaved bodies and chunks carrying offsets are placed in order
+
+mod common;
+
+use common::{packets, pair, Peer, Tap};
+use std::thread;
+use xtransport::error::ErrorKind;
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::{Capabilities, CrcPolicy, Feature, TransportConfig, XTransport};
+
+const MESSAGES: usize = 100;
+
+/// Message ID of a MessageHead or MessageData packet
+fn message_id(packet: &Packet) -> u64 {
+    // A head starts with the total length
+    let offset = if packet.header.pkt_type == PacketType::MessageHead as u8 { 8 } else { 0 };
+    u64::from_le_bytes(packet.data[offset..offset + 8].try_into().expect("message ID"))
+}
+
+/// Renumber `packets` from sequence number 0 and join them into a stream
+fn stream(packets: Vec<&Packet>) -> Vec<u8> {
+    wires(packets).concat()
+}
+
+/// Renumber `packets` from sequence number 0, each into its own wire bytes
+fn wires(packets: Vec<&Packet>) -> Vec<Vec<u8>> {
+    packets.into_iter().enumerate()
+        .map(|(seq, packet)| {
+            let pkt_type = PacketType::from_u8(packet.header.pkt_type).expect("packet type");
+            Packet::new(pkt_type, seq as u32, packet.data.clone()).to_wire()
+        })
+        .collect()
+}
+
+#[test]
+fn rejected_bodies_are_tracked_for_a_bounded_number_of_messages() {
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default().with_max_frame_size(256));
+    for i in 0..MESSAGES {
+        sender.send_message(&[i as u8; 1000]).expect("send");
+    }
+    sender.send_message(b"small").expect("send");
+    let sent = packets(&sender.get_ref().output);
+    let heads: Vec<&Packet> = sent.iter().filter(|packet| packet.header.pkt_type == PacketType::MessageHead as u8).collect();
+    let body = |id| sent.iter().find(|packet| packet.header.pkt_type == PacketType::MessageData as u8 && message_id(packet) == id).expect("body");
+    let (first, last) = (message_id(heads[0]), message_id(heads[MESSAGES - 1]));
+    let small = sent.last().expect("small message");
+
+    // Every head arrives before any body, as from a peer that never sends them
+    let mut input = heads.clone();
+    input.extend([body(last), small, body(first)]);
+    let config = TransportConfig::default().with_max_message_size(500);
+    let mut receiver = XTransport::new(Peer::new(stream(input)), config);
+    for _ in 0..MESSAGES {
+        assert_eq!(receiver.recv_message().expect_err("oversized message").kind(), ErrorKind::MessageTooLarge);
+    }
+    // The body of a recent rejected message is still skipped, that of the oldest no longer
+    assert_eq!(receiver.recv_message().expect("message after the skipped body"), b"small");
+    assert_eq!(receiver.recv_message().expect_err("forgotten message").kind(), ErrorKind::InvalidPacket);
+}
+
+#[test]
+fn packet_lost_between_interleaved_bodies_fails_its_message() {
+    let mut sender = XTransport::new(Peer::new(Vec::new()), TransportConfig::default().with_max_frame_size(256));
+    sender.send_message(&[1; 600]).expect("send");
+    sender.send_message(&[2; 400]).expect("send");
+    sender.send_message(b"after").expect("send");
+    let sent = packets(&sender.get_ref().output);
+    let is_head = |packet: &Packet| packet.header.pkt_type == PacketType::MessageHead as u8;
+    let (first, second) = match sent.iter().filter(|packet| is_head(packet)).collect::<Vec<_>>()[..] {
+        [first, second] => (first, second),
+        _ => panic!("expected two multi-packet messages"),
+    };
+    let body = |head: &Packet| -> Vec<&Packet> {
+        sent.iter().filter(|packet| packet.header.pkt_type == PacketType::MessageData as u8 && message_id(packet) == message_id(head)).collect()
+    };
+    let (a, b) = (body(first), body(second));
+    assert_eq!((a.len(), b.len()), (3, 2));
+
+    // The second body completes in the middle of the first, whose middle packet is lost
+    let mut input = wires(vec![first, a[0], second, b[0], b[1], a[1], a[2], sent.last().expect("small message")]);
+    *input[5].last_mut().expect("packet") ^= 0xff;
+    let config = TransportConfig::default().with_crc_policy(CrcPolicy::Drop);
+    let mut receiver = XTransport::new(Peer::new(input.concat()), config);
+    assert_eq!(receiver.recv_message().expect("interleaved message"), vec![2; 400]);
+    let error = receiver.recv_message().expect_err("message with a lost packet");
+    assert_eq!(error.kind(), ErrorKind::MissingPacket { expected: 2, got: 6 });
+    assert_eq!(receiver.recv_message().expect("message after the dropped one"), b"after");
+}
+
+fn offsets_config() -> TransportConfig {
+    TransportConfig::default()
+        .with_max_frame_size(256)
+        .with_capabilities(Capabilities::new().with_feature(Feature::FragmentOffsets))
+}
+
+/// Packets written sending `message` after a handshake that switches chunk offsets on
+fn sent_with_offsets(message: &[u8]) -> Vec<Packet> {
+    let (a, b) = pair();
+    let receiver = thread::spawn(move || XTransport::new(b, offsets_config()).recv_message().expect("message"));
+    let mut sender = XTransport::new(Tap::new(a), offsets_config());
+    sender.handshake().expect("handshake");
+    sender.send_message(message).expect("send");
+    assert_eq!(receiver.join().expect("receiver"), message);
+    sender.get_ref().packets()
+}
+
+/// Replay the handshake and head of `sent`, then its chunks in the order of `chunks`
+fn replay(sent: &[Packet], chunks: &[usize]) -> XTransport<Peer> {
+    let head = sent.iter().position(|packet| packet.header.pkt_type == PacketType::MessageHead as u8).expect("head");
+    let body = &sent[head + 1..];
+    assert_eq!(body.len(), 3);
+    let input = sent[..=head].iter().chain(chunks.iter().map(|&i| &body[i])).collect();
+    XTransport::new(Peer::new(stream(input)), offsets_config())
+}
+
+#[test]
+fn chunks_carrying_offsets_are_placed_in_order() {
+    let message: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+    let sent = sent_with_offsets(&message);
+    let mut receiver = replay(&sent, &[2, 0, 1]);
+    assert_eq!(receiver.recv_message().expect("reordered message"), message);
+}
+
+#[test]
+fn chunk_overlapping_received_bytes_fails_its_message() {
+    let message: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+    let sent = sent_with_offsets(&message);
+    let mut receiver = replay(&sent, &[0, 0, 1]);
+    assert_eq!(receiver.recv_message().expect_err("repeated chunk accepted").kind(), ErrorKind::InvalidPacket);
+}
@@ -0,0 +1,100 @@
+//! Recording a connection and replaying it under the recorded settings
+
+mod common;
+
+use common::Peer;
+use xtransport::capability::Capabilities;
+use xtransport::clock::StdClock;
+use xtransport::error::ErrorKind;
+use xtransport::protocol::{Packet, PacketType};
+use xtransport::replay::{Event, EventLog, RecordedConfig, Recorder, RecordingClock};
+use xtransport::{Result, TransportConfig, XTransport};
+
+fn config() -> TransportConfig {
+    TransportConfig::new().with_ack(true).with_window(4)
+}
+
+/// Log of a receiver in ACK mode taking three messages, then the end of the stream
+fn record() -> EventLog {
+    let input = (0..3).flat_map(|seq| Packet::new(PacketType::Data, seq, vec![seq as u8; 50]).to_wire()).collect();
+    let log = EventLog::new(&config());
+    let config = config().with_clock(RecordingClock::new(StdClock::new(), log.clone()));
+    let mut receiver = XTransport::new(Recorder::new(Peer::new(input), log.clone()), config);
+    for seq in 0..3u8 {
+        assert_eq!(receiver.recv_message().expect("message"), vec![seq; 50]);
+    }
+    assert_eq!(receiver.recv_message().expect_err("end of stream").kind(), ErrorKind::UnexpectedEof);
+    log
+}
+
+/// Kind of the error `result` must hold; neither the log nor a transport prints with `{:?}`
+fn error_kind<T>(result: Result<T>) -> ErrorKind {
+    match result {
+        Ok(_) => panic!("unexpected success"),
+        Err(e) => e.kind(),
+    }
+}
+
+#[test]
+fn replay_reproduces_the_recorded_writes() {
+    let log = EventLog::decode(&record().encode()).expect("decode");
+    assert_eq!(log.config(), RecordedConfig::of(&config()));
+    assert!(log.events().iter().any(|event| matches!(event, Event::Write(_))), "the ACKs were recorded");
+
+    let mut replay = log.replay(config()).expect("replay");
+    for seq in 0..3u8 {
+        assert_eq!(replay.recv_message().expect("message"), vec![seq; 50]);
+    }
+    assert_eq!(replay.recv_message().expect_err("end of stream").kind(), ErrorKind::UnexpectedEof);
+    assert!(replay.get_ref().is_exhausted());
+    assert_eq!(replay.get_ref().divergences(), 0);
+}
+
+#[test]
+fn altered_write_counts_as_a_divergence() {
+    // Same reads, but the recording claims the first ACK acknowledged another packet
+    let recorded = record();
+    let log = EventLog::new(&config());
+    let mut altered = false;
+    for event in recorded.events() {
+        match event {
+            Event::Write(mut data) if !altered => {
+                let last = data.len() - 1;
+                data[last] ^= 0xff;
+                altered = true;
+                log.push(Event::Write(data));
+            }
+            event => log.push(event),
+        }
+    }
+
+    let mut replay = log.replay(config()).expect("replay");
+    for seq in 0..3u8 {
+        assert_eq!(replay.recv_message().expect("message"), vec![seq; 50]);
+    }
+    assert!(replay.get_ref().divergences() > 0);
+}
+
+#[test]
+fn replay_with_other_settings_is_rejected() {
+    let log = record();
+    let others: [fn() -> TransportConfig; 4] = [
+        || config().with_ack(false),
+        || config().with_window(8),
+        || config().with_max_frame_size(1024),
+        || config().with_capabilities(Capabilities::new()),
+    ];
+    for other in others {
+        assert_eq!(error_kind(log.replay(other())), ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn log_without_a_header_is_rejected() {
+    let mut encoded = record().encode();
+    assert_eq!(error_kind(EventLog::decode(&encoded[..10])), ErrorKind::InvalidInput);
+    encoded[4] = 0xff;
+    assert_eq!(error_kind(EventLog::decode(&encoded)), ErrorKind::InvalidVersion);
+    encoded[0] ^= 0xff;
+    assert_eq!(error_kind(EventLog::decode(&encoded)), ErrorKind::InvalidMagic);
+}
@@ -0,0 +1,112 @@
+//! Recovering the packet stream after garbage, within the configured limit
+
+mod common;
+
+use common::{packets, Peer};
+use std::io::{Read, Write};
+use xtransport::error::ErrorKind;
+use xtransport::protocol::{Packet, PacketHeader, PacketType};
+use xtransport::{TransportConfig, XTransport, VERSION_2};
+
+fn data(seq: u32, text: &[u8]) -> Vec<u8> {
+    Packet::new(PacketType::Data, seq, text.to_vec()).to_wire()
+}
+
+/// Two messages with `garbage` between them
+fn around(garbage: Vec<u8>) -> Vec<u8> {
+    [data(0, b"before"), garbage, data(1, b"after")].concat()
+}
+
+/// Peer handing out at most `chunk` bytes per read, as a slow serial link would
+struct Trickle {
+    peer: Peer,
+    chunk: usize,
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.chunk);
+        self.peer.read(&mut buf[..n])
+    }
+}
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.peer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Receive both messages around `garbage`, returning the second and the number of resyncs
+fn recover(garbage: Vec<u8>) -> (Vec<u8>, u64) {
+    let mut receiver = XTransport::new(Peer::new(around(garbage)), TransportConfig::default().with_resync(64));
+    assert_eq!(receiver.recv_message().expect("first"), b"before");
+    let second = receiver.recv_message().expect("second");
+    (second, receiver.stats().resyncs)
+}
+
+#[test]
+fn garbage_within_the_limit_is_skipped() {
+    assert_eq!(recover(vec![0xee; 40]), (b"after".to_vec(), 1));
+}
+
+#[test]
+fn garbage_beyond_the_limit_fails() {
+    for config in [TransportConfig::default(), TransportConfig::default().with_resync(64)] {
+        let mut receiver = XTransport::new(Peer::new(around(vec![0xee; 200])), config);
+        assert_eq!(receiver.recv_message().expect("first"), b"before");
+        assert_eq!(receiver.recv_message().expect_err("garbage accepted").kind(), ErrorKind::InvalidMagic);
+        assert_eq!(receiver.stats().resyncs, 0);
+        // The connection is torn down rather than left to guess at packet boundaries
+        let written = packets(&receiver.into_parts().0.output);
+        assert!(written.iter().any(|packet| packet.header.pkt_type == PacketType::Goaway as u8));
+    }
+}
+
+#[test]
+fn corrupted_header_loses_only_its_packet() {
+    let mut lost = data(1, b"lost");
+    lost[0] ^= 0xff;
+    let stream = [data(0, b"before"), lost, data(2, b"after")].concat();
+    let mut receiver = XTransport::new(Peer::new(stream), TransportConfig::default().with_resync(64));
+    assert_eq!(receiver.recv_message().expect("first"), b"before");
+    // Without ACKs the lost packet is skipped like one dropped for its CRC
+    assert_eq!(receiver.recv_message().expect("second"), b"after");
+    assert_eq!(receiver.stats().resyncs, 1);
+}
+
+#[test]
+fn oversized_length_counts_as_malformed() {
+    let mut header = Vec::new();
+    PacketHeader::new(PacketType::Data, 1, 60_000).write_to(&mut header);
+    assert_eq!(recover(header), (b"after".to_vec(), 1));
+}
+
+#[test]
+fn packet_failing_its_crc_in_the_garbage_is_passed_over() {
+    let mut fake = data(5, b"fake");
+    *fake.last_mut().unwrap() ^= 0xff;
+    let garbage = [vec![0xee; 8], fake, vec![0xee; 8]].concat();
+    assert_eq!(recover(garbage), (b"after".to_vec(), 1));
+}
+
+#[test]
+fn version_2_packet_in_the_garbage_is_not_taken_before_negotiation() {
+    let mut packet = Packet::new(PacketType::Data, 5, b"v2".to_vec());
+    packet.header.version = VERSION_2;
+    let garbage = [vec![0xee; 8], packet.to_wire()].concat();
+    // Taking it would only fail again on its version, for a second resync
+    assert_eq!(recover(garbage), (b"after".to_vec(), 1));
+}
+
+#[test]
+fn garbage_spread_over_several_reads_is_skipped() {
+    let peer = Peer::new(around(vec![0xee; 40]));
+    let mut receiver = XTransport::new(Trickle { peer, chunk: 7 }, TransportConfig::default().with_resync(64));
+    assert_eq!(receiver.recv_message().expect("first"), b"before");
+    assert_eq!(receiver.recv_message().expect("second"), b"after");
+    assert_eq!(receiver.stats().resyncs, 1);
+}
@@ -0,0 +1,70 @@
+//! Channel self-test against a peer echoing the scripted exchange
+
+mod common;
+
+use common::pair;
+use std::thread;
+use xtransport::error::ErrorKind;
+use xtransport::selftest::SELF_TEST_SMALL_FRAMES;
+use xtransport::{TransportConfig, XTransport};
+
+#[test]
+fn self_test_runs_the_whole_script() {
+    let configs: [fn() -> TransportConfig; 3] = [
+        TransportConfig::default,
+        || TransportConfig::default().with_ack(true).with_window(4),
+        // The padded pings stop short of doubling past an odd limit
+        || TransportConfig { max_payload_size: 1000, ..TransportConfig::default() },
+    ];
+    for config in configs {
+        let limit = config().max_payload_size;
+        let (a, b) = pair();
+        let peer = thread::spawn(move || XTransport::new(b, config()).answer_self_test().expect("answer"));
+        let mut tester = XTransport::new(a, config());
+        let report = tester.self_test().expect("self-test");
+
+        // The small messages and the fragmented one
+        assert_eq!(peer.join().expect("peer"), SELF_TEST_SMALL_FRAMES + 1);
+        assert!(report.echo_intact && report.in_order);
+        assert_eq!(report.max_payload_size, limit);
+        assert!(report.rtt_min_micros <= report.rtt_avg_micros && report.rtt_avg_micros <= report.rtt_max_micros);
+        assert!(report.frames_per_sec > 0.0 && report.bytes_per_sec > 0.0);
+        assert!(report.peer_has_clock);
+        assert!(tester.is_write_shutdown() && tester.is_peer_write_shutdown());
+    }
+}
+
+#[test]
+fn damaged_echo_is_reported() {
+    let (a, b) = pair();
+    let peer = thread::spawn(move || {
+        let mut transport = XTransport::new(b, TransportConfig::default());
+        // Echo everything with its last byte changed, then close like `answer_self_test`
+        while let Ok(mut message) = transport.recv_message() {
+            *message.last_mut().expect("non-empty") ^= 0xff;
+            transport.send_message(&message).expect("echo");
+        }
+        transport.shutdown_write().expect("shutdown");
+    });
+    let report = XTransport::new(a, TransportConfig::default()).self_test().expect("self-test");
+    peer.join().expect("peer");
+    assert!(!report.echo_intact);
+    assert!(report.in_order, "damage is not reordering");
+}
+
+#[test]
+fn silent_peer_fails_the_test() {
+    // A peer that is not answering the self-test gives no Pong before the read timeout
+    let (a, _b) = pair();
+    a.set_read_timeout(Some(std::time::Duration::from_millis(50))).expect("read timeout");
+    let error = XTransport::new(a, TransportConfig::default()).self_test().expect_err("no replies");
+    assert!(matches!(error.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock), "{:?}", error.kind());
+}
+
+#[test]
+fn self_test_needs_a_clock() {
+    let (a, _b) = pair();
+    let config = TransportConfig { clock: None, ..TransportConfig::default() };
+    let error = XTransport::new(a, config).self_test().expect_err("ran without a clock");
+    assert_eq!(error.kind(), ErrorKind::Unsupported);
+}
@@ -0,0 +1,128 @@
+//! Messages queued from many threads and sent by one pump
+
+mod common;
+
+use common::{packets, Peer};
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use xtransport::error::ErrorKind;
+use xtransport::sender::MessageSender;
+use xtransport::{TransportConfig, XTransport};
+
+/// Stream refusing every write
+struct Broken;
+
+impl Read for Broken {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Broken {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sink whose first write waits for the test to let it through
+struct Gate {
+    /// Told when the first write starts, then waited on
+    gate: Option<(Sender<()>, Receiver<()>)>,
+    written: Vec<u8>,
+}
+
+impl Read for Gate {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Gate {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some((writing, release)) = self.gate.take() {
+            writing.send(()).expect("test waiting");
+            release.recv().expect("test releasing");
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn messages_from_every_thread_are_sent() {
+    let transport = XTransport::new(Peer::new(Vec::new()), TransportConfig::default());
+    let (sender, pump) = MessageSender::spawn(transport, 4);
+    let producers: Vec<_> = (0..4u8)
+        .map(|thread| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for i in 0..10u8 {
+                    sender.send(&[thread, i]).expect("queue");
+                }
+                sender.send_wait(&[thread, 0xff]).expect("sent");
+            })
+        })
+        .collect();
+    for producer in producers {
+        producer.join().expect("producer");
+    }
+    drop(sender);
+
+    let (transport, outcome) = pump.join().expect("pump");
+    outcome.expect("every send succeeded");
+    let sent: Vec<Vec<u8>> = packets(&transport.into_parts().0.output).into_iter().map(|packet| packet.data).collect();
+    let expected: BTreeSet<Vec<u8>> = (0..4u8).flat_map(|thread| (0..10u8).chain([0xff]).map(move |i| vec![thread, i])).collect();
+    assert_eq!(sent.iter().cloned().collect::<BTreeSet<_>>(), expected);
+    assert_eq!(sent.len(), expected.len());
+    // Threads interleave, but each one's messages keep the order it queued them in
+    for thread in 0..4u8 {
+        let order: Vec<u8> = sent.iter().filter(|message| message[0] == thread).map(|message| message[1]).collect();
+        assert_eq!(order, (0..10u8).chain([0xff]).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn failed_send_stops_the_pump() {
+    let transport = XTransport::new(Broken, TransportConfig::default());
+    let (sender, pump) = MessageSender::spawn(transport, 4);
+    let kind = sender.send_wait(b"lost").expect_err("write failed").kind();
+
+    let (_, outcome) = pump.join().expect("pump");
+    assert_eq!(outcome.expect_err("pump outcome").kind(), kind);
+    // Later sends fail with the kind that stopped the pump
+    assert_eq!(sender.send(b"late").expect_err("pump gone").kind(), kind);
+    assert_eq!(sender.try_send(b"late").expect_err("pump gone").kind(), kind);
+    assert_ne!(kind, ErrorKind::WriteShutdown);
+}
+
+#[test]
+fn full_queue_refuses_try_send_and_drains_after_the_last_handle() {
+    let (writing, started) = mpsc::channel();
+    let (release, released) = mpsc::channel();
+    let gate = Gate { gate: Some((writing, released)), written: Vec::new() };
+    let (sender, pump) = MessageSender::spawn(XTransport::new(gate, TransportConfig::default()), 1);
+
+    sender.send(b"first").expect("queue");
+    // The pump holds the first message in a write, so the second fills the queue
+    started.recv().expect("pump writing");
+    sender.send(b"second").expect("queue");
+    assert_eq!(sender.try_send(b"third").expect_err("queue full").kind(), ErrorKind::WouldBlock);
+
+    // Messages still queued when the last handle goes are sent before the pump stops
+    drop(sender);
+    release.send(()).expect("pump waiting");
+    let (transport, outcome) = pump.join().expect("pump");
+    outcome.expect("every send succeeded");
+    let sent: Vec<Vec<u8>> = packets(&transport.into_parts().0.written).into_iter().map(|packet| packet.data).collect();
+    assert_eq!(sent, [b"first".to_vec(), b"second".to_vec()]);
+}
@@ -0,0 +1,84 @@
+//! Sequence numbers around the wrap from `u32::MAX` back to 0
+
+use xtransport::window::{SackRanges, SendWindow};
+
+/// Window sizes, powers of two and others, so no size lines up with 2^32 by luck
+const WINDOW_SIZES: [u32; 8] = [1, 2, 3, 5, 7, 8, 13, 64];
+
+fn starts() -> impl Iterator<Item = u32> {
+    // Starts whose windows cross the wrap at every position
+    (0..70).map(|back| 0u32.wrapping_sub(back))
+}
+
+#[test]
+fn window_tracks_every_packet_across_the_wrap() {
+    for size in WINDOW_SIZES {
+        for start in starts() {
+            let mut window = SendWindow::new();
+            for step in 0..size {
+                window.push(start.wrapping_add(step), vec![0; 20], None);
+            }
+            for step in 0..size {
+                let seq = start.wrapping_add(step);
+                assert!(window.contains(seq), "{} in a window of {} from {}", seq, size, start);
+                assert_eq!(window.get(seq).map(|entry| entry.seq), Some(seq));
+            }
+            assert!(!window.contains(start.wrapping_sub(1)));
+            assert!(!window.contains(start.wrapping_add(size)));
+
+            // An ACK for a packet before the window, however near the wrap, acknowledges nothing
+            assert_eq!(window.ack(start.wrapping_sub(1)), 0);
+            assert_eq!(window.len(), size as usize);
+
+            for step in 0..size {
+                let seq = start.wrapping_add(step);
+                assert_eq!(window.ack(seq), 1, "ACK for {} in a window from {}", seq, start);
+                assert_eq!(window.ack(seq), 0, "repeated ACK for {}", seq);
+                assert!(window.get(seq).is_none());
+                assert!(!window.contains(seq));
+            }
+            assert!(window.is_empty());
+            assert_eq!(window.bytes(), 0);
+        }
+    }
+}
+
+#[test]
+fn cumulative_ack_across_the_wrap() {
+    for size in WINDOW_SIZES {
+        for start in starts() {
+            for last in 0..size {
+                let mut window = SendWindow::new();
+                for step in 0..size {
+                    window.push(start.wrapping_add(step), vec![0; 20], None);
+                }
+                assert_eq!(window.ack(start.wrapping_add(last)), last as usize + 1);
+                assert_eq!(window.len(), (size - last - 1) as usize);
+                let oldest = (last + 1 < size).then(|| start.wrapping_add(last + 1));
+                assert_eq!(window.oldest().map(|entry| entry.seq), oldest);
+            }
+        }
+    }
+}
+
+#[test]
+fn sack_ranges_cross_the_wrap() {
+    for start in starts() {
+        // Every other packet arrived after the gap at `start`
+        let received: Vec<u32> = (1..12).step_by(2).map(|step| start.wrapping_add(step)).collect();
+        let ranges = SackRanges::from_received(start, received.iter().copied());
+        let expected: Vec<(u32, u32)> = received.iter().take(4).map(|&seq| (seq, seq.wrapping_add(1))).collect();
+        assert_eq!(ranges.as_slice(), expected.as_slice(), "gap at {}", start);
+        assert_eq!(ranges.first_gap(start), 1);
+
+        // Consecutive packets merge into one range even where they wrap
+        let run: Vec<u32> = (3..9).map(|step| start.wrapping_add(step)).collect();
+        let ranges = SackRanges::from_received(start, run.iter().copied());
+        assert_eq!(ranges.as_slice(), &[(start.wrapping_add(3), start.wrapping_add(9))]);
+        assert_eq!(ranges.first_gap(start), 3);
+
+        // Packets before the next expected one are already delivered and left out
+        let stale = [start.wrapping_sub(2), start.wrapping_sub(1)];
+        assert!(SackRanges::from_received(start, stale).is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+//! Sessions of an `XServer` configured for the peer they were accepted from
+
+mod common;
+
+use common::{pair, recv};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use xtransport::error::ErrorKind;
+use xtransport::server::XServer;
+use xtransport::{Error, TransportConfig, XTransport};
+
+#[test]
+fn sessions_get_the_configuration_of_their_peer() {
+    let (trusted, trusted_end) = pair();
+    let (untrusted, untrusted_end) = pair();
+    // Session threads look at their commands between reads, so shutdown waits for a timeout at most
+    for end in [&trusted_end, &untrusted_end] {
+        end.set_read_timeout(Some(Duration::from_millis(50))).expect("read timeout");
+    }
+    let mut pending = vec![(untrusted_end, "untrusted"), (trusted_end, "trusted")];
+    let accept = move || pending.pop().ok_or_else(|| Error::new(ErrorKind::WouldBlock));
+    let config = |peer: &&str| match *peer {
+        "trusted" => TransportConfig::default(),
+        _ => TransportConfig::default().with_max_message_size(64 * 1024),
+    };
+    let echo = |_, transport: &mut XTransport<UnixStream>, message: Vec<u8>| transport.send_message(&message);
+    let server = XServer::spawn_per_peer(accept, config, echo);
+
+    let message = vec![7; 200_000];
+    let mut client = XTransport::new(trusted, TransportConfig::default());
+    client.send_message(&message).expect("send");
+    assert_eq!(recv(&mut client), message);
+
+    // The same message is beyond what an untrusted peer may send, which ends its session
+    let mut client = XTransport::new(untrusted, TransportConfig::default());
+    let echoed = client.send_message(&message).and_then(|()| client.recv_message());
+    assert!(echoed.is_err(), "untrusted peer got an echo");
+    server.shutdown();
+}
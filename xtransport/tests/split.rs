@@ -0,0 +1,107 @@
+//! Receive and send halves of one transport used from two threads
+
+mod common;
+
+use common::pair;
+use std::thread;
+use std::time::Duration;
+use xtransport::error::ErrorKind;
+use xtransport::{TransportConfig, XTransport};
+
+const MESSAGES: u8 = 20;
+
+fn config() -> TransportConfig {
+    TransportConfig::default().with_ack(true).with_window(4)
+}
+
+#[test]
+fn halves_send_and_receive_at_once() {
+    let (a, b) = pair();
+    let echo = thread::spawn(move || {
+        let mut transport = XTransport::new(b, config());
+        for _ in 0..MESSAGES {
+            let message = transport.recv_message().expect("request");
+            transport.send_message(&message).expect("echo");
+        }
+    });
+
+    // A waiting receive lets go of the transport whenever the read times out
+    a.set_read_timeout(Some(Duration::from_millis(5))).expect("read timeout");
+    let (mut read, mut write) = XTransport::new(a, config()).into_split();
+    let writer = thread::spawn(move || {
+        for i in 0..MESSAGES {
+            write.send_message(&[i; 100]).expect("send");
+        }
+        write
+    });
+    let mut echoed = Vec::new();
+    while echoed.len() < MESSAGES as usize {
+        match read.recv_message() {
+            Ok(message) => echoed.push(message),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => panic!("receive failed: {}", e),
+        }
+    }
+    assert_eq!(echoed, (0..MESSAGES).map(|i| vec![i; 100]).collect::<Vec<_>>());
+    let write = writer.join().expect("writer");
+    echo.join().expect("echo");
+
+    assert!(read.is_pair_of(&write));
+    assert!(read.reunite(write).is_ok());
+}
+
+#[test]
+fn halves_of_different_transports_do_not_reunite() {
+    let (a, b) = pair();
+    let (read, _) = XTransport::new(a, config()).into_split();
+    let (_, write) = XTransport::new(b, config()).into_split();
+    assert!(!read.is_pair_of(&write));
+    let (read, write) = read.reunite(write).err().expect("halves of two transports reunited");
+    assert!(!read.is_pair_of(&write));
+}
+
+#[test]
+fn message_read_by_a_send_waits_for_the_read_half() {
+    let (a, b) = pair();
+    let peer = thread::spawn(move || {
+        let mut transport = XTransport::new(b, config());
+        // Sent before this end's message is read, so it arrives ahead of the ACK
+        transport.send_message(b"early").expect("send");
+        transport.recv_message().expect("request")
+    });
+    let (mut read, mut write) = XTransport::new(a, config()).into_split();
+    write.send_message(b"request").expect("send");
+    assert_eq!(peer.join().expect("peer"), b"request");
+    assert_eq!(read.recv_message().expect("receive"), b"early");
+}
+
+#[test]
+fn write_half_shutdown_leaves_the_read_half_open() {
+    let (a, b) = pair();
+    let peer = thread::spawn(move || {
+        let mut transport = XTransport::new(b, config());
+        let error = transport.recv_message().expect_err("message after the Fin");
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        transport.send_message(b"reply").expect("send");
+    });
+    let (mut read, mut write) = XTransport::new(a, config()).into_split();
+    write.shutdown_write().expect("shutdown");
+    assert_eq!(write.send_message(b"more").expect_err("send after shutdown").kind(), ErrorKind::WriteShutdown);
+    assert_eq!(read.recv_message().expect("receive"), b"reply");
+    peer.join().expect("peer");
+}
+
+#[test]
+fn reunited_transport_carries_on_where_the_halves_left_off() {
+    let (a, b) = pair();
+    let peer = thread::spawn(move || {
+        let mut transport = XTransport::new(b, config());
+        (0..3).map(|_| transport.recv_message().expect("message")).collect::<Vec<_>>()
+    });
+    let (read, mut write) = XTransport::new(a, config()).into_split();
+    write.send_message(b"one").expect("send");
+    write.send_message(b"two").expect("send");
+    let mut transport = read.reunite(write).ok().expect("halves of one transport");
+    transport.send_message(b"three").expect("send");
+    assert_eq!(peer.join().expect("peer"), [b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+}
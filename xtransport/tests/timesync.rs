@@ -0,0 +1,124 @@
+//! Clock offset and drift estimated from PING/PONG timestamps
+
+mod common;
+
+use common::pair;
+use std::thread;
+use std::time::Instant;
+use xtransport::error::ErrorKind;
+use xtransport::timesync::TimeSync;
+use xtransport::{TransportConfig, XTransport};
+
+/// Microseconds since `start`, shifted by `offset`
+fn clock(start: Instant, offset: u64) -> impl Fn() -> u64 + Send + 'static {
+    move || start.elapsed().as_micros() as u64 + offset
+}
+
+fn no_clock() -> TransportConfig {
+    TransportConfig { clock: None, ..TransportConfig::new() }
+}
+
+#[test]
+fn estimator_finds_offset_and_drift() {
+    assert!(TimeSync::new().estimate().is_none());
+
+    // The peer clock runs 5 ms ahead and gains 20 µs per second; every exchange takes 200 µs each way
+    let mut sync = TimeSync::new();
+    for i in 0..10u64 {
+        let t0 = 1_000_000 + i * 500_000;
+        let peer = |local: u64| local + 5_000 + (local - 1_000_000) / 50_000;
+        sync.add_sample(t0, peer(t0 + 200), peer(t0 + 300), t0 + 500);
+    }
+    let estimate = sync.estimate().expect("estimate");
+    assert_eq!(estimate.samples, 10);
+    assert_eq!(estimate.round_trip_micros, 400);
+    assert!((estimate.drift_ppm - 20.0).abs() < 1.0, "drift {}", estimate.drift_ppm);
+    // The offset of the first sample, carried forward by 4.5 s of drift
+    assert!((estimate.offset_micros - 5_090).abs() <= 10, "offset {}", estimate.offset_micros);
+}
+
+#[test]
+fn fastest_exchange_sets_the_offset() {
+    // A ping held up 10 ms on the way out skews its offset sample by half of that;
+    // the exchange without queuing delay wins. Both lie within a second, so no drift.
+    let mut sync = TimeSync::new();
+    let (slow, fast) = (1_000_000, 2_000_000);
+    sync.add_sample(slow, slow + 11_200, slow + 11_300, slow + 10_500);
+    sync.add_sample(fast, fast + 1_200, fast + 1_300, fast + 500);
+    let estimate = sync.estimate().expect("estimate");
+    assert_eq!(estimate.offset_micros, 1_000);
+    assert_eq!(estimate.round_trip_micros, 400);
+    assert_eq!(estimate.drift_ppm, 0.0);
+}
+
+#[test]
+fn drift_waits_for_a_second_of_samples() {
+    // Offsets grow by 100 µs per 100 ms, but 0.9 s of samples is too short to trust
+    let mut sync = TimeSync::new();
+    for i in 0..10u64 {
+        let t0 = i * 100_000;
+        sync.add_sample(t0, t0 + 100 + i * 100, t0 + 100 + i * 100, t0 + 200);
+    }
+    assert_eq!(sync.estimate().expect("estimate").drift_ppm, 0.0);
+
+    sync.add_sample(1_000_000, 1_001_100, 1_001_100, 1_000_200);
+    assert!(sync.estimate().expect("estimate").drift_ppm > 0.0);
+}
+
+#[test]
+fn only_recent_samples_are_kept() {
+    let mut sync = TimeSync::new();
+    for i in 0..40u64 {
+        sync.add_sample(i * 1_000, i * 1_000 + 100, i * 1_000 + 100, i * 1_000 + 200);
+    }
+    assert_eq!(sync.estimate().expect("estimate").samples, 32);
+}
+
+#[test]
+fn sync_time_measures_the_peer_clock() {
+    let start = Instant::now();
+    let (a, b) = pair();
+    let peer = thread::spawn(move || {
+        let mut transport = XTransport::new(b, TransportConfig::new().with_clock(clock(start, 1_000_000)));
+        // Pings are answered inside the receive, which ends when the other side hangs up
+        transport.recv_message().expect_err("no message is sent").kind()
+    });
+    let mut transport = XTransport::new(a, TransportConfig::new().with_clock(clock(start, 0)));
+    assert!(transport.time_sync().is_none());
+    for _ in 0..5 {
+        let estimate = transport.sync_time().expect("sync");
+        let error = (estimate.offset_micros - 1_000_000).unsigned_abs();
+        assert!(error <= estimate.round_trip_micros.max(1_000), "offset {}", estimate.offset_micros);
+    }
+    assert_eq!(transport.time_sync().expect("estimate").samples, 5);
+    drop(transport);
+    assert_eq!(peer.join().expect("peer"), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn messages_arriving_during_sync_are_kept() {
+    let (a, b) = pair();
+    let peer = thread::spawn(move || {
+        let mut transport = XTransport::new(b, TransportConfig::new());
+        transport.send_message(b"sent before the pong").expect("send");
+        transport.recv_message().is_err()
+    });
+    let mut transport = XTransport::new(a, TransportConfig::new());
+    transport.sync_time().expect("sync");
+    assert_eq!(transport.recv_message().expect("message"), b"sent before the pong");
+    drop(transport);
+    assert!(peer.join().expect("peer"));
+}
+
+#[test]
+fn sync_time_needs_both_clocks() {
+    let (a, _b) = pair();
+    assert_eq!(XTransport::new(a, no_clock()).sync_time().expect_err("no local clock").kind(), ErrorKind::Unsupported);
+
+    let (a, b) = pair();
+    let peer = thread::spawn(move || XTransport::new(b, no_clock()).recv_message().is_err());
+    let mut transport = XTransport::new(a, TransportConfig::new());
+    assert_eq!(transport.sync_time().expect_err("peer has no clock").kind(), ErrorKind::Unsupported);
+    drop(transport);
+    assert!(peer.join().expect("peer"));
+}